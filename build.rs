@@ -0,0 +1,38 @@
+//! Records the ABI the server is built with, which libraries must match to be loaded.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn locked_version(lockfile: &Path, package: &str) -> Option<String> {
+    let data = fs::read_to_string(lockfile).ok()?;
+    let name = format!("name = \"{}\"", package);
+    let mut lines = data.lines().skip_while(|l| l.trim() != name).skip(1);
+    lines
+        .next()?
+        .trim()
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(String::from)
+}
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .expect("failed to run rustc");
+
+    let lockfile = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    let funck_version = locked_version(&lockfile, "funck").expect("funck missing from Cargo.lock");
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rustc-env=FUNCKY_ABI_FUNCK={}", funck_version);
+    println!("cargo:rustc-env=FUNCKY_ABI_RUSTC={}", rustc_version);
+    println!(
+        "cargo:rustc-env=FUNCKY_ABI_TARGET={}",
+        env::var("TARGET").unwrap()
+    );
+}
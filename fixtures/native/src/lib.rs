@@ -0,0 +1,86 @@
+//! A library exercising the loader in the server's tests: it exports a registry of two functions
+//! and both lifecycle hooks.
+//!
+//! Its init hook fails when the configuration has a `fail` message, and its shutdown hook writes
+//! the file named by `shutdown_marker`. Functions reply with their name and the `greeting` of the
+//! configuration.
+
+#[path = "../../../samples/testfn/src/abi.rs"]
+mod abi;
+
+use std::fs;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use funck::{CallResult, Funcktion, Request, Response};
+
+use serde_json::Value;
+
+static CONFIG: Mutex<Option<Value>> = Mutex::new(None);
+
+fn config(key: &str) -> Option<String> {
+    let config = CONFIG.lock().ok()?;
+    config.as_ref()?.get(key)?.as_str().map(String::from)
+}
+
+#[derive(Default)]
+pub struct Alpha;
+
+impl Alpha {
+    fn run(&self, _request: Request) -> CallResult<Response> {
+        Ok(Response::new().with_text(format!("alpha:{}", config("greeting").unwrap_or_default())))
+    }
+}
+
+funck::export!(Alpha, Alpha::run, "alpha");
+
+pub struct Beta;
+
+impl Funcktion for Beta {
+    fn name(&self) -> &'static str {
+        "beta"
+    }
+
+    fn _call_internal(&self, request: Request) -> CallResult<Response> {
+        Ok(Response::new().with_bytes(Vec::from(request.body())))
+    }
+}
+
+#[no_mangle]
+pub fn _funck_create_all() -> Vec<Box<dyn Funcktion>> {
+    vec![Box::new(Alpha), Box::new(Beta)]
+}
+
+/// # Safety
+///
+/// Called by the server with a valid configuration and error buffer.
+#[no_mangle]
+pub unsafe extern "C" fn _funck_init(
+    config: *const u8,
+    config_len: usize,
+    error: *mut u8,
+    error_len: usize,
+) -> i32 {
+    let config: Value = match serde_json::from_slice(slice::from_raw_parts(config, config_len)) {
+        Ok(config) => config,
+        Err(_) => return 1,
+    };
+    if let Some(message) = config.get("fail").and_then(Value::as_str) {
+        let len = message.len().min(error_len - 1);
+        ptr::copy_nonoverlapping(message.as_ptr(), error, len);
+        *error.add(len) = 0;
+        return 2;
+    }
+    if let Ok(mut slot) = CONFIG.lock() {
+        *slot = Some(config);
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn _funck_shutdown() {
+    if let Some(path) = config("shutdown_marker") {
+        fs::write(path, b"shut down").ok();
+    }
+}
@@ -3,14 +3,17 @@ use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 use clap::Clap;
 
 use reqwest::multipart;
 use rood::cli::OutputManager;
 
-use snafu::{ResultExt, Snafu};
+use sha2::{Digest, Sha256};
+
+use snafu::{ensure, ResultExt, Snafu};
 
 use tempfile::TempDir;
 
@@ -18,17 +21,45 @@ use crate::sysutil::zip;
 
 const DEPLOYABLE_FILES: [&str; 3] = ["Cargo.toml", "Cargo.lock", "src"];
 
+/// Directory the dependencies are vendored to, which the server builds from when present.
+const VENDOR_DIR: &str = "vendor";
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     FailedToGetCurrentDirectory { source: io::Error },
     FailedToListFiles { source: io::Error },
+    FailedToVendor { source: io::Error },
+    VendorExitCodeNonZero { code: i32 },
     FailedToCompress { source: io::Error },
     FailedToReadBundle { source: io::Error },
     FailedToUploadBundle { source: reqwest::Error },
+    FailedToBuild { source: io::Error },
+    BuildExitCodeNonZero { code: i32 },
+    MissingArtifact,
+    FailedToReadArtifact { source: io::Error },
+    #[snafu(display("Server responded with {}: {}", status, message))]
+    DeployRejected { status: u16, message: String },
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Fails on an error status, with the message of the JSON error body when there is one.
+async fn check_response(resp: reqwest::Response) -> Result<()> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = resp.text().await.context(FailedToUploadBundle)?;
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(String::from))
+        .unwrap_or(body);
+    Err(Error::DeployRejected {
+        status: status.as_u16(),
+        message,
+    })
+}
+
 #[derive(Clap)]
 pub struct DeployCommand {
     /// The path of the function to deploy.
@@ -42,12 +73,47 @@ pub struct DeployCommand {
     /// The port of the funck server.
     #[clap(default_value = "3030")]
     port: u16,
+
+    /// The namespace to deploy the function to.
+    #[clap(short = "n", long = "namespace")]
+    namespace: Option<String>,
+
+    /// The token of the namespace.
+    #[clap(long = "token")]
+    token: Option<String>,
+
+    /// Vendor the dependencies into the bundle, so the server builds it offline.
+    #[clap(long = "vendor")]
+    vendor: bool,
+
+    /// Cargo features to build the function with, comma-separated.
+    #[clap(long = "features")]
+    features: Option<String>,
+
+    /// Build the function without its default features.
+    #[clap(long = "no-default-features")]
+    no_default_features: bool,
+
+    /// The cargo profile to build the function with.
+    #[clap(long = "profile")]
+    profile: Option<String>,
+
+    /// RUSTFLAGS to build the function with.
+    #[clap(long = "rustflags")]
+    rustflags: Option<String>,
+
+    /// Build the function locally and upload the library, instead of its sources.
+    #[clap(long = "prebuilt")]
+    prebuilt: bool,
 }
 
 impl DeployCommand {
     fn get_deployable_files(&self) -> io::Result<Vec<PathBuf>> {
-        let fileset: HashSet<String> =
+        let mut fileset: HashSet<String> =
             HashSet::from_iter(DEPLOYABLE_FILES.iter().map(|s| String::from(*s)));
+        if self.vendor {
+            fileset.insert(String::from(VENDOR_DIR));
+        }
 
         Ok(fs::read_dir(&self.path)?
             .filter_map(|f| f.ok())
@@ -56,6 +122,123 @@ impl DeployCommand {
             .collect())
     }
 
+    fn vendor_dependencies(&self) -> Result<()> {
+        let status = Command::new("cargo")
+            .arg("vendor")
+            .arg(VENDOR_DIR)
+            .current_dir(&self.path)
+            .stdout(Stdio::null())
+            .status()
+            .context(FailedToVendor)?;
+        ensure!(
+            status.success(),
+            VendorExitCodeNonZero {
+                code: status.code().unwrap_or(-1)
+            }
+        );
+        Ok(())
+    }
+
+    /// Builds the function locally, returning the path of its library.
+    fn build(&self, dir: &Path) -> Result<PathBuf> {
+        let mut command = Command::new("cargo");
+        command
+            .args(["build", "--message-format=json-render-diagnostics"])
+            .arg("--profile")
+            .arg(self.profile.as_deref().unwrap_or("release"))
+            .current_dir(dir)
+            .stdout(Stdio::piped());
+        if let Some(features) = &self.features {
+            command.arg("--features").arg(features);
+        }
+        if self.no_default_features {
+            command.arg("--no-default-features");
+        }
+        if let Some(rustflags) = &self.rustflags {
+            command.env("RUSTFLAGS", rustflags);
+        }
+
+        let out = command.output().context(FailedToBuild)?;
+        ensure!(
+            out.status.success(),
+            BuildExitCodeNonZero {
+                code: out.status.code().unwrap_or(-1)
+            }
+        );
+
+        // The function's own cdylib, not one of a dependency.
+        let manifest_path = dir.join("Cargo.toml");
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+            .filter(|m| m["reason"] == "compiler-artifact")
+            .filter(|m| m["manifest_path"].as_str().map(Path::new) == Some(&manifest_path))
+            .filter(|m| {
+                m["target"]["kind"]
+                    .as_array()
+                    .is_some_and(|k| k.iter().any(|k| k == "cdylib"))
+            })
+            .flat_map(|m| {
+                m["filenames"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|f| f.as_str().map(PathBuf::from))
+                    .collect::<Vec<_>>()
+            })
+            .find(|f| matches!(f.extension().and_then(OsStr::to_str), Some("so" | "wasm")))
+            .ok_or(Error::MissingArtifact)
+    }
+
+    async fn build_and_upload(
+        &self,
+        output: OutputManager,
+        dir: &Path,
+        name: &OsStr,
+    ) -> Result<()> {
+        output.step("Build library");
+        let artifact = self.build(dir)?;
+        output
+            .push()
+            .progress(&format!("Library => {}", artifact.display()));
+
+        let data = fs::read(&artifact).context(FailedToReadArtifact)?;
+        let sha256: String = Sha256::digest(&data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let fmted_url = match &self.namespace {
+            Some(ns) => format!(
+                "http://{}:{}/_funck_add_prebuilt/{}",
+                self.host, self.port, ns
+            ),
+            None => format!("http://{}:{}/_funck_add_prebuilt", self.host, self.port),
+        };
+        output.step(&format!("Upload library to {}", fmted_url));
+        let client = reqwest::Client::new();
+
+        let form = multipart::Form::new()
+            .part(
+                "artifact",
+                multipart::Part::bytes(data).file_name(artifact.to_string_lossy().to_string()),
+            )
+            .text("sha256", sha256)
+            .text("name", name.to_string_lossy().to_string())
+            .text(
+                "manifest",
+                fs::read_to_string(dir.join("Cargo.toml")).context(FailedToReadArtifact)?,
+            );
+
+        let mut request = client.post(&fmted_url).multipart(form);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let resp = request.send().await.context(FailedToUploadBundle)?;
+        check_response(resp).await
+    }
+
     async fn zip_and_upload(&self, output: OutputManager, name: &OsStr) -> Result<()> {
         output.step("Create source bundle");
         let files = self.get_deployable_files().context(FailedToListFiles)?;
@@ -69,26 +252,37 @@ impl DeployCommand {
             .push()
             .progress(&format!("Source bundle => {}", zip_path.display()));
 
-        let fmted_url = format!("http://{}:{}/_funck_add", self.host, self.port);
+        let fmted_url = match &self.namespace {
+            Some(ns) => format!("http://{}:{}/_funck_add/{}", self.host, self.port, ns),
+            None => format!("http://{}:{}/_funck_add", self.host, self.port),
+        };
         output.step(&format!("Upload bundle to {}", fmted_url));
         let client = reqwest::Client::new();
 
-        let form = multipart::Form::new().part(
+        let mut form = multipart::Form::new().part(
             "src",
             multipart::Part::bytes(fs::read(&zip_path).context(FailedToReadBundle)?)
                 .file_name(zip_path.to_string_lossy().to_string()),
         );
+        if let Some(features) = &self.features {
+            form = form.text("features", features.clone());
+        }
+        if self.no_default_features {
+            form = form.text("no_default_features", "true");
+        }
+        if let Some(profile) = &self.profile {
+            form = form.text("profile", profile.clone());
+        }
+        if let Some(rustflags) = &self.rustflags {
+            form = form.text("rustflags", rustflags.clone());
+        }
 
-        let resp = client
-            .post(&fmted_url)
-            .multipart(form)
-            .send()
-            .await
-            .context(FailedToUploadBundle)?;
-
-        // TODO: Handle response.
-
-        Ok(())
+        let mut request = client.post(&fmted_url).multipart(form);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let resp = request.send().await.context(FailedToUploadBundle)?;
+        check_response(resp).await
     }
 
     pub async fn run(&self, output: OutputManager) -> Result<()> {
@@ -96,6 +290,16 @@ impl DeployCommand {
         let name = tgt_dir.file_name().unwrap_or("new_funcktion".as_ref());
         output.step(&format!("Deploy [{}]", name.to_string_lossy()));
 
+        if self.prebuilt {
+            self.build_and_upload(output.push(), &tgt_dir, name).await?;
+            output.success("OK");
+            return Ok(());
+        }
+
+        if self.vendor {
+            output.push().step("Vendor dependencies");
+            self.vendor_dependencies()?;
+        }
         self.zip_and_upload(output.push(), name).await?;
 
         output.success("OK");
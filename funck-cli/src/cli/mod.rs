@@ -1,4 +1,5 @@
 mod deploy;
+mod secrets;
 
 use clap::Clap;
 
@@ -10,7 +11,10 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("Deploy failed: {}", source))]
     DeployFailed { source: deploy::Error },
+    #[snafu(display("Secrets command failed: {}", source))]
+    SecretsFailed { source: secrets::Error },
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -19,6 +23,9 @@ type Result<T> = std::result::Result<T, Error>;
 pub enum Action {
     #[clap(name = "deploy")]
     Deploy(deploy::DeployCommand),
+
+    #[clap(name = "secrets")]
+    Secrets(secrets::SecretsCommand),
 }
 
 #[derive(Clap)]
@@ -37,6 +44,7 @@ impl CLI {
 
         match &self.action {
             Action::Deploy(cmd) => cmd.run(output_manager).await.context(DeployFailed)?,
+            Action::Secrets(cmd) => cmd.run(output_manager).await.context(SecretsFailed)?,
         }
 
         Ok(())
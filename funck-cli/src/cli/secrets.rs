@@ -0,0 +1,182 @@
+use std::io::{self, Read};
+
+use clap::Clap;
+
+use rood::cli::OutputManager;
+
+use serde_json::{json, Map, Value};
+
+use snafu::{ResultExt, Snafu};
+
+const SECRETS_ROUTE_PATH: &str = "_secrets";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    FailedToReadValue { source: io::Error },
+    RequestFailed { source: reqwest::Error },
+    #[snafu(display("Invalid response from server: {}", source))]
+    InvalidResponse { source: serde_json::Error },
+    #[snafu(display("Server responded with {}: {}", status, body))]
+    ServerError { status: u16, body: String },
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clap)]
+pub struct SecretsCommand {
+    #[clap(subcommand)]
+    action: SecretsAction,
+}
+
+/// The funck server, given after the other arguments like `deploy` does.
+#[derive(Clap)]
+pub struct Server {
+    /// The host of the funck server.
+    #[clap(default_value = "localhost")]
+    host: String,
+
+    /// The port of the funck server.
+    #[clap(default_value = "3030")]
+    port: u16,
+}
+
+impl Server {
+    fn url(&self, name: Option<&str>) -> String {
+        match name {
+            Some(n) => format!(
+                "http://{}:{}/{}/{}",
+                self.host, self.port, SECRETS_ROUTE_PATH, n
+            ),
+            None => format!("http://{}:{}/{}", self.host, self.port, SECRETS_ROUTE_PATH),
+        }
+    }
+}
+
+#[derive(Clap)]
+pub enum SecretsAction {
+    /// Creates or replaces a secret.
+    #[clap(name = "set")]
+    Set(SetSecret),
+
+    /// Lists secrets and the functions they are bound to.
+    #[clap(name = "list")]
+    List(ListSecrets),
+
+    /// Removes a secret.
+    #[clap(name = "rm")]
+    Remove(RemoveSecret),
+}
+
+#[derive(Clap)]
+pub struct SetSecret {
+    /// The name of the secret.
+    name: String,
+
+    #[clap(flatten)]
+    server: Server,
+
+    /// The value of the secret. Read from stdin if omitted, to keep it out of shell history.
+    #[clap(long = "value")]
+    value: Option<String>,
+
+    /// A function the secret is passed to. Can be repeated.
+    #[clap(short = "f", long = "function", multiple = true, number_of_values = 1)]
+    functions: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct ListSecrets {
+    #[clap(flatten)]
+    server: Server,
+}
+
+#[derive(Clap)]
+pub struct RemoveSecret {
+    /// The name of the secret.
+    name: String,
+
+    #[clap(flatten)]
+    server: Server,
+}
+
+impl SecretsCommand {
+    async fn check(resp: reqwest::Response) -> Result<String> {
+        let status = resp.status();
+        let body = resp.text().await.context(RequestFailed)?;
+        if !status.is_success() {
+            return Err(Error::ServerError {
+                status: status.as_u16(),
+                body,
+            });
+        }
+        Ok(body)
+    }
+
+    async fn set(&self, output: OutputManager, cmd: &SetSecret) -> Result<()> {
+        let value = match &cmd.value {
+            Some(v) => v.clone(),
+            None => {
+                let mut v = String::new();
+                io::stdin()
+                    .read_to_string(&mut v)
+                    .context(FailedToReadValue)?;
+                String::from(v.trim_end_matches('\n'))
+            }
+        };
+
+        output.step(&format!("Set secret [{}]", cmd.name));
+        let body = json!({ "value": value, "functions": cmd.functions });
+        let resp = reqwest::Client::new()
+            .put(&cmd.server.url(Some(&cmd.name)))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .context(RequestFailed)?;
+        SecretsCommand::check(resp).await?;
+
+        output.success("OK");
+        Ok(())
+    }
+
+    async fn list(&self, output: OutputManager, cmd: &ListSecrets) -> Result<()> {
+        let resp = reqwest::get(&cmd.server.url(None))
+            .await
+            .context(RequestFailed)?;
+        let body = SecretsCommand::check(resp).await?;
+
+        let secrets: Map<String, Value> = serde_json::from_str(&body).context(InvalidResponse)?;
+        for (name, info) in secrets.iter() {
+            let functions: Vec<&str> = info["functions"]
+                .as_array()
+                .map(|fns| fns.iter().filter_map(|f| f.as_str()).collect())
+                .unwrap_or_default();
+            output.step(name);
+            output
+                .push()
+                .progress(&format!("functions: {}", functions.join(", ")));
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, output: OutputManager, cmd: &RemoveSecret) -> Result<()> {
+        output.step(&format!("Remove secret [{}]", cmd.name));
+        let resp = reqwest::Client::new()
+            .delete(&cmd.server.url(Some(&cmd.name)))
+            .send()
+            .await
+            .context(RequestFailed)?;
+        SecretsCommand::check(resp).await?;
+
+        output.success("OK");
+        Ok(())
+    }
+
+    pub async fn run(&self, output: OutputManager) -> Result<()> {
+        match &self.action {
+            SecretsAction::Set(cmd) => self.set(output, cmd).await,
+            SecretsAction::List(cmd) => self.list(output, cmd).await,
+            SecretsAction::Remove(cmd) => self.remove(output, cmd).await,
+        }
+    }
+}
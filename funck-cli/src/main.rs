@@ -10,6 +10,7 @@ use cli::CLI;
 #[tokio::main]
 async fn main() {
     if let Err(e) = CLI::parse().run().await {
-        OutputManager::new(true).error(&e.to_string())
+        OutputManager::new(true).error(&e.to_string());
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,21 @@
+// ABI descriptor, checked by the funck server before it loads the library.
+//
+// The values are recorded by `build.rs`. Libraries without a descriptor, or built with a
+// different `funck` version, compiler or target than the server, are rejected.
+
+use std::os::raw::c_char;
+
+const ABI: &str = concat!(
+    "{\"funck\":\"",
+    env!("FUNCKY_ABI_FUNCK"),
+    "\",\"rustc\":\"",
+    env!("FUNCKY_ABI_RUSTC"),
+    "\",\"target\":\"",
+    env!("FUNCKY_ABI_TARGET"),
+    "\"}\0"
+);
+
+#[no_mangle]
+pub extern "C" fn _funck_abi() -> *const c_char {
+    ABI.as_ptr() as *const c_char
+}
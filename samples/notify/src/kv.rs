@@ -0,0 +1,74 @@
+//! Bindings to the key-value store provided by the funck server.
+//!
+//! The server hands its KV host API to the library when loading it, through the exported
+//! `_funck_bind_kv` symbol. Data is scoped to the function, and persists across redeploys.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[repr(C)]
+pub struct KvValue {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+#[repr(C)]
+pub struct KvHost {
+    ctx: *const c_void,
+    get: unsafe extern "C" fn(*const c_void, *const u8, usize, *mut KvValue) -> i32,
+    set: unsafe extern "C" fn(*const c_void, *const u8, usize, *const u8, usize) -> i32,
+    delete: unsafe extern "C" fn(*const c_void, *const u8, usize) -> i32,
+    free: unsafe extern "C" fn(KvValue),
+}
+
+static HOST: AtomicPtr<KvHost> = AtomicPtr::new(ptr::null_mut());
+
+#[no_mangle]
+pub unsafe extern "C" fn _funck_bind_kv(host: *const KvHost) {
+    HOST.store(host as *mut KvHost, Ordering::SeqCst);
+}
+
+#[derive(Debug)]
+pub struct KvError;
+
+fn host() -> Result<&'static KvHost, KvError> {
+    // The server keeps the host API alive for as long as the library is loaded.
+    unsafe { HOST.load(Ordering::SeqCst).as_ref().ok_or(KvError) }
+}
+
+pub fn get(key: &str) -> Result<Option<Vec<u8>>, KvError> {
+    let host = host()?;
+    let mut out = KvValue {
+        ptr: ptr::null_mut(),
+        len: 0,
+        cap: 0,
+    };
+    match unsafe { (host.get)(host.ctx, key.as_ptr(), key.len(), &mut out) } {
+        1 => {
+            let value = unsafe { std::slice::from_raw_parts(out.ptr, out.len).to_vec() };
+            unsafe { (host.free)(out) };
+            Ok(Some(value))
+        }
+        0 => Ok(None),
+        _ => Err(KvError),
+    }
+}
+
+pub fn set(key: &str, value: &[u8]) -> Result<(), KvError> {
+    let host = host()?;
+    match unsafe { (host.set)(host.ctx, key.as_ptr(), key.len(), value.as_ptr(), value.len()) } {
+        0 => Ok(()),
+        _ => Err(KvError),
+    }
+}
+
+#[allow(dead_code)]
+pub fn delete(key: &str) -> Result<(), KvError> {
+    let host = host()?;
+    match unsafe { (host.delete)(host.ctx, key.as_ptr(), key.len()) } {
+        0 => Ok(()),
+        _ => Err(KvError),
+    }
+}
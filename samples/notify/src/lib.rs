@@ -1,3 +1,6 @@
+mod abi;
+mod kv;
+
 use std::sync::Mutex;
 
 use funck::{CallError, CallResult, Request, Response};
@@ -6,6 +9,8 @@ use rood::sys::notify;
 
 use serde::{Deserialize, Serialize};
 
+const CALL_COUNT_KEY: &str = "call_count";
+
 fn default_notification_title() -> String {
     String::from("Funck Notification")
 }
@@ -32,7 +37,8 @@ impl NotificationResponse {
 
 #[derive(Debug, Default)]
 pub struct NotifFn {
-    call_count: Mutex<u16>,
+    // Serializes updates of the call count.
+    call_count_lock: Mutex<()>,
 }
 
 impl NotifFn {
@@ -41,11 +47,18 @@ impl NotifFn {
         let mut notif_req: NotificationRequest =
             serde_json::from_slice(request.body()).map_err(|e| CallError::new(e.to_string()))?;
 
-        // Get & Increment call count
+        // Get & Increment call count, kept in the server's KV store so it survives redeploys.
         let call_count = {
-            let mut count_lock = self.call_count.lock().unwrap(); // Disregard locking errors for our example.
-            *count_lock = *count_lock + 1;
-            *count_lock
+            let _guard = self.call_count_lock.lock().unwrap(); // Disregard locking errors for our example.
+            let kv_err = |_e| CallError::new("KV store unavailable");
+            let count = kv::get(CALL_COUNT_KEY)
+                .map_err(kv_err)?
+                .and_then(|v| String::from_utf8(v).ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0)
+                + 1;
+            kv::set(CALL_COUNT_KEY, count.to_string().as_bytes()).map_err(kv_err)?;
+            count
         };
 
         notif_req.body += &format!(" (called {} times)", call_count);
@@ -1,3 +1,5 @@
+mod abi;
+
 use funck::{CallError, CallResult, Request, Response};
 
 use serde::{Deserialize, Serialize};
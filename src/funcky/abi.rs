@@ -0,0 +1,142 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use serde::Deserialize;
+
+/// Exported by libraries, to describe the toolchain they were built with.
+///
+/// Returns a NUL-terminated JSON document: `{"funck": "0.2.0", "rustc": "rustc 1.x.y (...)",
+/// "target": "x86_64-unknown-linux-gnu"}`.
+pub const ABI_SYMBOL: &[u8] = b"_funck_abi";
+
+pub type FunckAbi = unsafe extern "C" fn() -> *const c_char;
+
+/// The ABI a library was built for. Functions are passed around as trait objects, whose layout
+/// is only stable for a given `funck` version, compiler and target.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct AbiDescriptor {
+    pub funck: String,
+    pub rustc: String,
+    pub target: String,
+}
+
+impl AbiDescriptor {
+    /// The ABI of the server itself.
+    pub fn host() -> AbiDescriptor {
+        AbiDescriptor {
+            funck: String::from(env!("FUNCKY_ABI_FUNCK")),
+            rustc: String::from(env!("FUNCKY_ABI_RUSTC")),
+            target: String::from(env!("FUNCKY_ABI_TARGET")),
+        }
+    }
+
+    /// Reads the descriptor returned by a library's ABI symbol.
+    ///
+    /// # Safety
+    /// `abi` must return a pointer to a NUL-terminated string.
+    pub unsafe fn read(abi: FunckAbi) -> Result<AbiDescriptor, String> {
+        let raw = abi();
+        if raw.is_null() {
+            return Err(String::from("ABI descriptor is null"));
+        }
+        serde_json::from_slice(CStr::from_ptr(raw).to_bytes())
+            .map_err(|e| format!("malformed ABI descriptor: {}", e))
+    }
+
+    /// Describes how a library's ABI differs from the server's, if it does.
+    pub fn mismatch(&self, host: &AbiDescriptor) -> Option<String> {
+        let fields = [
+            ("funck", &self.funck, &host.funck),
+            ("rustc", &self.rustc, &host.rustc),
+            ("target", &self.target, &host.target),
+        ];
+        let diffs: Vec<String> = fields
+            .iter()
+            .filter(|(_, lib, host)| lib != host)
+            .map(|(name, lib, host)| format!("{} is [{}], expected [{}]", name, lib, host))
+            .collect();
+
+        if diffs.is_empty() {
+            None
+        } else {
+            Some(diffs.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    /// The descriptor of the samples, built with the server's toolchain.
+    mod sample {
+        include!("../../samples/testfn/src/abi.rs");
+    }
+
+    fn descriptor(funck: &str, rustc: &str, target: &str) -> AbiDescriptor {
+        AbiDescriptor {
+            funck: String::from(funck),
+            rustc: String::from(rustc),
+            target: String::from(target),
+        }
+    }
+
+    #[test]
+    fn host_accepts_sample_descriptor() {
+        let abi = unsafe { AbiDescriptor::read(sample::_funck_abi) }.unwrap();
+        assert_eq!(abi, AbiDescriptor::host());
+        assert_eq!(abi.mismatch(&AbiDescriptor::host()), None);
+    }
+
+    #[test]
+    fn samples_record_the_abi_like_the_server() {
+        // Apart from their doc comment, the build scripts must stay the same.
+        let body = |script: &str| String::from(script.split_once('\n').unwrap().1);
+        let server = body(include_str!("../../build.rs"));
+        assert_eq!(body(include_str!("../../samples/testfn/build.rs")), server);
+        assert_eq!(body(include_str!("../../samples/notify/build.rs")), server);
+        assert_eq!(
+            include_str!("../../samples/notify/src/abi.rs"),
+            include_str!("../../samples/testfn/src/abi.rs")
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let host = descriptor("0.2.0", "rustc 1.95.0", "x86_64-unknown-linux-gnu");
+        let cases = [
+            (
+                descriptor("0.2.0", "rustc 1.95.0", "x86_64-unknown-linux-gnu"),
+                None,
+            ),
+            (
+                descriptor("0.2.1", "rustc 1.95.0", "x86_64-unknown-linux-gnu"),
+                Some("funck is [0.2.1], expected [0.2.0]"),
+            ),
+            (
+                descriptor("0.2.0", "rustc 1.94.0", "aarch64-unknown-linux-gnu"),
+                Some(
+                    "rustc is [rustc 1.94.0], expected [rustc 1.95.0], \
+                     target is [aarch64-unknown-linux-gnu], expected [x86_64-unknown-linux-gnu]",
+                ),
+            ),
+        ];
+        for (lib, expected) in cases.iter() {
+            assert_eq!(lib.mismatch(&host).as_deref(), *expected, "{:?}", lib);
+        }
+    }
+
+    #[test]
+    fn read_rejects_invalid_descriptors() {
+        extern "C" fn null() -> *const c_char {
+            ptr::null()
+        }
+        extern "C" fn malformed() -> *const c_char {
+            b"{\"funck\":\"0.2.0\"}\0".as_ptr() as *const c_char
+        }
+        assert!(unsafe { AbiDescriptor::read(null) }.is_err());
+        assert!(unsafe { AbiDescriptor::read(malformed) }.is_err());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use snafu::{ensure, ResultExt, Snafu};
+
+use super::clock::unix_now;
+use super::namespaces;
+
+/// Subdirectory of the cache holding the target directories of functions.
+const FUNCTIONS_DIR: &str = "functions";
+
+/// Subdirectory of the cache holding the target directories of namespaced functions.
+const NAMESPACES_DIR: &str = "namespaces";
+
+/// Default `CARGO_HOME` of builds, shared by every function.
+const CARGO_HOME_DIR: &str = "cargo_home";
+
+const TARGET_DIR: &str = "target";
+
+/// File of a cache entry holding the time it was last built with, in seconds since the epoch.
+const LAST_USED_FILE: &str = "last_used";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to prepare build cache [{}]: {}", path.display(), source))]
+    FailedToPrepareCache {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to purge build cache of function {}: {}", name, source))]
+    FailedToPurgeCache {
+        source: io::Error,
+        name: String,
+    },
+
+    #[snafu(display("Failed to read build cache: {}", source))]
+    FailedToReadCache {
+        source: io::Error,
+    },
+
+    #[snafu(display("Function {} is being built, its cache can't be purged", name))]
+    CacheInUse {
+        name: String,
+    },
+
+    #[snafu(display("No build cache for function: {}", name))]
+    UnknownCacheEntry {
+        name: String,
+    },
+
+    CacheLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheEntryStat {
+    pub size_bytes: u64,
+    pub last_used: u64,
+}
+
+/// Size of a directory tree, not following symlinks.
+fn tree_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += tree_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// A cache entry checked out for a build. Released when dropped.
+pub struct CacheLease<'a> {
+    cache: &'a BuildCache,
+    name: String,
+    pub target_dir: PathBuf,
+}
+
+impl Drop for CacheLease<'_> {
+    fn drop(&mut self) {
+        // A poisoned lock fails every later checkout, so there is nothing left to release.
+        if let Ok(mut in_use) = self.cache.in_use.lock() {
+            in_use.remove(&self.name);
+        }
+    }
+}
+
+/// The BuildCache keeps the cargo target directory of each function between builds, so that
+/// redeploys only rebuild what changed.
+///
+/// Entries are evicted least recently used first once the cache outgrows its size limit. The
+/// shared `CARGO_HOME` isn't counted, cargo keeps it in check itself.
+pub struct BuildCache {
+    root: PathBuf,
+    cargo_home: PathBuf,
+    max_bytes: u64,
+    in_use: Mutex<HashSet<String>>,
+}
+
+impl BuildCache {
+    /// Builds run from their own directory, so the cache paths are made absolute.
+    pub fn new<P: AsRef<Path>>(
+        root: P,
+        cargo_home: Option<PathBuf>,
+        max_bytes: u64,
+    ) -> Result<BuildCache> {
+        let root = Self::prepare_dir(root.as_ref())?;
+        let cargo_home =
+            Self::prepare_dir(&cargo_home.unwrap_or_else(|| root.join(CARGO_HOME_DIR)))?;
+        Ok(BuildCache {
+            root,
+            cargo_home,
+            max_bytes,
+            in_use: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn prepare_dir(path: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(path)
+            .and_then(|_| fs::canonicalize(path))
+            .context(FailedToPrepareCache { path })
+    }
+
+    pub fn cargo_home(&self) -> &Path {
+        &self.cargo_home
+    }
+
+    fn entry_dir(&self, fn_name: &str) -> PathBuf {
+        match namespaces::split(fn_name) {
+            (Some(ns), name) => self.root.join(NAMESPACES_DIR).join(ns).join(name),
+            (None, name) => self.root.join(FUNCTIONS_DIR).join(name),
+        }
+    }
+
+    /// Checks out the cache entry of a function for a build, creating it if needed.
+    pub fn checkout(&self, fn_name: &str) -> Result<CacheLease<'_>> {
+        let entry_dir = self.entry_dir(fn_name);
+        let target_dir = entry_dir.join(TARGET_DIR);
+        fs::create_dir_all(&target_dir)
+            .and_then(|_| fs::write(entry_dir.join(LAST_USED_FILE), unix_now().to_string()))
+            .context(FailedToPrepareCache { path: &entry_dir })?;
+
+        self.in_use
+            .lock()
+            .map_err(|_e| Error::CacheLockFailure)?
+            .insert(String::from(fn_name));
+        Ok(CacheLease {
+            cache: self,
+            name: String::from(fn_name),
+            target_dir,
+        })
+    }
+
+    /// Removes the cache entry of a function.
+    pub fn purge(&self, fn_name: &str) -> Result<()> {
+        let in_use = self.in_use.lock().map_err(|_e| Error::CacheLockFailure)?;
+        ensure!(!in_use.contains(fn_name), CacheInUse { name: fn_name });
+
+        let entry_dir = self.entry_dir(fn_name);
+        ensure!(entry_dir.exists(), UnknownCacheEntry { name: fn_name });
+        fs::remove_dir_all(&entry_dir).context(FailedToPurgeCache { name: fn_name })
+    }
+
+    /// Returns the cache entries, keyed by function name.
+    pub fn entries(&self) -> Result<BTreeMap<String, CacheEntryStat>> {
+        let mut entries = BTreeMap::new();
+        let mut dirs: Vec<(String, PathBuf)> = Self::subdirs(&self.root.join(FUNCTIONS_DIR))?;
+        for (ns, ns_dir) in Self::subdirs(&self.root.join(NAMESPACES_DIR))? {
+            for (name, dir) in Self::subdirs(&ns_dir)? {
+                dirs.push((namespaces::qualify(Some(&ns), &name), dir));
+            }
+        }
+
+        for (name, dir) in dirs {
+            let last_used = fs::read_to_string(dir.join(LAST_USED_FILE))
+                .ok()
+                .and_then(|t| t.trim().parse().ok())
+                .unwrap_or(0);
+            let size_bytes = tree_size(&dir).context(FailedToReadCache)?;
+            entries.insert(
+                name,
+                CacheEntryStat {
+                    size_bytes,
+                    last_used,
+                },
+            );
+        }
+        Ok(entries)
+    }
+
+    fn subdirs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+        let read = match fs::read_dir(dir) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(FailedToReadCache),
+        };
+        let mut dirs = Vec::new();
+        for entry in read {
+            let entry = entry.context(FailedToReadCache)?;
+            if entry.path().is_dir() {
+                dirs.push((
+                    entry.file_name().to_string_lossy().to_string(),
+                    entry.path(),
+                ));
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Evicts the least recently used entries until the cache fits its size limit. Entries being
+    /// built are kept, as is `keep`, whose artifact may not have been moved out yet.
+    pub fn evict(&self, keep: &str) -> Result<()> {
+        let mut entries: Vec<(String, CacheEntryStat)> = self.entries()?.into_iter().collect();
+        let mut total: u64 = entries.iter().map(|(_, e)| e.size_bytes).sum();
+        if total <= self.max_bytes {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|(_, e)| e.last_used);
+        for (name, entry) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if name == keep {
+                continue;
+            }
+            match self.purge(&name) {
+                Ok(()) => {
+                    log::info!(
+                        "evicted build cache of {} ({} bytes)",
+                        name,
+                        entry.size_bytes
+                    );
+                    total -= entry.size_bytes;
+                }
+                Err(Error::CacheInUse { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(dir: &Path, max_bytes: u64) -> BuildCache {
+        BuildCache::new(dir.join("cache"), None, max_bytes).unwrap()
+    }
+
+    /// Leaves an artifact of `size` bytes in the target directory, as a build would.
+    fn build(cache: &BuildCache, fn_name: &str, size: usize) -> PathBuf {
+        let lease = cache.checkout(fn_name).unwrap();
+        let artifact = lease.target_dir.join("lib.so");
+        if !artifact.exists() {
+            fs::write(&artifact, vec![0u8; size]).unwrap();
+        }
+        artifact
+    }
+
+    #[test]
+    fn builds_reuse_their_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), u64::MAX);
+
+        let cases = [
+            // (function, previously built)
+            ("alpha", false),
+            ("alpha", true),
+            ("ns/alpha", false),
+            ("beta", false),
+            ("ns/alpha", true),
+        ];
+        for &(fn_name, hit) in cases.iter() {
+            let lease = cache.checkout(fn_name).unwrap();
+            let artifact = lease.target_dir.join("lib.so");
+            assert_eq!(artifact.exists(), hit, "{}", fn_name);
+            fs::write(&artifact, fn_name).unwrap();
+        }
+        assert!(cache.cargo_home().is_dir());
+
+        let names: Vec<String> = cache.entries().unwrap().into_keys().collect();
+        assert_eq!(names, vec!["alpha", "beta", "ns/alpha"]);
+    }
+
+    #[test]
+    fn purged_entries_are_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), u64::MAX);
+        let artifact = build(&cache, "alpha", 1);
+
+        {
+            let _lease = cache.checkout("alpha").unwrap();
+            assert!(matches!(
+                cache.purge("alpha"),
+                Err(Error::CacheInUse { .. })
+            ));
+        }
+        cache.purge("alpha").unwrap();
+        assert!(!artifact.exists());
+        assert!(matches!(
+            cache.purge("alpha"),
+            Err(Error::UnknownCacheEntry { .. })
+        ));
+        assert!(!cache
+            .checkout("alpha")
+            .unwrap()
+            .target_dir
+            .join("lib.so")
+            .exists());
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 2500);
+        let built = |fn_name: &str, last_used: u64| {
+            build(&cache, fn_name, 1000);
+            let last_used_file = cache.entry_dir(fn_name).join(LAST_USED_FILE);
+            fs::write(last_used_file, last_used.to_string()).unwrap();
+        };
+        let names =
+            |cache: &BuildCache| -> Vec<String> { cache.entries().unwrap().into_keys().collect() };
+
+        built("alpha", 1);
+        built("beta", 3);
+        built("gamma", 2);
+        // Alpha is the least recently used, but is kept.
+        cache.evict("alpha").unwrap();
+        assert_eq!(names(&cache), vec!["alpha", "beta"]);
+
+        built("delta", 4);
+        cache.evict("delta").unwrap();
+        assert_eq!(names(&cache), vec!["beta", "delta"]);
+    }
+}
@@ -1,126 +1,740 @@
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
 
 use snafu::{ensure, ResultExt, Snafu};
 
-use super::{DirHook, DropDir, Status, StatusTracker};
+use super::build_cache::{self, BuildCache};
+use super::clock::unix_now;
+use super::git::GitSource;
+use super::manifest::{self, Manifest, Runtime};
+use super::namespaces;
+use super::sandbox::{Outcome, Sandbox, SandboxConfig};
+use super::state;
+use super::wasm::WASM_EXTENSION;
+use super::{DropDir, NamespaceStore, Status, StatusTracker};
+use crate::telemetry::{SpanContext, Tracer};
+
+/// Target WebAssembly functions are built for.
+const WASM_TARGET: &str = "wasm32-wasi";
+
+/// Directory of a source bundle holding its `cargo vendor` output.
+const VENDOR_DIR: &str = "vendor";
+
+/// Cargo source names of vendored bundles and of the configured registry.
+const VENDORED_SOURCE: &str = "vendored-sources";
+const MIRROR_SOURCE: &str = "funcky-mirror";
+
+const DEFAULT_PROFILE: &str = "release";
+
+/// How often a stopping worker is checked for completion.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Failed to move to directory [{}]: {}", path.display(), source))]
-    SwitchDirError { source: io::Error, path: PathBuf },
+    #[snafu(display("Invalid source directory [{}]: {}", path.display(), source))]
+    InvalidSourceDirectory { source: io::Error, path: PathBuf },
+
+    #[snafu(display("{}", source))]
+    ManifestError { source: manifest::Error },
+
+    #[snafu(display("Failed to prepare build sandbox: {}", source))]
+    SandboxError { source: io::Error },
 
     #[snafu(display("Failed to spawn build command: {}", source))]
     BuildSpawnError { source: io::Error },
 
-    #[snafu(display("Error waiting for build command: {}", source))]
-    BuildJoinError { source: io::Error },
-
     #[snafu(display("Build command exited with non-zero status code: {}", code))]
     ExitCodeNonZero { code: i32 },
 
+    #[snafu(display("Build timed out after {}s", timeout.as_secs()))]
+    BuildTimedOut { timeout: Duration },
+
+    #[snafu(display("Build cancelled"))]
+    BuildCancelled,
+
+    #[snafu(display(
+        "Crate {} is not available to offline builds, searched {}. Vendor it with `funck deploy --vendor`, or add it to the registry mirror",
+        name,
+        location
+    ))]
+    MissingCrate { name: String, location: String },
+
+    #[snafu(display("Cargo.lock is missing or out of date, which frozen builds don't allow"))]
+    LockfileOutdated,
+
+    #[snafu(display("Invalid dependency source [{}]: {}", path.display(), source))]
+    InvalidRegistry { source: io::Error, path: PathBuf },
+
     #[snafu(display("The final shared object file path ({}) is invalid: {}", path.display(), source))]
     InvalidOutputPath { source: io::Error, path: PathBuf },
 
+    #[snafu(display("The build produced no {} library", runtime))]
+    MissingArtifact { runtime: String },
+
+    #[snafu(display(
+        "The build produced several libraries, deploy their crates separately: {}",
+        artifacts.join(", ")
+    ))]
+    AmbiguousArtifact { artifacts: Vec<String> },
+
     #[snafu(display("Failed to acquire the job channel mutex"))]
     JobLockError,
 
-    #[snafu(display("Couldn't send the job to the compilation worker: {}", source))]
-    JobDispatchError { source: mpsc::SendError<Request> },
+    #[snafu(display("Couldn't send the job to the compilation worker, it has stopped"))]
+    JobDispatchError,
 
     #[snafu(display("Worker already started"))]
     WorkerAlreadyStarted,
 
     #[snafu(display("Compile worker is not started"))]
     WorkerNotStarted,
+
+    #[snafu(display("Compile worker is stopped"))]
+    WorkerStopped,
+
+    #[snafu(display("Unknown build: {}", id))]
+    UnknownBuild { id: u64 },
+
+    #[snafu(display("Failed to restore pending builds from [{}]: {}", path.display(), source))]
+    FailedToRestoreBuilds { source: io::Error, path: PathBuf },
+
+    #[snafu(display("{}", source))]
+    QuotaExceeded { source: namespaces::Error },
+
+    #[snafu(display("{}", source))]
+    BuildCacheError { source: build_cache::Error },
+}
+
+/// Registry replacing crates.io in builds, for hosts without internet access.
+#[derive(Clone, Debug)]
+pub enum RegistrySource {
+    /// A cargo local registry: an index and the `.crate` files it lists.
+    Local(PathBuf),
+
+    /// A directory of unpacked crates, as produced by `cargo vendor`.
+    Directory(PathBuf),
+}
+
+/// Whether builds may fetch crates over the network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkMode {
+    Online,
+
+    /// Builds run with `--offline`.
+    Offline,
+
+    /// Builds run with `--frozen`, so their `Cargo.lock` must also be up to date.
+    Frozen,
+}
+
+/// What happens to queued builds when the server stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuedBuilds {
+    /// Queued builds are built before stopping.
+    Finish,
+
+    /// Queued builds are kept with their sources, and queued again on the next start.
+    Persist,
+}
+
+#[derive(Clone, Debug)]
+pub struct BuildOptions {
+    pub registry: Option<RegistrySource>,
+    pub network: NetworkMode,
+    pub sandbox: SandboxConfig,
+}
+
+impl BuildOptions {
+    /// Builds run from their own directory, so the registry path is made absolute.
+    pub fn resolve(self) -> Result<BuildOptions, Error> {
+        let registry = match self.registry {
+            Some(RegistrySource::Local(path)) => Some(RegistrySource::Local(
+                fs::canonicalize(&path).context(InvalidRegistry { path })?,
+            )),
+            Some(RegistrySource::Directory(path)) => Some(RegistrySource::Directory(
+                fs::canonicalize(&path).context(InvalidRegistry { path })?,
+            )),
+            None => None,
+        };
+        Ok(BuildOptions {
+            registry,
+            network: self.network,
+            sandbox: self.sandbox,
+        })
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Cargo options of a build, chosen when deploying. They override the `build` table of the
+/// manifest, and are recorded in the manifest stored with the deployment.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildFlags {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub no_default_features: bool,
+
+    /// Cargo profile. Defaults to `release`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustflags: Option<String>,
+}
+
+impl BuildFlags {
+    pub fn validate(&self) -> Result<(), String> {
+        // Features may name a dependency's feature, as in `dep/feature`.
+        if let Some(f) = self.features.iter().find(|f| {
+            f.is_empty()
+                || f.starts_with('-')
+                || !f
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_/+.".contains(c))
+        }) {
+            return Err(format!("invalid feature name: {:?}", f));
+        }
+        if let Some(profile) = &self.profile {
+            if profile.is_empty()
+                || profile.starts_with('-')
+                || !profile
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!("invalid profile name: {:?}", profile));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the options left unset from `base`.
+    pub fn or(self, base: Option<&BuildFlags>) -> BuildFlags {
+        let base = match base {
+            Some(base) => base.clone(),
+            None => return self,
+        };
+        BuildFlags {
+            features: if self.features.is_empty() {
+                base.features
+            } else {
+                self.features
+            },
+            no_default_features: self.no_default_features || base.no_default_features,
+            profile: self.profile.or(base.profile),
+            rustflags: self.rustflags.or(base.rustflags),
+        }
+    }
+
+    fn apply(&self, command: &mut Command) {
+        command
+            .arg("--profile")
+            .arg(self.profile.as_deref().unwrap_or(DEFAULT_PROFILE));
+        if !self.features.is_empty() {
+            command.arg("--features").arg(self.features.join(","));
+        }
+        if self.no_default_features {
+            command.arg("--no-default-features");
+        }
+        if let Some(rustflags) = &self.rustflags {
+            command.env("RUSTFLAGS", rustflags);
+        }
+    }
+}
+
+/// Copies an artifact out of the build cache, which keeps its own, and which may be on another
+/// filesystem. The copy is renamed into place so a loading library is never half-written.
+fn place_artifact(src: &Path, dst: &Path) -> io::Result<()> {
+    let dir = dst.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    io::copy(&mut fs::File::open(src)?, tmp.as_file_mut())?;
+    tmp.as_file()
+        .set_permissions(fs::metadata(src)?.permissions())?;
+    tmp.persist(dst).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// File name a function's library is deployed under.
+pub fn artifact_file_name(name: &str, runtime: Runtime) -> String {
+    match runtime {
+        Runtime::Native => format!("lib{}.so", name),
+        Runtime::Wasm => format!("{}.{}", name, WASM_EXTENSION),
+    }
+}
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    manifest_path: Option<PathBuf>,
+    target: Option<CargoTarget>,
+    #[serde(default)]
+    filenames: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    kind: Vec<String>,
+}
+
+/// Finds the library a build produced in cargo's JSON messages. Only the bundle's own crates
+/// count, dependencies may be libraries too. A workspace may build several, in which case the
+/// one of the root package is picked.
+fn find_artifact(stdout: &[u8], source_dir: &Path, runtime: Runtime) -> Result<PathBuf, Error> {
+    let root_manifest = source_dir.join("Cargo.toml");
+    let vendor_dir = source_dir.join(VENDOR_DIR);
+    let extension = match runtime {
+        Runtime::Native => "so",
+        Runtime::Wasm => WASM_EXTENSION,
+    };
+
+    let mut artifacts: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for line in stdout.split(|b| *b == b'\n') {
+        let message: CargoMessage = match serde_json::from_slice(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let manifest = match message.manifest_path {
+            Some(manifest)
+                if manifest.starts_with(source_dir) && !manifest.starts_with(&vendor_dir) =>
+            {
+                manifest
+            }
+            _ => continue,
+        };
+        if message.reason != "compiler-artifact"
+            || !message
+                .target
+                .is_some_and(|t| t.kind.iter().any(|k| k == "cdylib"))
+        {
+            continue;
+        }
+        if let Some(file) = message
+            .filenames
+            .into_iter()
+            .find(|f| f.extension().is_some_and(|e| e == extension))
+        {
+            artifacts.push((manifest, file));
+        }
+    }
+
+    if let Some((_, file)) = artifacts
+        .iter()
+        .find(|(manifest, _)| *manifest == root_manifest)
+    {
+        return Ok(file.clone());
+    }
+    match artifacts.len() {
+        0 => MissingArtifact {
+            runtime: format!("{:?}", runtime).to_lowercase(),
+        }
+        .fail(),
+        1 => Ok(artifacts.remove(0).1),
+        _ => AmbiguousArtifact {
+            artifacts: artifacts
+                .iter()
+                .map(|(_, f)| f.to_string_lossy().to_string())
+                .collect::<Vec<_>>(),
+        }
+        .fail(),
+    }
+}
+
+/// Quotes a path as a TOML string, for `cargo --config`.
+fn toml_path(path: &Path) -> String {
+    toml::Value::String(path.to_string_lossy().to_string()).to_string()
+}
+
+/// Replaces crates.io with the given source in a cargo command.
+fn replace_crates_io(command: &mut Command, name: &str, kind: &str, path: &Path) {
+    command
+        .arg("--config")
+        .arg(format!("source.crates-io.replace-with=\"{}\"", name))
+        .arg("--config")
+        .arg(format!("source.{}.{}={}", name, kind, toml_path(path)));
+}
+
+/// Finds why a build failed in cargo's output, when a dependency couldn't be resolved offline.
+fn resolution_error(stderr: &str) -> Option<Error> {
+    let between_backticks = |line: &str, prefix: &str| {
+        line.split(prefix)
+            .nth(1)
+            .and_then(|rest| rest.split('`').next())
+            .map(String::from)
+    };
+    let location = stderr
+        .lines()
+        .find_map(|l| l.strip_prefix("location searched: "))
+        .unwrap_or("no location searched")
+        .to_string();
+
+    for line in stderr.lines() {
+        if let Some(name) = between_backticks(line, "no matching package named `") {
+            return Some(Error::MissingCrate { name, location });
+        }
+        if let Some(requirement) =
+            between_backticks(line, "failed to select a version for the requirement `")
+        {
+            return Some(Error::MissingCrate {
+                name: requirement,
+                location,
+            });
+        }
+        // Cargo.lock is either out of date, or missing altogether.
+        if line.contains("--frozen was passed to prevent this") {
+            return Some(Error::LockfileOutdated);
+        }
+    }
+    None
 }
 
 pub struct Response {
     pub so_path: PathBuf,
     pub job_name: String,
+    pub namespace: Option<String>,
+    pub manifest: Manifest,
+    pub flags: BuildFlags,
+    pub source_hash: Option<String>,
+    pub trace_context: Option<SpanContext>,
 }
 
 pub struct Request {
     pub source_directory: DropDir,
+    pub namespace: Option<String>,
+    pub flags: BuildFlags,
+    pub source: Option<GitSource>,
+    pub source_hash: Option<String>,
+    pub trace_context: Option<SpanContext>,
+    queued_at: SystemTime,
+
+    /// Assigned when the build is queued.
+    id: u64,
+    cancel: Arc<AtomicBool>,
 }
 
 impl Request {
-    pub fn new(source_dir: DropDir) -> Request {
+    pub fn new(
+        source_dir: DropDir,
+        namespace: Option<String>,
+        flags: BuildFlags,
+        trace_context: Option<SpanContext>,
+    ) -> Request {
         Request {
             source_directory: source_dir,
+            namespace,
+            flags,
+            source: None,
+            source_hash: None,
+            trace_context,
+            queued_at: SystemTime::now(),
+            id: 0,
+            cancel: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Execute a compilation job.
-    pub fn execute(&self) -> Result<PathBuf, Error> {
+    /// Records the repository the sources were checked out from. Its commit identifies them.
+    pub fn with_source(mut self, source: GitSource) -> Request {
+        self.source_hash = Some(source.commit.clone());
+        self.source = Some(source);
+        self
+    }
+
+    /// Records the SHA-256 of the bundle the sources were extracted from.
+    pub fn with_source_hash(mut self, source_hash: String) -> Request {
+        self.source_hash = Some(source_hash);
+        self
+    }
+
+    /// Execute a compilation job, returning the shared object path and the function's manifest.
+    ///
+    /// The function's target directory is kept in the build cache, so dependencies are only
+    /// rebuilt when they change.
+    ///
+    /// Bundles vendoring their dependencies are built from them, offline. Other builds use the
+    /// configured registry, if any.
+    ///
+    /// Builds run sandboxed, since a bundle's build scripts and proc-macros run arbitrary code.
+    pub fn execute(
+        &self,
+        cache: &BuildCache,
+        options: &BuildOptions,
+    ) -> Result<(PathBuf, Manifest), Error> {
         log::info!(
             "started compile job for {}",
             self.source_directory.path().display()
         );
 
-        let _hk = DirHook::new(self.source_directory.path()).context(SwitchDirError {
-            path: self.source_directory.path(),
-        })?;
+        // Validate the manifest first, so that a bad manifest doesn't cost a full build.
+        let mut manifest =
+            Manifest::from_cargo_toml(self.source_directory.path().join("Cargo.toml"))
+                .context(ManifestError)?;
+        let flags = self.flags.clone().or(manifest.build.as_ref());
 
-        let project_name = self
-            .source_directory
-            .path()
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
+        let source_dir =
+            fs::canonicalize(self.source_directory.path()).context(InvalidSourceDirectory {
+                path: self.source_directory.path(),
+            })?;
 
-        let mut cmd = Command::new("cargo")
+        let lease = cache
+            .checkout(&self.source_directory.name)
+            .context(BuildCacheError)?;
+
+        let sandbox = Sandbox::new(&options.sandbox)
+            .context(SandboxError)?
+            .cancel_on(self.cancel.clone());
+        let mut build = sandbox.command(
+            "cargo",
+            &source_dir,
+            &[&lease.target_dir, cache.cargo_home()],
+        );
+        // Diagnostics are still rendered on stderr, which failures are diagnosed from.
+        build
             .arg("build")
-            .arg("--release")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped()) // TODO: Get back combined output if build fails.
-            .spawn()
-            .context(BuildSpawnError)?;
+            .arg("--message-format=json-render-diagnostics")
+            .env("CARGO_TARGET_DIR", &lease.target_dir)
+            .env("CARGO_HOME", cache.cargo_home());
+        flags.apply(&mut build);
+        if manifest.runtime == Runtime::Wasm {
+            build.arg("--target").arg(WASM_TARGET);
+        }
 
-        let res = cmd.wait().context(BuildJoinError)?;
+        let vendor_dir = source_dir.join(VENDOR_DIR);
+        let vendored = vendor_dir.is_dir();
+        let network = if vendored && options.network == NetworkMode::Online {
+            NetworkMode::Offline
+        } else {
+            options.network
+        };
+        match network {
+            NetworkMode::Online => {}
+            NetworkMode::Offline => {
+                build.arg("--offline");
+            }
+            NetworkMode::Frozen => {
+                build.arg("--frozen");
+            }
+        }
 
-        ensure!(
-            res.success(),
-            ExitCodeNonZero {
-                code: res.code().unwrap_or(-1)
+        if vendored {
+            replace_crates_io(&mut build, VENDORED_SOURCE, "directory", &vendor_dir);
+        } else {
+            match &options.registry {
+                Some(RegistrySource::Local(path)) => {
+                    replace_crates_io(&mut build, MIRROR_SOURCE, "local-registry", path)
+                }
+                Some(RegistrySource::Directory(path)) => {
+                    replace_crates_io(&mut build, MIRROR_SOURCE, "directory", path)
+                }
+                None => {}
             }
-        );
+        }
+
+        // TODO: Get back combined output if build fails.
+        let (status, stdout, stderr) = match sandbox.run(build).context(BuildSpawnError)? {
+            Outcome::Exited {
+                status,
+                stdout,
+                stderr,
+            } => (status, stdout, stderr),
+            Outcome::TimedOut => {
+                return BuildTimedOut {
+                    timeout: options.sandbox.timeout,
+                }
+                .fail()
+            }
+            Outcome::Cancelled => return BuildCancelled.fail(),
+        };
+
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            if let Some(e) = resolution_error(&stderr) {
+                return Err(e);
+            }
+            return ExitCodeNonZero {
+                code: status.code().unwrap_or(-1),
+            }
+            .fail();
+        }
 
-        let rel_out_path = PathBuf::from(&format!("./target/release/lib{}.so", project_name));
-        let so_file_path = std::fs::canonicalize(&rel_out_path)
-            .context(InvalidOutputPath { path: rel_out_path })?;
+        let out_path = find_artifact(&stdout, &source_dir, manifest.runtime)?;
+        let so_file_path =
+            std::fs::canonicalize(&out_path).context(InvalidOutputPath { path: out_path })?;
+
+        manifest.build = Some(BuildFlags {
+            profile: Some(
+                flags
+                    .profile
+                    .clone()
+                    .unwrap_or_else(|| String::from(DEFAULT_PROFILE)),
+            ),
+            ..flags
+        });
+        manifest.source = self.source.clone();
 
         log::info!("compiled {} successfully", so_file_path.display());
-        Ok(so_file_path)
+        Ok((so_file_path, manifest))
+    }
+}
+
+/// A build persisted when the server stopped, to be queued again on the next start.
+#[derive(Deserialize, Serialize)]
+struct PendingBuild {
+    name: String,
+    namespace: Option<String>,
+
+    /// Directory holding the sources, and the subdirectory of the crate.
+    directory: PathBuf,
+    subdir: PathBuf,
+
+    flags: BuildFlags,
+    source: Option<GitSource>,
+    source_hash: Option<String>,
+}
+
+impl From<Request> for PendingBuild {
+    fn from(request: Request) -> PendingBuild {
+        let name = request.source_directory.name.clone();
+        let (directory, subdir) = request.source_directory.keep();
+        PendingBuild {
+            name,
+            namespace: request.namespace,
+            directory,
+            subdir,
+            flags: request.flags,
+            source: request.source,
+            source_hash: request.source_hash,
+        }
+    }
+}
+
+/// Whether a build is waiting in the queue or running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum BuildState {
+    Queued,
+    Running,
+}
+
+/// A queued or running build.
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildInfo {
+    pub id: u64,
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_hash: Option<String>,
+
+    pub state: BuildState,
+
+    /// Unix time the build was queued at.
+    pub queued_at: u64,
+
+    /// Unix time the build started at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+}
+
+struct QueueEntry {
+    info: BuildInfo,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Builds sent to the compile loop and not done yet, in queue order. Builds removed from it
+/// while queued are skipped by the compile loop.
+#[derive(Default)]
+struct BuildQueue {
+    next_id: u64,
+    entries: Vec<QueueEntry>,
+}
+
+impl BuildQueue {
+    /// Marks a build as running, unless it was removed from the queue.
+    fn start(&mut self, id: u64) -> bool {
+        match self.entries.iter_mut().find(|e| e.info.id == id) {
+            Some(entry) => {
+                entry.info.state = BuildState::Running;
+                entry.info.started_at = Some(unix_now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.entries.retain(|e| e.info.id != id);
     }
 }
 
 struct WorkHandle {
-    pub handle: thread::JoinHandle<()>,
-    pub job_tx: Mutex<mpsc::Sender<Request>>,
+    /// Taken when the worker stops.
+    pub handle: Mutex<Option<thread::JoinHandle<()>>>,
+    pub job_tx: Mutex<Option<mpsc::Sender<Request>>>,
 }
 
-pub struct Worker {
-    handle: Option<WorkHandle>,
+/// State shared by the worker and its compile loop.
+#[derive(Clone)]
+struct Shared {
     shared_object_destination: PathBuf,
     status_tracker: Arc<StatusTracker>,
+    namespaces: Arc<NamespaceStore>,
+    build_cache: Arc<BuildCache>,
+    build_options: BuildOptions,
+    queue: Arc<Mutex<BuildQueue>>,
+    tracer: Arc<Tracer>,
+
+    /// Where builds are persisted when the worker stops.
+    pending_path: PathBuf,
+
+    /// Set when stopping, so the rest of the queue is persisted instead of built.
+    persist_queue: Arc<AtomicBool>,
+
+    /// Set when stopping takes too long and the running build is cancelled, to be persisted.
+    cancel: Arc<AtomicBool>,
+}
+
+pub struct Worker {
+    handle: Option<WorkHandle>,
+    shared: Shared,
 }
 
 impl Worker {
     pub fn new<P: AsRef<Path>>(
         shared_object_path: P,
+        pending_path: P,
         status_tracker: Arc<StatusTracker>,
+        namespaces: Arc<NamespaceStore>,
+        build_cache: Arc<BuildCache>,
+        build_options: BuildOptions,
+        tracer: Arc<Tracer>,
     ) -> Worker {
         let shared_object_destination = PathBuf::from(shared_object_path.as_ref());
         Worker {
             handle: None,
-            shared_object_destination,
-            status_tracker,
+            shared: Shared {
+                shared_object_destination,
+                status_tracker,
+                namespaces,
+                build_cache,
+                build_options,
+                queue: Arc::new(Mutex::new(BuildQueue::default())),
+                tracer,
+                pending_path: PathBuf::from(pending_path.as_ref()),
+                persist_queue: Arc::new(AtomicBool::new(false)),
+                cancel: Arc::new(AtomicBool::new(false)),
+            },
         }
     }
 
@@ -128,43 +742,203 @@ impl Worker {
         ensure!(self.handle.is_none(), WorkerAlreadyStarted);
         let (job_tx, job_rx) = mpsc::channel();
         let (result_tx, result_rx) = mpsc::channel();
-        let dst_path = self.shared_object_destination.clone();
-        let stat = self.status_tracker.clone();
-        let handle = thread::spawn(move || Worker::compile_loop(job_rx, result_tx, dst_path, stat));
+        let shared = self.shared.clone();
+        let handle = thread::spawn(move || Worker::compile_loop(job_rx, result_tx, shared));
         let work_handle = WorkHandle {
-            handle,
-            job_tx: Mutex::new(job_tx),
+            handle: Mutex::new(Some(handle)),
+            job_tx: Mutex::new(Some(job_tx)),
         };
         self.handle = Some(work_handle);
+        self.restore_pending()?;
         Ok(result_rx)
     }
 
+    /// Queues the builds persisted when the worker last stopped.
+    fn restore_pending(&self) -> Result<(), Error> {
+        let path = &self.shared.pending_path;
+        let pending: Vec<PendingBuild> =
+            state::load_json(path).context(FailedToRestoreBuilds { path })?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        log::info!(
+            "queueing {} builds pending since the last stop",
+            pending.len()
+        );
+        for build in pending {
+            let mut source_dir = DropDir::new(&build.directory, &build.name)
+                .context(FailedToRestoreBuilds { path })?;
+            source_dir.enter(&build.subdir);
+            let request = Request {
+                source_directory: source_dir,
+                namespace: build.namespace,
+                flags: build.flags,
+                source: build.source,
+                source_hash: build.source_hash,
+                trace_context: None,
+                queued_at: SystemTime::now(),
+                id: 0,
+                cancel: Arc::new(AtomicBool::new(false)),
+            };
+            if let Err(e) = self.new_job(request) {
+                log::error!("failed to queue pending build of {}: {}", build.name, e);
+            }
+        }
+        fs::remove_file(path).context(FailedToRestoreBuilds { path })
+    }
+
+    /// Stops the worker once its queue is empty. Queued builds are built or persisted, and
+    /// after `max_wait` the rest of the queue is persisted and the running build is cancelled,
+    /// to be persisted as well.
+    pub fn stop(&self, queued: QueuedBuilds, max_wait: Duration) -> Result<(), Error> {
+        let work_handle = self.handle.as_ref().ok_or(Error::WorkerNotStarted)?;
+        if queued == QueuedBuilds::Persist {
+            self.shared.persist_queue.store(true, Ordering::SeqCst);
+        }
+
+        // The compile loop ends once the queue is empty and no more jobs can come.
+        work_handle
+            .job_tx
+            .lock()
+            .map_err(|_e| Error::JobLockError)?
+            .take();
+        let handle = match work_handle
+            .handle
+            .lock()
+            .map_err(|_e| Error::JobLockError)?
+            .take()
+        {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+
+        let deadline = Instant::now() + max_wait;
+        while !handle.is_finished() {
+            if Instant::now() >= deadline {
+                log::warn!("builds still running after {:?}, cancelling", max_wait);
+                self.shared.persist_queue.store(true, Ordering::SeqCst);
+                self.shared.cancel.store(true, Ordering::SeqCst);
+                let queue = self.shared.queue.lock().map_err(|_e| Error::JobLockError)?;
+                for entry in queue.entries.iter() {
+                    entry.cancel.store(true, Ordering::SeqCst);
+                }
+                break;
+            }
+            thread::sleep(STOP_POLL_INTERVAL);
+        }
+        if handle.join().is_err() {
+            log::error!("compile worker panicked");
+        }
+        Ok(())
+    }
+
+    fn persist(path: &Path, pending: Vec<Request>) {
+        let builds: Vec<PendingBuild> = pending.into_iter().map(PendingBuild::from).collect();
+        match state::save_json(path, &builds) {
+            Ok(()) => log::info!("persisted {} pending builds", builds.len()),
+            Err(e) => log::error!("failed to persist pending builds: {}", e),
+        }
+    }
+
     fn compile_loop(
         incoming_jobs: mpsc::Receiver<Request>,
         result_tx: mpsc::Sender<Response>,
-        so_out_dir: PathBuf,
-        status_tracker: Arc<StatusTracker>,
+        shared: Shared,
     ) {
+        let Shared {
+            shared_object_destination: so_out_dir,
+            status_tracker,
+            namespaces,
+            build_cache,
+            build_options,
+            queue,
+            tracer,
+            pending_path,
+            persist_queue,
+            cancel,
+        } = shared;
+        let mut pending = Vec::new();
         while let Ok(job) = incoming_jobs.recv() {
+            // A panic while the queue was locked leaves it unusable, and the worker with it.
+            let mut queue_guard = match queue.lock() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    log::error!("build queue lock poisoned, stopping the compile worker");
+                    break;
+                }
+            };
+            if persist_queue.load(Ordering::SeqCst) {
+                queue_guard.remove(job.id);
+                pending.push(job);
+                continue;
+            }
+
+            // Cancelled or superseded while queued.
+            let started = queue_guard.start(job.id);
+            drop(queue_guard);
+            if !started {
+                log::info!("skipping build of {}", job.source_directory.name);
+                continue;
+            }
+
+            tracer
+                .start_span_at("compile.queue", job.trace_context.as_ref(), job.queued_at)
+                .end();
+
             status_tracker.update_status(&job.source_directory.name, Status::Compiling);
-            match job.execute() {
-                Ok(so_file) => {
-                    // Move the so_file from the temp dir to the dest dir.
-                    let fname_maybe = so_file.file_name();
-                    if fname_maybe.is_none() {
-                        let e = String::from("shared object has no file name");
-                        log::error!("{}", e);
-                        status_tracker.update_status(
-                            &job.source_directory.name,
-                            Status::Failed(e.to_string()),
-                        );
+
+            let mut build_span = tracer.start_span("compile.build", job.trace_context.as_ref());
+            build_span.set_attribute("funcky.job", &job.source_directory.name);
+            let build_result = job.execute(&build_cache, &build_options);
+            if let Err(e) = &build_result {
+                build_span.set_error(e);
+            }
+            build_span.end();
+
+            if let Err(e) = build_cache.evict(&job.source_directory.name) {
+                log::error!("failed to evict build cache: {}", e);
+            }
+
+            match queue.lock() {
+                Ok(mut queue) => queue.remove(job.id),
+                Err(_) => {
+                    log::error!("build queue lock poisoned, stopping the compile worker");
+                    break;
+                }
+            }
+
+            // Cancelled when the server stops, to be built on the next start.
+            if matches!(build_result, Err(Error::BuildCancelled)) && cancel.load(Ordering::SeqCst) {
+                log::info!("cancelled build of {}", job.source_directory.name);
+                pending.push(job);
+                continue;
+            }
+
+            match build_result {
+                Ok((so_file, manifest)) => {
+                    // Copy the so_file from the build cache to the dest dir, named after the
+                    // function rather than its crate.
+                    let (_, bare_name) = namespaces::split(&job.source_directory.name);
+                    let dst_dir = match &job.namespace {
+                        Some(ns) => so_out_dir.join(ns),
+                        None => so_out_dir.clone(),
+                    };
+                    let dst_so_file = dst_dir.join(artifact_file_name(bare_name, manifest.runtime));
+                    let placed = match &job.namespace {
+                        Some(ns) => namespaces.check_disk_quota(ns, &so_file, &dst_so_file),
+                        None => Ok(()),
+                    }
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| fs::create_dir_all(&dst_dir).map_err(|e| e.to_string()));
+                    if let Err(e) = placed {
+                        log::error!("error placing shared object file: {}", e);
+                        status_tracker.update_status(&job.source_directory.name, Status::Failed(e));
                         continue;
                     }
 
-                    let fname = fname_maybe.unwrap();
-                    let dst_so_file = so_out_dir.join(fname);
-                    if let Err(e) = fs::rename(so_file, &dst_so_file) {
-                        log::error!("error moving shared object file: {}", e);
+                    if let Err(e) = place_artifact(&so_file, &dst_so_file) {
+                        log::error!("error copying shared object file: {}", e);
                         status_tracker.update_status(
                             &job.source_directory.name,
                             Status::Failed(format!("{}", e)),
@@ -175,6 +949,11 @@ impl Worker {
                     if let Err(e) = result_tx.send(Response {
                         so_path: dst_so_file,
                         job_name: job.source_directory.name.clone(),
+                        namespace: job.namespace.clone(),
+                        manifest,
+                        flags: job.flags.clone(),
+                        source_hash: job.source_hash,
+                        trace_context: job.trace_context,
                     }) {
                         log::error!("error sending result: {}", e);
                         status_tracker.update_status(
@@ -185,19 +964,585 @@ impl Worker {
                 }
                 Err(e) => {
                     log::error!("compile error: {}", e);
-                    status_tracker
-                        .update_status(&job.source_directory.name, Status::Failed(format!("{}", e)))
+                    let status = match &e {
+                        Error::BuildTimedOut { timeout } => Status::TimedOut(timeout.as_secs()),
+                        Error::BuildCancelled => Status::Cancelled,
+                        _ => Status::Failed(format!("{}", e)),
+                    };
+                    status_tracker.update_status(&job.source_directory.name, status)
                 }
             };
         }
+
+        if !pending.is_empty() {
+            Worker::persist(&pending_path, pending);
+        }
     }
 
-    pub fn new_job(&self, job: Request) -> Result<(), Error> {
+    /// Queues a job, unless its namespace already has as many builds as its quota allows.
+    ///
+    /// The job replaces any queued build of the same function, which is skipped.
+    pub fn new_job(&self, mut job: Request) -> Result<(), Error> {
         if let Some(worker) = &self.handle {
-            let mtx_handle = worker.job_tx.lock().map_err(|_e| Error::JobLockError)?;
-            mtx_handle.send(job).context(JobDispatchError)
+            let mut queue = self.shared.queue.lock().map_err(|_e| Error::JobLockError)?;
+            let name = job.source_directory.name.clone();
+            let superseded =
+                |e: &QueueEntry| e.info.name == name && e.info.state == BuildState::Queued;
+            if let Some(ns) = &job.namespace {
+                let max = self
+                    .shared
+                    .namespaces
+                    .quotas(ns)
+                    .context(QuotaExceeded)?
+                    .and_then(|q| q.max_builds);
+                let count = queue
+                    .entries
+                    .iter()
+                    .filter(|e| e.info.namespace.as_ref() == Some(ns) && !superseded(e))
+                    .count();
+                if let Some(max) = max.filter(|max| count >= *max) {
+                    return Err(Error::QuotaExceeded {
+                        source: namespaces::Error::BuildQuotaExceeded {
+                            name: ns.clone(),
+                            max,
+                        },
+                    });
+                }
+            }
+
+            let job_tx = worker.job_tx.lock().map_err(|_e| Error::JobLockError)?;
+            let job_tx = job_tx.as_ref().ok_or(Error::WorkerStopped)?;
+            for entry in queue.entries.iter().filter(|e| superseded(e)) {
+                log::info!(
+                    "build {} of {} superseded by a newer deploy",
+                    entry.info.id,
+                    name
+                );
+            }
+            queue.entries.retain(|e| !superseded(e));
+
+            queue.next_id += 1;
+            job.id = queue.next_id;
+            let info = BuildInfo {
+                id: job.id,
+                name: name.clone(),
+                namespace: job.namespace.clone(),
+                source_hash: job.source_hash.clone(),
+                state: BuildState::Queued,
+                queued_at: unix_now(),
+                started_at: None,
+            };
+            let cancel = job.cancel.clone();
+            self.shared.status_tracker.add(&name);
+            job_tx.send(job).map_err(|_| Error::JobDispatchError)?;
+            queue.entries.push(QueueEntry {
+                info,
+                cancel: cancel.clone(),
+            });
+            Ok(())
         } else {
             Err(Error::WorkerNotStarted)
         }
     }
+
+    /// Number of queued and running builds of a namespace.
+    pub fn builds(&self, namespace: &str) -> usize {
+        self.shared
+            .queue
+            .lock()
+            .map(|queue| {
+                queue
+                    .entries
+                    .iter()
+                    .filter(|e| e.info.namespace.as_deref() == Some(namespace))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Lists queued and running builds, in queue order.
+    pub fn queue(&self) -> Result<Vec<BuildInfo>, Error> {
+        let queue = self.shared.queue.lock().map_err(|_e| Error::JobLockError)?;
+        Ok(queue.entries.iter().map(|e| e.info.clone()).collect())
+    }
+
+    /// Cancels a build. Queued builds are skipped, running ones are killed.
+    pub fn cancel(&self, id: u64) -> Result<BuildInfo, Error> {
+        let mut queue = self.shared.queue.lock().map_err(|_e| Error::JobLockError)?;
+        let entry = queue
+            .entries
+            .iter()
+            .find(|e| e.info.id == id)
+            .ok_or(Error::UnknownBuild { id })?;
+        let info = entry.info.clone();
+        match info.state {
+            BuildState::Queued => {
+                queue.remove(id);
+                self.shared.status_tracker.cancel(&info.name);
+            }
+            // The compile loop reports the build as cancelled once it is killed.
+            BuildState::Running => entry.cancel.store(true, Ordering::SeqCst),
+        }
+        log::info!("cancelled build {} of {}", id, info.name);
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::funcky::DeploymentStore;
+    use crate::telemetry::ExportTarget;
+
+    const SOURCE_DIR: &str = "/build/f/src-1";
+
+    /// A cargo JSON message for a target of a crate, relative to the source directory.
+    fn message(reason: &str, krate: &str, kind: &str, files: &[&str]) -> String {
+        serde_json::json!({
+            "reason": reason,
+            "manifest_path": format!("{}/{}", krate, "Cargo.toml"),
+            "target": { "kind": [kind] },
+            "filenames": files,
+        })
+        .to_string()
+    }
+
+    fn artifact(krate: &str, kind: &str, file: &str) -> String {
+        message("compiler-artifact", krate, kind, &[file])
+    }
+
+    fn find(messages: &[String], runtime: Runtime) -> Result<PathBuf, Error> {
+        let stdout = messages.join("\n");
+        find_artifact(stdout.as_bytes(), Path::new(SOURCE_DIR), runtime)
+    }
+
+    #[test]
+    fn find_artifact_picks_the_bundles_library() {
+        let root = SOURCE_DIR;
+        let member = &format!("{}/members/a", SOURCE_DIR);
+        let other = &format!("{}/members/b", SOURCE_DIR);
+        let vendored = &format!("{}/vendor/dep", SOURCE_DIR);
+        let cases = [
+            (vec![artifact(root, "cdylib", "/t/libf.so")], "/t/libf.so"),
+            // Dependencies may build libraries of their own.
+            (
+                vec![
+                    artifact("/registry/dep", "cdylib", "/t/libdep.so"),
+                    artifact(vendored, "cdylib", "/t/libvendored.so"),
+                    artifact(root, "cdylib", "/t/libf.so"),
+                ],
+                "/t/libf.so",
+            ),
+            // The root package wins over other members of its workspace.
+            (
+                vec![
+                    artifact(member, "cdylib", "/t/liba.so"),
+                    artifact(root, "cdylib", "/t/libf.so"),
+                ],
+                "/t/libf.so",
+            ),
+            // A single member of a virtual workspace.
+            (
+                vec![
+                    artifact(member, "cdylib", "/t/liba.so"),
+                    artifact(other, "lib", "/t/libb.rlib"),
+                ],
+                "/t/liba.so",
+            ),
+            // Only the file of the runtime's type counts, and other lines are skipped.
+            (
+                vec![
+                    String::from("Compiling f v0.1.0"),
+                    message("build-script-executed", root, "cdylib", &["/t/libx.so"]),
+                    message(
+                        "compiler-artifact",
+                        root,
+                        "cdylib",
+                        &["/t/libf.rlib", "/t/libf.so"],
+                    ),
+                ],
+                "/t/libf.so",
+            ),
+        ];
+        for (messages, expected) in cases.iter() {
+            let found = find(messages, Runtime::Native).unwrap();
+            assert_eq!(found, PathBuf::from(expected), "{:?}", messages);
+        }
+
+        let wasm = vec![artifact(root, "cdylib", "/t/f.wasm")];
+        assert_eq!(
+            find(&wasm, Runtime::Wasm).unwrap(),
+            PathBuf::from("/t/f.wasm")
+        );
+    }
+
+    #[test]
+    fn find_artifact_without_a_single_library() {
+        let member = &format!("{}/members/a", SOURCE_DIR);
+        let other = &format!("{}/members/b", SOURCE_DIR);
+
+        let missing = [
+            vec![],
+            vec![artifact(SOURCE_DIR, "lib", "/t/libf.rlib")],
+            vec![artifact("/registry/dep", "cdylib", "/t/libdep.so")],
+            vec![artifact(SOURCE_DIR, "cdylib", "/t/f.wasm")],
+        ];
+        for messages in missing.iter() {
+            assert!(
+                matches!(
+                    find(messages, Runtime::Native),
+                    Err(Error::MissingArtifact { .. })
+                ),
+                "{:?}",
+                messages
+            );
+        }
+
+        let several = vec![
+            artifact(member, "cdylib", "/t/liba.so"),
+            artifact(other, "cdylib", "/t/libb.so"),
+        ];
+        match find(&several, Runtime::Native) {
+            Err(Error::AmbiguousArtifact { artifacts }) => {
+                assert_eq!(artifacts, vec!["/t/liba.so", "/t/libb.so"])
+            }
+            r => panic!("expected an ambiguous artifact, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resolution_errors() {
+        let cases = [
+            (
+                "error: no matching package named `left-pad` found\n\
+                 location searched: registry `crates-io`\n\
+                 required by package `f v0.1.0`",
+                Some(("left-pad", "registry `crates-io`")),
+            ),
+            (
+                "error: failed to select a version for the requirement `serde = \"^9.0\"`\n\
+                 candidate versions found which didn't match: 1.0.130\n\
+                 location searched: directory source `/build/f/src-1/vendor`",
+                Some((
+                    "serde = \"^9.0\"",
+                    "directory source `/build/f/src-1/vendor`",
+                )),
+            ),
+            (
+                "error: no matching package named `left-pad` found",
+                Some(("left-pad", "no location searched")),
+            ),
+            ("error[E0425]: cannot find value `x` in this scope", None),
+            ("", None),
+        ];
+        for (stderr, expected) in cases.iter() {
+            match (resolution_error(stderr), expected) {
+                (Some(Error::MissingCrate { name, location }), Some((n, l))) => {
+                    assert_eq!((name.as_str(), location.as_str()), (*n, *l), "{}", stderr)
+                }
+                (None, None) => {}
+                (r, _) => panic!("unexpected result for {:?}: {:?}", stderr, r),
+            }
+        }
+
+        let lockfiles = [
+            "error: the lock file /build/f/src-1/Cargo.lock needs to be updated but --frozen \
+             was passed to prevent this",
+            "error: cannot create the lock file /build/f/src-1/Cargo.lock because --frozen was \
+             passed to prevent this",
+        ];
+        for stderr in lockfiles.iter() {
+            assert!(
+                matches!(resolution_error(stderr), Some(Error::LockfileOutdated)),
+                "{}",
+                stderr
+            );
+        }
+    }
+
+    #[test]
+    fn validate_build_flags() {
+        let flags = |features: &[&str], profile: Option<&str>| BuildFlags {
+            features: features.iter().map(|f| String::from(*f)).collect(),
+            profile: profile.map(String::from),
+            ..BuildFlags::default()
+        };
+        let cases = [
+            (flags(&[], None), true),
+            (
+                flags(&["json", "serde/derive", "a-b_c", "v1.2+x"], None),
+                true,
+            ),
+            (flags(&[], Some("release")), true),
+            (flags(&[], Some("fast-build_2")), true),
+            (flags(&[""], None), false),
+            (flags(&["--all-features"], None), false),
+            (flags(&["a b"], None), false),
+            (flags(&["a,b"], None), false),
+            (flags(&["$(id)"], None), false),
+            (flags(&[], Some("")), false),
+            (flags(&[], Some("-Zunstable")), false),
+            (flags(&[], Some("../release")), false),
+            (flags(&[], Some("release debug")), false),
+        ];
+        for (flags, valid) in cases.iter() {
+            assert_eq!(flags.validate().is_ok(), *valid, "{:?}", flags);
+        }
+    }
+
+    fn sandbox_config() -> SandboxConfig {
+        SandboxConfig {
+            cpu_secs: 600,
+            memory_bytes: u64::MAX,
+            file_size_bytes: u64::MAX,
+            timeout: Duration::from_secs(600),
+            isolate: false,
+        }
+    }
+
+    /// A bundle of a library crate depending on `dependencies`, optionally with an empty
+    /// `vendor` directory.
+    fn bundle(dir: &Path, name: &str, dependencies: &str, vendored: bool) -> DropDir {
+        let bundle = DropDir::new(dir.join(name), name).unwrap();
+        let cargo_toml = format!(
+            "[package]\nname = \"f\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+             [lib]\ncrate-type = [\"cdylib\"]\n\n[dependencies]\n{}",
+            dependencies
+        );
+        fs::write(bundle.path().join("Cargo.toml"), cargo_toml).unwrap();
+        fs::create_dir_all(bundle.path().join("src")).unwrap();
+        fs::write(bundle.path().join("src/lib.rs"), "").unwrap();
+        if vendored {
+            fs::create_dir_all(bundle.path().join(VENDOR_DIR)).unwrap();
+        }
+        bundle
+    }
+
+    fn build(
+        dir: &Path,
+        bundle: DropDir,
+        registry: Option<RegistrySource>,
+        network: NetworkMode,
+    ) -> Result<PathBuf, Error> {
+        let cache = BuildCache::new(dir.join("cache"), None, u64::MAX).unwrap();
+        let options = BuildOptions {
+            registry,
+            network,
+            sandbox: sandbox_config(),
+        }
+        .resolve()?;
+        let request = Request::new(bundle, None, BuildFlags::default(), None);
+        request
+            .execute(&cache, &options)
+            .map(|(so_path, _)| so_path)
+    }
+
+    #[test]
+    fn offline_builds() {
+        let missing = "left-pad = \"1\"\n";
+        let cases = [
+            // (dependencies, vendored, mirrored, network, missing crate)
+            ("", true, false, NetworkMode::Online, None),
+            ("", false, true, NetworkMode::Offline, None),
+            (
+                missing,
+                true,
+                false,
+                NetworkMode::Online,
+                Some("directory source"),
+            ),
+            (
+                missing,
+                false,
+                true,
+                NetworkMode::Offline,
+                Some("directory source"),
+            ),
+            // Vendored dependencies win over the mirror.
+            (missing, true, true, NetworkMode::Offline, Some("/vendor")),
+        ];
+        for &(dependencies, vendored, mirrored, network, missing) in cases.iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let mirror = dir.path().join("mirror");
+            fs::create_dir_all(&mirror).unwrap();
+            let registry = Some(RegistrySource::Directory(mirror)).filter(|_| mirrored);
+
+            let bundle = bundle(dir.path(), "f", dependencies, vendored);
+            let result = build(dir.path(), bundle, registry, network);
+            match (result, missing) {
+                (Ok(so_path), None) => assert!(so_path.ends_with("libf.so")),
+                (Err(Error::MissingCrate { name, location }), Some(searched)) => {
+                    assert_eq!(name, "left-pad");
+                    assert!(location.contains(searched), "{}", location);
+                }
+                (r, _) => panic!(
+                    "unexpected result for {:?} {} {}: {:?}",
+                    dependencies, vendored, mirrored, r
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn frozen_builds_need_a_lockfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = bundle(dir.path(), "f", "", false);
+        assert!(matches!(
+            build(dir.path(), bundle, None, NetworkMode::Frozen),
+            Err(Error::LockfileOutdated)
+        ));
+    }
+
+    #[test]
+    fn registries_must_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = bundle(dir.path(), "f", "", false);
+        let registry = Some(RegistrySource::Local(dir.path().join("missing")));
+        assert!(matches!(
+            build(dir.path(), bundle, registry, NetworkMode::Offline),
+            Err(Error::InvalidRegistry { .. })
+        ));
+    }
+
+    /// A bundle whose build script runs until its build is killed.
+    fn slow_bundle(dir: &Path, name: &str) -> DropDir {
+        let bundle = bundle(dir, name, "", false);
+        let build_rs = "fn main() { std::thread::sleep(std::time::Duration::from_secs(600)); }";
+        fs::write(bundle.path().join("build.rs"), build_rs).unwrap();
+        bundle
+    }
+
+    fn compile_worker(dir: &Path) -> Worker {
+        let deployments = DeploymentStore::load(dir.join("deployments.json"), 1).unwrap();
+        let options = BuildOptions {
+            registry: None,
+            network: NetworkMode::Offline,
+            sandbox: sandbox_config(),
+        };
+        Worker::new(
+            dir.join("so"),
+            dir.join("pending_builds.json"),
+            Arc::new(StatusTracker::new(Arc::new(deployments))),
+            Arc::new(NamespaceStore::load(dir.join("namespaces.json")).unwrap()),
+            Arc::new(BuildCache::new(dir.join("cache"), None, u64::MAX).unwrap()),
+            options,
+            Arc::new(Tracer::new(&ExportTarget::Disabled, "test").unwrap()),
+        )
+    }
+
+    fn queue(worker: &Worker, dir: &Path, name: &str, slow: bool) {
+        let bundle = if slow {
+            slow_bundle(dir, name)
+        } else {
+            bundle(dir, name, "", false)
+        };
+        worker
+            .new_job(Request::new(bundle, None, BuildFlags::default(), None))
+            .unwrap();
+    }
+
+    /// The queued and running builds, by name.
+    fn builds(worker: &Worker) -> Vec<(String, BuildState)> {
+        let queue = worker.queue().unwrap();
+        queue.into_iter().map(|b| (b.name, b.state)).collect()
+    }
+
+    fn status(worker: &Worker, name: &str) -> Status {
+        let statuses = worker.shared.status_tracker.all().unwrap();
+        statuses[name].status.clone()
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn builds_are_superseded_and_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut worker = compile_worker(dir.path());
+        let results = worker.start().unwrap();
+
+        queue(&worker, dir.path(), "slow", true);
+        wait_until(|| builds(&worker)[0].1 == BuildState::Running);
+
+        // A newer deploy replaces the queued build of the same function.
+        queue(&worker, dir.path(), "f", false);
+        queue(&worker, dir.path(), "f", false);
+        let queued = worker.queue().unwrap();
+        assert_eq!(
+            builds(&worker),
+            vec![
+                (String::from("slow"), BuildState::Running),
+                (String::from("f"), BuildState::Queued),
+            ]
+        );
+        assert_eq!(queued[1].id, 3);
+
+        assert_eq!(worker.cancel(queued[1].id).unwrap().name, "f");
+        assert_eq!(status(&worker, "f"), Status::Cancelled);
+        assert!(matches!(
+            worker.cancel(queued[1].id),
+            Err(Error::UnknownBuild { id: 3 })
+        ));
+
+        // Cancelling the running build kills cargo.
+        assert_eq!(
+            worker.cancel(queued[0].id).unwrap().state,
+            BuildState::Running
+        );
+        wait_until(|| worker.queue().unwrap().is_empty());
+        assert_eq!(status(&worker, "slow"), Status::Cancelled);
+
+        worker
+            .stop(QueuedBuilds::Finish, Duration::from_secs(5))
+            .unwrap();
+        assert!(results.try_recv().is_err());
+        assert!(!dir.path().join("pending_builds.json").exists());
+    }
+
+    #[test]
+    fn queued_builds_are_persisted_on_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let pending_path = dir.path().join("pending_builds.json");
+        let mut worker = compile_worker(dir.path());
+        let _results = worker.start().unwrap();
+
+        queue(&worker, dir.path(), "slow", true);
+        wait_until(|| builds(&worker)[0].1 == BuildState::Running);
+        queue(&worker, dir.path(), "a", false);
+        queue(&worker, dir.path(), "b", false);
+
+        // The running build outlives the wait, so it is cancelled and persisted too.
+        let started = Instant::now();
+        worker
+            .stop(QueuedBuilds::Persist, Duration::from_millis(500))
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(30));
+
+        let pending: Vec<PendingBuild> = state::load_json(&pending_path).unwrap();
+        let names: Vec<&str> = pending.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["slow", "a", "b"]);
+        for build in pending.iter() {
+            assert!(
+                build.directory.join("Cargo.toml").exists(),
+                "{}",
+                build.name
+            );
+        }
+
+        // They are queued again on the next start.
+        let mut restarted = compile_worker(dir.path());
+        let _results = restarted.start().unwrap();
+        let names: Vec<String> = builds(&restarted).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["slow", "a", "b"]);
+        assert!(!pending_path.exists());
+        restarted
+            .stop(QueuedBuilds::Persist, Duration::from_millis(100))
+            .unwrap();
+    }
 }
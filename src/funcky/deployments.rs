@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ResultExt, Snafu};
+
+use super::clock::unix_now;
+use super::compiler::BuildFlags;
+use super::state;
+use super::{GitSource, Status};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to load deployments from [{}]: {}", path.display(), source))]
+    FailedToLoadDeployments {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to save deployments: {}", source))]
+    FailedToSaveDeployments {
+        source: io::Error,
+    },
+
+    DeploymentLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What the server knows of a deployed function, kept across restarts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Deployment {
+    /// Installed library, relative to the artifact directory.
+    pub artifact: Option<PathBuf>,
+
+    /// Incremented by every install.
+    pub version: u64,
+
+    /// SHA-256 of what the installed library was built from: the source bundle, the commit of
+    /// git deploys, or the library itself when deployed prebuilt.
+    pub source_hash: Option<String>,
+
+    pub status: Status,
+
+    /// Functions exported by the installed library.
+    #[serde(default)]
+    pub functions: Vec<String>,
+
+    /// Unix time of the last change.
+    pub updated_at: u64,
+
+    /// Installed versions, oldest first, as many as the retention allows.
+    #[serde(default)]
+    pub versions: Vec<Version>,
+}
+
+/// An installed version of a deployment, and what it was built from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Version {
+    pub version: u64,
+
+    /// Unix time of the install.
+    pub installed_at: u64,
+
+    pub source_hash: Option<String>,
+
+    /// Repository and commit of git deploys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<GitSource>,
+
+    #[serde(default)]
+    pub flags: BuildFlags,
+}
+
+impl Version {
+    /// A version about to be installed. It is numbered by the registry.
+    pub fn new(
+        source_hash: Option<String>,
+        source: Option<GitSource>,
+        flags: BuildFlags,
+    ) -> Version {
+        Version {
+            version: 0,
+            installed_at: unix_now(),
+            source_hash,
+            source,
+            flags,
+        }
+    }
+}
+
+impl Deployment {
+    fn new(status: Status) -> Deployment {
+        Deployment {
+            artifact: None,
+            version: 0,
+            source_hash: None,
+            status,
+            functions: Vec::new(),
+            updated_at: unix_now(),
+            versions: Vec::new(),
+        }
+    }
+
+    /// Looks up an installed version, the current one by default.
+    pub fn version(&self, version: Option<u64>) -> Option<Version> {
+        let version = version.unwrap_or(self.version);
+        if let Some(v) = self.versions.iter().find(|v| v.version == version) {
+            return Some(v.clone());
+        }
+
+        // Deployments installed before versions were kept only know their current one.
+        if version == self.version && self.versions.is_empty() && self.artifact.is_some() {
+            return Some(Version {
+                version,
+                installed_at: self.updated_at,
+                source_hash: self.source_hash.clone(),
+                source: None,
+                flags: BuildFlags::default(),
+            });
+        }
+        None
+    }
+
+    /// A deployment found on startup, installed before the registry existed.
+    fn existing() -> Deployment {
+        Deployment {
+            version: 1,
+            ..Deployment::new(Status::Ready)
+        }
+    }
+}
+
+/// The DeploymentStore keeps the persisted deployment registry, by job name.
+pub struct DeploymentStore {
+    path: PathBuf,
+    deployments: RwLock<BTreeMap<String, Deployment>>,
+
+    /// Versions kept per deployment.
+    retention: usize,
+}
+
+impl DeploymentStore {
+    pub fn load<P: AsRef<Path>>(path: P, retention: usize) -> Result<DeploymentStore> {
+        let deployments = state::load_json(path.as_ref()).context(FailedToLoadDeployments {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+        Ok(DeploymentStore {
+            path: PathBuf::from(path.as_ref()),
+            deployments: RwLock::new(deployments),
+            retention: retention.max(1),
+        })
+    }
+
+    /// Records the status of a deployment, creating it if needed.
+    pub fn set_status(&self, name: &str, status: &Status) -> Result<()> {
+        let mut deployments = self
+            .deployments
+            .write()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        match deployments.get_mut(name) {
+            Some(deployment) if &deployment.status == status => return Ok(()),
+            Some(deployment) => {
+                deployment.status = status.clone();
+                deployment.updated_at = unix_now();
+            }
+            None => {
+                deployments.insert(String::from(name), Deployment::new(status.clone()));
+            }
+        }
+        self.save(&deployments)
+    }
+
+    /// Records a newly installed library, as a new version of its deployment.
+    pub fn installed(
+        &self,
+        name: &str,
+        artifact: &Path,
+        functions: &[String],
+        mut version: Version,
+    ) -> Result<()> {
+        let mut deployments = self
+            .deployments
+            .write()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        let deployment = deployments
+            .entry(String::from(name))
+            .or_insert_with(|| Deployment::new(Status::Ready));
+        deployment.artifact = Some(PathBuf::from(artifact));
+        deployment.version += 1;
+        deployment.source_hash = version.source_hash.clone();
+        deployment.status = Status::Ready;
+        deployment.functions = functions.to_vec();
+        deployment.updated_at = unix_now();
+
+        version.version = deployment.version;
+        deployment.versions.push(version);
+        let excess = deployment.versions.len().saturating_sub(self.retention);
+        deployment.versions.drain(..excess);
+        self.save(&deployments)
+    }
+
+    /// Records a library loaded on startup. Libraries deployed before the registry existed
+    /// start at version 1. The status of the last deploy is kept, even if it failed, since it
+    /// didn't replace the library.
+    pub fn loaded(&self, name: &str, artifact: &Path, functions: &[String]) -> Result<()> {
+        let mut deployments = self
+            .deployments
+            .write()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        let deployment = deployments
+            .entry(String::from(name))
+            .or_insert_with(Deployment::existing);
+        deployment.artifact = Some(PathBuf::from(artifact));
+        if let Status::Quarantined(_) = deployment.status {
+            deployment.status = Status::Ready;
+        }
+        deployment.functions = functions.to_vec();
+        self.save(&deployments)
+    }
+
+    /// Records a library set aside on startup, at its new location.
+    pub fn quarantined(&self, name: &str, artifact: &Path, reason: String) -> Result<()> {
+        let mut deployments = self
+            .deployments
+            .write()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        let deployment = deployments
+            .entry(String::from(name))
+            .or_insert_with(Deployment::existing);
+        deployment.artifact = Some(PathBuf::from(artifact));
+        deployment.status = Status::Quarantined(reason);
+        deployment.functions.clear();
+        deployment.updated_at = unix_now();
+        self.save(&deployments)
+    }
+
+    /// Settles the deployments left behind by the previous run, once every library is loaded:
+    /// builds that were queued or running are failed, and ready deployments whose library is
+    /// gone are reported.
+    pub fn reconcile(&self, loaded: &[String]) -> Result<Vec<(String, Status)>> {
+        let mut deployments = self
+            .deployments
+            .write()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        for (name, deployment) in deployments.iter_mut() {
+            let reason = match deployment.status {
+                Status::Accepted | Status::Compiling => "interrupted by a server restart",
+                Status::Ready if !loaded.contains(name) => "library not found on startup",
+                _ => continue,
+            };
+            deployment.status = Status::Failed(String::from(reason));
+            deployment.updated_at = unix_now();
+        }
+        self.save(&deployments)?;
+
+        Ok(deployments
+            .iter()
+            .filter(|(name, _)| !loaded.contains(name))
+            .map(|(name, d)| (name.clone(), d.status.clone()))
+            .collect())
+    }
+
+    pub fn all(&self) -> Result<BTreeMap<String, Deployment>> {
+        let deployments = self
+            .deployments
+            .read()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        Ok(deployments.clone())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Deployment>> {
+        let deployments = self
+            .deployments
+            .read()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        Ok(deployments.get(name).cloned())
+    }
+
+    /// Hashes of the sources of every kept version.
+    pub fn source_hashes(&self) -> Result<HashSet<String>> {
+        let deployments = self
+            .deployments
+            .read()
+            .map_err(|_e| Error::DeploymentLockFailure)?;
+        Ok(deployments
+            .values()
+            .flat_map(|d| {
+                d.versions
+                    .iter()
+                    .filter_map(|v| v.source_hash.clone())
+                    .chain(d.source_hash.clone())
+            })
+            .collect())
+    }
+
+    fn save(&self, deployments: &BTreeMap<String, Deployment>) -> Result<()> {
+        state::save_json(&self.path, deployments).context(FailedToSaveDeployments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &Path) -> DeploymentStore {
+        DeploymentStore::load(dir.join("deployments.json"), 2).unwrap()
+    }
+
+    fn functions(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    fn version(source_hash: &str) -> Version {
+        Version::new(Some(String::from(source_hash)), None, BuildFlags::default())
+    }
+
+    #[test]
+    fn installs_are_versioned_and_retained() {
+        let dir = tempfile::tempdir().unwrap();
+        let deployments = store(dir.path());
+        for hash in ["a", "b", "c"].iter() {
+            deployments
+                .installed("f", Path::new("libf.so"), &functions(&["f"]), version(hash))
+                .unwrap();
+        }
+
+        // Reloaded, since the registry is kept across restarts.
+        let deployment = store(dir.path()).get("f").unwrap().unwrap();
+        assert_eq!(deployment.version, 3);
+        assert_eq!(deployment.source_hash.as_deref(), Some("c"));
+        let kept: Vec<u64> = deployment.versions.iter().map(|v| v.version).collect();
+        assert_eq!(kept, vec![2, 3]);
+        assert!(deployment.version(Some(1)).is_none());
+        assert_eq!(
+            deployment.version(None).unwrap().source_hash.as_deref(),
+            Some("c")
+        );
+
+        let hashes = deployments.source_hashes().unwrap();
+        assert_eq!(
+            hashes,
+            ["b", "c"].iter().map(|h| String::from(*h)).collect()
+        );
+    }
+
+    #[test]
+    fn quarantined_libraries_are_ready_once_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let deployments = store(dir.path());
+        let quarantined = Path::new(".quarantine/libf.so");
+        deployments
+            .quarantined("f", quarantined, String::from("bad"))
+            .unwrap();
+
+        let deployment = deployments.get("f").unwrap().unwrap();
+        assert_eq!(deployment.status, Status::Quarantined(String::from("bad")));
+        assert_eq!(deployment.artifact.as_deref(), Some(quarantined));
+        assert_eq!(deployment.version, 1);
+
+        deployments
+            .loaded("f", Path::new("libf.so"), &functions(&["f", "g"]))
+            .unwrap();
+        let deployment = deployments.get("f").unwrap().unwrap();
+        assert_eq!(deployment.status, Status::Ready);
+        assert_eq!(deployment.functions, functions(&["f", "g"]));
+    }
+
+    #[test]
+    fn reconcile_settles_the_previous_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let deployments = store(dir.path());
+        let failed = |reason: &str| Status::Failed(String::from(reason));
+        let cases = [
+            // (name, status, loaded, settled)
+            (
+                "accepted",
+                Status::Accepted,
+                false,
+                failed("interrupted by a server restart"),
+            ),
+            (
+                "compiling",
+                Status::Compiling,
+                true,
+                failed("interrupted by a server restart"),
+            ),
+            ("ready", Status::Ready, true, Status::Ready),
+            (
+                "gone",
+                Status::Ready,
+                false,
+                failed("library not found on startup"),
+            ),
+            ("failed", failed("build"), false, failed("build")),
+            ("cancelled", Status::Cancelled, false, Status::Cancelled),
+        ];
+        for (name, status, _, _) in cases.iter() {
+            deployments.set_status(name, status).unwrap();
+        }
+
+        let loaded: Vec<String> = cases
+            .iter()
+            .filter(|(_, _, loaded, _)| *loaded)
+            .map(|(name, _, _, _)| String::from(*name))
+            .collect();
+        let reported: BTreeMap<String, Status> = deployments
+            .reconcile(&loaded)
+            .unwrap()
+            .into_iter()
+            .collect();
+        for (name, _, loaded, settled) in cases.iter() {
+            assert_eq!(
+                &deployments.get(name).unwrap().unwrap().status,
+                settled,
+                "{}",
+                name
+            );
+            assert_eq!(
+                reported.get(*name),
+                Some(settled).filter(|_| !loaded),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use sha2::{Digest, Sha256};
+
+/// Returns the hex-encoded SHA-256 digest of some data.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -4,28 +4,47 @@ use std::path::{Path, PathBuf};
 
 // TODO: Rename to dropjob.
 pub struct DropDir {
+    root: PathBuf,
     path: PathBuf,
     pub name: String, // Name of the drop job.
+    keep: bool,
 }
 
 impl DropDir {
     pub fn new<T: AsRef<Path>>(path: T, name: &str) -> io::Result<DropDir> {
         fs::create_dir_all(path.as_ref())?;
         Ok(DropDir {
+            root: PathBuf::from(path.as_ref()),
             path: PathBuf::from(path.as_ref()),
             name: String::from(name),
+            keep: false,
         })
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// Points the job at a subdirectory. The whole directory is still removed on drop.
+    pub fn enter<T: AsRef<Path>>(&mut self, subdir: T) {
+        self.path = self.root.join(subdir);
+    }
+
+    /// Keeps the directory around, returning it and the subdirectory the job is pointed at.
+    pub fn keep(mut self) -> (PathBuf, PathBuf) {
+        self.keep = true;
+        let subdir = self.path.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        (self.root.clone(), PathBuf::from(subdir))
+    }
 }
 
 impl Drop for DropDir {
     fn drop(&mut self) {
-        if let Err(e) = fs::remove_dir_all(&self.path) {
-            log::error!("error dropping dir [{}]: {}", self.path.display(), e);
+        if self.keep {
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&self.root) {
+            log::error!("error dropping dir [{}]: {}", self.root.display(), e);
         }
     }
 }
@@ -0,0 +1,37 @@
+//! Libraries loaded by the tests, built from `fixtures/` once per test run.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Builds the native fixture library, and copies it to `dir` as `{name}.so`.
+///
+/// Every copy is a library of its own once loaded, so tests don't share its state.
+pub fn native_library(dir: &Path, name: &str) -> PathBuf {
+    static BUILT: OnceLock<PathBuf> = OnceLock::new();
+    let built = BUILT.get_or_init(|| {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = root.join("target").join("fixtures");
+        let output = Command::new(env::var("CARGO").unwrap_or_else(|_| String::from("cargo")))
+            .arg("build")
+            .arg("--offline")
+            .arg("--manifest-path")
+            .arg(root.join("fixtures").join("native").join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .output()
+            .expect("failed to run cargo");
+        assert!(
+            output.status.success(),
+            "failed to build the native fixture: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        target_dir.join("debug").join("libfixture.so")
+    });
+
+    let path = dir.join(format!("{}.so", name));
+    fs::copy(built, &path).unwrap();
+    path
+}
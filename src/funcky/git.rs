@@ -0,0 +1,383 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, ResultExt, Snafu};
+
+use super::compiler::BuildFlags;
+use super::digest::sha256_hex;
+use super::namespaces::is_valid_function_name;
+use super::sandbox::{Outcome, Sandbox, SandboxConfig};
+
+/// Ref deployed when a request doesn't name one.
+const DEFAULT_REF: &str = "HEAD";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid git deploy: {}", reason))]
+    InvalidGitSpec { reason: String },
+
+    #[snafu(display("Failed to run git: {}", source))]
+    FailedToRunGit { source: io::Error },
+
+    #[snafu(display("git {} failed: {}", command, stderr))]
+    GitFailed { command: String, stderr: String },
+
+    #[snafu(display("git {} timed out after {:?}", command, timeout))]
+    GitTimedOut { command: String, timeout: Duration },
+
+    #[snafu(display("Unknown ref: {}", reference))]
+    UnknownRef { reference: String },
+
+    #[snafu(display("Subdirectory [{}] is not in the repository", subdir))]
+    MissingSubdirectory { subdir: String },
+
+    #[snafu(display("Failed to prepare [{}]: {}", path.display(), source))]
+    FailedToPrepareDirectory { source: io::Error, path: PathBuf },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A deploy from a git repository.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitSpec {
+    /// URL or local path of the repository, bare or not.
+    pub url: String,
+
+    /// Branch, tag or commit to deploy. Defaults to the repository's HEAD.
+    #[serde(default, rename = "ref")]
+    pub reference: Option<String>,
+
+    /// Directory of the function's crate, relative to the repository root.
+    #[serde(default)]
+    pub subdir: Option<String>,
+
+    /// Name of the function. Defaults to the last component of the subdirectory or URL.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub build: Option<BuildFlags>,
+}
+
+/// Where a deployment was built from, recorded in its manifest.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GitSource {
+    pub url: String,
+
+    #[serde(rename = "ref")]
+    pub reference: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<String>,
+
+    /// The commit the ref resolved to.
+    pub commit: String,
+}
+
+/// Whether a ref is a plain branch, tag or commit name, following the rules of
+/// `git check-ref-format` rather than the revision syntax of `git rev-parse`.
+fn is_valid_reference(reference: &str) -> bool {
+    !reference.is_empty()
+        && !reference.starts_with('-')
+        && !reference.starts_with('/')
+        && !reference.ends_with('/')
+        && !reference.ends_with('.')
+        && !reference.contains("..")
+        && !reference.contains("//")
+        && !reference.contains("@{")
+        && !reference
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
+
+impl GitSpec {
+    pub fn reference(&self) -> &str {
+        self.reference.as_deref().unwrap_or(DEFAULT_REF)
+    }
+
+    /// Checks the request, returning the name of the function.
+    pub fn validate(&self) -> Result<String> {
+        ensure!(
+            !self.url.trim().is_empty() && !self.url.starts_with('-'),
+            InvalidGitSpec {
+                reason: "url must be a repository URL or path"
+            }
+        );
+        // Local repositories are given as paths, so they share a mirror however they're written.
+        // Remote helpers, as in `ext::{command}`, can run arbitrary commands.
+        ensure!(
+            !self.url.to_ascii_lowercase().starts_with("file:")
+                && !self.url.split('/').next().unwrap_or("").contains("::"),
+            InvalidGitSpec {
+                reason: "url must be a remote repository URL or a local path"
+            }
+        );
+        ensure!(
+            is_valid_reference(self.reference()),
+            InvalidGitSpec {
+                reason: "ref must be a branch, tag or commit"
+            }
+        );
+        if let Some(subdir) = &self.subdir {
+            ensure!(
+                Path::new(subdir)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir)),
+                InvalidGitSpec {
+                    reason: "subdir must be relative to the repository root"
+                }
+            );
+        }
+        if let Some(build) = &self.build {
+            build
+                .validate()
+                .map_err(|reason| Error::InvalidGitSpec { reason })?;
+        }
+
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                let from = self
+                    .subdir
+                    .as_deref()
+                    .filter(|s| Path::new(s).file_name().is_some())
+                    .unwrap_or_else(|| self.url.trim_end_matches('/'));
+                let last = from.rsplit(['/', ':']).next().unwrap_or("");
+                String::from(last.trim_end_matches(".git"))
+            }
+        };
+        ensure!(
+            is_valid_function_name(&name),
+            InvalidGitSpec {
+                reason: format!(
+                    "invalid function name [{}]: names may only contain ASCII letters, digits, '-' and '_'",
+                    name
+                )
+            }
+        );
+        Ok(name)
+    }
+}
+
+/// Checks out repositories into build directories, through a cache of mirrors so that
+/// redeploys only fetch what changed.
+pub struct Repositories {
+    cache_dir: PathBuf,
+    sandbox: SandboxConfig,
+}
+
+impl Repositories {
+    pub fn new<P: AsRef<Path>>(cache_dir: P, sandbox: &SandboxConfig) -> Repositories {
+        Repositories {
+            cache_dir: PathBuf::from(cache_dir.as_ref()),
+            // Git runs without namespaces even when `FUNCKY_BUILD_ISOLATION` is set: fetches need
+            // the network, which isolated builds don't have. Only git itself runs, never code of
+            // the repository, since hooks and filters come from the mirror's own configuration,
+            // and it only writes to the cache and the checkout. The cleared environment, resource
+            // limits and timeout still apply.
+            sandbox: SandboxConfig {
+                isolate: false,
+                ..sandbox.clone()
+            },
+        }
+    }
+
+    /// Fetches the repository of a deploy and checks its ref out into `destination`, returning
+    /// where it was built from.
+    ///
+    /// Callers serialize checkouts of a repository, since they share its mirror.
+    pub fn checkout(&self, spec: &GitSpec, destination: &Path) -> Result<GitSource> {
+        // Git runs from the cache, so relative paths won't do.
+        let cache_dir = fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| fs::canonicalize(&self.cache_dir))
+            .context(FailedToPrepareDirectory {
+                path: &self.cache_dir,
+            })?;
+        let destination = fs::canonicalize(destination)
+            .context(FailedToPrepareDirectory { path: destination })?;
+        let url = match fs::canonicalize(&spec.url) {
+            Ok(path) => path.to_string_lossy().to_string(),
+            Err(_) => spec.url.clone(),
+        };
+        let mirror = cache_dir.join(sha256_hex(url.as_bytes()));
+
+        if mirror.join("HEAD").is_file() {
+            self.git(
+                &cache_dir,
+                Some(&mirror),
+                &["fetch", "--prune", "--quiet", "origin"],
+            )?;
+        } else {
+            // A mirror left incomplete by a failed clone is started over.
+            if mirror.exists() {
+                fs::remove_dir_all(&mirror).context(FailedToPrepareDirectory { path: &mirror })?;
+            }
+            let mut args = vec!["clone", "--mirror", "--quiet", "--"];
+            args.push(&url);
+            let mirror_path = mirror.to_string_lossy();
+            args.push(&mirror_path);
+            self.git(&cache_dir, None, &args)?;
+        }
+
+        let commit = self
+            .git(
+                &cache_dir,
+                Some(&mirror),
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("{}^{{commit}}", spec.reference()),
+                ],
+            )
+            .map_err(|_e| Error::UnknownRef {
+                reference: String::from(spec.reference()),
+            })?;
+        let commit = commit.trim();
+
+        let work_tree = destination.to_string_lossy();
+        self.git(
+            &cache_dir,
+            Some(&mirror),
+            &[
+                "--work-tree",
+                &work_tree,
+                "checkout",
+                "--force",
+                commit,
+                "--",
+                ":/",
+            ],
+        )?;
+
+        if let Some(subdir) = &spec.subdir {
+            ensure!(
+                destination.join(subdir).is_dir(),
+                MissingSubdirectory { subdir }
+            );
+        }
+
+        log::info!("checked out {} at {}", spec.url, commit);
+        Ok(GitSource {
+            url: spec.url.clone(),
+            reference: String::from(spec.reference()),
+            subdir: spec.subdir.clone(),
+            commit: String::from(commit),
+        })
+    }
+
+    /// Runs a git command in the cache, on the repository at `git_dir` if any, returning its
+    /// standard output.
+    fn git(&self, cache_dir: &Path, git_dir: Option<&Path>, args: &[&str]) -> Result<String> {
+        let subcommand = args.iter().find(|a| !a.starts_with('-')).unwrap_or(&"");
+        let sandbox = Sandbox::new(&self.sandbox).context(FailedToRunGit)?;
+        let mut command = sandbox.command("git", cache_dir, &[]);
+        command.args(args).env("GIT_TERMINAL_PROMPT", "0");
+        if let Some(git_dir) = git_dir {
+            command.env("GIT_DIR", git_dir);
+        }
+
+        match sandbox.run(command).context(FailedToRunGit)? {
+            Outcome::Exited { status, stdout, .. } if status.success() => {
+                Ok(String::from_utf8_lossy(&stdout).to_string())
+            }
+            Outcome::Exited { stderr, .. } => GitFailed {
+                command: *subcommand,
+                stderr: String::from_utf8_lossy(&stderr).trim(),
+            }
+            .fail(),
+            Outcome::TimedOut | Outcome::Cancelled => GitTimedOut {
+                command: *subcommand,
+                timeout: self.sandbox.timeout,
+            }
+            .fail(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(url: &str, reference: Option<&str>, subdir: Option<&str>) -> GitSpec {
+        GitSpec {
+            url: String::from(url),
+            reference: reference.map(String::from),
+            subdir: subdir.map(String::from),
+            name: None,
+            build: None,
+        }
+    }
+
+    #[test]
+    fn validate_names_the_function() {
+        let cases = [
+            (spec("https://git.example.com/echo.git", None, None), "echo"),
+            (spec("https://git.example.com/echo/", None, None), "echo"),
+            (spec("git@git.example.com:fns/echo.git", None, None), "echo"),
+            (spec("/srv/git/fns.git", None, Some("fns/echo")), "echo"),
+            (spec("../fns", Some("v1.2.0"), Some(".")), "fns"),
+            (spec("/srv/git/fns", Some("release/2.x"), None), "fns"),
+            (spec("/srv/git/fns", Some("0123456789abcdef"), None), "fns"),
+        ];
+        for (spec, name) in cases.iter() {
+            assert_eq!(spec.validate().unwrap(), *name, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn validate_rejects() {
+        let cases = [
+            // URLs
+            spec("", None, None),
+            spec("  ", None, None),
+            spec("--upload-pack=touch /tmp/x", None, None),
+            spec("file:///srv/git/fns.git", None, None),
+            spec("FILE:///srv/git/fns.git", None, None),
+            spec("ext::sh -c touch% /tmp/x", None, None),
+            spec("fd::17", None, None),
+            // Refs
+            spec("/srv/git/fns", Some(""), None),
+            spec("/srv/git/fns", Some("-v1"), None),
+            spec("/srv/git/fns", Some("--output=/tmp/x"), None),
+            spec("/srv/git/fns", Some("main..evil"), None),
+            spec("/srv/git/fns", Some(".."), None),
+            spec("/srv/git/fns", Some("../../etc"), None),
+            spec("/srv/git/fns", Some("main@{1}"), None),
+            spec("/srv/git/fns", Some("main~1"), None),
+            spec("/srv/git/fns", Some("main:Cargo.toml"), None),
+            spec("/srv/git/fns", Some("v1 v2"), None),
+            spec("/srv/git/fns", Some("main/"), None),
+            // Subdirectories
+            spec("/srv/git/fns", None, Some("../echo")),
+            spec("/srv/git/fns", None, Some("echo/../../x")),
+            spec("/srv/git/fns", None, Some("/echo")),
+            // Names
+            spec("https://git.example.com/", None, None),
+            spec("https://git.example.com/my fns.git", None, None),
+        ];
+        for spec in cases.iter() {
+            assert!(
+                matches!(spec.validate(), Err(Error::InvalidGitSpec { .. })),
+                "{:?}",
+                spec
+            );
+        }
+
+        let mut named = spec("/srv/git/fns", None, None);
+        named.name = Some(String::from("a/b"));
+        assert!(named.validate().is_err());
+
+        let mut flagged = spec("/srv/git/fns", None, None);
+        flagged.build = Some(BuildFlags {
+            profile: Some(String::from("-Zx")),
+            ..BuildFlags::default()
+        });
+        assert!(flagged.validate().is_err());
+    }
+}
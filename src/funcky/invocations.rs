@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use funck::Response;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::Snafu;
+
+use super::clock::unix_now;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    InvocationLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum InvocationStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed(String),
+}
+
+/// A funcktion response, as stored for later retrieval.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredResponse {
+    /// The response body, if it is valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    /// The response body, base64-encoded, if it is not valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+
+    pub metadata: HashMap<String, String>,
+}
+
+impl From<&Response> for StoredResponse {
+    fn from(resp: &Response) -> StoredResponse {
+        let (body, body_base64) = match std::str::from_utf8(resp.body()) {
+            Ok(text) => (Some(String::from(text)), None),
+            Err(_) => (None, Some(base64::encode(resp.body()))),
+        };
+        StoredResponse {
+            body,
+            body_base64,
+            metadata: resp.metadata().clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Invocation {
+    pub id: String,
+    pub function: String,
+    pub status: InvocationStatus,
+    pub submitted_at: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<StoredResponse>,
+}
+
+/// The InvocationStore keeps track of asynchronous invocations and their results.
+///
+/// Completed invocations are expired once they are older than the retention period, or once more
+/// than `max_completed` have completed, oldest first.
+pub struct InvocationStore {
+    invocations: RwLock<HashMap<String, Invocation>>,
+    retention: Duration,
+    max_completed: usize,
+}
+
+impl InvocationStore {
+    pub fn new(retention: Duration, max_completed: usize) -> InvocationStore {
+        InvocationStore {
+            invocations: RwLock::new(HashMap::new()),
+            retention,
+            max_completed,
+        }
+    }
+
+    /// Registers a new pending invocation and returns its ID.
+    pub fn create(&self, fn_name: &str) -> Result<String> {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let mut guard = self
+            .invocations
+            .write()
+            .map_err(|_e| Error::InvocationLockFailure)?;
+        self.expire(&mut guard);
+        guard.insert(
+            id.clone(),
+            Invocation {
+                id: id.clone(),
+                function: String::from(fn_name),
+                status: InvocationStatus::Pending,
+                submitted_at: unix_now(),
+                completed_at: None,
+                response: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn set_running(&self, id: &str) -> Result<()> {
+        let mut guard = self
+            .invocations
+            .write()
+            .map_err(|_e| Error::InvocationLockFailure)?;
+        if let Some(invocation) = guard.get_mut(id) {
+            invocation.status = InvocationStatus::Running;
+        }
+        Ok(())
+    }
+
+    pub fn complete<E: ToString>(
+        &self,
+        id: &str,
+        result: std::result::Result<&Response, E>,
+    ) -> Result<()> {
+        let mut guard = self
+            .invocations
+            .write()
+            .map_err(|_e| Error::InvocationLockFailure)?;
+        if let Some(invocation) = guard.get_mut(id) {
+            match result {
+                Ok(resp) => {
+                    invocation.status = InvocationStatus::Succeeded;
+                    invocation.response = Some(StoredResponse::from(resp));
+                }
+                Err(e) => invocation.status = InvocationStatus::Failed(e.to_string()),
+            }
+            invocation.completed_at = Some(unix_now());
+        }
+        self.expire(&mut guard);
+        Ok(())
+    }
+
+    /// Forgets an invocation that was never run.
+    pub fn remove(&self, id: &str) -> Result<()> {
+        let mut guard = self
+            .invocations
+            .write()
+            .map_err(|_e| Error::InvocationLockFailure)?;
+        guard.remove(id);
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Invocation>> {
+        let mut guard = self
+            .invocations
+            .write()
+            .map_err(|_e| Error::InvocationLockFailure)?;
+        self.expire(&mut guard);
+        Ok(guard.get(id).cloned())
+    }
+
+    fn expire(&self, invocations: &mut HashMap<String, Invocation>) {
+        let cutoff = unix_now().saturating_sub(self.retention.as_secs());
+        invocations.retain(|_, inv| inv.completed_at.map(|t| t >= cutoff).unwrap_or(true));
+
+        let mut completed: Vec<(u64, String)> = invocations
+            .values()
+            .filter_map(|inv| inv.completed_at.map(|t| (t, inv.id.clone())))
+            .collect();
+        if completed.len() > self.max_completed {
+            completed.sort();
+            let excess = completed.len() - self.max_completed;
+            for (_, id) in completed.into_iter().take(excess) {
+                invocations.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completed(store: &InvocationStore, completed_at: u64) -> String {
+        let id = store.create("f").unwrap();
+        store
+            .complete(&id, Ok::<_, String>(&Response::new()))
+            .unwrap();
+        store
+            .invocations
+            .write()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .completed_at = Some(completed_at);
+        id
+    }
+
+    #[test]
+    fn invocations_complete() {
+        let store = InvocationStore::new(Duration::from_secs(60), 10);
+        let cases: [(Result<Response, String>, InvocationStatus); 2] = [
+            (
+                Ok(Response::new().with_text(String::from("done"))),
+                InvocationStatus::Succeeded,
+            ),
+            (
+                Err(String::from("boom")),
+                InvocationStatus::Failed(String::from("boom")),
+            ),
+        ];
+        for (result, status) in cases.iter() {
+            let id = store.create("f").unwrap();
+            assert_eq!(
+                store.get(&id).unwrap().unwrap().status,
+                InvocationStatus::Pending
+            );
+            store.set_running(&id).unwrap();
+            assert_eq!(
+                store.get(&id).unwrap().unwrap().status,
+                InvocationStatus::Running
+            );
+
+            store.complete(&id, result.as_ref()).unwrap();
+            let invocation = store.get(&id).unwrap().unwrap();
+            assert_eq!(&invocation.status, status);
+            assert!(invocation.completed_at.is_some());
+        }
+    }
+
+    #[test]
+    fn old_invocations_expire() {
+        let store = InvocationStore::new(Duration::from_secs(60), 10);
+        let old = completed(&store, unix_now() - 120);
+        let recent = completed(&store, unix_now() - 30);
+        let pending = store.create("f").unwrap();
+
+        assert!(store.get(&old).unwrap().is_none());
+        assert!(store.get(&recent).unwrap().is_some());
+        assert!(store.get(&pending).unwrap().is_some());
+    }
+
+    #[test]
+    fn oldest_invocations_are_evicted_past_the_cap() {
+        let store = InvocationStore::new(Duration::from_secs(3600), 2);
+        let now = unix_now();
+        let ids: Vec<String> = (0..4).map(|i| completed(&store, now - 10 + i)).collect();
+        let pending = store.create("f").unwrap();
+
+        assert!(store.get(&ids[0]).unwrap().is_none());
+        assert!(store.get(&ids[1]).unwrap().is_none());
+        assert!(store.get(&ids[2]).unwrap().is_some());
+        assert!(store.get(&ids[3]).unwrap().is_some());
+        // Only completed invocations count towards the cap.
+        assert!(store.get(&pending).unwrap().is_some());
+    }
+}
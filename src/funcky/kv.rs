@@ -0,0 +1,305 @@
+//! The per-function key-value store, and the host API functions reach it through.
+//!
+//! The host API isn't passed alongside each `funck::Request`, which would change the `Funcktion`
+//! trait. Instead, a library exports `_funck_bind_kv`, which the loader calls once with the host
+//! API of the library's store. The library keeps it in a process-global static, so every function
+//! of a library shares that store, under the first function's name.
+
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use serde::Serialize;
+
+use snafu::{ResultExt, Snafu};
+
+/// Exported by libraries that use the KV store, to receive their host API when loaded.
+pub const BIND_KV_SYMBOL: &[u8] = b"_funck_bind_kv";
+
+/// Maximum number of entries returned when inspecting a function's data.
+const MAX_INSPECTED_ENTRIES: usize = 1000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to open KV store at [{}]: {}", path.display(), source))]
+    FailedToOpenStore { source: sled::Error, path: PathBuf },
+
+    #[snafu(display("KV store error: {}", source))]
+    StoreError { source: sled::Error },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A value handed over to a function. Allocated by the host, and released with `KvHost::free`.
+#[repr(C)]
+pub struct KvValue {
+    pub ptr: *mut u8,
+    pub len: usize,
+    pub cap: usize,
+}
+
+/// The KV host API, as seen by functions.
+///
+/// Every call takes the `ctx` pointer, which scopes it to the function's own data. `get` returns
+/// 1 and fills `out` when the key exists, 0 when it doesn't. Every call returns -1 on errors.
+#[repr(C)]
+pub struct KvHost {
+    pub ctx: *const c_void,
+    pub get: unsafe extern "C" fn(*const c_void, *const u8, usize, *mut KvValue) -> i32,
+    pub set: unsafe extern "C" fn(*const c_void, *const u8, usize, *const u8, usize) -> i32,
+    pub delete: unsafe extern "C" fn(*const c_void, *const u8, usize) -> i32,
+    pub free: unsafe extern "C" fn(KvValue),
+}
+
+/// Reads a buffer passed by a function, which may be `(null, 0)` when empty.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(ptr, len)
+    }
+}
+
+unsafe fn tree_and_key<'a>(
+    ctx: *const c_void,
+    key: *const u8,
+    key_len: usize,
+) -> (&'a sled::Tree, &'a [u8]) {
+    (&*(ctx as *const sled::Tree), bytes(key, key_len))
+}
+
+unsafe extern "C" fn kv_get(
+    ctx: *const c_void,
+    key: *const u8,
+    key_len: usize,
+    out: *mut KvValue,
+) -> i32 {
+    if out.is_null() {
+        return -1;
+    }
+    let (tree, key) = tree_and_key(ctx, key, key_len);
+    match tree.get(key) {
+        Ok(Some(value)) => {
+            let mut value = ManuallyDrop::new(value.to_vec());
+            *out = KvValue {
+                ptr: value.as_mut_ptr(),
+                len: value.len(),
+                cap: value.capacity(),
+            };
+            1
+        }
+        Ok(None) => 0,
+        Err(e) => {
+            log::error!("kv get failed: {}", e);
+            -1
+        }
+    }
+}
+
+unsafe extern "C" fn kv_set(
+    ctx: *const c_void,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> i32 {
+    let (tree, key) = tree_and_key(ctx, key, key_len);
+    match tree.insert(key, bytes(value, value_len)) {
+        Ok(_) => 0,
+        Err(e) => {
+            log::error!("kv set failed: {}", e);
+            -1
+        }
+    }
+}
+
+unsafe extern "C" fn kv_delete(ctx: *const c_void, key: *const u8, key_len: usize) -> i32 {
+    let (tree, key) = tree_and_key(ctx, key, key_len);
+    match tree.remove(key) {
+        Ok(_) => 0,
+        Err(e) => {
+            log::error!("kv delete failed: {}", e);
+            -1
+        }
+    }
+}
+
+unsafe extern "C" fn kv_free(value: KvValue) {
+    if !value.ptr.is_null() {
+        drop(Vec::from_raw_parts(value.ptr, value.len, value.cap));
+    }
+}
+
+/// The host API bound to a loaded library. Must outlive the library.
+pub struct KvBinding {
+    // Boxed so their addresses stay stable while the library holds them.
+    host: Box<KvHost>,
+    #[allow(dead_code)] // Only accessed by the library, through `host.ctx`.
+    tree: Box<sled::Tree>,
+}
+
+// The host API only points to the binding's own tree, which is thread-safe.
+unsafe impl Send for KvBinding {}
+unsafe impl Sync for KvBinding {}
+
+impl KvBinding {
+    fn new(tree: sled::Tree) -> KvBinding {
+        let tree = Box::new(tree);
+        let host = Box::new(KvHost {
+            ctx: &*tree as *const sled::Tree as *const c_void,
+            get: kv_get,
+            set: kv_set,
+            delete: kv_delete,
+            free: kv_free,
+        });
+        KvBinding { host, tree }
+    }
+
+    pub fn host(&self) -> *const KvHost {
+        &*self.host
+    }
+}
+
+/// A stored value, as shown to administrators.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KvEntry {
+    Text(String),
+    Base64(String),
+}
+
+/// The KvStore persists the key-value data of functions, in a separate namespace per function.
+pub struct KvStore {
+    db: sled::Db,
+}
+
+impl KvStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KvStore> {
+        let db = sled::open(path.as_ref()).context(FailedToOpenStore {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+        Ok(KvStore { db })
+    }
+
+    fn tree(&self, fn_name: &str) -> Result<sled::Tree> {
+        self.db.open_tree(fn_name).context(StoreError)
+    }
+
+    /// Builds the host API of a function, to be handed to its library.
+    pub fn bind(&self, fn_name: &str) -> Result<KvBinding> {
+        Ok(KvBinding::new(self.tree(fn_name)?))
+    }
+
+    /// Returns the first entries of a function's data, keyed by their UTF-8 lossy key.
+    pub fn entries(&self, fn_name: &str) -> Result<BTreeMap<String, KvEntry>> {
+        let mut entries = BTreeMap::new();
+        for item in self.tree(fn_name)?.iter().take(MAX_INSPECTED_ENTRIES) {
+            let (key, value) = item.context(StoreError)?;
+            let entry = match std::str::from_utf8(&value) {
+                Ok(text) => KvEntry::Text(String::from(text)),
+                Err(_) => KvEntry::Base64(base64::encode(&value)),
+            };
+            entries.insert(String::from_utf8_lossy(&key).to_string(), entry);
+        }
+        Ok(entries)
+    }
+
+    pub fn clear(&self, fn_name: &str) -> Result<()> {
+        self.tree(fn_name)?.clear().context(StoreError)?;
+        self.db.flush().context(StoreError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn empty_value() -> KvValue {
+        KvValue {
+            ptr: ptr::null_mut(),
+            len: 0,
+            cap: 0,
+        }
+    }
+
+    #[test]
+    fn host_api_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        let binding = store.bind("counter").unwrap();
+        let host = unsafe { &*binding.host() };
+        let key = b"hits";
+        let value = b"42";
+
+        unsafe {
+            let mut out = empty_value();
+            assert_eq!((host.get)(host.ctx, key.as_ptr(), key.len(), &mut out), 0);
+
+            let set = (host.set)(host.ctx, key.as_ptr(), key.len(), value.as_ptr(), 2);
+            assert_eq!(set, 0);
+            assert_eq!((host.get)(host.ctx, key.as_ptr(), key.len(), &mut out), 1);
+            assert_eq!(slice::from_raw_parts(out.ptr, out.len), value);
+            (host.free)(out);
+
+            assert_eq!((host.delete)(host.ctx, key.as_ptr(), key.len()), 0);
+            let mut out = empty_value();
+            assert_eq!((host.get)(host.ctx, key.as_ptr(), key.len(), &mut out), 0);
+        }
+    }
+
+    #[test]
+    fn null_buffers_are_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        let binding = store.bind("counter").unwrap();
+        let host = unsafe { &*binding.host() };
+
+        unsafe {
+            assert_eq!((host.set)(host.ctx, ptr::null(), 0, ptr::null(), 0), 0);
+            let mut out = empty_value();
+            assert_eq!((host.get)(host.ctx, ptr::null(), 0, &mut out), 1);
+            assert_eq!(out.len, 0);
+            (host.free)(out);
+            assert_eq!((host.get)(host.ctx, ptr::null(), 0, ptr::null_mut()), -1);
+            assert_eq!((host.delete)(host.ctx, ptr::null(), 0), 0);
+        }
+    }
+
+    #[test]
+    fn data_is_scoped_to_the_function() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.tree("a").unwrap().insert("k", "from a").unwrap();
+        store
+            .tree("b")
+            .unwrap()
+            .insert("k", vec![0xff, 0xfe])
+            .unwrap();
+
+        let a = store.entries("a").unwrap();
+        assert!(matches!(&a["k"], KvEntry::Text(text) if text == "from a"));
+        let b = store.entries("b").unwrap();
+        assert!(matches!(&b["k"], KvEntry::Base64(encoded) if encoded == "//4="));
+
+        store.clear("a").unwrap();
+        assert!(store.entries("a").unwrap().is_empty());
+        assert_eq!(store.entries("b").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn data_persists_across_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = KvStore::open(dir.path()).unwrap();
+            store.tree("f").unwrap().insert("k", "v").unwrap();
+            store.db.flush().unwrap();
+        }
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.entries("f").unwrap().len(), 1);
+    }
+}
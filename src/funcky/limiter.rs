@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use snafu::Snafu;
+
+/// Past this many tracked clients, idle client state is pruned on admission.
+const PRUNE_THRESHOLD: usize = 1024;
+
+const ANONYMOUS_CLIENT: &str = "anonymous";
+
+/// Slowest accepted refill rate, one token every 1000 seconds.
+const MIN_PER_SECOND: f64 = 0.001;
+
+/// Longest wait for a token at the slowest rate.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(1000);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    LimiterLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Identifies the client invoking a funcktion.
+#[derive(Clone, Debug, Default)]
+pub struct Caller {
+    pub api_key: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitScope {
+    /// Limits are shared by all callers of the function.
+    #[default]
+    Function,
+    /// Limits apply separately to each API key. Keys aren't authenticated, so a caller can dodge
+    /// the limit by sending a new one: these limits are advisory.
+    ApiKey,
+    /// Limits apply separately to each client IP.
+    ClientIp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// Tokens added to the bucket every second.
+    pub per_second: f64,
+    /// Maximum number of tokens in the bucket.
+    pub burst: u32,
+}
+
+/// Admission control settings for a single function.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Limits {
+    #[serde(default)]
+    pub max_in_flight: Option<u32>,
+    #[serde(default)]
+    pub rate: Option<RateLimit>,
+    #[serde(default)]
+    pub scope: LimitScope,
+}
+
+impl Limits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_in_flight == Some(0) {
+            return Err(String::from("max_in_flight must be at least 1"));
+        }
+        if let Some(rate) = &self.rate {
+            if !rate.per_second.is_finite() || rate.per_second < MIN_PER_SECOND {
+                return Err(format!(
+                    "rate.per_second must be at least {}",
+                    MIN_PER_SECOND
+                ));
+            }
+            if rate.burst == 0 {
+                return Err(String::from("rate.burst must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LimiterStat {
+    pub limits: Limits,
+    pub in_flight: u32,
+    pub tracked_clients: usize,
+    pub rejected: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Rejection {
+    TooManyInFlight,
+    RateExceeded {
+        retry_after: Duration,
+    },
+    /// The limiter's state was poisoned by a panic.
+    LockFailure,
+}
+
+struct ClientState {
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: u32,
+}
+
+impl ClientState {
+    fn new(limits: &Limits) -> ClientState {
+        ClientState {
+            tokens: limits.rate.as_ref().map(|r| r.burst as f64).unwrap_or(0.0),
+            last_refill: Instant::now(),
+            in_flight: 0,
+        }
+    }
+
+    fn refill(&mut self, rate: &RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn is_idle(&self, limits: &Limits) -> bool {
+        self.in_flight == 0
+            && limits
+                .rate
+                .as_ref()
+                .map(|r| self.tokens >= r.burst as f64)
+                .unwrap_or(true)
+    }
+}
+
+struct FunctionLimiter {
+    limits: Limits,
+    clients: Mutex<HashMap<String, ClientState>>,
+    rejected: AtomicU64,
+}
+
+impl FunctionLimiter {
+    fn client_key(&self, caller: &Caller) -> String {
+        let key = match self.limits.scope {
+            LimitScope::Function => None,
+            LimitScope::ApiKey => caller.api_key.clone(),
+            LimitScope::ClientIp => caller.ip.map(|ip| ip.to_string()),
+        };
+        key.unwrap_or_else(|| String::from(ANONYMOUS_CLIENT))
+    }
+
+    fn acquire(&self, caller: &Caller) -> Result<String, Rejection> {
+        let client_key = self.client_key(caller);
+        let mut clients = self.clients.lock().map_err(|_e| Rejection::LockFailure)?;
+
+        if clients.len() > PRUNE_THRESHOLD {
+            let limits = &self.limits;
+            clients.retain(|_, c| {
+                if let Some(rate) = &limits.rate {
+                    c.refill(rate);
+                }
+                !c.is_idle(limits)
+            });
+        }
+
+        let client = clients
+            .entry(client_key.clone())
+            .or_insert_with(|| ClientState::new(&self.limits));
+
+        if let Some(max) = self.limits.max_in_flight {
+            if client.in_flight >= max {
+                return Err(self.reject(Rejection::TooManyInFlight));
+            }
+        }
+
+        if let Some(rate) = &self.limits.rate {
+            client.refill(rate);
+            if client.tokens < 1.0 {
+                // Limits are validated, but a bad rate must not panic with the lock held.
+                let retry_after =
+                    Duration::try_from_secs_f64((1.0 - client.tokens) / rate.per_second)
+                        .map_or(MAX_RETRY_AFTER, |d| d.min(MAX_RETRY_AFTER));
+                return Err(self.reject(Rejection::RateExceeded { retry_after }));
+            }
+            client.tokens -= 1.0;
+        }
+
+        client.in_flight += 1;
+        Ok(client_key)
+    }
+
+    fn release(&self, client_key: &str) {
+        // A poisoned lock fails every later acquire, so there is nothing left to release.
+        if let Ok(mut clients) = self.clients.lock() {
+            if let Some(client) = clients.get_mut(client_key) {
+                client.in_flight = client.in_flight.saturating_sub(1);
+            }
+        }
+    }
+
+    fn reject(&self, rejection: Rejection) -> Rejection {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        rejection
+    }
+
+    fn stat(&self) -> Result<LimiterStat> {
+        let clients = self
+            .clients
+            .lock()
+            .map_err(|_e| Error::LimiterLockFailure)?;
+        Ok(LimiterStat {
+            limits: self.limits.clone(),
+            in_flight: clients.values().map(|c| c.in_flight).sum(),
+            tracked_clients: clients.len(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Releases the caller's in-flight slot when dropped.
+pub struct Permit {
+    limiter: Arc<FunctionLimiter>,
+    client_key: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client_key);
+    }
+}
+
+/// The RateLimiter applies per-function concurrency caps and token-bucket rate limits.
+pub struct RateLimiter {
+    functions: RwLock<HashMap<String, Arc<FunctionLimiter>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            functions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the limits of a function, resetting its limiter state.
+    pub fn set_limits(&self, fn_name: &str, limits: Limits) -> Result<()> {
+        let mut guard = self
+            .functions
+            .write()
+            .map_err(|_e| Error::LimiterLockFailure)?;
+        guard.insert(
+            String::from(fn_name),
+            Arc::new(FunctionLimiter {
+                limits,
+                clients: Mutex::new(HashMap::new()),
+                rejected: AtomicU64::new(0),
+            }),
+        );
+        Ok(())
+    }
+
+    pub fn remove_limits(&self, fn_name: &str) -> Result<bool> {
+        let mut guard = self
+            .functions
+            .write()
+            .map_err(|_e| Error::LimiterLockFailure)?;
+        Ok(guard.remove(fn_name).is_some())
+    }
+
+    /// Admits a call, or rejects it if the function is over its limits.
+    ///
+    /// Returns `None` if the function has no limits configured.
+    pub fn acquire(&self, fn_name: &str, caller: &Caller) -> Result<Option<Permit>, Rejection> {
+        let limiter = {
+            let guard = self.functions.read().map_err(|_e| Rejection::LockFailure)?;
+            match guard.get(fn_name) {
+                Some(l) => l.clone(),
+                None => return Ok(None),
+            }
+        };
+
+        let client_key = limiter.acquire(caller)?;
+        Ok(Some(Permit {
+            limiter,
+            client_key,
+        }))
+    }
+
+    pub fn stat(&self, fn_name: &str) -> Result<Option<LimiterStat>> {
+        let guard = self
+            .functions
+            .read()
+            .map_err(|_e| Error::LimiterLockFailure)?;
+        guard.get(fn_name).map(|l| l.stat()).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_in_flight: Option<u32>, rate: Option<(f64, u32)>) -> Limits {
+        Limits {
+            max_in_flight,
+            rate: rate.map(|(per_second, burst)| RateLimit { per_second, burst }),
+            scope: LimitScope::Function,
+        }
+    }
+
+    fn limiter(limits: Limits) -> RateLimiter {
+        let limiter = RateLimiter::new();
+        limiter.set_limits("f", limits).unwrap();
+        limiter
+    }
+
+    #[test]
+    fn burst_is_exhausted() {
+        let limiter = limiter(limits(None, Some((1.0, 3))));
+        let caller = Caller::default();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire("f", &caller).is_ok());
+        }
+        match limiter.acquire("f", &caller) {
+            Err(Rejection::RateExceeded { retry_after }) => {
+                assert!(retry_after > Duration::from_millis(900));
+                assert!(retry_after <= Duration::from_secs(1));
+            }
+            _ => panic!("expected the burst to be exhausted"),
+        }
+        assert_eq!(limiter.stat("f").unwrap().unwrap().rejected, 1);
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let rate = RateLimit {
+            per_second: 2.0,
+            burst: 4,
+        };
+        let cases = [
+            // (tokens, elapsed ms, expected tokens)
+            (0.0, 0, 0.0),
+            (0.0, 500, 1.0),
+            (1.0, 1000, 3.0),
+            (0.0, 10_000, 4.0),
+            (4.0, 1000, 4.0),
+        ];
+        for &(tokens, elapsed, expected) in cases.iter() {
+            let mut client = ClientState {
+                tokens,
+                last_refill: Instant::now() - Duration::from_millis(elapsed),
+                in_flight: 0,
+            };
+            client.refill(&rate);
+            assert!(
+                (client.tokens - expected).abs() < 0.01,
+                "{} tokens after {}ms: expected {}, got {}",
+                tokens,
+                elapsed,
+                expected,
+                client.tokens
+            );
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        // Bypasses validation, like limits set before the minimum rate existed.
+        let limiter = limiter(limits(None, Some((1e-20, 1))));
+        let caller = Caller::default();
+
+        assert!(limiter.acquire("f", &caller).is_ok());
+        match limiter.acquire("f", &caller) {
+            Err(Rejection::RateExceeded { retry_after }) => {
+                assert_eq!(retry_after, MAX_RETRY_AFTER)
+            }
+            _ => panic!("expected the burst to be exhausted"),
+        }
+        // The clients lock isn't poisoned.
+        assert_eq!(limiter.stat("f").unwrap().unwrap().rejected, 1);
+    }
+
+    #[test]
+    fn rejected_call_is_admitted_after_refill() {
+        let limiter = limiter(limits(None, Some((1.0, 1))));
+        let caller = Caller::default();
+
+        assert!(limiter.acquire("f", &caller).is_ok());
+        assert!(limiter.acquire("f", &caller).is_err());
+
+        // Rewind the bucket rather than sleeping.
+        {
+            let functions = limiter.functions.read().unwrap();
+            let mut clients = functions["f"].clients.lock().unwrap();
+            let client = clients.get_mut(ANONYMOUS_CLIENT).unwrap();
+            client.last_refill -= Duration::from_secs(1);
+        }
+        assert!(limiter.acquire("f", &caller).is_ok());
+    }
+
+    #[test]
+    fn dropping_permit_releases_slot() {
+        let limiter = limiter(limits(Some(1), None));
+        let caller = Caller::default();
+
+        let permit = limiter.acquire("f", &caller).unwrap();
+        assert!(permit.is_some());
+        assert_eq!(limiter.stat("f").unwrap().unwrap().in_flight, 1);
+        assert!(matches!(
+            limiter.acquire("f", &caller),
+            Err(Rejection::TooManyInFlight)
+        ));
+
+        drop(permit);
+        assert_eq!(limiter.stat("f").unwrap().unwrap().in_flight, 0);
+        assert!(limiter.acquire("f", &caller).is_ok());
+    }
+
+    #[test]
+    fn scopes_limit_callers_separately() {
+        let mut per_key = limits(Some(1), None);
+        per_key.scope = LimitScope::ApiKey;
+        let limiter = limiter(per_key);
+        let caller = |key: &str| Caller {
+            api_key: Some(String::from(key)),
+            ip: None,
+        };
+
+        let _a = limiter.acquire("f", &caller("a")).unwrap();
+        let _b = limiter.acquire("f", &caller("b")).unwrap();
+        assert!(limiter.acquire("f", &caller("a")).is_err());
+        assert_eq!(limiter.stat("f").unwrap().unwrap().tracked_clients, 2);
+    }
+
+    #[test]
+    fn unlimited_functions_are_admitted() {
+        let limiter = RateLimiter::new();
+        assert!(matches!(limiter.acquire("f", &Caller::default()), Ok(None)));
+    }
+
+    #[test]
+    fn validate() {
+        let cases = [
+            (limits(None, None), true),
+            (limits(Some(1), Some((0.5, 1))), true),
+            (limits(Some(0), None), false),
+            (limits(None, Some((0.0, 1))), false),
+            (limits(None, Some((1e-20, 1))), false),
+            (limits(None, Some((MIN_PER_SECOND, 1))), true),
+            (limits(None, Some((f64::NAN, 1))), false),
+            (limits(None, Some((1.0, 0))), false),
+        ];
+        for (limits, valid) in cases.iter() {
+            assert_eq!(limits.validate().is_ok(), *valid, "{:?}", limits);
+        }
+    }
+}
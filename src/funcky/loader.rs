@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use libloading::{Library, Symbol};
 
@@ -8,6 +9,12 @@ use funck::{Funcktion, Request, Response};
 
 use snafu::{ResultExt, Snafu};
 
+use super::abi::{AbiDescriptor, FunckAbi, ABI_SYMBOL};
+use super::kv::{self, KvBinding, KvHost, KvStore, BIND_KV_SYMBOL};
+use super::manifest::Manifest;
+use super::namespaces::{self, NAMESPACE_SEPARATOR};
+use super::wasm::{self, WasmFunck, WASM_EXTENSION};
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("FFI Error calling function [{}]", name))]
@@ -29,120 +36,555 @@ pub enum Error {
         symbol: String,
     },
 
+    #[snafu(display(
+        "Shared object [{}] is incompatible with this server: {}",
+        path.display(),
+        reason
+    ))]
+    IncompatibleLibrary { path: PathBuf, reason: String },
+
+    #[snafu(display("{}", source))]
+    WasmError { source: Box<wasm::Error> },
+
+    #[snafu(display("Shared object [{}] exports no functions", path.display()))]
+    EmptyRegistry { path: PathBuf },
+
+    #[snafu(display(
+        "Function [{}] is exported twice by shared object [{}]",
+        name,
+        path.display()
+    ))]
+    DuplicateFunction { name: String, path: PathBuf },
+
+    #[snafu(display(
+        "Function name [{}] exported by [{}] may not contain '{}'",
+        name,
+        path.display(),
+        NAMESPACE_SEPARATOR
+    ))]
+    InvalidFunctionName { name: String, path: PathBuf },
+
     #[snafu(display("Unknown function: {}", name))]
     UnknownFunction { name: String },
+
+    #[snafu(display("Failed to bind KV store of function [{}]: {}", name, source))]
+    FailedToBindKv { source: kv::Error, name: String },
+
+    #[snafu(display(
+        "Initialization of function [{}] failed (code {}): {}",
+        name,
+        code,
+        reason
+    ))]
+    InitFailed {
+        name: String,
+        code: i32,
+        reason: String,
+    },
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-struct LoadedFunck {
-    pub funck: Box<dyn Funcktion>,
+/// Optionally exported by libraries, to set up the function once loaded.
+///
+/// Called with the JSON configuration of the function, and a buffer the library may write a
+/// NUL-terminated error message to. Returns 0 on success.
+const INIT_SYMBOL: &[u8] = b"_funck_init";
+
+/// Optionally exported by libraries, to release their resources before being unloaded.
+const SHUTDOWN_SYMBOL: &[u8] = b"_funck_shutdown";
+
+/// Size of the buffer init hooks can write their error message to.
+const INIT_ERROR_CAPACITY: usize = 1024;
+
+/// Optionally exported by libraries that export several functions, instead of `_funck_create`.
+const REGISTRY_SYMBOL: &[u8] = b"_funck_create_all";
+
+const CTOR_SYMBOL: &[u8] = b"_funck_create";
+
+type FunckRegistry = unsafe fn() -> Vec<Box<dyn Funcktion>>;
+type FunckCreate = unsafe fn() -> *mut dyn Funcktion;
+type FunckInit = unsafe extern "C" fn(*const u8, usize, *mut u8, usize) -> i32;
+type FunckShutdown = unsafe extern "C" fn();
+
+// Fields are dropped in order: the library is unloaded before the host API it was bound to.
+struct NativeLibrary {
+    pub funcks: Vec<Box<dyn Funcktion>>,
+    // Only valid while the library is loaded, which outlives it.
+    shutdown: Option<FunckShutdown>,
+    #[allow(dead_code)] // Keeps the shared object mapped for as long as the funcks are alive.
     pub lib: Library,
+    #[allow(dead_code)] // Keeps the KV host API alive for as long as the library is loaded.
+    pub kv: Option<KvBinding>,
 }
 
-impl LoadedFunck {
-    pub fn load<P: AsRef<Path>>(dylib_file: P) -> Result<LoadedFunck> {
+impl NativeLibrary {
+    pub fn load<P: AsRef<Path>>(
+        dylib_file: P,
+        namespace: Option<&str>,
+        kv_store: &KvStore,
+        config: &[u8],
+    ) -> Result<NativeLibrary> {
         let lib = Library::new(dylib_file.as_ref()).context(FailedToLoadLibrary {
             path: PathBuf::from(dylib_file.as_ref()),
         })?;
 
-        let funck: Box<dyn Funcktion> = unsafe {
-            type FunckCreate = unsafe fn() -> *mut dyn Funcktion;
-            const CTOR_SYMBOL: &[u8] = b"_funck_create";
-            let constructor: Symbol<FunckCreate> = lib.get(CTOR_SYMBOL).context(MissingSymbol {
+        // The funcks are trait objects, so their layout must match the server's before they are used.
+        let compatible = unsafe {
+            match lib.get::<FunckAbi>(ABI_SYMBOL) {
+                Ok(abi) => AbiDescriptor::read(*abi),
+                Err(_) => Err(String::from("missing ABI descriptor")),
+            }
+        }
+        .and_then(|abi| match abi.mismatch(&AbiDescriptor::host()) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        });
+        if let Err(reason) = compatible {
+            return IncompatibleLibrary {
                 path: PathBuf::from(dylib_file.as_ref()),
-                symbol: String::from_utf8_lossy(CTOR_SYMBOL).to_string(),
-            })?;
+                reason,
+            }
+            .fail();
+        }
 
-            let boxed_raw = constructor();
+        // Libraries export either a registry of funcks, or a single one.
+        let funcks: Vec<Box<dyn Funcktion>> = unsafe {
+            match lib.get::<FunckRegistry>(REGISTRY_SYMBOL) {
+                Ok(registry) => registry(),
+                Err(_) => {
+                    let constructor: Symbol<FunckCreate> =
+                        lib.get(CTOR_SYMBOL).context(MissingSymbol {
+                            path: PathBuf::from(dylib_file.as_ref()),
+                            symbol: String::from_utf8_lossy(CTOR_SYMBOL).to_string(),
+                        })?;
+                    vec![Box::from_raw(constructor())]
+                }
+            }
+        };
 
-            Box::from_raw(boxed_raw)
+        let names: Vec<&str> = funcks.iter().map(|f| f.name()).collect();
+        check_registry(&names, dylib_file.as_ref())?;
+        let first = names[0];
+
+        // Libraries that use the KV store export a symbol to receive its host API. Functions of
+        // a library share the store of its first function.
+        let kv = unsafe {
+            type BindKv = unsafe extern "C" fn(*const KvHost);
+            match lib.get::<BindKv>(BIND_KV_SYMBOL) {
+                Ok(bind) => {
+                    let kv_name = namespaces::qualify(namespace, first);
+                    let binding = kv_store
+                        .bind(&kv_name)
+                        .context(FailedToBindKv { name: kv_name })?;
+                    bind(binding.host());
+                    Some(binding)
+                }
+                Err(_) => None,
+            }
         };
 
-        log::debug!(
-            "loaded funcktion <{}> from shared object [{}]",
-            funck.name(),
-            dylib_file.as_ref().display()
-        );
-        Ok(LoadedFunck { funck, lib })
+        // Init runs last, so it can already use the KV store.
+        if let Ok(init) = unsafe { lib.get::<FunckInit>(INIT_SYMBOL) } {
+            let mut error = vec![0u8; INIT_ERROR_CAPACITY];
+            let code = unsafe {
+                init(
+                    config.as_ptr(),
+                    config.len(),
+                    error.as_mut_ptr(),
+                    error.len(),
+                )
+            };
+            if code != 0 {
+                let len = error.iter().position(|b| *b == 0).unwrap_or(error.len());
+                return InitFailed {
+                    name: first,
+                    code,
+                    reason: String::from_utf8_lossy(&error[..len]).to_string(),
+                }
+                .fail();
+            }
+        }
+
+        let shutdown = unsafe { lib.get::<FunckShutdown>(SHUTDOWN_SYMBOL) }
+            .ok()
+            .map(|s| *s);
+
+        for funck in funcks.iter() {
+            log::debug!(
+                "loaded funcktion <{}> from shared object [{}]",
+                funck.name(),
+                dylib_file.as_ref().display()
+            );
+        }
+        Ok(NativeLibrary {
+            funcks,
+            shutdown,
+            lib,
+            kv,
+        })
+    }
+}
+
+impl Drop for NativeLibrary {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown {
+            log::debug!("shutting down library of <{}>", self.funcks[0].name());
+            unsafe { shutdown() };
+        }
+    }
+}
+
+/// A library loaded by either runtime, with the functions it exports.
+enum LoadedLibrary {
+    Native(NativeLibrary),
+    Wasm(Box<WasmFunck>),
+}
+
+impl LoadedLibrary {
+    /// Names of the library's functions, in the order they are exported.
+    fn names(&self) -> Vec<String> {
+        match self {
+            LoadedLibrary::Native(native) => native
+                .funcks
+                .iter()
+                .map(|f| String::from(f.name()))
+                .collect(),
+            LoadedLibrary::Wasm(wasm) => vec![String::from(wasm.name())],
+        }
+    }
+
+    fn call(&self, function_name: &str, request: Request) -> Result<Response> {
+        match self {
+            LoadedLibrary::Native(native) => native
+                .funcks
+                .iter()
+                .find(|f| f.name() == function_name)
+                .ok_or(Error::UnknownFunction {
+                    name: String::from(function_name),
+                })?
+                ._call_internal(request)
+                .context(CallError {
+                    name: function_name,
+                }),
+            LoadedLibrary::Wasm(wasm) => wasm.call(request).map_err(|e| match e {
+                wasm::Error::CallFailed { source, name } => Error::CallError { source, name },
+                e => Error::WasmError {
+                    source: Box::new(e),
+                },
+            }),
+        }
     }
 }
 
 /// The FunckLoader manages all Funcks currently loaded, as well as their associated dylibs.
 pub struct FunckLoader {
-    funcks: HashMap<String, LoadedFunck>,
-    lib_index: HashMap<String, String>,
+    libraries: HashMap<String, LoadedLibrary>,
+
+    /// Name of the library serving each function.
+    functions: HashMap<String, String>,
+
+    /// Names of the libraries, in the order they were loaded.
+    load_order: Vec<String>,
+
+    kv_store: Arc<KvStore>,
 }
 
 impl FunckLoader {
-    pub fn new() -> FunckLoader {
+    pub fn new(kv_store: Arc<KvStore>) -> FunckLoader {
         FunckLoader {
-            funcks: HashMap::new(),
-            lib_index: HashMap::new(),
+            libraries: HashMap::new(),
+            functions: HashMap::new(),
+            load_order: Vec::new(),
+            kv_store,
         }
     }
 
-    pub fn load_funcktion<P: AsRef<Path>>(&mut self, dylib_file: P) -> Result<String> {
+    /// Loads a shared object or WebAssembly module, replacing the one of the same name, and
+    /// initializes it with the configuration of its manifest.
+    ///
+    /// Returns the names of the library's functions, in the order they are exported. Functions
+    /// of a namespace are named `{ns}/{fn}`, so they never replace those of another namespace.
+    pub fn load_library<P: AsRef<Path>>(
+        &mut self,
+        dylib_file: P,
+        namespace: Option<&str>,
+        manifest: &Manifest,
+    ) -> Result<Vec<String>> {
         log::debug!(
             "request load of shared object: {}",
             dylib_file.as_ref().to_string_lossy()
         );
 
-        let library_name = dylib_file
-            .as_ref()
-            .file_stem()
-            .unwrap_or_else(|| "libunknown".as_ref())
-            .to_string_lossy()
-            .to_string();
+        let library_name = namespaces::qualify(
+            namespace,
+            &dylib_file
+                .as_ref()
+                .file_stem()
+                .unwrap_or_else(|| "libunknown".as_ref())
+                .to_string_lossy(),
+        );
 
         self.unload_library(&library_name);
 
-        let foreign_funck = LoadedFunck::load(dylib_file)?;
+        let config = manifest.config_json();
+        let library = if dylib_file.as_ref().extension() == Some(WASM_EXTENSION.as_ref()) {
+            let limits = manifest.wasm.clone().unwrap_or_default();
+            let wasm = WasmFunck::load(dylib_file.as_ref(), &config, &limits).map_err(|e| {
+                Error::WasmError {
+                    source: Box::new(e),
+                }
+            })?;
+            LoadedLibrary::Wasm(Box::new(wasm))
+        } else {
+            LoadedLibrary::Native(NativeLibrary::load(
+                dylib_file.as_ref(),
+                namespace,
+                &self.kv_store,
+                &config,
+            )?)
+        };
 
-        let fn_name = String::from(foreign_funck.funck.name());
+        // A separator in a function name would let it pass for a function of another namespace.
+        let names = library.names();
+        if let Some(name) = names.iter().find(|n| n.contains(NAMESPACE_SEPARATOR)) {
+            return InvalidFunctionName {
+                name: name.clone(),
+                path: PathBuf::from(dylib_file.as_ref()),
+            }
+            .fail();
+        }
+        let names: Vec<String> = names
+            .iter()
+            .map(|n| namespaces::qualify(namespace, n))
+            .collect();
+        self.libraries.insert(library_name.clone(), library);
+        self.load_order.push(library_name.clone());
 
-        self.lib_index.insert(library_name, fn_name.clone());
-        self.funcks.insert(fn_name.clone(), foreign_funck);
+        // Functions may have been served by another library, e.g. before switching runtimes.
+        // That library is unloaded once it serves no function anymore.
+        let mut previous_libraries = Vec::new();
+        for name in names.iter() {
+            if let Some(previous) = self.functions.insert(name.clone(), library_name.clone()) {
+                if previous != library_name {
+                    previous_libraries.push(previous);
+                }
+            }
+        }
+        for previous in previous_libraries {
+            if !self.functions.values().any(|l| l == &previous) {
+                self.unload_library(&previous);
+            }
+        }
 
-        Ok(fn_name)
+        Ok(names)
     }
 
     pub fn call(&self, function_name: &str, request: Request) -> Result<Response> {
-        self.funcks
+        let (_, name) = namespaces::split(function_name);
+        self.functions
             .get(function_name)
+            .and_then(|library_name| self.libraries.get(library_name))
             .ok_or(Error::UnknownFunction {
                 name: String::from(function_name),
             })?
-            .funck
-            ._call_internal(request)
-            .context(CallError {
-                name: String::from(function_name),
+            .call(name, request)
+            .map_err(|e| match e {
+                Error::CallError { source, .. } => Error::CallError {
+                    source,
+                    name: String::from(function_name),
+                },
+                e => e,
             })
     }
 
-    fn unload_library(&mut self, library_name: &str) {
-        if let Some(fnk_name) = self.lib_index.remove(library_name) {
-            if let Some(fnk) = self.funcks.remove(&fnk_name) {
-                // Force dropping of lib.
-                drop(fnk);
-                log::debug!("unloaded {}", fnk_name);
+    pub fn contains(&self, function_name: &str) -> bool {
+        self.functions.contains_key(function_name)
+    }
+
+    /// Returns the names of the functions served by a library.
+    pub fn library_functions(&self, library_name: &str) -> Vec<String> {
+        self.functions
+            .iter()
+            .filter(|(_, l)| l.as_str() == library_name)
+            .map(|(f, _)| f.clone())
+            .collect()
+    }
+
+    /// Returns the names of the functions of a namespace.
+    pub fn namespace_functions(&self, namespace: &str) -> Vec<String> {
+        self.functions
+            .keys()
+            .filter(|f| namespaces::split(f).0 == Some(namespace))
+            .cloned()
+            .collect()
+    }
+
+    pub fn unload_library(&mut self, library_name: &str) {
+        self.functions.retain(|_, l| l != library_name);
+        self.load_order.retain(|l| l != library_name);
+        if let Some(library) = self.libraries.remove(library_name) {
+            // Force dropping of lib.
+            drop(library);
+            log::debug!("unloaded {}", library_name);
+        }
+    }
+
+    /// Unloads every library, running their shutdown hooks, in the reverse order they were
+    /// loaded.
+    pub fn unload(&mut self) {
+        self.functions.clear();
+        while let Some(library_name) = self.load_order.pop() {
+            if let Some(library) = self.libraries.remove(&library_name) {
+                drop(library);
+                log::info!("unloaded {}", library_name);
             }
         }
     }
+}
 
-    fn unload(&mut self) {
-        self.lib_index.clear();
-        for (fnk_n, fnk) in self.funcks.drain() {
-            drop(fnk_n);
-            drop(fnk);
+/// Checks that a registry exports at least one function, and no function twice.
+fn check_registry(names: &[&str], path: &Path) -> Result<()> {
+    if names.is_empty() {
+        return EmptyRegistry { path }.fail();
+    }
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return DuplicateFunction { name: *name, path }.fail();
         }
     }
+    Ok(())
 }
 
 impl Drop for FunckLoader {
     fn drop(&mut self) {
-        if !self.funcks.is_empty() || !self.lib_index.is_empty() {
+        if !self.libraries.is_empty() {
             self.unload();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use crate::funcky::fixtures;
+
+    fn loader(dir: &TempDir) -> FunckLoader {
+        FunckLoader::new(Arc::new(KvStore::open(dir.path().join("kv")).unwrap()))
+    }
+
+    fn manifest(config: serde_json::Value) -> Manifest {
+        Manifest {
+            config: Some(config),
+            ..Manifest::default()
+        }
+    }
+
+    fn call(loader: &FunckLoader, function: &str, body: &str) -> String {
+        let request = Request::new(Vec::from(body), HashMap::new());
+        let response = loader.call(function, request).unwrap();
+        String::from_utf8(Vec::from(response.body())).unwrap()
+    }
+
+    #[test]
+    fn init_receives_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixtures::native_library(dir.path(), "libhooks");
+        let mut loader = loader(&dir);
+
+        let config = serde_json::json!({ "greeting": "hello" });
+        loader.load_library(&path, None, &manifest(config)).unwrap();
+        assert_eq!(call(&loader, "alpha", ""), "alpha:hello");
+    }
+
+    #[test]
+    fn failed_init_fails_the_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixtures::native_library(dir.path(), "libhooks");
+        let mut loader = loader(&dir);
+
+        let config = serde_json::json!({ "fail": "missing api_url" });
+        match loader.load_library(&path, None, &manifest(config)) {
+            Err(Error::InitFailed { name, code, reason }) => {
+                assert_eq!(name, "alpha");
+                assert_eq!(code, 2);
+                assert_eq!(reason, "missing api_url");
+            }
+            _ => panic!("expected the init hook to fail the load"),
+        }
+        assert!(!loader.contains("alpha"));
+    }
+
+    #[test]
+    fn shutdown_runs_on_unload() {
+        let cases = [false, true];
+        for unload_all in cases.iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = fixtures::native_library(dir.path(), "libhooks");
+            let marker = dir.path().join("shut_down");
+            let mut loader = loader(&dir);
+
+            let config = serde_json::json!({ "shutdown_marker": marker });
+            loader.load_library(&path, None, &manifest(config)).unwrap();
+            assert!(!marker.exists());
+
+            if *unload_all {
+                loader.unload();
+            } else {
+                loader.unload_library("libhooks");
+            }
+            assert_eq!(fs::read(&marker).unwrap(), b"shut down", "{}", unload_all);
+        }
+    }
+
+    #[test]
+    fn registries_load_every_function() {
+        let cases = [(None, "alpha", "beta"), (Some("ns"), "ns/alpha", "ns/beta")];
+        for &(namespace, alpha, beta) in cases.iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = fixtures::native_library(dir.path(), "libregistry");
+            let mut loader = loader(&dir);
+
+            let config = serde_json::json!({ "greeting": "hi" });
+            let names = loader
+                .load_library(&path, namespace, &manifest(config))
+                .unwrap();
+            assert_eq!(names, vec![alpha, beta]);
+            assert_eq!(call(&loader, alpha, ""), "alpha:hi");
+            assert_eq!(call(&loader, beta, "echo"), "echo");
+            assert!(!loader.contains(if namespace.is_some() {
+                "alpha"
+            } else {
+                "ns/alpha"
+            }));
+
+            let library = namespaces::qualify(namespace, "libregistry");
+            let mut functions = loader.library_functions(&library);
+            functions.sort();
+            assert_eq!(functions, vec![alpha, beta]);
+
+            loader.unload_library(&library);
+            assert!(!loader.contains(alpha));
+            assert!(!loader.contains(beta));
+            assert!(loader.library_functions(&library).is_empty());
+        }
+    }
+
+    #[test]
+    fn check_registries() {
+        let path = Path::new("lib.so");
+        assert!(check_registry(&["alpha", "beta"], path).is_ok());
+        assert!(matches!(
+            check_registry(&[], path),
+            Err(Error::EmptyRegistry { .. })
+        ));
+        match check_registry(&["alpha", "beta", "alpha"], path) {
+            Err(Error::DuplicateFunction { name, .. }) => assert_eq!(name, "alpha"),
+            _ => panic!("expected a duplicate function"),
+        }
+    }
+}
@@ -1,18 +1,42 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use funck::{Request, Response};
 
-use snafu::{ensure, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 
-use super::compiler;
+use super::build_cache::{self, CacheEntryStat};
+use super::clock::unix_now;
+use super::compiler::{self, BuildFlags, BuildInfo, QueuedBuilds};
+use super::deployments::{self, Deployment, DeploymentStore, Version};
+use super::digest::sha256_hex;
+use super::git::{self, GitSpec, Repositories};
+use super::invocations::{self, StoredResponse};
+use super::kv::{self, KvEntry, KvStore};
+use super::limiter::{self, Permit, Rejection};
 pub use super::loader::Error as LoaderError;
-use super::{FunckLoader, FuncktionEntry, Status, StatusTracker};
+use super::manifest::{self, Manifest, Runtime};
+use super::namespaces::{self, NamespaceSpec, NamespaceStat};
+use super::pipelines;
+use super::router::{self, RouteSpec, PARAM_METADATA_PREFIX};
+use super::scheduler::{self, RunRecord, RunStatus, ScheduledRun};
+use super::secrets;
+use super::sources;
+use super::status;
+use super::wasm::WASM_EXTENSION;
+use super::{
+    BuildCache, Caller, FunckLoader, FuncktionEntry, Invocation, InvocationStore, Limits,
+    NamespaceStore, PipelineSpec, PipelineStore, RateLimiter, Router, ScheduleSpec, ScheduleStat,
+    Scheduler, SecretInfo, SecretSpec, SecretStore, SourceStore, Status, StatusTracker, WorkerPool,
+};
+use crate::telemetry::{self, ExportTarget, SpanContext, Tracer};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -20,6 +44,12 @@ pub enum Error {
     CallError {
         source: LoaderError,
     },
+    #[snafu(display("Function {} failed: {}", name, source))]
+    FunctionFailed {
+        name: String,
+        status: u16,
+        source: funck::CallError,
+    },
     #[snafu(display("Failed to move shared object: {}", source))]
     CantMoveSharedObject {
         source: io::Error,
@@ -30,43 +60,691 @@ pub enum Error {
     CompileWorkerStartError {
         source: compiler::Error,
     },
+    #[snafu(display("Failed to stop compile worker: {}", source))]
+    CompileWorkerStopError {
+        source: compiler::Error,
+    },
+    #[snafu(display("Failed to queue build: {}", source))]
+    CompileJobError {
+        source: Box<compiler::Error>,
+    },
     ManagerAlreadyStarted,
     MissingFileName,
-    MissingSharedObject,
     LoaderLockFailure,
+    ManifestLockFailure,
+    CallJoinFailure,
+    ShutdownLockFailure,
+    BuildLockFailure,
+    #[snafu(display("Method {} not allowed for function: {}", method, name))]
+    MethodNotAllowed {
+        name: String,
+        method: String,
+    },
+    #[snafu(display("Request body exceeds the {} byte limit of function: {}", limit, name))]
+    BodyTooLarge {
+        name: String,
+        limit: u64,
+    },
+    #[snafu(display("The server is shutting down"))]
+    ShuttingDown,
+    #[snafu(display(
+        "Too many queued asynchronous calls, rejected call to function: {}",
+        name
+    ))]
+    AsyncQueueFull {
+        name: String,
+    },
+    #[snafu(display(
+        "Too many timed pipeline steps running, rejected call to function: {}",
+        name
+    ))]
+    StepQueueFull {
+        name: String,
+    },
+    #[snafu(display("Call to function {} timed out after {}ms", name, timeout_ms))]
+    CallTimedOut {
+        name: String,
+        timeout_ms: u64,
+    },
+    #[snafu(display("{}", source))]
+    ManifestError {
+        source: manifest::Error,
+    },
+    #[snafu(display("{}", source))]
     LoadError {
         source: LoaderError,
     },
+    #[snafu(display("Invalid limits: {}", reason))]
+    InvalidLimits {
+        reason: String,
+    },
+    #[snafu(display("Invalid build options: {}", reason))]
+    InvalidBuildFlags {
+        reason: String,
+    },
+    #[snafu(display("Checksum mismatch: expected {}, got {}", expected, actual))]
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    #[snafu(display("Invalid artifact: {}", reason))]
+    InvalidArtifact {
+        reason: String,
+    },
+    #[snafu(display("Failed to stage artifact: {}", source))]
+    FailedToStageArtifact {
+        source: io::Error,
+    },
+    #[snafu(display("{}", source))]
+    GitError {
+        source: git::Error,
+    },
+    #[snafu(display("Failed to create build directory: {}", source))]
+    FailedToCreateBuildDirectory {
+        source: io::Error,
+    },
+    #[snafu(display("Another deploy of {} is preparing its sources", name))]
+    DeployInProgress {
+        name: String,
+    },
+    #[snafu(display("Unknown build: {}", id))]
+    UnknownBuild {
+        id: u64,
+    },
+    RepositoryLockFailure,
+    #[snafu(display("{}", source))]
+    DeploymentError {
+        source: deployments::Error,
+    },
+    #[snafu(display("{}", source))]
+    SourceError {
+        source: sources::Error,
+    },
+    #[snafu(display("Unknown deployment: {}", name))]
+    UnknownDeployment {
+        name: String,
+    },
+    #[snafu(display("Unknown version {} of {}", version, name))]
+    UnknownVersion {
+        name: String,
+        version: u64,
+    },
+    #[snafu(display("No source is stored for version {} of {}", version, name))]
+    SourceNotStored {
+        name: String,
+        version: u64,
+    },
+    #[snafu(display("Failed to quarantine [{}]: {}", path.display(), source))]
+    FailedToQuarantine {
+        source: io::Error,
+        path: PathBuf,
+    },
+    CheckoutJoinFailure,
+    #[snafu(display("Invalid source bundle: {}", reason))]
+    InvalidBundle {
+        reason: String,
+    },
+    #[snafu(display(
+        "Failed to extract the source of version {} of {}: {}",
+        version,
+        name,
+        reason
+    ))]
+    FailedToExtractSource {
+        name: String,
+        version: u64,
+        reason: String,
+    },
+    ExtractJoinFailure,
+    #[snafu(display("Too many in-flight calls to function: {}", name))]
+    TooManyInFlight {
+        name: String,
+    },
+    #[snafu(display("Rate limit exceeded for function: {}", name))]
+    RateLimited {
+        name: String,
+        retry_after: Duration,
+    },
+    #[snafu(display("{}", source))]
+    SchedulerError {
+        source: scheduler::Error,
+    },
+    #[snafu(display("{}", source))]
+    LimiterError {
+        source: limiter::Error,
+    },
+    #[snafu(display("{}", source))]
+    StatusError {
+        source: status::Error,
+    },
+    #[snafu(display("{}", source))]
+    InvocationError {
+        source: invocations::Error,
+    },
+    #[snafu(display("{}", source))]
+    PipelineError {
+        source: pipelines::Error,
+    },
+    #[snafu(display("{}", source))]
+    KvError {
+        source: kv::Error,
+    },
+    #[snafu(display("{}", source))]
+    RouteError {
+        source: router::Error,
+    },
+    #[snafu(display("{}", source))]
+    SecretsError {
+        source: secrets::Error,
+    },
+    #[snafu(display("{}", source))]
+    NamespaceError {
+        source: namespaces::Error,
+    },
+    #[snafu(display("{}", source))]
+    BuildCacheError {
+        source: build_cache::Error,
+    },
+    #[snafu(display(
+        "Pipeline {} failed at step {} ({}): {}",
+        pipeline,
+        step,
+        function,
+        source
+    ))]
+    PipelineStepFailed {
+        pipeline: String,
+        step: usize,
+        function: String,
+        source: Box<Error>,
+    },
+    #[snafu(display("Failed to initialize tracing: {}", source))]
+    TracerInitError {
+        source: telemetry::Error,
+    },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+const SCHEDULES_FILE: &str = "schedules.json";
+const PIPELINES_FILE: &str = "pipelines.json";
+const SECRETS_FILE: &str = "secrets.json";
+const NAMESPACES_FILE: &str = "namespaces.json";
+const DEPLOYMENTS_FILE: &str = "deployments.json";
+const PENDING_BUILDS_FILE: &str = "pending_builds.json";
+const KV_DIR: &str = "kv";
+const SOURCES_DIR: &str = "sources";
+
+/// Subdirectory of the build directory holding the sources of namespaced functions.
+const NAMESPACE_BUILD_DIR: &str = "namespaces";
+
+/// Subdirectory of the artifact directory holding libraries that failed to load on startup.
+const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Subdirectory of the build directory caching the repositories of git deploys.
+const GIT_CACHE_DIR: &str = "git";
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+
+/// How often in-flight calls are counted while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// HTTP status of function errors, for functions that don't set `error_status` in their manifest.
+const DEFAULT_ERROR_STATUS: u16 = 500;
+
+/// Request body limit of functions that don't set `max_body_bytes` in their manifest.
+const DEFAULT_MAX_BODY_BYTES: u64 = 1024;
+
 pub struct Config {
     pub shared_object_directory: PathBuf,
     pub tmp_dir: PathBuf,
+    pub state_directory: PathBuf,
+    pub trace_export: ExportTarget,
+    pub invocation_retention: Duration,
+
+    /// Completed invocations kept at most, regardless of their age.
+    pub max_invocations: usize,
+
+    /// Threads running asynchronous calls.
+    pub async_workers: usize,
+
+    /// Asynchronous calls waiting for a thread, past which new ones are rejected.
+    pub async_queue_capacity: usize,
+
+    /// Threads running pipeline steps that have a timeout.
+    pub step_workers: usize,
+
+    /// Timed pipeline steps waiting for a thread, past which new ones are rejected.
+    pub step_queue_capacity: usize,
+
+    /// Base64-encoded master key of the secret store. Secrets are unavailable without one.
+    pub secrets_key: Option<String>,
+
+    /// Directory keeping the cargo target directory of each function between builds.
+    pub build_cache_directory: PathBuf,
+
+    /// Size above which the least recently used target directories are evicted.
+    pub build_cache_max_bytes: u64,
+
+    /// `CARGO_HOME` of builds. Defaults to one inside the build cache.
+    pub cargo_home: Option<PathBuf>,
+
+    /// Where builds get their dependencies from, and whether they may use the network.
+    pub build_options: compiler::BuildOptions,
+
+    /// Versions of each deployment whose source is kept.
+    pub source_retention: usize,
+}
+
+/// Loads built shared objects and applies their manifests.
+struct Installer {
+    loader: Arc<RwLock<FunckLoader>>,
+    limiter: Arc<RateLimiter>,
+    scheduler: Arc<Scheduler>,
+    router: Arc<Router>,
+    manifests: Arc<RwLock<HashMap<String, Manifest>>>,
+    namespaces: Arc<NamespaceStore>,
+    deployments: Arc<DeploymentStore>,
+    so_dir: PathBuf,
+}
+
+impl Installer {
+    /// Installs a built library, returning the names of its functions.
+    fn install(&self, res: &compiler::Response) -> Result<Vec<String>> {
+        let namespace = res.namespace.as_deref();
+
+        // Move the shared object file to the managed .so directory.
+        let output_file_name = res.so_path.file_name().ok_or(Error::MissingFileName)?;
+
+        let so_dir = match namespace {
+            Some(ns) => self.so_dir.join(ns),
+            None => self.so_dir.clone(),
+        };
+        let so_file_path = so_dir.join(output_file_name);
+
+        // Routes are checked before anything is replaced, so a conflict leaves the current
+        // deployment untouched.
+        let library_name = namespaces::qualify(
+            namespace,
+            &so_file_path
+                .file_stem()
+                .ok_or(Error::MissingFileName)?
+                .to_string_lossy(),
+        );
+        let replacing = {
+            let loader_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
+            let mut replacing = loader_guard.library_functions(&library_name);
+            // The function may be loaded from another library, after switching runtimes.
+            if loader_guard.contains(&res.job_name) && !replacing.contains(&res.job_name) {
+                replacing.push(res.job_name.clone());
+            }
+            replacing
+        };
+        self.router
+            .check(
+                &Installer::routes(&res.manifest, &res.job_name, namespace),
+                &replacing,
+            )
+            .context(RouteError)?;
+
+        fs::rename(&res.so_path, &so_file_path).context(CantMoveSharedObject)?;
+
+        // Store the manifest with the deployment, so it can be re-applied on restart.
+        res.manifest
+            .save(Manifest::path_for(&so_file_path))
+            .context(ManifestError)?;
+
+        // The previous library is unloaded first, so a failed load or init leaves no function.
+        let loaded = {
+            let mut loader_guard = self.loader.write().map_err(|_e| Error::LoaderLockFailure)?;
+            let loaded = loader_guard
+                .load_library(&so_file_path, namespace, &res.manifest)
+                .context(LoadError)
+                .and_then(|functions| {
+                    self.check_function_quota(&loader_guard, namespace)?;
+                    Ok(functions)
+                });
+            if loaded.is_err() {
+                loader_guard.unload_library(&library_name);
+            }
+            loaded
+        };
+        let functions = match loaded {
+            Ok(functions) => functions,
+            Err(e) => {
+                for previous in replacing.iter() {
+                    self.router.remove(previous).ok();
+                }
+                if let Error::NamespaceError { .. } = e {
+                    // Over quota, the library must not come back on restart either.
+                    fs::remove_file(Manifest::path_for(&so_file_path)).ok();
+                    fs::remove_file(&so_file_path).ok();
+                }
+                return Err(e);
+            }
+        };
+
+        // Switching runtimes leaves the previous artifact behind, which would be loaded on restart.
+        let (_, bare_name) = namespaces::split(&res.job_name);
+        let stale_file = match res.manifest.runtime {
+            Runtime::Native => compiler::artifact_file_name(bare_name, Runtime::Wasm),
+            Runtime::Wasm => compiler::artifact_file_name(bare_name, Runtime::Native),
+        };
+        let stale_path = so_dir.join(stale_file);
+        if stale_path != so_file_path && stale_path.exists() {
+            log::info!("removing previous artifact [{}]", stale_path.display());
+            fs::remove_file(Manifest::path_for(&stale_path)).ok();
+            if let Err(e) = fs::remove_file(&stale_path) {
+                log::error!("failed to remove [{}]: {}", stale_path.display(), e);
+            }
+        }
+
+        self.apply_manifest(&functions, res.manifest.clone(), namespace, &replacing)?;
+
+        let artifact = so_file_path
+            .strip_prefix(&self.so_dir)
+            .unwrap_or(&so_file_path);
+        let version = Version::new(
+            res.source_hash.clone(),
+            res.manifest.source.clone(),
+            res.flags.clone(),
+        );
+        if let Err(e) = self
+            .deployments
+            .installed(&res.job_name, artifact, &functions, version)
+        {
+            log::error!("failed to record deployment of {}: {}", res.job_name, e);
+        }
+        Ok(functions)
+    }
+
+    /// Fails if a namespace has more functions loaded than its quota allows.
+    fn check_function_quota(&self, loader: &FunckLoader, namespace: Option<&str>) -> Result<()> {
+        let ns = match namespace {
+            Some(ns) => ns,
+            None => return Ok(()),
+        };
+        let max = self
+            .namespaces
+            .quotas(ns)
+            .context(NamespaceError)?
+            .and_then(|q| q.max_functions);
+        match max {
+            Some(max) if loader.namespace_functions(ns).len() > max => Err(Error::NamespaceError {
+                source: namespaces::Error::FunctionQuotaExceeded {
+                    name: String::from(ns),
+                    max,
+                },
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Routes of a library's functions. Routes of a namespace are served under `/{ns}`, and
+    /// name functions of the same namespace.
+    fn routes(
+        manifest: &Manifest,
+        default_function: &str,
+        namespace: Option<&str>,
+    ) -> Vec<(String, RouteSpec)> {
+        let routes = manifest.function_routes(default_function);
+        let ns = match namespace {
+            Some(ns) => ns,
+            None => return routes,
+        };
+        routes
+            .into_iter()
+            .map(|(function, mut spec)| {
+                let function = match &spec.function {
+                    Some(f) => namespaces::qualify(namespace, f),
+                    None => function,
+                };
+                spec.path = format!("/{}{}", ns, spec.path);
+                (function, spec)
+            })
+            .collect()
+    }
+
+    /// Applies a library's manifest to its functions, replacing the settings of `replacing`.
+    ///
+    /// Settings apply to every function, and each function is called on the schedule.
+    fn apply_manifest(
+        &self,
+        functions: &[String],
+        manifest: Manifest,
+        namespace: Option<&str>,
+        replacing: &[String],
+    ) -> Result<()> {
+        // Loaded libraries always export at least one function.
+        let first = &functions[0];
+
+        let routes = Installer::routes(&manifest, first, namespace);
+        if let Some((function, route)) = routes.iter().find(|(f, _)| !functions.contains(f)) {
+            return Err(router::Error::InvalidRoute {
+                route: route.clone(),
+                reason: format!("function {} is not exported by the library", function),
+            })
+            .context(RouteError);
+        }
+        let mut replaced: Vec<String> = replacing.to_vec();
+        replaced.extend(functions.iter().cloned());
+        self.router.set(&routes, &replaced).context(RouteError)?;
+
+        if let Some(limits) = &manifest.limits {
+            for fn_name in functions.iter() {
+                self.limiter
+                    .set_limits(fn_name, limits.clone())
+                    .context(LimiterError)?;
+            }
+        }
+
+        if let Some(schedule) = &manifest.schedule {
+            for fn_name in functions.iter() {
+                self.scheduler
+                    .set(fn_name, schedule.clone())
+                    .context(SchedulerError)?;
+            }
+        }
+
+        let mut manifests = self
+            .manifests
+            .write()
+            .map_err(|_e| Error::ManifestLockFailure)?;
+        manifests.retain(|f, _| !replacing.contains(f));
+        for fn_name in functions.iter() {
+            manifests.insert(fn_name.clone(), manifest.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Locks a function while a deploy prepares its sources. Released when dropped.
+pub struct SourceLease<'a> {
+    preparing: &'a Mutex<HashSet<String>>,
+    name: String,
+}
+
+impl Drop for SourceLease<'_> {
+    fn drop(&mut self) {
+        // A poisoned lock fails every later lease, so there is nothing left to release.
+        if let Ok(mut preparing) = self.preparing.lock() {
+            preparing.remove(&self.name);
+        }
+    }
+}
+
+/// Counts a call as in flight until dropped.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(count: &'a AtomicUsize) -> InFlight<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A library built elsewhere, e.g. by CI, deployed without going through the compile worker.
+pub struct Prebuilt {
+    /// Name of the function, which the library is deployed under.
+    pub name: String,
+
+    /// The shared object or WebAssembly module.
+    pub data: Vec<u8>,
+
+    /// Expected SHA-256 digest of `data`, hex-encoded.
+    pub sha256: String,
+
+    /// The crate's Cargo.toml, for its `[package.metadata.funcky]` manifest.
+    pub cargo_toml: Option<String>,
+}
+
+impl Prebuilt {
+    /// Checks the artifact against its checksum, and tells its runtime from its magic number.
+    fn verify(&self) -> Result<Runtime> {
+        let actual = sha256_hex(&self.data);
+        ensure!(
+            actual.eq_ignore_ascii_case(self.sha256.trim()),
+            ChecksumMismatch {
+                expected: self.sha256.trim(),
+                actual
+            }
+        );
+        ensure!(
+            namespaces::is_valid_function_name(&self.name),
+            InvalidArtifact {
+                reason: "names may only contain ASCII letters, digits, '-' and '_'"
+            }
+        );
+
+        if self.data.starts_with(b"\x7fELF") {
+            Ok(Runtime::Native)
+        } else if self.data.starts_with(b"\0asm") {
+            Ok(Runtime::Wasm)
+        } else {
+            InvalidArtifact {
+                reason: "not a shared object or WebAssembly module",
+            }
+            .fail()
+        }
+    }
 }
 
 pub struct FunckManager {
     pub cfg: Config,
     compile_worker: compiler::Worker,
     loader: Arc<RwLock<FunckLoader>>,
-    result_thread_handle: Option<thread::JoinHandle<()>>,
+    kv_store: Arc<KvStore>,
+    limiter: Arc<RateLimiter>,
+    invocations: InvocationStore,
+    async_workers: WorkerPool,
+    step_workers: WorkerPool,
+    scheduler: Arc<Scheduler>,
+    router: Arc<Router>,
+    manifests: Arc<RwLock<HashMap<String, Manifest>>>,
+    pipelines: PipelineStore,
+    secrets: SecretStore,
+    namespaces: Arc<NamespaceStore>,
+    deployments: Arc<DeploymentStore>,
+    sources: SourceStore,
+    build_cache: Arc<BuildCache>,
+    repositories: Mutex<Repositories>,
+    preparing: Mutex<HashSet<String>>,
+    result_thread_handle: Mutex<Option<thread::JoinHandle<()>>>,
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
     status_tracker: Arc<StatusTracker>,
+    tracer: Arc<Tracer>,
 }
 
 impl FunckManager {
     pub fn new(cfg: Config) -> Result<FunckManager> {
         FunckManager::ensure_dirs_exist(&cfg)?;
 
-        let stat_tracker = Arc::new(StatusTracker::new());
-        let compile_worker =
-            compiler::Worker::new(&cfg.shared_object_directory, stat_tracker.clone());
+        let tracer = Arc::new(Tracer::new(&cfg.trace_export, "funcky").context(TracerInitError)?);
+        let deployments = Arc::new(
+            DeploymentStore::load(
+                cfg.state_directory.join(DEPLOYMENTS_FILE),
+                cfg.source_retention,
+            )
+            .context(DeploymentError)?,
+        );
+        let sources =
+            SourceStore::new(cfg.state_directory.join(SOURCES_DIR)).context(SourceError)?;
+        let stat_tracker = Arc::new(StatusTracker::new(deployments.clone()));
+        let namespaces = Arc::new(
+            NamespaceStore::load(cfg.state_directory.join(NAMESPACES_FILE))
+                .context(NamespaceError)?,
+        );
+        let build_cache = Arc::new(
+            BuildCache::new(
+                &cfg.build_cache_directory,
+                cfg.cargo_home.clone(),
+                cfg.build_cache_max_bytes,
+            )
+            .context(BuildCacheError)?,
+        );
+        let build_options = cfg
+            .build_options
+            .clone()
+            .resolve()
+            .context(CompileWorkerStartError)?;
+        let compile_worker = compiler::Worker::new(
+            &cfg.shared_object_directory,
+            &cfg.state_directory.join(PENDING_BUILDS_FILE),
+            stat_tracker.clone(),
+            namespaces.clone(),
+            build_cache.clone(),
+            build_options,
+            tracer.clone(),
+        );
+
+        let invocations = InvocationStore::new(cfg.invocation_retention, cfg.max_invocations);
+        let async_workers = WorkerPool::new(cfg.async_workers, cfg.async_queue_capacity);
+        let step_workers = WorkerPool::new(cfg.step_workers, cfg.step_queue_capacity);
+        let scheduler =
+            Scheduler::load(cfg.state_directory.join(SCHEDULES_FILE)).context(SchedulerError)?;
+        let pipelines =
+            PipelineStore::load(cfg.state_directory.join(PIPELINES_FILE)).context(PipelineError)?;
+        let secrets = SecretStore::load(
+            cfg.state_directory.join(SECRETS_FILE),
+            cfg.secrets_key.as_deref(),
+        )
+        .context(SecretsError)?;
+        let kv_store = Arc::new(KvStore::open(cfg.state_directory.join(KV_DIR)).context(KvError)?);
+        let repositories = Mutex::new(Repositories::new(
+            cfg.tmp_dir.join(GIT_CACHE_DIR),
+            &cfg.build_options.sandbox,
+        ));
 
         let mut manager = FunckManager {
             cfg,
             compile_worker,
-            loader: Arc::new(RwLock::new(FunckLoader::new())),
-            result_thread_handle: None,
+            loader: Arc::new(RwLock::new(FunckLoader::new(kv_store.clone()))),
+            kv_store,
+            limiter: Arc::new(RateLimiter::new()),
+            invocations,
+            async_workers,
+            step_workers,
+            scheduler: Arc::new(scheduler),
+            router: Arc::new(Router::new()),
+            manifests: Arc::new(RwLock::new(HashMap::new())),
+            pipelines,
+            secrets,
+            namespaces,
+            deployments,
+            sources,
+            build_cache,
+            repositories,
+            preparing: Mutex::new(HashSet::new()),
+            result_thread_handle: Mutex::new(None),
+            shutting_down: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
             status_tracker: stat_tracker,
+            tracer,
         };
 
         // Perform initial loading of .so files.
@@ -75,58 +753,118 @@ impl FunckManager {
     }
 
     pub fn start(&mut self) -> Result<()> {
-        ensure!(self.result_thread_handle.is_none(), ManagerAlreadyStarted);
+        ensure!(
+            self.result_thread_handle
+                .get_mut()
+                .map_err(|_e| Error::ShutdownLockFailure)?
+                .is_none(),
+            ManagerAlreadyStarted
+        );
 
         let result_rx = self
             .compile_worker
             .start()
             .context(CompileWorkerStartError)?;
-        let loader = self.loader.clone();
-        let so_directory = self.cfg.shared_object_directory.clone();
+        self.collect_sources();
+        let installer = self.installer();
         let tracker = self.status_tracker.clone();
-        let result_thread_handle = thread::spawn(move || {
-            FunckManager::shared_object_install_loop(loader, tracker, so_directory, result_rx)
+        let tracer = self.tracer.clone();
+        let handle = thread::spawn(move || {
+            FunckManager::shared_object_install_loop(installer, tracker, tracer, result_rx)
         });
-        self.result_thread_handle = Some(result_thread_handle);
+        *self
+            .result_thread_handle
+            .get_mut()
+            .map_err(|_e| Error::ShutdownLockFailure)? = Some(handle);
         Ok(())
     }
 
-    fn install_shared_object(
-        res: &compiler::Response,
-        loader: Arc<RwLock<FunckLoader>>,
-        so_dir: &Path,
-    ) -> Result<()> {
-        // Move the shared object file to the managed .so directory.
-        let output_file_name = res.so_path.file_name().ok_or(Error::MissingFileName)?;
+    /// Turns new calls and deploys away, for the server to stop.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
 
-        let so_file_path = so_dir.join(output_file_name);
-        fs::rename(&res.so_path, &so_file_path).context(CantMoveSharedObject)?;
+    fn ensure_running(&self) -> Result<()> {
+        ensure!(!self.shutting_down.load(Ordering::SeqCst), ShuttingDown);
+        Ok(())
+    }
+
+    /// Waits for in-flight calls to complete, returning how many are still running after
+    /// `timeout`.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 || Instant::now() >= deadline {
+                return in_flight;
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
 
-        let mut loader_guard = loader.write().map_err(|_e| Error::LoaderLockFailure)?;
-        loader_guard
-            .load_funcktion(&res.so_path)
-            .context(LoadError)?;
+    /// Stops the compile worker and the installer, then unloads every function.
+    pub fn shutdown(&self, queued: QueuedBuilds, max_build_wait: Duration) -> Result<()> {
+        self.begin_shutdown();
+        self.compile_worker
+            .stop(queued, max_build_wait)
+            .context(CompileWorkerStopError)?;
 
-        Ok(())
+        // The installer stops once the compile worker is gone, after installing its last build.
+        let installer = self
+            .result_thread_handle
+            .lock()
+            .map_err(|_e| Error::ShutdownLockFailure)?
+            .take();
+        if let Some(handle) = installer {
+            if handle.join().is_err() {
+                log::error!("shared object installer panicked");
+            }
+        }
+
+        if let Err(e) = self.scheduler.flush() {
+            log::error!("failed to save schedules: {}", e);
+        }
+        self.unload_functions()
+    }
+
+    fn installer(&self) -> Installer {
+        Installer {
+            loader: self.loader.clone(),
+            limiter: self.limiter.clone(),
+            scheduler: self.scheduler.clone(),
+            router: self.router.clone(),
+            manifests: self.manifests.clone(),
+            namespaces: self.namespaces.clone(),
+            deployments: self.deployments.clone(),
+            so_dir: self.cfg.shared_object_directory.clone(),
+        }
     }
 
     fn shared_object_install_loop(
-        loader: Arc<RwLock<FunckLoader>>,
+        installer: Installer,
         status: Arc<StatusTracker>,
-        so_dir: PathBuf,
+        tracer: Arc<Tracer>,
         so_rx: mpsc::Receiver<compiler::Response>,
     ) {
         loop {
             match so_rx.recv() {
                 Ok(res) => {
-                    match FunckManager::install_shared_object(&res, loader.clone(), &so_dir) {
-                        Ok(_) => {
+                    let mut span = tracer.start_span("install", res.trace_context.as_ref());
+                    span.set_attribute("funcky.job", &res.job_name);
+                    match installer.install(&res) {
+                        Ok(functions) => {
                             status.update_status(&res.job_name, Status::Ready);
+                            for fn_name in functions.iter().filter(|f| *f != &res.job_name) {
+                                status.new_with_status(fn_name, Status::Ready);
+                            }
                         }
                         Err(e) => {
-                            log::error!("install error: {}", e.to_string());
+                            log::error!("install error: {}", e);
+                            span.set_error(&e);
+                            status.update_status(&res.job_name, Status::Failed(e.to_string()));
                         }
                     }
+                    span.end();
                 }
                 Err(_e) => {
                     log::info!("shared object installer disconnected");
@@ -146,33 +884,152 @@ impl FunckManager {
         if !cfg.tmp_dir.exists() {
             fs::create_dir_all(&cfg.tmp_dir).context(InitializationError)?;
         }
+
+        if !cfg.state_directory.exists() {
+            fs::create_dir_all(&cfg.state_directory).context(InitializationError)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the artifacts of a directory, i.e. shared objects and WebAssembly modules.
+    fn artifacts(dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)
+            .context(InitializationError)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| match p.extension() {
+                Some(ext) => ext == "so" || ext == WASM_EXTENSION,
+                None => false,
+            })
+            .collect())
+    }
+
+    /// Name of the deployment of an artifact: `libecho.so` was deployed as `echo`.
+    fn job_name(namespace: Option<&str>, path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) if ext == "so" => stem.strip_prefix("lib").unwrap_or(&stem),
+            _ => &stem,
+        };
+        namespaces::qualify(namespace, name)
+    }
+
+    /// Loads an artifact found on startup and re-applies the manifest stored with it.
+    fn load_artifact(
+        installer: &Installer,
+        loader: &mut FunckLoader,
+        path: &Path,
+        namespace: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let manifest = Manifest::load(Manifest::path_for(path))
+            .context(ManifestError)?
+            .unwrap_or_default();
+        let library_name = namespaces::qualify(
+            namespace,
+            &path.file_stem().unwrap_or_default().to_string_lossy(),
+        );
+
+        let functions = match loader.load_library(path, namespace, &manifest) {
+            Ok(functions) => functions,
+            Err(e) => {
+                loader.unload_library(&library_name);
+                return Err(e).context(LoadError);
+            }
+        };
+        if let Err(e) = installer.apply_manifest(&functions, manifest, namespace, &[]) {
+            for fn_name in functions.iter() {
+                installer.router.remove(fn_name).ok();
+            }
+            loader.unload_library(&library_name);
+            return Err(e);
+        }
+        Ok(functions)
+    }
+
+    /// Moves an artifact and its manifest out of the artifact directory.
+    fn quarantine(path: &Path, destination: &Path) -> Result<()> {
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).context(FailedToQuarantine { path })?;
+        }
+        fs::rename(path, destination).context(FailedToQuarantine { path })?;
+        let manifest = Manifest::path_for(path);
+        if manifest.exists() {
+            fs::rename(&manifest, Manifest::path_for(destination))
+                .context(FailedToQuarantine { path: &manifest })?;
+        }
         Ok(())
     }
 
     fn refresh_shared_objects(&mut self) -> Result<()> {
         log::info!("refreshing loaded shared objects...");
-        let mut fn_loader = FunckLoader::new();
-        for f in fs::read_dir(&self.cfg.shared_object_directory)
+        let installer = self.installer();
+        let mut fn_loader = FunckLoader::new(self.kv_store.clone());
+
+        // Artifacts of namespaces are stored in a subdirectory per namespace.
+        let mut artifacts: Vec<(Option<String>, PathBuf)> =
+            FunckManager::artifacts(&self.cfg.shared_object_directory)?
+                .into_iter()
+                .map(|p| (None, p))
+                .collect();
+        for entry in fs::read_dir(&self.cfg.shared_object_directory)
             .context(InitializationError)?
             .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
         {
-            if let Some(ext) = f.path().extension() {
-                if ext != "so" {
-                    continue;
-                }
-            } else {
+            let ns = entry.file_name().to_string_lossy().to_string();
+            if namespaces::validate_name(&ns).is_err() {
                 continue;
             }
+            for path in FunckManager::artifacts(&entry.path())? {
+                artifacts.push((Some(ns.clone()), path));
+            }
+        }
 
-            log::info!("found shared library: {}", f.path().display());
+        let so_dir = self.cfg.shared_object_directory.clone();
+        let mut loaded = Vec::new();
+        for (namespace, path) in artifacts {
+            log::info!("found shared library: {}", path.display());
+            let job_name = FunckManager::job_name(namespace.as_deref(), &path);
+            let artifact = path.strip_prefix(&so_dir).unwrap_or(&path);
 
-            // Load .so file.
-            let name = fn_loader.load_funcktion(f.path()).context(LoadError)?;
-            self.status_tracker.new_with_status(&name, Status::Ready);
+            // A library that fails to load is set aside, rather than keeping the server down.
+            match FunckManager::load_artifact(
+                &installer,
+                &mut fn_loader,
+                &path,
+                namespace.as_deref(),
+            ) {
+                Ok(functions) => {
+                    self.deployments
+                        .loaded(&job_name, artifact, &functions)
+                        .context(DeploymentError)?;
+                    for fn_name in functions.iter() {
+                        self.status_tracker.new_with_status(fn_name, Status::Ready);
+                    }
+                    loaded.push(job_name);
+                }
+                Err(e) => {
+                    log::error!("quarantining shared library [{}]: {}", path.display(), e);
+                    let quarantined = Path::new(QUARANTINE_DIR).join(artifact);
+                    FunckManager::quarantine(&path, &so_dir.join(&quarantined))?;
+                    self.deployments
+                        .quarantined(&job_name, &quarantined, e.to_string())
+                        .context(DeploymentError)?;
+                }
+            }
         }
 
+        // Deployments that aren't loaded are still reported, with their last status.
+        for (name, status) in self
+            .deployments
+            .reconcile(&loaded)
+            .context(DeploymentError)?
         {
-            let mut loader_lock = self.loader.write().unwrap(); // TODO: Handle.
+            self.status_tracker.new_with_status(&name, status);
+        }
+
+        {
+            let mut loader_lock = self.loader.write().map_err(|_e| Error::LoaderLockFailure)?;
             std::mem::swap(loader_lock.deref_mut(), &mut fn_loader);
         }
 
@@ -180,23 +1037,1202 @@ impl FunckManager {
         Ok(())
     }
 
-    pub fn add(&self, src_dir: super::DropDir) -> Result<()> {
-        // Build the function.
-        self.status_tracker.add(&src_dir.name);
+    /// Queues the build of a function. Functions of a namespace are named `{ns}/{fn}`.
+    pub fn add(
+        &self,
+        src_dir: super::DropDir,
+        namespace: Option<&str>,
+        flags: BuildFlags,
+        source_hash: String,
+        trace_context: Option<SpanContext>,
+    ) -> Result<()> {
+        self.build(
+            compiler::Request::new(src_dir, namespace.map(String::from), flags, trace_context)
+                .with_source_hash(source_hash),
+        )
+    }
+
+    /// Checks out a function from a git repository and builds it, returning the commit it was
+    /// checked out at.
+    pub fn add_git(
+        &self,
+        spec: GitSpec,
+        namespace: Option<&str>,
+        trace_context: Option<SpanContext>,
+    ) -> Result<String> {
+        let name = spec.validate().context(GitError)?;
+        let job_name = namespaces::qualify(namespace, &name);
+        self.check_function_slot(&job_name, namespace)?;
+
+        let (mut src_dir, _lease) = self.source_directory(namespace, &name)?;
+        let source = {
+            let mut span = self
+                .tracer
+                .start_span("deploy.checkout", trace_context.as_ref());
+            span.set_attribute("funcky.job", &job_name);
+            let repositories = self
+                .repositories
+                .lock()
+                .map_err(|_e| Error::RepositoryLockFailure)?;
+            let source = repositories.checkout(&spec, src_dir.path());
+            if let Err(e) = &source {
+                span.set_error(e);
+            }
+            span.end();
+            source.context(GitError)?
+        };
+        if let Some(subdir) = &spec.subdir {
+            src_dir.enter(subdir);
+        }
+
+        let commit = source.commit.clone();
+        self.build(
+            compiler::Request::new(
+                src_dir,
+                namespace.map(String::from),
+                spec.build.unwrap_or_default(),
+                trace_context,
+            )
+            .with_source(source),
+        )?;
+        Ok(commit)
+    }
+
+    /// Where the sources of a namespace's functions are built.
+    pub fn build_directory(&self, namespace: Option<&str>) -> PathBuf {
+        match namespace {
+            Some(ns) => self.cfg.tmp_dir.join(NAMESPACE_BUILD_DIR).join(ns),
+            None => self.cfg.tmp_dir.clone(),
+        }
+    }
+
+    /// Creates the directory a deploy prepares its sources in. Every deploy gets its own, so a
+    /// queued build's sources aren't replaced by the next deploy, and deploys of a function
+    /// prepare their sources one at a time: the function is locked until the lease is dropped,
+    /// once the build is queued.
+    pub fn source_directory(
+        &self,
+        namespace: Option<&str>,
+        name: &str,
+    ) -> Result<(super::DropDir, SourceLease<'_>)> {
+        let job_name = namespaces::qualify(namespace, name);
+        let lease = {
+            let mut preparing = self
+                .preparing
+                .lock()
+                .map_err(|_e| Error::BuildLockFailure)?;
+            ensure!(
+                preparing.insert(job_name.clone()),
+                DeployInProgress { name: &job_name }
+            );
+            SourceLease {
+                preparing: &self.preparing,
+                name: job_name.clone(),
+            }
+        };
+
+        let parent = self.build_directory(namespace).join(name);
+        let path = fs::create_dir_all(&parent)
+            .and_then(|_| tempfile::Builder::new().prefix("src-").tempdir_in(&parent))
+            .context(FailedToCreateBuildDirectory)?
+            .keep();
+        let src_dir = super::DropDir::new(path, &job_name).context(FailedToCreateBuildDirectory)?;
+        Ok((src_dir, lease))
+    }
+
+    /// Queued and running builds of a namespace, or of no namespace, in queue order. Builds of a
+    /// namespace require its token.
+    pub fn build_queue(
+        &self,
+        namespace: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<Vec<BuildInfo>> {
+        if let Some(ns) = namespace {
+            self.authorize_namespace(ns, token)?;
+        }
+        Ok(self
+            .build_queue_unchecked()?
+            .into_iter()
+            .filter(|b| b.namespace.as_deref() == namespace)
+            .collect())
+    }
+
+    /// Cancels a queued or running build. Builds of a namespace require its token.
+    pub fn cancel_build(&self, id: u64, token: Option<&str>) -> Result<BuildInfo> {
+        let build = self
+            .build_queue_unchecked()?
+            .into_iter()
+            .find(|b| b.id == id)
+            .context(UnknownBuild { id })?;
+        if let Some(ns) = &build.namespace {
+            self.authorize_namespace(ns, token)?;
+        }
+        self.compile_worker.cancel(id).map_err(|e| match e {
+            compiler::Error::UnknownBuild { id } => Error::UnknownBuild { id },
+            e => Error::CompileJobError {
+                source: Box::new(e),
+            },
+        })
+    }
+
+    fn build_queue_unchecked(&self) -> Result<Vec<BuildInfo>> {
         self.compile_worker
-            .new_job(compiler::Request::new(src_dir))
-            .unwrap(); // TODO: Handle.
+            .queue()
+            .map_err(|e| Error::CompileJobError {
+                source: Box::new(e),
+            })
+    }
+
+    /// Queues a build.
+    fn build(&self, request: compiler::Request) -> Result<()> {
+        self.ensure_running()?;
+        request
+            .flags
+            .validate()
+            .map_err(|reason| Error::InvalidBuildFlags { reason })?;
+        self.check_function_slot(&request.source_directory.name, request.namespace.as_deref())?;
+
+        self.compile_worker.new_job(request).map_err(|e| match e {
+            compiler::Error::QuotaExceeded { source } => Error::NamespaceError { source },
+            e => Error::CompileJobError {
+                source: Box::new(e),
+            },
+        })
+    }
+
+    /// Installs a prebuilt library right away, returning the names of its functions.
+    pub fn add_prebuilt(
+        &self,
+        prebuilt: Prebuilt,
+        namespace: Option<&str>,
+        trace_context: Option<SpanContext>,
+    ) -> Result<Vec<String>> {
+        self.ensure_running()?;
+        let runtime = prebuilt.verify()?;
+        let manifest = match &prebuilt.cargo_toml {
+            Some(cargo_toml) => Manifest::from_cargo_toml_str(cargo_toml).context(ManifestError)?,
+            None => Manifest {
+                runtime,
+                ..Manifest::default()
+            },
+        };
+        ensure!(
+            manifest.runtime == runtime,
+            InvalidArtifact {
+                reason: format!(
+                    "the manifest declares a {:?} function, the artifact is {:?}",
+                    manifest.runtime, runtime
+                )
+                .to_lowercase()
+            }
+        );
+
+        let job_name = namespaces::qualify(namespace, &prebuilt.name);
+        self.check_function_slot(&job_name, namespace)?;
+
+        // Staged next to the other artifacts, so installing it is a rename.
+        let staging = tempfile::Builder::new()
+            .prefix("prebuilt-")
+            .tempdir_in(&self.cfg.tmp_dir)
+            .context(FailedToStageArtifact)?;
+        let file_name = compiler::artifact_file_name(&prebuilt.name, runtime);
+        let staged = staging.path().join(&file_name);
+        fs::write(&staged, &prebuilt.data).context(FailedToStageArtifact)?;
+        if let Some(ns) = namespace {
+            let destination = self.cfg.shared_object_directory.join(ns).join(&file_name);
+            self.namespaces
+                .check_disk_quota(ns, &staged, &destination)
+                .context(NamespaceError)?;
+            fs::create_dir_all(self.cfg.shared_object_directory.join(ns))
+                .context(FailedToStageArtifact)?;
+        }
+
+        let mut span = self.tracer.start_span("install", trace_context.as_ref());
+        span.set_attribute("funcky.job", &job_name);
+        let installed = self.installer().install(&compiler::Response {
+            so_path: staged,
+            job_name: job_name.clone(),
+            namespace: namespace.map(String::from),
+            manifest,
+            flags: BuildFlags::default(),
+            source_hash: Some(sha256_hex(&prebuilt.data)),
+            trace_context,
+        });
+        if let Err(e) = &installed {
+            span.set_error(e);
+        }
+        span.end();
+
+        let functions = installed?;
+        log::info!(
+            "installed prebuilt {} ({} bytes)",
+            job_name,
+            prebuilt.data.len()
+        );
+        self.status_tracker
+            .new_with_status(&job_name, Status::Ready);
+        for fn_name in functions.iter().filter(|f| *f != &job_name) {
+            self.status_tracker.new_with_status(fn_name, Status::Ready);
+        }
+        Ok(functions)
+    }
+
+    /// Turns a new function away early if its namespace is full. Libraries exporting several
+    /// functions are checked again once loaded.
+    fn check_function_slot(&self, job_name: &str, namespace: Option<&str>) -> Result<()> {
+        let ns = match namespace {
+            Some(ns) => ns,
+            None => return Ok(()),
+        };
+        let max = self
+            .namespaces
+            .quotas(ns)
+            .context(NamespaceError)?
+            .and_then(|q| q.max_functions);
+        if let Some(max) = max {
+            let loader_r_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
+            if !loader_r_guard.contains(job_name)
+                && loader_r_guard.namespace_functions(ns).len() >= max
+            {
+                return Err(Error::NamespaceError {
+                    source: namespaces::Error::FunctionQuotaExceeded {
+                        name: String::from(ns),
+                        max,
+                    },
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the token of a namespace, for deploying to it.
+    pub fn authorize_namespace(&self, namespace: &str, token: Option<&str>) -> Result<()> {
+        self.namespaces
+            .authorize(namespace, token)
+            .map(|_| ())
+            .context(NamespaceError)
+    }
+
+    /// Checks the token of the namespace of a function, if it belongs to one.
+    pub fn authorize_function(&self, function_name: &str, token: Option<&str>) -> Result<()> {
+        match namespaces::split(function_name) {
+            (Some(ns), _) => self.authorize_namespace(ns, token),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Checks the tokens of the namespaces of several functions.
+    fn authorize_functions<'a, I>(&self, function_names: I, token: Option<&str>) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        function_names
+            .into_iter()
+            .try_for_each(|name| self.authorize_function(name, token))
+    }
+
+    /// Whether a function is listed to a caller: functions of no namespace are listed to everyone,
+    /// those of a namespace only with its token.
+    fn visible_to(&self, token: Option<&str>) -> Result<impl Fn(&str) -> bool> {
+        let authorized = self.namespaces.authorized(token).context(NamespaceError)?;
+        Ok(
+            move |function_name: &str| match namespaces::split(function_name) {
+                (Some(ns), _) => authorized.contains(ns),
+                (None, _) => true,
+            },
+        )
+    }
+
+    pub fn set_namespace(
+        &self,
+        namespace: &str,
+        spec: NamespaceSpec,
+        token: Option<&str>,
+    ) -> Result<()> {
+        self.namespaces
+            .set(namespace, spec, token)
+            .context(NamespaceError)
+    }
+
+    /// Removes a namespace. Its functions must have been removed first.
+    pub fn remove_namespace(&self, namespace: &str, token: Option<&str>) -> Result<()> {
+        self.authorize_namespace(namespace, token)?;
+        let count = {
+            let loader_r_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
+            loader_r_guard.namespace_functions(namespace).len()
+        };
+        if count > 0 {
+            return Err(Error::NamespaceError {
+                source: namespaces::Error::NamespaceNotEmpty {
+                    name: String::from(namespace),
+                    count,
+                },
+            });
+        }
+        self.namespaces
+            .remove(namespace, token)
+            .context(NamespaceError)
+    }
+
+    pub fn namespaces(&self) -> Result<HashMap<String, NamespaceStat>> {
+        let loader_r_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
+        Ok(self
+            .namespaces
+            .all()
+            .context(NamespaceError)?
+            .into_iter()
+            .map(|(name, quotas)| {
+                let disk_bytes =
+                    namespaces::disk_usage(&self.cfg.shared_object_directory.join(&name), None)
+                        .unwrap_or(0);
+                let stat = NamespaceStat {
+                    quotas,
+                    functions: loader_r_guard.namespace_functions(&name).len(),
+                    builds: self.compile_worker.builds(&name),
+                    disk_bytes,
+                };
+                (name, stat)
+            })
+            .collect())
+    }
+
+    pub fn call(&self, function_name: &str, request: Request, caller: &Caller) -> Result<Response> {
+        self.ensure_running()?;
+        // Held until the call returns, to count towards the in-flight limit.
+        let _permit = self.acquire_permit(function_name, caller)?;
+        self.call_admitted(function_name, request)
+    }
+
+    fn acquire_permit(&self, function_name: &str, caller: &Caller) -> Result<Option<Permit>> {
+        self.limiter
+            .acquire(function_name, caller)
+            .map_err(|rejection| match rejection {
+                Rejection::TooManyInFlight => Error::TooManyInFlight {
+                    name: String::from(function_name),
+                },
+                Rejection::RateExceeded { retry_after } => Error::RateLimited {
+                    name: String::from(function_name),
+                    retry_after,
+                },
+                Rejection::LockFailure => Error::LimiterError {
+                    source: limiter::Error::LimiterLockFailure,
+                },
+            })
+    }
+
+    /// Calls a function that passed its limits.
+    fn call_admitted(&self, function_name: &str, request: Request) -> Result<Response> {
+        let _in_flight = InFlight::new(&self.in_flight);
+
+        let parent = request
+            .metadata()
+            .get(telemetry::TRACEPARENT_HEADER)
+            .and_then(|h| SpanContext::from_traceparent(h));
+        let mut span = self.tracer.start_span("invoke", parent.as_ref());
+        span.set_attribute("funcky.function", function_name);
+
+        let result = self
+            .with_secrets(function_name, request)
+            .and_then(|request| {
+                let loader_r_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
+                loader_r_guard
+                    .call(function_name, request)
+                    .map_err(|e| match e {
+                        LoaderError::CallError { source, .. } => Error::FunctionFailed {
+                            name: String::from(function_name),
+                            status: self.error_status(function_name),
+                            source,
+                        },
+                        e => Error::CallError { source: e },
+                    })
+            });
+        if let Err(e) = &result {
+            span.set_error(e);
+        }
+        result
+    }
+
+    /// HTTP status reported when a function returns an error.
+    fn error_status(&self, function_name: &str) -> u16 {
+        self.manifests
+            .read()
+            .ok()
+            .and_then(|m| m.get(function_name).and_then(|m| m.error_status))
+            .unwrap_or(DEFAULT_ERROR_STATUS)
+    }
+
+    /// Replaces any secret metadata of a request with the secrets bound to the function.
+    fn with_secrets(&self, function_name: &str, request: Request) -> Result<Request> {
+        let bound = self.secrets.resolve(function_name).context(SecretsError)?;
+        Ok(secrets::replace_secrets(request, bound))
+    }
+
+    pub fn tracer(&self) -> Arc<Tracer> {
+        self.tracer.clone()
+    }
+
+    /// Unloads every function, running their shutdown hooks. Used when the server exits.
+    pub fn unload_functions(&self) -> Result<()> {
+        log::info!("unloading functions");
+        let mut loader_guard = self.loader.write().map_err(|_e| Error::LoaderLockFailure)?;
+        loader_guard.unload();
         Ok(())
     }
 
-    pub fn call(&self, function_name: &str, request: Request) -> Result<Response> {
+    fn ensure_loaded(&self, function_name: &str) -> Result<()> {
         let loader_r_guard = self.loader.read().map_err(|_e| Error::LoaderLockFailure)?;
-        loader_r_guard
-            .call(function_name, request)
-            .context(CallError)
+        if !loader_r_guard.contains(function_name) {
+            return Err(Error::CallError {
+                source: LoaderError::UnknownFunction {
+                    name: String::from(function_name),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks an HTTP call against the function's manifest, returning the call timeout, if any.
+    ///
+    /// Routed calls pass no method, since their route already restricts it.
+    pub fn admit_http_call(
+        &self,
+        function_name: &str,
+        method: Option<&str>,
+        body_len: usize,
+    ) -> Result<Option<Duration>> {
+        self.ensure_loaded(function_name)?;
+
+        let manifests = self
+            .manifests
+            .read()
+            .map_err(|_e| Error::ManifestLockFailure)?;
+        let manifest = manifests.get(function_name).cloned().unwrap_or_default();
+
+        if let Some(method) = method {
+            ensure!(
+                manifest.allows_method(method),
+                MethodNotAllowed {
+                    name: function_name,
+                    method
+                }
+            );
+        }
+
+        let limit = manifest.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        ensure!(
+            body_len as u64 <= limit,
+            BodyTooLarge {
+                name: function_name,
+                limit
+            }
+        );
+
+        Ok(manifest.timeout_ms.map(Duration::from_millis))
+    }
+
+    /// Resolves a request to a custom route, returning the function and the path parameters as
+    /// request metadata.
+    pub fn resolve_route(
+        &self,
+        method: &str,
+        path: &str,
+    ) -> Result<Option<(String, HashMap<String, String>)>> {
+        let resolved = self.router.resolve(method, path).context(RouteError)?;
+        Ok(resolved.map(|m| {
+            let metadata = m
+                .params
+                .into_iter()
+                .map(|(k, v)| (format!("{}{}", PARAM_METADATA_PREFIX, k), v))
+                .collect();
+            (m.function, metadata)
+        }))
+    }
+
+    pub fn routes(&self, token: Option<&str>) -> Result<HashMap<String, Vec<RouteSpec>>> {
+        let visible = self.visible_to(token)?;
+        let mut routes = self.router.all().context(RouteError)?;
+        routes.retain(|name, _| visible(name));
+        Ok(routes)
+    }
+
+    /// Admits an asynchronous call to a function and queues it, returning its invocation ID. The
+    /// call counts towards the function's limits from the moment it is queued.
+    pub fn call_async(
+        self: &Arc<Self>,
+        function_name: &str,
+        request: Request,
+        caller: &Caller,
+    ) -> Result<String> {
+        self.ensure_running()?;
+        let permit = self.acquire_permit(function_name, caller)?;
+        let invocation_id = self
+            .invocations
+            .create(function_name)
+            .context(InvocationError)?;
+
+        let manager = self.clone();
+        let id = invocation_id.clone();
+        let name = String::from(function_name);
+        let job = Box::new(move || {
+            let _permit = permit;
+            let result = manager
+                .invocations
+                .set_running(&id)
+                .context(InvocationError)
+                .and_then(|_| manager.ensure_running())
+                .and_then(|_| manager.call_admitted(&name, request));
+            if let Err(e) = manager.invocations.complete(&id, result.as_ref()) {
+                log::error!("failed to record invocation {}: {}", id, e);
+            }
+        });
+        if self.async_workers.submit(job).is_err() {
+            self.invocations
+                .remove(&invocation_id)
+                .context(InvocationError)?;
+            return AsyncQueueFull {
+                name: function_name,
+            }
+            .fail();
+        }
+        Ok(invocation_id)
+    }
+
+    pub fn invocation(
+        &self,
+        invocation_id: &str,
+        token: Option<&str>,
+    ) -> Result<Option<Invocation>> {
+        match self
+            .invocations
+            .get(invocation_id)
+            .context(InvocationError)?
+        {
+            Some(invocation) => {
+                self.authorize_function(&invocation.function, token)?;
+                Ok(Some(invocation))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Starts firing scheduled calls. The scheduler thread stops once the manager is dropped.
+    pub fn start_scheduler(manager: &Arc<FunckManager>) {
+        let weak_manager = Arc::downgrade(manager);
+        thread::spawn(move || FunckManager::scheduler_loop(weak_manager));
+    }
+
+    fn scheduler_loop(manager: Weak<FunckManager>) {
+        loop {
+            thread::sleep(SCHEDULER_TICK);
+            let manager = match manager.upgrade() {
+                Some(m) if !m.shutting_down.load(Ordering::SeqCst) => m,
+                _ => break,
+            };
+
+            let runs = match manager.scheduler.due() {
+                Ok(runs) => runs,
+                Err(e) => {
+                    log::error!("failed to check schedules: {}", e);
+                    break;
+                }
+            };
+            for run in runs {
+                let m = manager.clone();
+                thread::spawn(move || m.run_scheduled(run));
+            }
+        }
+        log::info!("scheduler stopped");
+    }
+
+    fn run_scheduled(&self, run: ScheduledRun) {
+        log::info!(
+            "running scheduled call of {} ({:?})",
+            run.function,
+            run.trigger
+        );
+
+        let mut span = self.tracer.start_span("schedule", None);
+        span.set_attribute("funcky.function", &run.function);
+
+        let mut metadata = run.spec.metadata;
+        metadata.insert(
+            String::from(telemetry::TRACEPARENT_HEADER),
+            span.context().to_traceparent(),
+        );
+        let request = Request::new(run.spec.payload.into_bytes(), metadata);
+
+        let started_at = unix_now();
+        let start = Instant::now();
+        let result = self.call(&run.function, request, &Caller::default());
+
+        let status = match &result {
+            Ok(_) => RunStatus::Succeeded,
+            Err(e) => {
+                log::error!("scheduled call of {} failed: {}", run.function, e);
+                span.set_error(e);
+                RunStatus::Failed(e.to_string())
+            }
+        };
+
+        let recorded = self.scheduler.record(
+            &run.function,
+            RunRecord {
+                trigger: run.trigger,
+                started_at,
+                duration_ms: start.elapsed().as_millis() as u64,
+                status,
+            },
+            result.ok().as_ref().map(StoredResponse::from),
+        );
+        if let Err(e) = recorded {
+            log::error!("failed to record scheduled call of {}: {}", run.function, e);
+        }
+    }
+
+    pub fn set_schedule(&self, function_name: &str, spec: ScheduleSpec) -> Result<()> {
+        self.ensure_loaded(function_name)?;
+        self.scheduler
+            .set(function_name, spec)
+            .context(SchedulerError)
+    }
+
+    pub fn remove_schedule(&self, function_name: &str) -> Result<()> {
+        self.scheduler.remove(function_name).context(SchedulerError)
+    }
+
+    pub fn schedule(&self, function_name: &str) -> Result<Option<ScheduleStat>> {
+        self.scheduler.get(function_name).context(SchedulerError)
+    }
+
+    pub fn schedules(&self, token: Option<&str>) -> Result<HashMap<String, ScheduleStat>> {
+        let visible = self.visible_to(token)?;
+        let mut schedules = self.scheduler.all().context(SchedulerError)?;
+        schedules.retain(|name, _| visible(name));
+        Ok(schedules)
     }
 
-    pub fn stat(&self) -> HashMap<String, FuncktionEntry> {
-        self.status_tracker.all()
+    /// Calls the steps of a pipeline in order. Each step is admitted and timed out like an HTTP
+    /// `POST` to its function.
+    pub fn call_pipeline(
+        self: &Arc<Self>,
+        pipeline_name: &str,
+        request: Request,
+        caller: &Caller,
+        token: Option<&str>,
+    ) -> Result<Response> {
+        let pipeline = self.pipelines.get(pipeline_name).context(PipelineError)?;
+        self.authorize_functions(&pipeline.steps, token)?;
+
+        // Every step shares the trace context of the original request.
+        let traceparent = request
+            .metadata()
+            .get(telemetry::TRACEPARENT_HEADER)
+            .cloned();
+
+        let (last_step, first_steps) =
+            pipeline
+                .steps
+                .split_last()
+                .ok_or_else(|| Error::PipelineError {
+                    source: pipelines::Error::InvalidPipeline {
+                        reason: String::from("a pipeline needs at least one step"),
+                    },
+                })?;
+
+        // Steps are numbered from 1 in errors.
+        let call_step = |step: usize, function_name: &str, request: Request| {
+            self.admit_http_call(function_name, Some("POST"), request.body().len())
+                .and_then(|timeout| self.call_with_timeout(function_name, request, caller, timeout))
+                .map_err(|e| Error::PipelineStepFailed {
+                    pipeline: String::from(pipeline_name),
+                    step,
+                    function: String::from(function_name),
+                    source: Box::new(e),
+                })
+        };
+
+        let mut request = request;
+        for (i, function_name) in first_steps.iter().enumerate() {
+            let response = call_step(i + 1, function_name, request)?;
+
+            let mut metadata: HashMap<String, String> = pipeline
+                .forward_metadata
+                .iter()
+                .filter_map(|k| response.metadata().get(k).map(|v| (k.clone(), v.clone())))
+                .collect();
+            if let Some(tp) = &traceparent {
+                metadata.insert(String::from(telemetry::TRACEPARENT_HEADER), tp.clone());
+            }
+            request = Request::new(Vec::from(response.body()), metadata);
+        }
+
+        call_step(pipeline.steps.len(), last_step, request)
+    }
+
+    /// Calls a function on the step workers, giving up after `timeout`, which includes the time
+    /// spent waiting for a worker.
+    ///
+    /// The call can't be interrupted, and keeps its worker until it completes. Steps that hang
+    /// thus tie up at most every step worker, after which new steps queue and then get rejected.
+    fn call_with_timeout(
+        self: &Arc<Self>,
+        function_name: &str,
+        request: Request,
+        caller: &Caller,
+        timeout: Option<Duration>,
+    ) -> Result<Response> {
+        let timeout = match timeout {
+            Some(t) => t,
+            None => return self.call(function_name, request, caller),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let manager = self.clone();
+        let name = String::from(function_name);
+        let caller = caller.clone();
+        let job = Box::new(move || {
+            tx.send(manager.call(&name, request, &caller)).ok();
+        });
+        if self.step_workers.submit(job).is_err() {
+            return StepQueueFull {
+                name: function_name,
+            }
+            .fail();
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => CallTimedOut {
+                name: function_name,
+                timeout_ms: timeout.as_millis() as u64,
+            }
+            .fail(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::CallJoinFailure),
+        }
+    }
+
+    /// Stores a pipeline. Replacing one needs the tokens of the namespaces of both its old and new
+    /// steps.
+    pub fn set_pipeline(
+        &self,
+        pipeline_name: &str,
+        spec: PipelineSpec,
+        token: Option<&str>,
+    ) -> Result<()> {
+        if let Ok(old) = self.pipelines.get(pipeline_name) {
+            self.authorize_functions(&old.steps, token)?;
+        }
+        self.authorize_functions(&spec.steps, token)?;
+        self.pipelines
+            .set(pipeline_name, spec)
+            .context(PipelineError)
+    }
+
+    pub fn remove_pipeline(&self, pipeline_name: &str, token: Option<&str>) -> Result<()> {
+        if let Ok(old) = self.pipelines.get(pipeline_name) {
+            self.authorize_functions(&old.steps, token)?;
+        }
+        self.pipelines.remove(pipeline_name).context(PipelineError)
+    }
+
+    /// The pipelines whose steps are all listed to the caller.
+    pub fn pipelines(&self, token: Option<&str>) -> Result<HashMap<String, PipelineSpec>> {
+        let visible = self.visible_to(token)?;
+        let mut pipelines = self.pipelines.all().context(PipelineError)?;
+        pipelines.retain(|_, p| p.steps.iter().all(|s| visible(s)));
+        Ok(pipelines)
+    }
+
+    /// Stores a secret. Replacing one needs the tokens of the namespaces of the functions it was
+    /// and will be bound to.
+    pub fn set_secret(
+        &self,
+        secret_name: &str,
+        spec: SecretSpec,
+        token: Option<&str>,
+    ) -> Result<()> {
+        if let Some(old) = self.secrets.all().context(SecretsError)?.get(secret_name) {
+            self.authorize_functions(&old.functions, token)?;
+        }
+        self.authorize_functions(&spec.functions, token)?;
+        self.secrets.set(secret_name, spec).context(SecretsError)
+    }
+
+    pub fn remove_secret(&self, secret_name: &str, token: Option<&str>) -> Result<()> {
+        if let Some(old) = self.secrets.all().context(SecretsError)?.get(secret_name) {
+            self.authorize_functions(&old.functions, token)?;
+        }
+        self.secrets.remove(secret_name).context(SecretsError)
+    }
+
+    /// The secrets whose functions are all listed to the caller.
+    pub fn secrets(&self, token: Option<&str>) -> Result<HashMap<String, SecretInfo>> {
+        let visible = self.visible_to(token)?;
+        let mut secrets = self.secrets.all().context(SecretsError)?;
+        secrets.retain(|_, s| s.functions.iter().all(|f| visible(f)));
+        Ok(secrets)
+    }
+
+    pub fn kv_entries(&self, function_name: &str) -> Result<BTreeMap<String, KvEntry>> {
+        self.kv_store.entries(function_name).context(KvError)
+    }
+
+    pub fn clear_kv(&self, function_name: &str) -> Result<()> {
+        self.kv_store.clear(function_name).context(KvError)
+    }
+
+    pub fn build_cache(&self, token: Option<&str>) -> Result<BTreeMap<String, CacheEntryStat>> {
+        let visible = self.visible_to(token)?;
+        let mut entries = self.build_cache.entries().context(BuildCacheError)?;
+        entries.retain(|name, _| visible(name));
+        Ok(entries)
+    }
+
+    /// Removes the build cache of a function, so its next build starts from scratch.
+    pub fn purge_build_cache(&self, function_name: &str) -> Result<()> {
+        self.build_cache
+            .purge(function_name)
+            .context(BuildCacheError)?;
+        log::info!("purged build cache of {}", function_name);
+        Ok(())
+    }
+
+    pub fn set_limits(&self, function_name: &str, limits: Limits) -> Result<()> {
+        limits
+            .validate()
+            .map_err(|reason| Error::InvalidLimits { reason })?;
+        self.limiter
+            .set_limits(function_name, limits)
+            .context(LimiterError)
+    }
+
+    pub fn remove_limits(&self, function_name: &str) -> Result<bool> {
+        self.limiter
+            .remove_limits(function_name)
+            .context(LimiterError)
+    }
+
+    /// Stores an uploaded source bundle, returning its hash and where it is stored. Bundles no
+    /// longer referenced by a deployment are removed on the way.
+    pub fn store_source(&self, data: &[u8]) -> Result<(String, PathBuf)> {
+        let stored = self.sources.store(data).context(SourceError)?;
+        self.collect_sources();
+        Ok(stored)
+    }
+
+    fn collect_sources(&self) {
+        // Collecting without the referenced hashes would remove bundles still in use.
+        let mut keep = match self.deployments.source_hashes() {
+            Ok(keep) => keep,
+            Err(e) => {
+                log::error!("failed to collect source bundles: {}", e);
+                return;
+            }
+        };
+        if let Ok(queue) = self.compile_worker.queue() {
+            keep.extend(queue.into_iter().filter_map(|b| b.source_hash));
+        }
+        match self.sources.collect(&keep) {
+            Ok(0) => {}
+            Ok(removed) => log::info!("removed {} unreferenced source bundles", removed),
+            Err(e) => log::error!("failed to collect source bundles: {}", e),
+        }
+    }
+
+    /// Looks up an installed version of a function, the current one by default.
+    pub fn version(&self, function_name: &str, version: Option<u64>) -> Result<Version> {
+        let deployment = self
+            .deployments
+            .get(function_name)
+            .context(DeploymentError)?
+            .ok_or_else(|| Error::UnknownDeployment {
+                name: String::from(function_name),
+            })?;
+        deployment
+            .version(version)
+            .ok_or_else(|| Error::UnknownVersion {
+                name: String::from(function_name),
+                version: version.unwrap_or(deployment.version),
+            })
+    }
+
+    /// Returns where the source bundle of a version is stored.
+    pub fn source_bundle(&self, function_name: &str, version: &Version) -> Result<PathBuf> {
+        version
+            .source_hash
+            .as_deref()
+            .and_then(|hash| self.sources.get(hash))
+            .ok_or_else(|| Error::SourceNotStored {
+                name: String::from(function_name),
+                version: version.version,
+            })
+    }
+
+    /// Reads the source bundle of a version.
+    pub fn read_source(&self, function_name: &str, version: &Version) -> Result<Vec<u8>> {
+        let not_stored = || Error::SourceNotStored {
+            name: String::from(function_name),
+            version: version.version,
+        };
+        let hash = version.source_hash.as_deref().ok_or_else(not_stored)?;
+        self.sources
+            .read(hash)
+            .context(SourceError)?
+            .ok_or_else(not_stored)
+    }
+
+    /// The deployment registry, by function.
+    pub fn deployments(&self, token: Option<&str>) -> Result<BTreeMap<String, Deployment>> {
+        let visible = self.visible_to(token)?;
+        let mut deployments = self.deployments.all().context(DeploymentError)?;
+        deployments.retain(|name, _| visible(name));
+        Ok(deployments)
+    }
+
+    pub fn stat(&self, token: Option<&str>) -> Result<HashMap<String, FuncktionEntry>> {
+        let visible = self.visible_to(token)?;
+        let mut entries = self.status_tracker.all().context(StatusError)?;
+        entries.retain(|name, _| visible(name));
+        let manifests = self.manifests.read().ok();
+        for (name, entry) in entries.iter_mut() {
+            entry.limiter = self.limiter.stat(name).context(LimiterError)?;
+            entry.source = manifests
+                .as_ref()
+                .and_then(|m| m.get(name))
+                .and_then(|m| m.source.clone());
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::funcky::fixtures;
+    use crate::funcky::SandboxConfig;
+
+    fn config(dir: &Path) -> Config {
+        Config {
+            shared_object_directory: dir.join("so"),
+            tmp_dir: dir.join("tmp"),
+            state_directory: dir.join("state"),
+            trace_export: ExportTarget::Disabled,
+            invocation_retention: Duration::from_secs(60),
+            max_invocations: 16,
+            async_workers: 1,
+            async_queue_capacity: 4,
+            step_workers: 1,
+            step_queue_capacity: 4,
+            secrets_key: None,
+            build_cache_directory: dir.join("build_cache"),
+            build_cache_max_bytes: u64::MAX,
+            cargo_home: None,
+            build_options: compiler::BuildOptions {
+                registry: None,
+                network: compiler::NetworkMode::Offline,
+                sandbox: SandboxConfig {
+                    cpu_secs: 60,
+                    memory_bytes: u64::MAX,
+                    file_size_bytes: u64::MAX,
+                    timeout: Duration::from_secs(60),
+                    isolate: false,
+                },
+            },
+            source_retention: 2,
+        }
+    }
+
+    fn prebuilt(name: &str, data: Vec<u8>, cargo_toml: Option<&str>) -> Prebuilt {
+        Prebuilt {
+            name: String::from(name),
+            sha256: sha256_hex(&data),
+            data,
+            cargo_toml: cargo_toml.map(String::from),
+        }
+    }
+
+    /// The fixture library, which exports `alpha` and `beta`.
+    fn fixture(dir: &Path) -> Vec<u8> {
+        fs::read(fixtures::native_library(dir, "libfixture")).unwrap()
+    }
+
+    fn call(manager: &FunckManager, function: &str, body: &str) -> Result<String> {
+        let request = Request::new(Vec::from(body), HashMap::new());
+        let response = manager.call(function, request, &Caller::default())?;
+        Ok(String::from_utf8_lossy(response.body()).to_string())
+    }
+
+    #[test]
+    fn verify_prebuilt_artifacts() {
+        let elf = b"\x7fELF...".to_vec();
+        let wasm = b"\0asm...".to_vec();
+        let mut uppercase = prebuilt("f", elf.clone(), None);
+        uppercase.sha256 = format!(" {} ", uppercase.sha256.to_uppercase());
+        let mut mismatch = prebuilt("f", elf.clone(), None);
+        mismatch.sha256 = sha256_hex(b"other");
+
+        let cases = [
+            (prebuilt("f", elf.clone(), None), Some(Runtime::Native)),
+            (prebuilt("f", wasm, None), Some(Runtime::Wasm)),
+            (uppercase, Some(Runtime::Native)),
+            (mismatch, None),
+            (prebuilt("f", b"#!/bin/sh".to_vec(), None), None),
+            (prebuilt("../f", elf.clone(), None), None),
+            (prebuilt("ns/f", elf, None), None),
+        ];
+        for (prebuilt, runtime) in cases.iter() {
+            assert_eq!(prebuilt.verify().ok(), *runtime, "{}", prebuilt.name);
+        }
+    }
+
+    #[test]
+    fn prebuilt_libraries_are_installed() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = FunckManager::new(config(dir.path())).unwrap();
+        let data = fixture(dir.path());
+        let sha256 = sha256_hex(&data);
+
+        let functions = manager
+            .add_prebuilt(prebuilt("hooks", data, None), None, None)
+            .unwrap();
+        assert_eq!(functions, vec!["alpha", "beta"]);
+        assert_eq!(call(&manager, "beta", "hello").unwrap(), "hello");
+
+        let deployment = manager.deployments.get("hooks").unwrap().unwrap();
+        assert_eq!(deployment.artifact, Some(PathBuf::from("libhooks.so")));
+        assert_eq!(deployment.source_hash, Some(sha256));
+        assert!(dir.path().join("so/libhooks.so").exists());
+    }
+
+    #[test]
+    fn invalid_prebuilt_libraries_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = FunckManager::new(config(dir.path())).unwrap();
+        let wasm_manifest = "[package]\nname = \"hooks\"\n\n\
+                             [package.metadata.funcky]\nruntime = \"wasm\"\n";
+
+        let mut mismatch = prebuilt("hooks", fixture(dir.path()), None);
+        mismatch.sha256 = sha256_hex(b"other");
+        assert!(matches!(
+            manager.add_prebuilt(mismatch, None, None),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        let runtime_mismatch = prebuilt("hooks", fixture(dir.path()), Some(wasm_manifest));
+        assert!(matches!(
+            manager.add_prebuilt(runtime_mismatch, None, None),
+            Err(Error::InvalidArtifact { .. })
+        ));
+
+        // Not a funck library, so it has no `_funck_create` symbol.
+        let not_a_library = prebuilt("hooks", b"\x7fELF, truncated".to_vec(), None);
+        assert!(matches!(
+            manager.add_prebuilt(not_a_library, None, None),
+            Err(Error::LoadError { .. })
+        ));
+        assert!(call(&manager, "alpha", "").is_err());
+        assert!(manager.deployments.get("hooks").unwrap().is_none());
+    }
+
+    #[test]
+    fn broken_libraries_are_quarantined_on_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = FunckManager::new(config(dir.path())).unwrap();
+        let data = fixture(dir.path());
+        manager
+            .add_prebuilt(prebuilt("hooks", data, None), None, None)
+            .unwrap();
+        drop(manager);
+
+        let so_dir = dir.path().join("so");
+        fs::create_dir_all(so_dir.join("ns")).unwrap();
+        let broken = [("broken", "libbroken.so"), ("ns/broken", "ns/libbroken.so")];
+        for (_, artifact) in broken.iter() {
+            fs::write(so_dir.join(artifact), b"\x7fELF, truncated").unwrap();
+            Manifest::default()
+                .save(Manifest::path_for(so_dir.join(artifact)))
+                .unwrap();
+        }
+
+        let manager = FunckManager::new(config(dir.path())).unwrap();
+        assert_eq!(call(&manager, "alpha", "").unwrap(), "alpha:");
+        for (name, artifact) in broken.iter() {
+            let quarantined = Path::new(QUARANTINE_DIR).join(artifact);
+            assert!(!so_dir.join(artifact).exists(), "{}", name);
+            assert!(so_dir.join(&quarantined).exists(), "{}", name);
+            assert!(Manifest::path_for(so_dir.join(&quarantined)).exists());
+
+            let deployment = manager.deployments.get(name).unwrap().unwrap();
+            assert!(
+                matches!(deployment.status, Status::Quarantined(_)),
+                "{}",
+                name
+            );
+            assert_eq!(deployment.artifact, Some(quarantined));
+        }
+        assert_eq!(
+            manager.deployments.get("hooks").unwrap().unwrap().status,
+            Status::Ready
+        );
+        drop(manager);
+
+        // Quarantined libraries are no longer loaded, nor reported as missing.
+        let manager = FunckManager::new(config(dir.path())).unwrap();
+        let deployment = manager.deployments.get("broken").unwrap().unwrap();
+        assert!(matches!(deployment.status, Status::Quarantined(_)));
+    }
+
+    #[test]
+    fn drain_waits_for_in_flight_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(FunckManager::new(config(dir.path())).unwrap());
+        assert_eq!(manager.drain(Duration::from_secs(5)), 0);
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let call = {
+            let manager = manager.clone();
+            thread::spawn(move || {
+                let _in_flight = InFlight::new(&manager.in_flight);
+                started_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(500));
+            })
+        };
+        started_rx.recv().unwrap();
+        assert_eq!(manager.drain(Duration::from_millis(50)), 1);
+        assert_eq!(manager.drain(Duration::from_secs(10)), 0);
+        call.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_unloads_functions_and_turns_requests_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = FunckManager::new(config(dir.path())).unwrap();
+        manager.start().unwrap();
+
+        let marker = dir.path().join("shut_down");
+        let cargo_toml = format!(
+            "[package]\nname = \"hooks\"\n\n\
+             [package.metadata.funcky.config]\nshutdown_marker = {:?}\n",
+            marker.display().to_string()
+        );
+        let data = fixture(dir.path());
+        manager
+            .add_prebuilt(prebuilt("hooks", data, Some(&cargo_toml)), None, None)
+            .unwrap();
+        assert_eq!(call(&manager, "alpha", "").unwrap(), "alpha:");
+
+        manager
+            .shutdown(QueuedBuilds::Finish, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(fs::read(&marker).unwrap(), b"shut down");
+        assert!(matches!(
+            call(&manager, "alpha", ""),
+            Err(Error::ShuttingDown)
+        ));
+        let data = fixture(dir.path());
+        assert!(matches!(
+            manager.add_prebuilt(prebuilt("hooks", data, None), None, None),
+            Err(Error::ShuttingDown)
+        ));
     }
 }
@@ -0,0 +1,290 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, ResultExt, Snafu};
+
+use super::compiler::BuildFlags;
+use super::git::GitSource;
+use super::router::{self, RouteSpec};
+use super::scheduler;
+use super::wasm::WasmLimits;
+use super::{Limits, ScheduleSpec};
+
+pub(super) const VALID_METHODS: [&str; 7] =
+    ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read [{}]: {}", path.display(), source))]
+    FailedToReadCargoToml { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Invalid [package.metadata.funcky] table: {}", source))]
+    MalformedManifest { source: toml::de::Error },
+
+    #[snafu(display("Invalid [package.metadata.funcky] table: {}", reason))]
+    InvalidManifest { reason: String },
+
+    #[snafu(display("Failed to read manifest [{}]: {}", path.display(), source))]
+    FailedToReadManifest { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Failed to write manifest [{}]: {}", path.display(), source))]
+    FailedToWriteManifest { source: io::Error, path: PathBuf },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How a function is built and run.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    /// A shared object, loaded in the server's process.
+    #[default]
+    Native,
+
+    /// A `wasm32-wasi` module, run in the embedded WebAssembly interpreter.
+    Wasm,
+}
+
+/// Per-function settings, read from the `[package.metadata.funcky]` table of the function's Cargo.toml.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Maximum duration of a synchronous HTTP call, in milliseconds.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+
+    /// Maximum size of a request body, in bytes.
+    #[serde(default)]
+    pub max_body_bytes: Option<u64>,
+
+    /// HTTP status of responses to calls that return a `CallError`, from 400 to 599.
+    /// Defaults to 500.
+    #[serde(default)]
+    pub error_status: Option<u16>,
+
+    /// HTTP methods accepted on `/call/{name}`. Defaults to `POST` only.
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub limits: Option<Limits>,
+
+    #[serde(default)]
+    pub schedule: Option<ScheduleSpec>,
+
+    /// Custom HTTP routes served by the function, in addition to `/call/{name}`.
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+
+    #[serde(default)]
+    pub runtime: Runtime,
+
+    /// Resource limits of `wasm` functions.
+    #[serde(default)]
+    pub wasm: Option<WasmLimits>,
+
+    /// Configuration handed to the function's init hook when it is loaded.
+    #[serde(default)]
+    pub config: Option<serde_json::Value>,
+
+    /// Default cargo options of the function's builds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildFlags>,
+
+    /// Repository and commit of functions deployed from git. Recorded by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<GitSource>,
+}
+
+#[derive(Deserialize)]
+struct CargoToml {
+    package: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    metadata: Option<PackageMetadata>,
+}
+
+#[derive(Deserialize)]
+struct PackageMetadata {
+    funcky: Option<Manifest>,
+}
+
+impl Manifest {
+    /// Reads and validates the manifest of a crate. A missing table yields the default manifest.
+    pub fn from_cargo_toml<P: AsRef<Path>>(cargo_toml: P) -> Result<Manifest> {
+        let data = fs::read_to_string(cargo_toml.as_ref()).context(FailedToReadCargoToml {
+            path: PathBuf::from(cargo_toml.as_ref()),
+        })?;
+        Manifest::from_cargo_toml_str(&data)
+    }
+
+    /// Reads and validates the manifest of a crate from the contents of its Cargo.toml.
+    pub fn from_cargo_toml_str(data: &str) -> Result<Manifest> {
+        let parsed: CargoToml = toml::from_str(data).context(MalformedManifest)?;
+
+        let manifest = parsed
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.funcky)
+            .unwrap_or_default();
+        ensure!(
+            manifest.source.is_none(),
+            InvalidManifest {
+                reason: "source is recorded by the server"
+            }
+        );
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Loads a manifest stored alongside a shared object, if there is one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Manifest>> {
+        match fs::read(path.as_ref()) {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data)
+                    .map_err(io::Error::from)
+                    .context(FailedToReadManifest {
+                        path: PathBuf::from(path.as_ref()),
+                    })?,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(FailedToReadManifest {
+                path: PathBuf::from(path.as_ref()),
+            }),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        serde_json::to_vec_pretty(self)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(path.as_ref(), data))
+            .context(FailedToWriteManifest {
+                path: PathBuf::from(path.as_ref()),
+            })
+    }
+
+    /// Path of the manifest stored alongside a shared object.
+    pub fn path_for<P: AsRef<Path>>(so_path: P) -> PathBuf {
+        so_path.as_ref().with_extension("manifest.json")
+    }
+
+    /// The configuration of the function, as a JSON document. Defaults to an empty object.
+    pub fn config_json(&self) -> Vec<u8> {
+        match &self.config {
+            Some(config) => serde_json::to_vec(config).unwrap_or_default(),
+            None => b"{}".to_vec(),
+        }
+    }
+
+    /// Pairs every route with the function serving it, `default_function` unless it names one.
+    pub fn function_routes(&self, default_function: &str) -> Vec<(String, RouteSpec)> {
+        self.routes
+            .iter()
+            .map(|r| {
+                let function = r.function.as_deref().unwrap_or(default_function);
+                (String::from(function), r.clone())
+            })
+            .collect()
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        match &self.methods {
+            Some(methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(method)),
+            None => method.eq_ignore_ascii_case("POST"),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.timeout_ms != Some(0),
+            InvalidManifest {
+                reason: "timeout_ms must be positive"
+            }
+        );
+        ensure!(
+            self.max_body_bytes != Some(0),
+            InvalidManifest {
+                reason: "max_body_bytes must be positive"
+            }
+        );
+
+        if let Some(status) = self.error_status {
+            ensure!(
+                (400..600).contains(&status),
+                InvalidManifest {
+                    reason: "error_status must be a 4xx or 5xx status"
+                }
+            );
+        }
+
+        if let Some(methods) = &self.methods {
+            ensure!(
+                !methods.is_empty(),
+                InvalidManifest {
+                    reason: "methods must not be empty"
+                }
+            );
+            if let Some(m) = methods
+                .iter()
+                .find(|m| !VALID_METHODS.contains(&m.to_uppercase().as_str()))
+            {
+                return InvalidManifest {
+                    reason: format!("unsupported HTTP method: {}", m),
+                }
+                .fail();
+            }
+        }
+
+        if let Some(limits) = &self.limits {
+            limits.validate().map_err(|reason| Error::InvalidManifest {
+                reason: format!("limits: {}", reason),
+            })?;
+        }
+
+        if let Some(schedule) = &self.schedule {
+            scheduler::validate(&schedule.cron).map_err(|e| Error::InvalidManifest {
+                reason: format!("schedule: {}", e),
+            })?;
+        }
+
+        if let Some(wasm) = &self.wasm {
+            ensure!(
+                self.runtime == Runtime::Wasm,
+                InvalidManifest {
+                    reason: "wasm limits require runtime = \"wasm\""
+                }
+            );
+            wasm.validate().map_err(|reason| Error::InvalidManifest {
+                reason: format!("wasm: {}", reason),
+            })?;
+        }
+
+        if let Some(config) = &self.config {
+            ensure!(
+                config.is_object(),
+                InvalidManifest {
+                    reason: "config must be a table"
+                }
+            );
+        }
+
+        if let Some(build) = &self.build {
+            build.validate().map_err(|reason| Error::InvalidManifest {
+                reason: format!("build: {}", reason),
+            })?;
+        }
+
+        for route in &self.routes {
+            router::validate(route).map_err(|e| Error::InvalidManifest {
+                reason: e.to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,15 +1,58 @@
+mod abi;
+mod build_cache;
+mod clock;
 mod compiler;
-mod dirmove;
+mod deployments;
+mod digest;
 mod dropdir;
+#[cfg(test)]
+mod fixtures;
+mod git;
+mod invocations;
+mod kv;
+mod limiter;
 mod loader;
 mod manager;
+mod manifest;
+mod namespaces;
+mod pipelines;
+mod router;
+mod sandbox;
+mod scheduler;
+mod secrets;
+mod sources;
+mod state;
 mod status;
+mod wasm;
+mod workers;
 
 // === Private Exports ===
-use dirmove::DirHook;
+use build_cache::BuildCache;
+use deployments::DeploymentStore;
+use invocations::InvocationStore;
+use limiter::{LimiterStat, RateLimiter};
 use loader::FunckLoader;
+use namespaces::NamespaceStore;
+use pipelines::PipelineStore;
+use router::Router;
+use scheduler::Scheduler;
+use secrets::SecretStore;
+use sources::SourceStore;
 use status::{FuncktionEntry, Status, StatusTracker};
+use workers::WorkerPool;
 
 // === Public Exports ===
+pub use build_cache::Error as BuildCacheError;
+pub use compiler::{BuildFlags, BuildOptions, NetworkMode, QueuedBuilds, RegistrySource};
 pub use dropdir::DropDir;
-pub use manager::{Config, Error, FunckManager, LoaderError};
+pub use git::{Error as GitError, GitSource, GitSpec};
+pub use invocations::Invocation;
+pub use limiter::{Caller, Limits};
+pub use manager::{Config, Error, FunckManager, LoaderError, Prebuilt};
+pub use manifest::Error as ManifestError;
+pub use namespaces::{is_valid_function_name, split, Error as NamespaceError, NamespaceSpec};
+pub use pipelines::{Error as PipelineError, PipelineSpec};
+pub use router::Error as RouteError;
+pub use sandbox::SandboxConfig;
+pub use scheduler::{Error as SchedulerError, ScheduleSpec, ScheduleStat};
+pub use secrets::{Error as SecretsError, SecretInfo, SecretSpec};
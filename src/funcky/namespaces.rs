@@ -0,0 +1,333 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use super::digest::sha256_hex;
+use super::state;
+
+/// Separates the namespace of a function from its name, as in `/call/{ns}/{fn}`.
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+const MAX_NAME_LENGTH: usize = 64;
+const MIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid namespace: {}", reason))]
+    InvalidNamespace {
+        reason: String,
+    },
+
+    #[snafu(display("Unknown namespace: {}", name))]
+    UnknownNamespace {
+        name: String,
+    },
+
+    #[snafu(display("Missing or invalid token for namespace: {}", name))]
+    Unauthorized {
+        name: String,
+    },
+
+    #[snafu(display("Namespace {} still has {} function(s) deployed", name, count))]
+    NamespaceNotEmpty {
+        name: String,
+        count: usize,
+    },
+
+    #[snafu(display("Namespace {} is limited to {} function(s)", name, max))]
+    FunctionQuotaExceeded {
+        name: String,
+        max: usize,
+    },
+
+    #[snafu(display("Namespace {} is limited to {} concurrent build(s)", name, max))]
+    BuildQuotaExceeded {
+        name: String,
+        max: usize,
+    },
+
+    #[snafu(display(
+        "Namespace {} is limited to {} bytes of artifacts, {} needed",
+        name,
+        max,
+        needed
+    ))]
+    DiskQuotaExceeded {
+        name: String,
+        max: u64,
+        needed: u64,
+    },
+
+    #[snafu(display("Failed to load namespaces from [{}]: {}", path.display(), source))]
+    FailedToLoadNamespaces {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to save namespaces: {}", source))]
+    FailedToSaveNamespaces {
+        source: io::Error,
+    },
+
+    #[snafu(display("Failed to measure the disk usage of namespace {}: {}", name, source))]
+    FailedToMeasureUsage {
+        source: io::Error,
+        name: String,
+    },
+
+    NamespaceLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Resource limits of a namespace. Unset limits don't apply.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Quotas {
+    /// Maximum number of functions deployed in the namespace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_functions: Option<usize>,
+
+    /// Maximum number of builds of the namespace queued or running at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_builds: Option<usize>,
+
+    /// Maximum size of the namespace's artifacts, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_disk_bytes: Option<u64>,
+}
+
+/// A namespace as submitted by an administrator.
+///
+/// Deliberately not `Debug`, so the token can't end up in logs.
+#[derive(Deserialize)]
+pub struct NamespaceSpec {
+    /// Token required to deploy to and manage the functions of the namespace.
+    pub token: String,
+
+    #[serde(flatten)]
+    pub quotas: Quotas,
+}
+
+/// The quotas of a namespace and its current usage.
+#[derive(Clone, Debug, Serialize)]
+pub struct NamespaceStat {
+    #[serde(flatten)]
+    pub quotas: Quotas,
+    pub functions: usize,
+    pub builds: usize,
+    pub disk_bytes: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct StoredNamespace {
+    /// Hex-encoded SHA-256 digest of the token. The token itself is never persisted.
+    token_sha256: String,
+
+    #[serde(flatten)]
+    quotas: Quotas,
+}
+
+/// Qualifies a function or library name with its namespace, if any.
+pub fn qualify(namespace: Option<&str>, name: &str) -> String {
+    match namespace {
+        Some(ns) => format!("{}{}{}", ns, NAMESPACE_SEPARATOR, name),
+        None => String::from(name),
+    }
+}
+
+/// Splits a qualified name into its namespace, if any, and its name.
+pub fn split(qualified: &str) -> (Option<&str>, &str) {
+    match qualified.split_once(NAMESPACE_SEPARATOR) {
+        Some((ns, name)) => (Some(ns), name),
+        None => (None, qualified),
+    }
+}
+
+/// Whether a function name, without its namespace, only has ASCII letters, digits, '-' and '_'.
+pub fn is_valid_function_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && name.len() <= MAX_NAME_LENGTH,
+        InvalidNamespace {
+            reason: format!("names must be 1 to {} characters long", MAX_NAME_LENGTH)
+        }
+    );
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        InvalidNamespace {
+            reason: "names may only contain ASCII letters, digits, '-' and '_'"
+        }
+    );
+    Ok(())
+}
+
+/// Total size of the files of a directory, leaving out `excluding`, e.g. an artifact about to be
+/// replaced. A missing directory is empty.
+pub fn disk_usage(dir: &Path, excluding: Option<&OsStr>) -> io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut total = 0;
+    for entry in entries {
+        let entry = entry?;
+        if Some(entry.file_name().as_os_str()) == excluding {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+fn token_digest(token: &str) -> String {
+    sha256_hex(token.as_bytes())
+}
+
+/// The NamespaceStore keeps the persisted namespaces, with their tokens and quotas.
+pub struct NamespaceStore {
+    path: PathBuf,
+    namespaces: RwLock<HashMap<String, StoredNamespace>>,
+}
+
+impl NamespaceStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NamespaceStore> {
+        let namespaces = state::load_json(path.as_ref()).context(FailedToLoadNamespaces {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+        Ok(NamespaceStore {
+            path: PathBuf::from(path.as_ref()),
+            namespaces: RwLock::new(namespaces),
+        })
+    }
+
+    /// Creates or replaces a namespace. Replacing one requires its current token.
+    pub fn set(&self, name: &str, spec: NamespaceSpec, token: Option<&str>) -> Result<()> {
+        validate_name(name)?;
+        ensure!(
+            spec.token.len() >= MIN_TOKEN_LENGTH,
+            InvalidNamespace {
+                reason: format!(
+                    "tokens must be at least {} characters long",
+                    MIN_TOKEN_LENGTH
+                )
+            }
+        );
+
+        let mut namespaces = self
+            .namespaces
+            .write()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        if let Some(existing) = namespaces.get(name) {
+            Self::check_token(name, existing, token)?;
+        }
+        namespaces.insert(
+            String::from(name),
+            StoredNamespace {
+                token_sha256: token_digest(&spec.token),
+                quotas: spec.quotas,
+            },
+        );
+        self.save(&namespaces)
+    }
+
+    pub fn remove(&self, name: &str, token: Option<&str>) -> Result<()> {
+        let mut namespaces = self
+            .namespaces
+            .write()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        let existing = namespaces.get(name).context(UnknownNamespace { name })?;
+        Self::check_token(name, existing, token)?;
+        namespaces.remove(name);
+        self.save(&namespaces)
+    }
+
+    /// Checks a token against the one of a namespace, returning the namespace's quotas.
+    pub fn authorize(&self, name: &str, token: Option<&str>) -> Result<Quotas> {
+        let namespaces = self
+            .namespaces
+            .read()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        let existing = namespaces.get(name).context(UnknownNamespace { name })?;
+        Self::check_token(name, existing, token)?;
+        Ok(existing.quotas.clone())
+    }
+
+    /// Names of the namespaces a token is valid for.
+    pub fn authorized(&self, token: Option<&str>) -> Result<HashSet<String>> {
+        let namespaces = self
+            .namespaces
+            .read()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        Ok(namespaces
+            .iter()
+            .filter(|(name, n)| Self::check_token(name, n, token).is_ok())
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    pub fn quotas(&self, name: &str) -> Result<Option<Quotas>> {
+        let namespaces = self
+            .namespaces
+            .read()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        Ok(namespaces.get(name).map(|n| n.quotas.clone()))
+    }
+
+    pub fn all(&self) -> Result<HashMap<String, Quotas>> {
+        let namespaces = self
+            .namespaces
+            .read()
+            .map_err(|_e| Error::NamespaceLockFailure)?;
+        Ok(namespaces
+            .iter()
+            .map(|(name, n)| (name.clone(), n.quotas.clone()))
+            .collect())
+    }
+
+    /// Checks that an artifact fits in the disk quota of its namespace once moved to
+    /// `destination`, counting the artifact it replaces as freed.
+    pub fn check_disk_quota(&self, name: &str, artifact: &Path, destination: &Path) -> Result<()> {
+        let max = match self.quotas(name)?.and_then(|q| q.max_disk_bytes) {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+
+        let dst_dir = destination.parent().unwrap_or_else(|| Path::new("."));
+        let needed = fs::metadata(artifact)
+            .and_then(|m| Ok(disk_usage(dst_dir, destination.file_name())? + m.len()))
+            .context(FailedToMeasureUsage { name })?;
+        ensure!(needed <= max, DiskQuotaExceeded { name, max, needed });
+        Ok(())
+    }
+
+    fn check_token(name: &str, namespace: &StoredNamespace, token: Option<&str>) -> Result<()> {
+        ensure!(
+            token.map(token_digest).as_ref() == Some(&namespace.token_sha256),
+            Unauthorized { name }
+        );
+        Ok(())
+    }
+
+    fn save(&self, namespaces: &HashMap<String, StoredNamespace>) -> Result<()> {
+        state::save_json(&self.path, namespaces).context(FailedToSaveNamespaces)
+    }
+}
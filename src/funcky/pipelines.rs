@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use super::state;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid pipeline: {}", reason))]
+    InvalidPipeline {
+        reason: String,
+    },
+
+    #[snafu(display("Failed to load pipelines from [{}]: {}", path.display(), source))]
+    FailedToLoadPipelines {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to save pipelines: {}", source))]
+    FailedToSavePipelines {
+        source: io::Error,
+    },
+
+    #[snafu(display("Unknown pipeline: {}", name))]
+    UnknownPipeline {
+        name: String,
+    },
+
+    PipelineLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PipelineSpec {
+    /// Functions to call in order. Each response body is the request body of the next step.
+    pub steps: Vec<String>,
+
+    /// Response metadata keys forwarded as request metadata to the next step.
+    #[serde(default)]
+    pub forward_metadata: Vec<String>,
+}
+
+/// The PipelineStore keeps the persisted pipeline definitions.
+pub struct PipelineStore {
+    path: PathBuf,
+    pipelines: RwLock<HashMap<String, PipelineSpec>>,
+}
+
+impl PipelineStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PipelineStore> {
+        let pipelines = state::load_json(path.as_ref()).context(FailedToLoadPipelines {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+        Ok(PipelineStore {
+            path: PathBuf::from(path.as_ref()),
+            pipelines: RwLock::new(pipelines),
+        })
+    }
+
+    pub fn set(&self, name: &str, spec: PipelineSpec) -> Result<()> {
+        ensure!(
+            !spec.steps.is_empty(),
+            InvalidPipeline {
+                reason: "a pipeline needs at least one step"
+            }
+        );
+
+        let mut pipelines = self
+            .pipelines
+            .write()
+            .map_err(|_e| Error::PipelineLockFailure)?;
+        pipelines.insert(String::from(name), spec);
+        self.save(&pipelines)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut pipelines = self
+            .pipelines
+            .write()
+            .map_err(|_e| Error::PipelineLockFailure)?;
+        pipelines.remove(name).context(UnknownPipeline { name })?;
+        self.save(&pipelines)
+    }
+
+    pub fn get(&self, name: &str) -> Result<PipelineSpec> {
+        let pipelines = self
+            .pipelines
+            .read()
+            .map_err(|_e| Error::PipelineLockFailure)?;
+        pipelines
+            .get(name)
+            .cloned()
+            .context(UnknownPipeline { name })
+    }
+
+    pub fn all(&self) -> Result<HashMap<String, PipelineSpec>> {
+        let pipelines = self
+            .pipelines
+            .read()
+            .map_err(|_e| Error::PipelineLockFailure)?;
+        Ok(pipelines.clone())
+    }
+
+    fn save(&self, pipelines: &HashMap<String, PipelineSpec>) -> Result<()> {
+        state::save_json(&self.path, pipelines).context(FailedToSavePipelines)
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use percent_encoding::percent_decode_str;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, Snafu};
+
+use super::manifest::VALID_METHODS;
+
+/// Prefix of the request metadata keys path parameters are passed under.
+pub const PARAM_METADATA_PREFIX: &str = "funcky-param-";
+
+/// First path segments used by the server's own routes.
+const RESERVED_SEGMENTS: [&str; 3] = ["call", "call-async", "pipeline"];
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid route {}: {}", route, reason))]
+    InvalidRoute {
+        route: RouteSpec,
+        reason: String,
+    },
+
+    #[snafu(display(
+        "Route {} conflicts with route {} of function {}",
+        route,
+        other_route,
+        function
+    ))]
+    RouteConflict {
+        route: Box<RouteSpec>,
+        other_route: Box<RouteSpec>,
+        function: String,
+    },
+
+    RouteLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A custom HTTP route, e.g. `GET /users/{id}`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSpec {
+    pub method: String,
+
+    /// Path template. Segments written as `{name}` match any value and are passed to the
+    /// function as path parameters.
+    pub path: String,
+
+    /// Function serving the route, for libraries that export several functions. Defaults to the
+    /// first function of the library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+}
+
+impl fmt::Display for RouteSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method.to_uppercase(), self.path)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    spec: RouteSpec,
+    method: String,
+    segments: Vec<Segment>,
+    function: String,
+}
+
+impl Route {
+    fn parse(spec: &RouteSpec, function: &str) -> Result<Route> {
+        let invalid = |reason: &str| Error::InvalidRoute {
+            route: spec.clone(),
+            reason: String::from(reason),
+        };
+
+        let method = spec.method.to_uppercase();
+        ensure!(
+            VALID_METHODS.contains(&method.as_str()),
+            InvalidRoute {
+                route: spec.clone(),
+                reason: "unsupported HTTP method"
+            }
+        );
+        ensure!(
+            spec.path.starts_with('/'),
+            InvalidRoute {
+                route: spec.clone(),
+                reason: "paths must start with '/'"
+            }
+        );
+
+        let mut segments = Vec::new();
+        for raw in path_segments(&spec.path) {
+            let segment = match raw.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => {
+                    if name.is_empty()
+                        || !name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        return Err(invalid(
+                            "parameter names may only contain ASCII letters, digits, '-' and '_'",
+                        ));
+                    }
+                    if segments.contains(&Segment::Param(String::from(name))) {
+                        return Err(invalid("duplicate parameter name"));
+                    }
+                    Segment::Param(String::from(name))
+                }
+                None => {
+                    if raw.contains('{') || raw.contains('}') {
+                        return Err(invalid("parameters must span a whole path segment"));
+                    }
+                    Segment::Literal(String::from(raw))
+                }
+            };
+            segments.push(segment);
+        }
+
+        if let Some(Segment::Literal(first)) = segments.first() {
+            if first.starts_with('_') || RESERVED_SEGMENTS.contains(&first.as_str()) {
+                return Err(invalid("path is reserved by the server"));
+            }
+        }
+
+        Ok(Route {
+            spec: spec.clone(),
+            method,
+            segments,
+            function: String::from(function),
+        })
+    }
+
+    /// Two routes conflict if some request could match both.
+    fn conflicts_with(&self, other: &Route) -> bool {
+        self.method == other.method
+            && self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(other.segments.iter())
+                .all(|pair| match pair {
+                    (Segment::Literal(a), Segment::Literal(b)) => a == b,
+                    _ => true,
+                })
+    }
+
+    fn matches(&self, method: &str, path: &str) -> Option<HashMap<String, String>> {
+        if !self.method.eq_ignore_ascii_case(method) {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        let mut segments = self.segments.iter();
+        for raw in path_segments(path) {
+            match segments.next()? {
+                Segment::Literal(lit) => {
+                    if lit != raw {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = percent_decode_str(raw).decode_utf8().ok()?;
+                    params.insert(name.clone(), value.into_owned());
+                }
+            }
+        }
+
+        if segments.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+/// A route matched by a request.
+pub struct RouteMatch {
+    pub function: String,
+    pub params: HashMap<String, String>,
+}
+
+/// Checks that a route template is well-formed.
+pub fn validate(spec: &RouteSpec) -> Result<()> {
+    Route::parse(spec, "").map(|_| ())
+}
+
+/// The Router maps custom HTTP routes to functions.
+///
+/// The routing table is immutable once built: changes build a new table, which replaces the
+/// current one atomically, so requests never see a partially updated table.
+pub struct Router {
+    table: RwLock<Arc<Vec<Route>>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            table: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+
+    /// Builds the routes of a library, given as `(function, route)` pairs. They are checked
+    /// against each other, and against the routes of every function except `replacing`.
+    fn build(
+        table: &[Route],
+        routes: &[(String, RouteSpec)],
+        replacing: &[String],
+    ) -> Result<Vec<Route>> {
+        let mut built: Vec<Route> = Vec::new();
+        for (fn_name, spec) in routes {
+            let route = Route::parse(spec, fn_name)?;
+            if let Some(other) = table
+                .iter()
+                .filter(|r| !replacing.contains(&r.function))
+                .chain(built.iter())
+                .find(|r| r.conflicts_with(&route))
+            {
+                return RouteConflict {
+                    route: Box::new(spec.clone()),
+                    other_route: Box::new(other.spec.clone()),
+                    function: other.function.clone(),
+                }
+                .fail();
+            }
+            built.push(route);
+        }
+        Ok(built)
+    }
+
+    /// Checks that the routes of a library could replace the routes of `replacing`.
+    pub fn check(&self, routes: &[(String, RouteSpec)], replacing: &[String]) -> Result<()> {
+        Router::build(&self.snapshot()?, routes, replacing).map(|_| ())
+    }
+
+    /// Replaces the routes of the `replacing` functions with the routes of a library.
+    pub fn set(&self, routes: &[(String, RouteSpec)], replacing: &[String]) -> Result<()> {
+        let mut table = self.table.write().map_err(|_e| Error::RouteLockFailure)?;
+        let built = Router::build(&table, routes, replacing)?;
+        let mut new_table: Vec<Route> = table
+            .iter()
+            .filter(|r| !replacing.contains(&r.function))
+            .cloned()
+            .collect();
+        new_table.extend(built);
+        *table = Arc::new(new_table);
+        Ok(())
+    }
+
+    pub fn remove(&self, fn_name: &str) -> Result<()> {
+        let mut table = self.table.write().map_err(|_e| Error::RouteLockFailure)?;
+        let new_table: Vec<Route> = table
+            .iter()
+            .filter(|r| r.function != fn_name)
+            .cloned()
+            .collect();
+        *table = Arc::new(new_table);
+        Ok(())
+    }
+
+    pub fn resolve(&self, method: &str, path: &str) -> Result<Option<RouteMatch>> {
+        Ok(self.snapshot()?.iter().find_map(|r| {
+            r.matches(method, path).map(|params| RouteMatch {
+                function: r.function.clone(),
+                params,
+            })
+        }))
+    }
+
+    /// Returns the routes of every function.
+    pub fn all(&self) -> Result<HashMap<String, Vec<RouteSpec>>> {
+        let mut routes: HashMap<String, Vec<RouteSpec>> = HashMap::new();
+        for r in self.snapshot()?.iter() {
+            routes
+                .entry(r.function.clone())
+                .or_default()
+                .push(r.spec.clone());
+        }
+        Ok(routes)
+    }
+
+    fn snapshot(&self) -> Result<Arc<Vec<Route>>> {
+        let table = self.table.read().map_err(|_e| Error::RouteLockFailure)?;
+        Ok(table.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(route: &str) -> RouteSpec {
+        let (method, path) = route.split_once(' ').unwrap();
+        RouteSpec {
+            method: String::from(method),
+            path: String::from(path),
+            function: None,
+        }
+    }
+
+    fn routes(function: &str, specs: &[&str]) -> Vec<(String, RouteSpec)> {
+        specs
+            .iter()
+            .map(|s| (String::from(function), spec(s)))
+            .collect()
+    }
+
+    #[test]
+    fn validate_routes() {
+        let cases = [
+            ("GET /users/{id}", true),
+            ("get /users", true),
+            ("DELETE /a/{x}/b/{y_2}", true),
+            ("GET /", true),
+            ("FETCH /users", false),
+            ("GET users", false),
+            ("GET /users/{}", false),
+            ("GET /users/{id.x}", false),
+            ("GET /users/{id}/{id}", false),
+            ("GET /users/x{id}", false),
+            ("GET /users/{id", false),
+            ("GET /_funck_add", false),
+            ("GET /call/f", false),
+            ("GET /call-async", false),
+            ("GET /pipeline/{name}", false),
+            ("GET /{id}/call", true),
+        ];
+        for (route, valid) in cases.iter() {
+            assert_eq!(validate(&spec(route)).is_ok(), *valid, "{}", route);
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        let cases = [
+            ("GET /users/{id}", "GET /users/{name}", true),
+            ("GET /users/{id}", "GET /users/me", true),
+            ("GET /users/me", "get /users/me", true),
+            ("GET /{a}/posts", "GET /users/{b}", true),
+            ("GET /users/{id}", "POST /users/{id}", false),
+            ("GET /users/{id}", "GET /users/{id}/posts", false),
+            ("GET /users/me", "GET /users/you", false),
+            ("GET /users", "GET /posts", false),
+        ];
+        for (a, b, conflict) in cases.iter() {
+            let a = Route::parse(&spec(a), "f").unwrap();
+            let b = Route::parse(&spec(b), "g").unwrap();
+            assert_eq!(a.conflicts_with(&b), *conflict, "{} / {}", a.spec, b.spec);
+            assert_eq!(b.conflicts_with(&a), *conflict, "{} / {}", b.spec, a.spec);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        type Params = Option<&'static [(&'static str, &'static str)]>;
+        let cases: &[(&str, &str, &str, Params)] = &[
+            ("GET /users", "GET", "/users", Some(&[])),
+            ("GET /users", "get", "/users/", Some(&[])),
+            ("GET /users", "POST", "/users", None),
+            ("GET /users/{id}", "GET", "/users/42", Some(&[("id", "42")])),
+            (
+                "GET /users/{id}",
+                "GET",
+                "/users/a%20b",
+                Some(&[("id", "a b")]),
+            ),
+            ("GET /users/{id}", "GET", "/users/%FF", None),
+            ("GET /users/{id}", "GET", "/users", None),
+            ("GET /users/{id}", "GET", "/users/42/posts", None),
+            (
+                "GET /{org}/repos/{repo}",
+                "GET",
+                "/acme/repos/funcky",
+                Some(&[("org", "acme"), ("repo", "funcky")]),
+            ),
+            (
+                "GET /{org}/repos/{repo}",
+                "GET",
+                "/acme/issues/funcky",
+                None,
+            ),
+        ];
+        for (route, method, path, expected) in cases.iter() {
+            let r = Route::parse(&spec(route), "f").unwrap();
+            let expected = expected.map(|params| {
+                params
+                    .iter()
+                    .map(|(k, v)| (String::from(*k), String::from(*v)))
+                    .collect::<HashMap<_, _>>()
+            });
+            assert_eq!(
+                r.matches(method, path),
+                expected,
+                "{} on {} {}",
+                route,
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn set_rejects_conflicts_with_other_functions() {
+        let router = Router::new();
+        router
+            .set(&routes("users", &["GET /users/{id}"]), &[])
+            .unwrap();
+
+        // Another function can't take over the route, but a new version of its own can.
+        let other = routes("people", &["GET /users/me"]);
+        assert!(matches!(
+            router.check(&other, &[]),
+            Err(Error::RouteConflict { .. })
+        ));
+        let replacement = routes("users", &["GET /users/me"]);
+        assert!(router.set(&replacement, &[String::from("users")]).is_ok());
+        assert_eq!(
+            router
+                .resolve("GET", "/users/42")
+                .unwrap()
+                .map(|m| m.function),
+            None
+        );
+
+        // Routes of the same library are checked against each other too.
+        let twice = routes("posts", &["GET /posts/{id}", "GET /posts/{slug}"]);
+        assert!(router.check(&twice, &[]).is_err());
+    }
+
+    #[test]
+    fn resolve_and_remove() {
+        let router = Router::new();
+        router
+            .set(&routes("users", &["GET /users/{id}"]), &[])
+            .unwrap();
+        router.set(&routes("posts", &["POST /posts"]), &[]).unwrap();
+
+        let matched = router.resolve("GET", "/users/7").unwrap().unwrap();
+        assert_eq!(matched.function, "users");
+        assert_eq!(matched.params["id"], "7");
+        assert_eq!(
+            router
+                .resolve("POST", "/posts")
+                .unwrap()
+                .map(|m| m.function),
+            Some(String::from("posts"))
+        );
+
+        router.remove("users").unwrap();
+        assert!(router.resolve("GET", "/users/7").unwrap().is_none());
+        assert_eq!(router.all().unwrap().len(), 1);
+    }
+}
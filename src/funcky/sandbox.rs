@@ -0,0 +1,310 @@
+use std::env;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+/// Variables of the server's environment builds still get. Everything else is cleared.
+const PASSTHROUGH_ENV: [&str; 4] = ["PATH", "RUSTUP_TOOLCHAIN", "LANG", "TZ"];
+
+/// How often a running build is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sets up the namespaces of isolated builds, run with `sh -c` by `unshare`: every mount is
+/// made read-only, then the `$1` directories that follow are bound back writable, and the build
+/// command is executed.
+const ISOLATION_SCRIPT: &str = r#"awk '{print $2}' /proc/self/mounts | while read -r m; do mount -o remount,bind,ro "$m" 2>/dev/null; done
+n=$1; shift
+while [ "$n" -gt 0 ]; do mount --bind "$1" "$1" && mount -o remount,bind,rw "$1" || exit 1; shift; n=$((n-1)); done
+exec "$@""#;
+
+/// Resource limits and isolation of builds.
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+    /// CPU time limit of each build process, in seconds.
+    pub cpu_secs: u64,
+
+    /// Address space limit of each build process, in bytes.
+    pub memory_bytes: u64,
+
+    /// Largest file a build process may write, in bytes.
+    pub file_size_bytes: u64,
+
+    /// Wall-clock limit of a whole build, after which its process tree is killed.
+    pub timeout: Duration,
+
+    /// Runs builds in their own user, mount and network namespaces: without network access,
+    /// and with a read-only view of the host outside of their own directories.
+    pub isolate: bool,
+}
+
+/// How a sandboxed command ended.
+pub enum Outcome {
+    Exited {
+        status: ExitStatus,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    TimedOut,
+    Cancelled,
+}
+
+/// Rustup is found through HOME by default, which builds don't share with the server.
+fn rustup_home() -> Option<PathBuf> {
+    env::var_os("RUSTUP_HOME").map(PathBuf::from).or_else(|| {
+        let default = PathBuf::from(env::var_os("HOME")?).join(".rustup");
+        Some(default).filter(|p| p.is_dir())
+    })
+}
+
+/// A sandbox for one command, with its own scratch HOME. The scratch directory is removed when
+/// the sandbox is dropped.
+pub struct Sandbox {
+    config: SandboxConfig,
+    scratch: TempDir,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Sandbox {
+    pub fn new(config: &SandboxConfig) -> io::Result<Sandbox> {
+        Ok(Sandbox {
+            config: config.clone(),
+            scratch: tempfile::Builder::new().prefix("funcky-build-").tempdir()?,
+            cancel: None,
+        })
+    }
+
+    /// Kills commands, like on timeout, once `cancel` is set.
+    pub fn cancel_on(mut self, cancel: Arc<AtomicBool>) -> Sandbox {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Prepares a command running `program` in `dir`, with a cleared environment. Only `dir`,
+    /// the scratch HOME and the `writable` directories can be written to by isolated builds.
+    pub fn command(&self, program: &str, dir: &Path, writable: &[&Path]) -> Command {
+        let mut command = if self.config.isolate {
+            let mut unshare = Command::new("unshare");
+            unshare
+                .args(["--user", "--map-root-user", "--net", "--mount"])
+                .args([
+                    "--propagation",
+                    "private",
+                    "sh",
+                    "-c",
+                    ISOLATION_SCRIPT,
+                    "sandbox",
+                ])
+                .arg((writable.len() + 2).to_string())
+                .arg(dir)
+                .arg(self.scratch.path())
+                .args(writable)
+                .arg(program);
+            unshare
+        } else {
+            Command::new(program)
+        };
+
+        command.current_dir(dir).env_clear();
+        for var in PASSTHROUGH_ENV.iter() {
+            if let Some(value) = env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+        if let Some(rustup_home) = rustup_home() {
+            command.env("RUSTUP_HOME", rustup_home);
+        }
+        command
+            .env("HOME", self.scratch.path())
+            .env("TMPDIR", self.scratch.path());
+
+        let rlimits = [
+            (libc::RLIMIT_CPU, self.config.cpu_secs),
+            (libc::RLIMIT_AS, self.config.memory_bytes),
+            (libc::RLIMIT_FSIZE, self.config.file_size_bytes),
+        ];
+        unsafe {
+            // Only async-signal-safe calls: this runs between fork and exec.
+            command.pre_exec(move || {
+                // Its own process group, so the whole tree can be killed on timeout.
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                for (resource, limit) in rlimits.iter() {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: *limit as libc::rlim_t,
+                        rlim_max: *limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(*resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command
+    }
+
+    /// Runs a command prepared by `command`, killing its process tree if it outlives the
+    /// timeout or gets cancelled.
+    pub fn run(&self, mut command: Command) -> io::Result<Outcome> {
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Both pipes are drained while waiting, so a chatty build can't fill them and hang.
+        let stdout = Self::drain(child.stdout.take());
+        let stderr = Self::drain(child.stderr.take());
+
+        let deadline = Instant::now() + self.config.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                Self::kill_tree(&mut child)?;
+                break None;
+            }
+            if self
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.load(Ordering::SeqCst))
+            {
+                Self::kill_tree(&mut child)?;
+                let _ = stdout.join();
+                let _ = stderr.join();
+                return Ok(Outcome::Cancelled);
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        Ok(match status {
+            Some(status) => Outcome::Exited {
+                status,
+                stdout,
+                stderr,
+            },
+            None => Outcome::TimedOut,
+        })
+    }
+
+    fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+
+    fn kill_tree(child: &mut Child) -> io::Result<()> {
+        // The child leads its process group, see `command`.
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        child.wait().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(timeout: Duration) -> Sandbox {
+        Sandbox::new(&SandboxConfig {
+            cpu_secs: 60,
+            memory_bytes: u64::MAX,
+            file_size_bytes: 1024,
+            timeout,
+            isolate: false,
+        })
+        .unwrap()
+    }
+
+    fn sh(sandbox: &Sandbox, dir: &Path, script: &str) -> Outcome {
+        let mut command = sandbox.command("sh", dir, &[]);
+        command.arg("-c").arg(script);
+        sandbox.run(command).unwrap()
+    }
+
+    #[test]
+    fn commands_run_with_a_cleared_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = sandbox(Duration::from_secs(30));
+        env::set_var("FUNCKY_SANDBOX_TEST_SECRET", "secret");
+
+        let script = "echo \"$HOME|$TMPDIR|$FUNCKY_SANDBOX_TEST_SECRET\"; pwd";
+        match sh(&sandbox, dir.path(), script) {
+            Outcome::Exited { status, stdout, .. } => {
+                assert!(status.success());
+                let scratch = sandbox.scratch.path().display();
+                let expected = format!("{}|{}|\n{}\n", scratch, scratch, dir.path().display());
+                assert_eq!(String::from_utf8_lossy(&stdout), expected);
+            }
+            _ => panic!("expected the command to exit"),
+        }
+    }
+
+    #[test]
+    fn file_size_is_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = sandbox(Duration::from_secs(30));
+        let cases = [(512, true), (4096, false)];
+        for &(size, success) in cases.iter() {
+            let script = format!("head -c {} /dev/zero > out", size);
+            match sh(&sandbox, dir.path(), &script) {
+                Outcome::Exited { status, .. } => assert_eq!(status.success(), success, "{}", size),
+                _ => panic!("expected the command to exit"),
+            }
+        }
+    }
+
+    #[test]
+    fn timeouts_kill_the_process_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = sandbox(Duration::from_millis(300));
+        let marker = dir.path().join("survived");
+
+        // The background child outlives its parent, unless its whole group is killed.
+        let script = format!("(sleep 2; touch {}) & sleep 30", marker.display());
+        let started = Instant::now();
+        assert!(matches!(
+            sh(&sandbox, dir.path(), &script),
+            Outcome::TimedOut
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        thread::sleep(Duration::from_secs(3));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn cancelling_kills_the_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let sandbox = sandbox(Duration::from_secs(30)).cancel_on(cancel.clone());
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            cancel.store(true, Ordering::SeqCst);
+        });
+        let started = Instant::now();
+        assert!(matches!(
+            sh(&sandbox, dir.path(), "sleep 30"),
+            Outcome::Cancelled
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+}
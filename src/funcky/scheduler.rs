@@ -0,0 +1,611 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::{TimeZone, Utc};
+
+use cron::Schedule;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use super::clock::unix_now;
+use super::invocations::StoredResponse;
+use super::state;
+
+const MAX_HISTORY: usize = 20;
+
+/// Fire times older than this when they come due are considered missed, e.g. across a restart.
+const MISSED_GRACE_SECS: u64 = 60;
+
+/// Fire times and run history are saved at most this often. Changes to schedules are saved
+/// right away.
+const SAVE_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid cron expression [{}]: {}", expression, source))]
+    InvalidCronExpression {
+        source: cron::error::Error,
+        expression: String,
+    },
+
+    #[snafu(display("Cron expression [{}] never fires", expression))]
+    CronNeverFires {
+        expression: String,
+    },
+
+    #[snafu(display("Failed to load schedules from [{}]: {}", path.display(), source))]
+    FailedToLoadSchedules {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to save schedules: {}", source))]
+    FailedToSaveSchedules {
+        source: io::Error,
+    },
+
+    #[snafu(display("No schedule for function: {}", name))]
+    UnknownSchedule {
+        name: String,
+    },
+
+    ScheduleLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What to do when fire times were missed, e.g. while the server was down.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRunPolicy {
+    /// Missed fire times are coalesced into a single catch-up run.
+    #[default]
+    RunOnce,
+    /// Missed fire times are skipped.
+    Skip,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScheduleSpec {
+    /// Cron expression, in UTC. Both 5-field and 6-field (with seconds) expressions are accepted.
+    pub cron: String,
+
+    /// Body of the request sent to the function.
+    #[serde(default)]
+    pub payload: String,
+
+    /// Metadata of the request sent to the function.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+
+    #[serde(default)]
+    pub missed: MissedRunPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Trigger {
+    Scheduled,
+    CatchUp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RunStatus {
+    Succeeded,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RunRecord {
+    pub trigger: Trigger,
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub status: RunStatus,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ScheduleEntry {
+    spec: ScheduleSpec,
+
+    /// Time from which the next fire time is computed.
+    last_fire: u64,
+
+    #[serde(default)]
+    history: VecDeque<RunRecord>,
+
+    #[serde(default)]
+    last_response: Option<StoredResponse>,
+
+    #[serde(skip)]
+    running: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScheduleStat {
+    pub spec: ScheduleSpec,
+    pub running: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_fire: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_response: Option<StoredResponse>,
+
+    pub history: VecDeque<RunRecord>,
+}
+
+/// A run that came due, to be executed by the caller and then passed to `Scheduler::record`.
+pub struct ScheduledRun {
+    pub function: String,
+    pub spec: ScheduleSpec,
+    pub trigger: Trigger,
+}
+
+fn parse_cron(expression: &str) -> Result<Schedule> {
+    // The cron crate requires a seconds field, which most people leave out.
+    let normalized = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        String::from(expression)
+    };
+    Schedule::from_str(&normalized).context(InvalidCronExpression { expression })
+}
+
+fn next_fire(schedule: &Schedule, after: u64) -> Option<u64> {
+    let after = Utc.timestamp_opt(after as i64, 0).single()?;
+    schedule.after(&after).next().map(|t| t.timestamp() as u64)
+}
+
+/// Checks that a cron expression is valid and fires at least once in the future.
+pub fn validate(expression: &str) -> Result<()> {
+    let schedule = parse_cron(expression)?;
+    ensure!(
+        next_fire(&schedule, unix_now()).is_some(),
+        CronNeverFires { expression }
+    );
+    Ok(())
+}
+
+/// The Scheduler keeps track of cron schedules, their run history and when they are due.
+///
+/// Schedules are persisted so that missed runs can be detected across restarts.
+pub struct Scheduler {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, ScheduleEntry>>,
+
+    /// Whether fire times or run history changed since the last save.
+    dirty: AtomicBool,
+    saved_at: AtomicU64,
+}
+
+impl Scheduler {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scheduler> {
+        let entries = state::load_json(path.as_ref()).context(FailedToLoadSchedules {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+        Ok(Scheduler {
+            path: PathBuf::from(path.as_ref()),
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(false),
+            saved_at: AtomicU64::new(unix_now()),
+        })
+    }
+
+    /// Creates or replaces the schedule of a function.
+    ///
+    /// Run history is preserved, and so is the last fire time if the cron expression is unchanged.
+    pub fn set(&self, fn_name: &str, spec: ScheduleSpec) -> Result<()> {
+        validate(&spec.cron)?;
+        let now = unix_now();
+
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        match entries.get_mut(fn_name) {
+            Some(entry) => {
+                if entry.spec.cron != spec.cron {
+                    entry.last_fire = now;
+                }
+                entry.spec = spec;
+            }
+            None => {
+                entries.insert(
+                    String::from(fn_name),
+                    ScheduleEntry {
+                        spec,
+                        last_fire: now,
+                        history: VecDeque::new(),
+                        last_response: None,
+                        running: false,
+                    },
+                );
+            }
+        }
+        self.save(&entries)
+    }
+
+    pub fn remove(&self, fn_name: &str) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        entries
+            .remove(fn_name)
+            .context(UnknownSchedule { name: fn_name })?;
+        self.save(&entries)
+    }
+
+    pub fn get(&self, fn_name: &str) -> Result<Option<ScheduleStat>> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        Ok(entries.get(fn_name).map(Scheduler::stat))
+    }
+
+    pub fn all(&self) -> Result<HashMap<String, ScheduleStat>> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        Ok(entries
+            .iter()
+            .map(|(k, v)| (k.clone(), Scheduler::stat(v)))
+            .collect())
+    }
+
+    /// Returns the runs that are due, marking them as running.
+    pub fn due(&self) -> Result<Vec<ScheduledRun>> {
+        let now = unix_now();
+        let mut runs = Vec::new();
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        let mut changed = false;
+
+        for (fn_name, entry) in entries.iter_mut() {
+            let due_at = match parse_cron(&entry.spec.cron)
+                .ok()
+                .and_then(|s| next_fire(&s, entry.last_fire))
+            {
+                Some(t) if t <= now => t,
+                _ => continue,
+            };
+
+            // Advancing past `now` coalesces every missed fire time into this one.
+            entry.last_fire = now;
+            changed = true;
+
+            if entry.running {
+                log::warn!("skipped run of {}: previous run still in progress", fn_name);
+                continue;
+            }
+
+            let trigger = if now - due_at > MISSED_GRACE_SECS {
+                if entry.spec.missed == MissedRunPolicy::Skip {
+                    log::info!("skipped missed run of {}", fn_name);
+                    continue;
+                }
+                Trigger::CatchUp
+            } else {
+                Trigger::Scheduled
+            };
+
+            entry.running = true;
+            runs.push(ScheduledRun {
+                function: fn_name.clone(),
+                spec: entry.spec.clone(),
+                trigger,
+            });
+        }
+
+        if changed {
+            self.save_progress(&entries);
+        }
+
+        Ok(runs)
+    }
+
+    /// Records the outcome of a run returned by `due`.
+    pub fn record(
+        &self,
+        fn_name: &str,
+        record: RunRecord,
+        response: Option<StoredResponse>,
+    ) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        if let Some(entry) = entries.get_mut(fn_name) {
+            entry.running = false;
+            entry.history.push_front(record);
+            entry.history.truncate(MAX_HISTORY);
+            if response.is_some() {
+                entry.last_response = response;
+            }
+        }
+
+        self.save_progress(&entries);
+        Ok(())
+    }
+
+    /// Saves fire times and run history not saved yet, e.g. on shutdown.
+    pub fn flush(&self) -> Result<()> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_e| Error::ScheduleLockFailure)?;
+        if self.dirty.load(Ordering::SeqCst) {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+
+    fn stat(entry: &ScheduleEntry) -> ScheduleStat {
+        ScheduleStat {
+            spec: entry.spec.clone(),
+            running: entry.running,
+            next_fire: parse_cron(&entry.spec.cron)
+                .ok()
+                .and_then(|s| next_fire(&s, entry.last_fire)),
+            last_response: entry.last_response.clone(),
+            history: entry.history.clone(),
+        }
+    }
+
+    /// Saves a change to fire times or run history, unless the last save was too recent.
+    fn save_progress(&self, entries: &HashMap<String, ScheduleEntry>) {
+        self.dirty.store(true, Ordering::SeqCst);
+        if unix_now().saturating_sub(self.saved_at.load(Ordering::SeqCst)) < SAVE_INTERVAL_SECS {
+            return;
+        }
+        if let Err(e) = self.save(entries) {
+            log::error!("{}", e);
+        }
+    }
+
+    fn save(&self, entries: &HashMap<String, ScheduleEntry>) -> Result<()> {
+        state::save_json(&self.path, entries).context(FailedToSaveSchedules)?;
+        self.dirty.store(false, Ordering::SeqCst);
+        self.saved_at.store(unix_now(), Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14 22:13:20 UTC.
+    const T: u64 = 1_700_000_000;
+
+    fn spec(cron: &str, missed: MissedRunPolicy) -> ScheduleSpec {
+        ScheduleSpec {
+            cron: String::from(cron),
+            payload: String::new(),
+            metadata: HashMap::new(),
+            missed,
+        }
+    }
+
+    fn scheduler(dir: &Path, entries: Vec<(&str, ScheduleSpec, u64, bool)>) -> Scheduler {
+        let entries = entries
+            .into_iter()
+            .map(|(name, spec, last_fire, running)| {
+                (
+                    String::from(name),
+                    ScheduleEntry {
+                        spec,
+                        last_fire,
+                        history: VecDeque::new(),
+                        last_response: None,
+                        running,
+                    },
+                )
+            })
+            .collect();
+        Scheduler {
+            path: dir.join("schedules.json"),
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(false),
+            saved_at: AtomicU64::new(unix_now()),
+        }
+    }
+
+    #[test]
+    fn parse_cron_accepts_5_and_6_fields() {
+        let cases = [
+            ("*/5 * * * *", true),
+            ("0 9 * * MON-FRI", true),
+            ("0 */5 * * * *", true),
+            ("30 0 9 * * *", true),
+            ("* * * *", false),
+            ("61 * * * *", false),
+            ("every minute", false),
+            ("", false),
+        ];
+        for (expression, valid) in cases.iter() {
+            assert_eq!(parse_cron(expression).is_ok(), *valid, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn next_fire_times() {
+        let cases = [
+            // 5-field expressions fire on the minute.
+            ("0 * * * *", T, T + 2800),
+            ("*/15 * * * *", T, T + 100),
+            ("* * * * *", T, T + 40),
+            // 6-field expressions start with seconds.
+            ("30 * * * * *", T, T + 10),
+            ("*/5 * * * * *", T, T + 5),
+            // Fire times are strictly after the given time.
+            ("0 * * * *", T + 2800, T + 2800 + 3600),
+        ];
+        for &(expression, after, expected) in cases.iter() {
+            let schedule = parse_cron(expression).unwrap();
+            assert_eq!(
+                next_fire(&schedule, after),
+                Some(expected),
+                "{} after {}",
+                expression,
+                after
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_schedules_in_the_past() {
+        assert!(validate("0 0 0 1 1 * 2000").is_err());
+        assert!(validate("0 0 0 1 1 *").is_ok());
+    }
+
+    #[test]
+    fn due_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = unix_now();
+        let every_second = "* * * * * *";
+        let cases = [
+            // (spec, seconds since the last fire, running, expected trigger)
+            (
+                spec(every_second, MissedRunPolicy::RunOnce),
+                2,
+                false,
+                Some(Trigger::Scheduled),
+            ),
+            (
+                spec(every_second, MissedRunPolicy::Skip),
+                2,
+                false,
+                Some(Trigger::Scheduled),
+            ),
+            // Hours of missed fire times are coalesced into a single run.
+            (
+                spec(every_second, MissedRunPolicy::RunOnce),
+                3 * 3600,
+                false,
+                Some(Trigger::CatchUp),
+            ),
+            (
+                spec(every_second, MissedRunPolicy::Skip),
+                3 * 3600,
+                false,
+                None,
+            ),
+            // A run doesn't overlap the previous one.
+            (spec(every_second, MissedRunPolicy::RunOnce), 2, true, None),
+            // Not due yet.
+            (
+                spec("0 0 0 1 1 *", MissedRunPolicy::RunOnce),
+                0,
+                false,
+                None,
+            ),
+        ];
+        for (spec, elapsed, running, expected) in cases.iter() {
+            let scheduler = scheduler(
+                dir.path(),
+                vec![("f", spec.clone(), now - elapsed, *running)],
+            );
+            let runs = scheduler.due().unwrap();
+            assert_eq!(
+                runs.iter().map(|r| r.trigger).collect::<Vec<_>>(),
+                expected.iter().cloned().collect::<Vec<_>>(),
+                "{} {:?} {}s ago, running: {}",
+                spec.cron,
+                spec.missed,
+                elapsed,
+                running
+            );
+
+            // The run is marked as running, and missed fire times aren't run again.
+            assert!(scheduler.due().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn record_clears_running_and_caps_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = scheduler(
+            dir.path(),
+            vec![("f", spec("* * * * *", MissedRunPolicy::Skip), 0, true)],
+        );
+        for i in 0..MAX_HISTORY + 5 {
+            scheduler
+                .record(
+                    "f",
+                    RunRecord {
+                        trigger: Trigger::Scheduled,
+                        started_at: i as u64,
+                        duration_ms: 1,
+                        status: RunStatus::Succeeded,
+                    },
+                    None,
+                )
+                .unwrap();
+        }
+        let stat = scheduler.get("f").unwrap().unwrap();
+        assert!(!stat.running);
+        assert_eq!(stat.history.len(), MAX_HISTORY);
+        assert_eq!(stat.history[0].started_at, (MAX_HISTORY + 4) as u64);
+    }
+
+    #[test]
+    fn progress_is_saved_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedules.json");
+        let scheduler = scheduler(
+            dir.path(),
+            vec![("f", spec("* * * * * *", MissedRunPolicy::RunOnce), 0, false)],
+        );
+
+        // Due runs were saved moments ago, so they wait for the next batch.
+        assert_eq!(scheduler.due().unwrap().len(), 1);
+        assert!(!path.exists());
+
+        scheduler.saved_at.store(0, Ordering::SeqCst);
+        let record = RunRecord {
+            trigger: Trigger::CatchUp,
+            started_at: 0,
+            duration_ms: 1,
+            status: RunStatus::Succeeded,
+        };
+        scheduler.record("f", record, None).unwrap();
+        assert!(path.exists());
+        assert!(!scheduler.dirty.load(Ordering::SeqCst));
+
+        // Changes to the schedules themselves are saved right away.
+        std::fs::remove_file(&path).unwrap();
+        scheduler
+            .set("g", spec("0 * * * *", MissedRunPolicy::Skip))
+            .unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn flush_saves_pending_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedules.json");
+        let scheduler = scheduler(
+            dir.path(),
+            vec![("f", spec("* * * * * *", MissedRunPolicy::RunOnce), 0, false)],
+        );
+
+        scheduler.flush().unwrap();
+        assert!(!path.exists());
+
+        scheduler.due().unwrap();
+        scheduler.flush().unwrap();
+        let saved = Scheduler::load(&path).unwrap();
+        assert!(saved.get("f").unwrap().unwrap().next_fire.unwrap() > 0);
+        assert!(!scheduler.dirty.load(Ordering::SeqCst));
+    }
+}
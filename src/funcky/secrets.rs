@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use funck::Request;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use super::clock::unix_now;
+use super::state;
+
+/// Prefix of the request metadata keys secrets are passed under.
+pub const SECRET_METADATA_PREFIX: &str = "funcky-secret-";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid master key: {}", reason))]
+    InvalidMasterKey {
+        reason: String,
+    },
+
+    #[snafu(display("No master key configured, secrets are unavailable"))]
+    MissingMasterKey,
+
+    #[snafu(display("Invalid secret: {}", reason))]
+    InvalidSecret {
+        reason: String,
+    },
+
+    #[snafu(display("Failed to encrypt secret: {}", name))]
+    FailedToEncrypt {
+        name: String,
+    },
+
+    #[snafu(display("Failed to decrypt secret {}, is the master key correct?", name))]
+    FailedToDecrypt {
+        name: String,
+    },
+
+    #[snafu(display("Failed to load secrets from [{}]: {}", path.display(), source))]
+    FailedToLoadSecrets {
+        source: io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to save secrets: {}", source))]
+    FailedToSaveSecrets {
+        source: io::Error,
+    },
+
+    #[snafu(display("Unknown secret: {}", name))]
+    UnknownSecret {
+        name: String,
+    },
+
+    SecretLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A secret as submitted by an administrator.
+///
+/// Deliberately not `Debug`, so the value can't end up in logs.
+#[derive(Deserialize)]
+pub struct SecretSpec {
+    pub value: String,
+
+    /// Functions the secret is passed to.
+    #[serde(default)]
+    pub functions: Vec<String>,
+}
+
+/// Everything about a secret except its value.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SecretInfo {
+    pub functions: Vec<String>,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct EncryptedSecret {
+    #[serde(flatten)]
+    info: SecretInfo,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && name.len() <= MAX_NAME_LENGTH,
+        InvalidSecret {
+            reason: format!("names must be 1 to {} characters long", MAX_NAME_LENGTH)
+        }
+    );
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        InvalidSecret {
+            reason: "names may only contain ASCII letters, digits, '-' and '_'"
+        }
+    );
+    Ok(())
+}
+
+/// Replaces any secret metadata of a request with `bound`, so callers can't spoof secrets.
+pub fn replace_secrets(request: Request, bound: HashMap<String, String>) -> Request {
+    let spoofed = request
+        .metadata()
+        .keys()
+        .any(|k| k.starts_with(SECRET_METADATA_PREFIX));
+    if bound.is_empty() && !spoofed {
+        return request;
+    }
+
+    let mut metadata: HashMap<String, String> = request
+        .metadata()
+        .iter()
+        .filter(|(k, _)| !k.starts_with(SECRET_METADATA_PREFIX))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    for (name, value) in bound {
+        metadata.insert(format!("{}{}", SECRET_METADATA_PREFIX, name), value);
+    }
+    Request::new(Vec::from(request.body()), metadata)
+}
+
+/// The SecretStore keeps secrets encrypted at rest, and decrypts them for the functions they are
+/// bound to.
+///
+/// Secrets are encrypted with ChaCha20-Poly1305 under a master key that is never persisted.
+/// The secret name is authenticated along with the value, so ciphertexts can't be swapped around.
+pub struct SecretStore {
+    path: PathBuf,
+    cipher: Option<ChaCha20Poly1305>,
+    secrets: RwLock<HashMap<String, EncryptedSecret>>,
+}
+
+impl SecretStore {
+    /// Loads the secret store. `master_key` is the base64 encoding of a 32-byte key.
+    pub fn load<P: AsRef<Path>>(path: P, master_key: Option<&str>) -> Result<SecretStore> {
+        let cipher = match master_key {
+            Some(encoded) => {
+                let key = base64::decode(encoded.trim()).map_err(|e| Error::InvalidMasterKey {
+                    reason: e.to_string(),
+                })?;
+                let key = <[u8; KEY_LENGTH]>::try_from(key.as_slice()).map_err(|_e| {
+                    Error::InvalidMasterKey {
+                        reason: format!("expected {} bytes, got {}", KEY_LENGTH, key.len()),
+                    }
+                })?;
+                Some(ChaCha20Poly1305::new(&Key::from(key)))
+            }
+            None => None,
+        };
+
+        let secrets: HashMap<String, EncryptedSecret> =
+            state::load_json(path.as_ref()).context(FailedToLoadSecrets {
+                path: PathBuf::from(path.as_ref()),
+            })?;
+        if cipher.is_none() && !secrets.is_empty() {
+            log::warn!("secrets are stored but no master key is configured");
+        }
+
+        Ok(SecretStore {
+            path: PathBuf::from(path.as_ref()),
+            cipher,
+            secrets: RwLock::new(secrets),
+        })
+    }
+
+    /// Creates or replaces a secret.
+    pub fn set(&self, name: &str, spec: SecretSpec) -> Result<()> {
+        validate_name(name)?;
+        let cipher = self.cipher.as_ref().context(MissingMasterKey)?;
+
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: spec.value.as_bytes(),
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_e| Error::FailedToEncrypt {
+                name: String::from(name),
+            })?;
+
+        let mut secrets = self
+            .secrets
+            .write()
+            .map_err(|_e| Error::SecretLockFailure)?;
+        secrets.insert(
+            String::from(name),
+            EncryptedSecret {
+                info: SecretInfo {
+                    functions: spec.functions,
+                    updated_at: unix_now(),
+                },
+                nonce: base64::encode(nonce),
+                ciphertext: base64::encode(&ciphertext),
+            },
+        );
+        self.save(&secrets)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut secrets = self
+            .secrets
+            .write()
+            .map_err(|_e| Error::SecretLockFailure)?;
+        secrets.remove(name).context(UnknownSecret { name })?;
+        self.save(&secrets)
+    }
+
+    pub fn all(&self) -> Result<HashMap<String, SecretInfo>> {
+        let secrets = self.secrets.read().map_err(|_e| Error::SecretLockFailure)?;
+        Ok(secrets
+            .iter()
+            .map(|(k, v)| (k.clone(), v.info.clone()))
+            .collect())
+    }
+
+    /// Decrypts the secrets bound to a function, keyed by secret name.
+    pub fn resolve(&self, fn_name: &str) -> Result<HashMap<String, String>> {
+        let secrets = self.secrets.read().map_err(|_e| Error::SecretLockFailure)?;
+        let mut resolved = HashMap::new();
+
+        for (name, secret) in secrets
+            .iter()
+            .filter(|(_, s)| s.info.functions.iter().any(|f| f == fn_name))
+        {
+            let cipher = self.cipher.as_ref().context(MissingMasterKey)?;
+            let decrypt_err = || Error::FailedToDecrypt { name: name.clone() };
+
+            let nonce = base64::decode(&secret.nonce).map_err(|_e| decrypt_err())?;
+            let nonce =
+                <[u8; NONCE_LENGTH]>::try_from(nonce.as_slice()).map_err(|_e| decrypt_err())?;
+            let ciphertext = base64::decode(&secret.ciphertext).map_err(|_e| decrypt_err())?;
+
+            let plaintext = cipher
+                .decrypt(
+                    &Nonce::from(nonce),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: name.as_bytes(),
+                    },
+                )
+                .map_err(|_e| decrypt_err())?;
+            let value = String::from_utf8(plaintext).map_err(|_e| decrypt_err())?;
+            resolved.insert(name.clone(), value);
+        }
+
+        Ok(resolved)
+    }
+
+    fn save(&self, secrets: &HashMap<String, EncryptedSecret>) -> Result<()> {
+        state::save_json(&self.path, secrets).context(FailedToSaveSecrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn spec(value: &str, functions: &[&str]) -> SecretSpec {
+        SecretSpec {
+            value: String::from(value),
+            functions: functions.iter().map(|f| String::from(*f)).collect(),
+        }
+    }
+
+    fn store(dir: &Path) -> SecretStore {
+        SecretStore::load(dir.join("secrets.json"), Some(MASTER_KEY)).unwrap()
+    }
+
+    #[test]
+    fn master_keys() {
+        let cases = [
+            (MASTER_KEY, true),
+            ("not base64!", false),
+            ("c2hvcnQ=", false),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        for (key, valid) in cases.iter() {
+            let path = dir.path().join("secrets.json");
+            assert_eq!(
+                SecretStore::load(path, Some(key)).is_ok(),
+                *valid,
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn secrets_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path())
+            .set("db-password", spec("hunter2", &["f"]))
+            .unwrap();
+
+        let stored = std::fs::read_to_string(dir.path().join("secrets.json")).unwrap();
+        assert!(!stored.contains("hunter2"));
+
+        // Reloading from disk decrypts with the same master key.
+        let reloaded = store(dir.path());
+        let resolved = reloaded.resolve("f").unwrap();
+        assert_eq!(resolved["db-password"], "hunter2");
+        assert!(reloaded.resolve("g").unwrap().is_empty());
+    }
+
+    #[test]
+    fn swapped_ciphertexts_fail_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = store(dir.path());
+        secrets.set("a", spec("value of a", &["f"])).unwrap();
+        secrets.set("b", spec("value of b", &[])).unwrap();
+
+        {
+            let mut guard = secrets.secrets.write().unwrap();
+            let b = guard["b"].clone();
+            let a = guard.get_mut("a").unwrap();
+            a.nonce = b.nonce;
+            a.ciphertext = b.ciphertext;
+        }
+        assert!(matches!(
+            secrets.resolve("f"),
+            Err(Error::FailedToDecrypt { name }) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn wrong_master_key_fails_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path()).set("a", spec("value", &["f"])).unwrap();
+
+        let other_key = base64::encode([7u8; KEY_LENGTH]);
+        let secrets = SecretStore::load(dir.path().join("secrets.json"), Some(&other_key)).unwrap();
+        assert!(matches!(
+            secrets.resolve("f"),
+            Err(Error::FailedToDecrypt { .. })
+        ));
+    }
+
+    #[test]
+    fn spoofed_secrets_are_replaced() {
+        type Pairs = &'static [(&'static str, &'static str)];
+        let cases: [(Pairs, Pairs, Pairs); 3] = [
+            (&[("x-user", "1")], &[], &[("x-user", "1")]),
+            (
+                &[("x-user", "1"), ("funcky-secret-token", "forged")],
+                &[],
+                &[("x-user", "1")],
+            ),
+            (
+                &[("funcky-secret-token", "forged")],
+                &[("token", "real")],
+                &[("funcky-secret-token", "real")],
+            ),
+        ];
+        for (metadata, bound, expected) in cases.iter() {
+            let to_map = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+                pairs
+                    .iter()
+                    .map(|(k, v)| (String::from(*k), String::from(*v)))
+                    .collect()
+            };
+            let request = Request::new(b"body".to_vec(), to_map(metadata));
+            let request = replace_secrets(request, to_map(bound));
+            assert_eq!(request.metadata(), &to_map(expected), "{:?}", metadata);
+            assert_eq!(request.body(), b"body");
+        }
+    }
+
+    #[test]
+    fn names_are_validated() {
+        let cases = [
+            ("api-key_2", true),
+            ("", false),
+            ("with space", false),
+            ("a/b", false),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = store(dir.path());
+        for (name, valid) in cases.iter() {
+            assert_eq!(
+                secrets.set(name, spec("v", &[])).is_ok(),
+                *valid,
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use snafu::{ResultExt, Snafu};
+
+use super::digest::sha256_hex;
+
+const BUNDLE_EXTENSION: &str = "zip";
+
+/// Unreferenced bundles are kept this long, so deploys still being prepared keep theirs.
+const GRACE_PERIOD: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to prepare source store [{}]: {}", path.display(), source))]
+    FailedToPrepareSourceStore { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Failed to store source bundle: {}", source))]
+    FailedToStoreSource { source: io::Error },
+
+    #[snafu(display("Failed to read source bundle {}: {}", hash, source))]
+    FailedToReadSource { source: io::Error, hash: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The SourceStore keeps uploaded source bundles by the SHA-256 of their contents, so that
+/// what a deployment was built from can be downloaded or rebuilt.
+pub struct SourceStore {
+    root: PathBuf,
+}
+
+impl SourceStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<SourceStore> {
+        let root = PathBuf::from(root.as_ref());
+        fs::create_dir_all(&root).context(FailedToPrepareSourceStore { path: &root })?;
+        Ok(SourceStore { root })
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(format!("{}.{}", hash, BUNDLE_EXTENSION))
+    }
+
+    /// Stores a bundle, returning its hash and where it is stored. Storing a bundle again
+    /// restarts its grace period.
+    pub fn store(&self, data: &[u8]) -> Result<(String, PathBuf)> {
+        let hash = sha256_hex(data);
+        let path = self.path(&hash);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .context(FailedToStoreSource)?;
+        Ok((hash, path))
+    }
+
+    /// Returns where a bundle is stored, if it is.
+    pub fn get(&self, hash: &str) -> Option<PathBuf> {
+        // Hashes come from the registry, but are checked before becoming a path all the same.
+        if !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(self.path(hash)).filter(|p| p.is_file())
+    }
+
+    /// Reads a stored bundle.
+    pub fn read(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.get(hash) {
+            Some(path) => fs::read(path)
+                .map(Some)
+                .context(FailedToReadSource { hash }),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the bundles not in `keep` and past their grace period, returning how many were
+    /// removed.
+    pub fn collect(&self, keep: &HashSet<String>) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.root).context(FailedToStoreSource)? {
+            let path = entry.context(FailedToStoreSource)?.path();
+            let hash = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if keep.contains(&hash) {
+                continue;
+            }
+            let age = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if age < GRACE_PERIOD {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => log::error!("failed to remove [{}]: {}", path.display(), e),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_are_stored_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = SourceStore::new(dir.path().join("sources")).unwrap();
+
+        let (hash, path) = sources.store(b"bundle").unwrap();
+        assert_eq!(hash, sha256_hex(b"bundle"));
+        assert_eq!(
+            sources.store(b"bundle").unwrap(),
+            (hash.clone(), path.clone())
+        );
+        assert_eq!(sources.get(&hash), Some(path));
+        assert_eq!(sources.read(&hash).unwrap(), Some(b"bundle".to_vec()));
+
+        let missing = sha256_hex(b"other");
+        for hash in [missing.as_str(), "../sources/x", ""].iter() {
+            assert_eq!(sources.get(hash), None, "{}", hash);
+            assert_eq!(sources.read(hash).unwrap(), None, "{}", hash);
+        }
+    }
+
+    #[test]
+    fn unreferenced_bundles_are_collected_after_their_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = SourceStore::new(dir.path()).unwrap();
+        let old = SystemTime::now() - GRACE_PERIOD - Duration::from_secs(60);
+
+        let cases = [
+            // (bundle, referenced, old, kept)
+            ("a", true, true, true),
+            ("b", false, true, false),
+            ("c", false, false, true),
+            ("d", true, false, true),
+        ];
+        let mut keep = HashSet::new();
+        for &(bundle, referenced, is_old, _) in cases.iter() {
+            let (hash, path) = sources.store(bundle.as_bytes()).unwrap();
+            if referenced {
+                keep.insert(hash);
+            }
+            if is_old {
+                fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(old))
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(sources.collect(&keep).unwrap(), 1);
+        for &(bundle, _, _, kept) in cases.iter() {
+            let hash = sha256_hex(bundle.as_bytes());
+            assert_eq!(sources.get(&hash).is_some(), kept, "{}", bundle);
+        }
+    }
+}
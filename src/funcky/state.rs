@@ -0,0 +1,22 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Loads a JSON state file, returning the default value if it doesn't exist yet.
+pub fn load_json<T: DeserializeOwned + Default, P: AsRef<Path>>(path: P) -> io::Result<T> {
+    match fs::read(path.as_ref()) {
+        Ok(data) => serde_json::from_slice(&data).map_err(io::Error::from),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Atomically replaces a JSON state file.
+pub fn save_json<T: Serialize, P: AsRef<Path>>(path: P, value: &T) -> io::Result<()> {
+    let tmp_path = path.as_ref().with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&tmp_path, path.as_ref())
+}
@@ -1,68 +1,150 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+use snafu::Snafu;
+
+use super::{DeploymentStore, GitSource, LimiterStat};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    StatusLockFailure,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Status {
     Accepted,
     Compiling,
     Failed(String),
+
+    /// The build was killed after running for this many seconds.
+    TimedOut(u64),
     Ready,
+
+    /// The library failed to load on startup, and was set aside.
+    Quarantined(String),
+
+    /// The build was cancelled through the build queue.
+    Cancelled,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct FuncktionEntry {
     pub status: Status,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limiter: Option<LimiterStat>,
+
+    /// Repository and commit of functions deployed from git.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<GitSource>,
 }
 
 pub struct StatusTracker {
     registrations: RwLock<HashMap<String, FuncktionEntry>>,
+
+    /// Where the statuses of builds are persisted.
+    deployments: Arc<DeploymentStore>,
 }
 
 impl StatusTracker {
-    pub fn new() -> StatusTracker {
+    pub fn new(deployments: Arc<DeploymentStore>) -> StatusTracker {
         StatusTracker {
             registrations: RwLock::new(HashMap::new()),
+            deployments,
+        }
+    }
+
+    fn persist(&self, fn_name: &str, status: &Status) {
+        if let Err(e) = self.deployments.set_status(fn_name, status) {
+            log::error!("failed to record status of {}: {}", fn_name, e);
         }
     }
 
     pub fn add(&self, fn_name: &str) {
-        let mut stat_guard = self.registrations.write().unwrap(); // TODO: Handle.
+        self.persist(fn_name, &Status::Accepted);
+        let mut stat_guard = match self.registrations.write() {
+            Ok(guard) => guard,
+            Err(_) => return log::error!("status lock poisoned, not tracking {}", fn_name),
+        };
         stat_guard.insert(
             String::from(fn_name),
             FuncktionEntry {
                 status: Status::Accepted,
+                limiter: None,
+                source: None,
             },
         );
     }
 
     pub fn update_status(&self, fn_name: &str, new_status: Status) {
-        let mut stat_guard = self.registrations.write().unwrap(); // TODO: Handle.
+        let mut stat_guard = match self.registrations.write() {
+            Ok(guard) => guard,
+            Err(_) => return log::error!("status lock poisoned, not updating {}", fn_name),
+        };
         let stat = stat_guard.get_mut(fn_name).unwrap(); // TODO: Handle.
         match &new_status {
-            Status::Ready => assert_eq!(stat.status, Status::Compiling),
-            Status::Failed(_) => assert_eq!(stat.status, Status::Compiling),
-            Status::Compiling => assert_eq!(stat.status, Status::Accepted),
+            Status::Compiling => {}
+            Status::Ready | Status::Failed(_) | Status::TimedOut(_) | Status::Cancelled => {
+                // The outcome of a build doesn't replace the status of a newer deploy queued
+                // behind it.
+                if stat.status == Status::Accepted {
+                    log::info!(
+                        "{} has a newer deploy queued, not reporting {:?}",
+                        fn_name,
+                        new_status
+                    );
+                    return;
+                }
+            }
             _ => panic!(), // TODO: Handle.
         }
+        self.persist(fn_name, &new_status);
         stat.status = new_status;
     }
 
+    /// Records a build cancelled before it started.
+    pub fn cancel(&self, fn_name: &str) {
+        let mut stat_guard = match self.registrations.write() {
+            Ok(guard) => guard,
+            Err(_) => return log::error!("status lock poisoned, not cancelling {}", fn_name),
+        };
+        if let Some(stat) = stat_guard.get_mut(fn_name) {
+            self.persist(fn_name, &Status::Cancelled);
+            stat.status = Status::Cancelled;
+        }
+    }
+
     /// Used for reloading funcktions at server startup.
     pub fn new_with_status(&self, fn_name: &str, new_status: Status) {
-        let mut stat_guard = self.registrations.write().unwrap(); // TODO: Handle.
-        stat_guard.insert(String::from(fn_name), FuncktionEntry { status: new_status });
+        let mut stat_guard = match self.registrations.write() {
+            Ok(guard) => guard,
+            Err(_) => return log::error!("status lock poisoned, not tracking {}", fn_name),
+        };
+        stat_guard.insert(
+            String::from(fn_name),
+            FuncktionEntry {
+                status: new_status,
+                limiter: None,
+                source: None,
+            },
+        );
     }
 
-    pub fn all(&self) -> HashMap<String, FuncktionEntry> {
+    pub fn all(&self) -> Result<HashMap<String, FuncktionEntry>> {
         let mut hsh = HashMap::new();
-        let stat_guard = self.registrations.read().unwrap(); // TODO: Handle.
+        let stat_guard = self
+            .registrations
+            .read()
+            .map_err(|_e| Error::StatusLockFailure)?;
 
         for (k, v) in stat_guard.iter() {
             hsh.insert(k.clone(), v.clone());
         }
 
-        hsh
+        Ok(hsh)
     }
 }
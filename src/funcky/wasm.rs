@@ -0,0 +1,652 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use funck::{CallError, Request, Response};
+
+use rand::RngCore;
+
+use serde::{Deserialize, Serialize};
+
+use snafu::{ResultExt, Snafu};
+
+use wasmi::core::{Trap, TrapCode, ValueType};
+use wasmi::{
+    Caller, Config, Engine, Extern, ExternType, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, TypedFunc, Value,
+};
+
+/// Extension of WebAssembly modules in the shared object directory.
+pub const WASM_EXTENSION: &str = "wasm";
+
+/// Fuel given to each call by default, roughly one unit per instruction executed.
+const DEFAULT_FUEL: u64 = 1_000_000_000;
+
+/// Linear memory allowed by default, in bytes.
+const DEFAULT_MAX_MEMORY_BYTES: u64 = 64 * 1024 * 1024;
+
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// WASI functions provided to modules. Other WASI imports are stubbed, and fail with `ENOSYS`.
+const WASI_FUNCTIONS: [&str; 9] = [
+    "fd_write",
+    "environ_sizes_get",
+    "environ_get",
+    "args_sizes_get",
+    "args_get",
+    "clock_time_get",
+    "random_get",
+    "proc_exit",
+    "sched_yield",
+];
+
+const WASI_ESUCCESS: i32 = 0;
+const WASI_EBADF: i32 = 8;
+const WASI_ENOSYS: i32 = 52;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read WebAssembly module [{}]: {}", path.display(), source))]
+    FailedToReadModule { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Invalid WebAssembly module [{}]: {}", path.display(), source))]
+    InvalidModule { source: wasmi::Error, path: PathBuf },
+
+    #[snafu(display("Failed to instantiate function [{}]: {}", name, source))]
+    InstantiationFailed { source: wasmi::Error, name: String },
+
+    #[snafu(display("Function [{}] does not export {}", name, export))]
+    MissingExport { name: String, export: String },
+
+    #[snafu(display("Initialization of function [{}] failed (code {})", name, code))]
+    InitFailed { name: String, code: i32 },
+
+    #[snafu(display("Function [{}] ran out of fuel ({} units)", name, fuel))]
+    OutOfFuel { name: String, fuel: u64 },
+
+    #[snafu(display("Function [{}] trapped: {}", name, source))]
+    Trapped { source: wasmi::Error, name: String },
+
+    #[snafu(display("Function [{}] returned an invalid response: {}", name, reason))]
+    InvalidResponse { name: String, reason: String },
+
+    #[snafu(display("Function [{}] failed: {}", name, source))]
+    CallFailed { source: CallError, name: String },
+
+    #[snafu(display("Instance of function [{}] is poisoned", name))]
+    InstanceLockFailure { name: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Resource limits of a WebAssembly function, from the `[package.metadata.funcky.wasm]` table.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WasmLimits {
+    /// Fuel given to each call. Calls that run out of fuel are aborted.
+    #[serde(default)]
+    pub fuel: Option<u64>,
+
+    /// Maximum size of the function's linear memory, in bytes.
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+}
+
+impl WasmLimits {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.fuel == Some(0) {
+            return Err(String::from("fuel must be positive"));
+        }
+        if self.max_memory_bytes == Some(0) {
+            return Err(String::from("max_memory_bytes must be positive"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct WasmRequest<'a> {
+    body: String,
+    metadata: &'a HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct WasmResponse {
+    #[serde(default)]
+    body: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+struct HostState {
+    name: String,
+    limits: StoreLimits,
+}
+
+fn trap<E: ToString>(e: E) -> Trap {
+    Trap::new(e.to_string())
+}
+
+fn caller_memory(caller: &Caller<'_, HostState>) -> Result<Memory, Trap> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("module does not export its memory"))
+}
+
+fn read_u32(caller: &Caller<'_, HostState>, memory: &Memory, ptr: i32) -> Result<u32, Trap> {
+    let mut buf = [0u8; 4];
+    memory
+        .read(caller, ptr as u32 as usize, &mut buf)
+        .map_err(trap)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, data: &[u8]) -> Result<(), Trap> {
+    let memory = caller_memory(caller)?;
+    memory
+        .write(caller, ptr as u32 as usize, data)
+        .map_err(trap)
+}
+
+/// Defines the subset of WASI available to functions: output is logged, and there is no
+/// filesystem, network or environment access.
+fn define_wasi(linker: &mut Linker<HostState>, module: &Module) -> Result<(), wasmi::Error> {
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_write",
+        |mut caller: Caller<'_, HostState>,
+         fd: i32,
+         iovs: i32,
+         iovs_len: i32,
+         nwritten: i32|
+         -> Result<i32, Trap> {
+            if fd != 1 && fd != 2 {
+                return Ok(WASI_EBADF);
+            }
+            let memory = caller_memory(&caller)?;
+            let mut output = Vec::new();
+            for i in 0..iovs_len {
+                let iov = iovs.wrapping_add(i.wrapping_mul(8));
+                let ptr = read_u32(&caller, &memory, iov)?;
+                let len = read_u32(&caller, &memory, iov.wrapping_add(4))?;
+                let data = memory
+                    .data(&caller)
+                    .get(ptr as usize..(ptr as usize).saturating_add(len as usize))
+                    .ok_or_else(|| Trap::new("iovec is out of bounds"))?;
+                output.extend_from_slice(data);
+            }
+            log::info!(
+                "<{}> {}",
+                caller.data().name,
+                String::from_utf8_lossy(&output).trim_end()
+            );
+            write_bytes(&mut caller, nwritten, &(output.len() as u32).to_le_bytes())?;
+            Ok(WASI_ESUCCESS)
+        },
+    )?;
+
+    // Functions get no environment variables nor arguments.
+    for sizes_get in &["environ_sizes_get", "args_sizes_get"] {
+        linker.func_wrap(
+            WASI_MODULE,
+            sizes_get,
+            |mut caller: Caller<'_, HostState>, count: i32, size: i32| -> Result<i32, Trap> {
+                write_bytes(&mut caller, count, &0u32.to_le_bytes())?;
+                write_bytes(&mut caller, size, &0u32.to_le_bytes())?;
+                Ok(WASI_ESUCCESS)
+            },
+        )?;
+    }
+    for get in &["environ_get", "args_get"] {
+        linker.func_wrap(WASI_MODULE, get, |_: i32, _: i32| WASI_ESUCCESS)?;
+    }
+
+    linker.func_wrap(
+        WASI_MODULE,
+        "clock_time_get",
+        |mut caller: Caller<'_, HostState>,
+         _id: i32,
+         _precision: i64,
+         out: i32|
+         -> Result<i32, Trap> {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            write_bytes(&mut caller, out, &now.to_le_bytes())?;
+            Ok(WASI_ESUCCESS)
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "random_get",
+        |mut caller: Caller<'_, HostState>, buf: i32, len: i32| -> Result<i32, Trap> {
+            let mut data = vec![0u8; len as u32 as usize];
+            rand::thread_rng().fill_bytes(&mut data);
+            write_bytes(&mut caller, buf, &data)?;
+            Ok(WASI_ESUCCESS)
+        },
+    )?;
+    linker.func_wrap(WASI_MODULE, "proc_exit", |code: i32| -> Result<(), Trap> {
+        Err(Trap::i32_exit(code))
+    })?;
+    linker.func_wrap(WASI_MODULE, "sched_yield", || WASI_ESUCCESS)?;
+
+    for import in module.imports() {
+        if import.module() != WASI_MODULE || WASI_FUNCTIONS.contains(&import.name()) {
+            continue;
+        }
+        if let ExternType::Func(ty) = import.ty() {
+            linker.func_new(
+                WASI_MODULE,
+                import.name(),
+                ty.clone(),
+                |_caller, _params, results| {
+                    for (i, result) in results.iter_mut().enumerate() {
+                        *result = match result.ty() {
+                            ValueType::I32 if i == 0 => Value::I32(WASI_ENOSYS),
+                            ty => Value::default(ty),
+                        };
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// An instantiated module, with the exports used to call it.
+struct Running {
+    store: Store<HostState>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    call: TypedFunc<(i32, i32), i64>,
+    free: Option<TypedFunc<(i32, i32), ()>>,
+    fuel_added: u64,
+}
+
+impl Running {
+    /// Refills the store's fuel up to `fuel`.
+    fn refuel(&mut self, fuel: u64) {
+        let consumed = self.store.fuel_consumed().unwrap_or_default();
+        let remaining = self.fuel_added.saturating_sub(consumed);
+        if remaining < fuel {
+            // Fuel metering is always enabled on the engine.
+            self.store.add_fuel(fuel - remaining).ok();
+            self.fuel_added += fuel - remaining;
+        }
+    }
+
+    /// Copies data to a buffer allocated by the module.
+    fn write_input(&mut self, data: &[u8]) -> Result<i32, wasmi::Error> {
+        let ptr = self.alloc.call(&mut self.store, data.len() as i32)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, data)?;
+        Ok(ptr)
+    }
+}
+
+/// A function compiled to `wasm32-wasi`, run in an embedded WebAssembly interpreter.
+///
+/// Modules export their `memory`, `funck_alloc(len) -> ptr` and `funck_call(ptr, len) -> i64`.
+/// Calls are passed a JSON request, and return the pointer and length of a JSON response packed
+/// as `ptr << 32 | len`. The response is released with `funck_free(ptr, len)` when exported.
+/// `funck_init(ptr, len) -> i32` and `funck_shutdown()` are optional lifecycle hooks.
+///
+/// Calls run one at a time, and a trap discards the instance: the next call starts from a fresh one.
+pub struct WasmFunck {
+    name: String,
+    engine: Engine,
+    module: Module,
+    config: Vec<u8>,
+    fuel: u64,
+    max_memory_bytes: u64,
+    running: Mutex<Option<Running>>,
+}
+
+impl WasmFunck {
+    pub fn load<P: AsRef<Path>>(path: P, config: &[u8], limits: &WasmLimits) -> Result<WasmFunck> {
+        let data = fs::read(path.as_ref()).context(FailedToReadModule {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, &data[..]).context(InvalidModule {
+            path: PathBuf::from(path.as_ref()),
+        })?;
+
+        let name = path
+            .as_ref()
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+
+        let mut funck = WasmFunck {
+            name,
+            engine,
+            module,
+            config: Vec::from(config),
+            fuel: limits.fuel.unwrap_or(DEFAULT_FUEL),
+            max_memory_bytes: limits.max_memory_bytes.unwrap_or(DEFAULT_MAX_MEMORY_BYTES),
+            running: Mutex::new(None),
+        };
+
+        // Instantiate right away, so a failing init fails the load.
+        let running = funck.instantiate()?;
+        match funck.running.get_mut() {
+            Ok(slot) => *slot = Some(running),
+            Err(_) => return InstanceLockFailure { name: &funck.name }.fail(),
+        }
+
+        log::debug!(
+            "loaded wasm funcktion <{}> from [{}]",
+            funck.name,
+            path.as_ref().display()
+        );
+        Ok(funck)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn trapped<E: Into<wasmi::Error>>(&self, e: E) -> Error {
+        match e.into() {
+            wasmi::Error::Trap(t) if matches!(t.trap_code(), Some(TrapCode::OutOfFuel)) => {
+                Error::OutOfFuel {
+                    name: self.name.clone(),
+                    fuel: self.fuel,
+                }
+            }
+            e => Error::Trapped {
+                source: e,
+                name: self.name.clone(),
+            },
+        }
+    }
+
+    fn missing_export(&self, export: &str) -> Error {
+        Error::MissingExport {
+            name: self.name.clone(),
+            export: String::from(export),
+        }
+    }
+
+    fn instantiate(&self) -> Result<Running> {
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                name: self.name.clone(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory_bytes as usize)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        // Fuel metering is always enabled on the engine.
+        store.add_fuel(self.fuel).ok();
+
+        let mut linker = Linker::new(&self.engine);
+        define_wasi(&mut linker, &self.module).context(InstantiationFailed {
+            name: self.name.clone(),
+        })?;
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .context(InstantiationFailed {
+                name: self.name.clone(),
+            })?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| self.missing_export("memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "funck_alloc")
+            .map_err(|_| self.missing_export("funck_alloc(i32) -> i32"))?;
+        let call = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "funck_call")
+            .map_err(|_| self.missing_export("funck_call(i32, i32) -> i64"))?;
+        let free = instance
+            .get_typed_func::<(i32, i32), ()>(&store, "funck_free")
+            .ok();
+
+        let mut running = Running {
+            store,
+            instance,
+            memory,
+            alloc,
+            call,
+            free,
+            fuel_added: self.fuel,
+        };
+
+        if let Ok(init) = instance.get_typed_func::<(i32, i32), i32>(&running.store, "funck_init") {
+            running.refuel(self.fuel);
+            let code = running
+                .write_input(&self.config)
+                .and_then(|ptr| {
+                    init.call(&mut running.store, (ptr, self.config.len() as i32))
+                        .map_err(wasmi::Error::from)
+                })
+                .map_err(|e| self.trapped(e))?;
+            if code != 0 {
+                return InitFailed {
+                    name: self.name.clone(),
+                    code,
+                }
+                .fail();
+            }
+        }
+
+        Ok(running)
+    }
+
+    fn invoke(&self, running: &mut Running, payload: &[u8]) -> Result<Vec<u8>> {
+        let ptr = running.write_input(payload).map_err(|e| self.trapped(e))?;
+        let packed = running
+            .call
+            .call(&mut running.store, (ptr, payload.len() as i32))
+            .map_err(|e| self.trapped(e))?;
+
+        let (out_ptr, out_len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        let output = running
+            .memory
+            .data(&running.store)
+            .get(out_ptr..out_ptr.saturating_add(out_len))
+            .map(Vec::from)
+            .ok_or_else(|| Error::InvalidResponse {
+                name: self.name.clone(),
+                reason: String::from("response is out of bounds"),
+            })?;
+
+        if let Some(free) = &running.free {
+            free.call(&mut running.store, (out_ptr as i32, out_len as i32))
+                .map_err(|e| self.trapped(e))?;
+        }
+        Ok(output)
+    }
+
+    pub fn call(&self, request: Request) -> Result<Response> {
+        let payload = serde_json::to_vec(&WasmRequest {
+            body: base64::encode(request.body()),
+            metadata: request.metadata(),
+        })
+        .unwrap_or_default();
+
+        let output = {
+            let mut guard = self
+                .running
+                .lock()
+                .map_err(|_e| Error::InstanceLockFailure {
+                    name: self.name.clone(),
+                })?;
+            let mut running = match guard.take() {
+                Some(running) => running,
+                None => self.instantiate()?,
+            };
+            running.refuel(self.fuel);
+
+            let output = self.invoke(&mut running, &payload);
+            // A trap can leave the instance in an inconsistent state, so only keep it on success.
+            if output.is_ok() {
+                *guard = Some(running);
+            }
+            output?
+        };
+
+        let invalid = |reason: String| Error::InvalidResponse {
+            name: self.name.clone(),
+            reason,
+        };
+        let response: WasmResponse =
+            serde_json::from_slice(&output).map_err(|e| invalid(e.to_string()))?;
+        if let Some(message) = response.error {
+            return Err(Error::CallFailed {
+                source: CallError::new(message),
+                name: self.name.clone(),
+            });
+        }
+
+        let body = base64::decode(&response.body).map_err(|e| invalid(e.to_string()))?;
+        let mut resp = Response::new().with_bytes(body);
+        for (key, value) in response.metadata.iter() {
+            resp = resp.with_meta(key, value);
+        }
+        Ok(resp)
+    }
+}
+
+impl Drop for WasmFunck {
+    fn drop(&mut self) {
+        let running = match self.running.get_mut() {
+            Ok(Some(running)) => running,
+            _ => return,
+        };
+        if let Ok(shutdown) = running
+            .instance
+            .get_typed_func::<(), ()>(&running.store, "funck_shutdown")
+        {
+            log::debug!("shutting down wasm funcktion <{}>", self.name);
+            running.refuel(self.fuel);
+            if let Err(e) = shutdown.call(&mut running.store, ()) {
+                log::error!("shutdown of function [{}] failed: {}", self.name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Responds `ok` from its data segment, after running `body`.
+    fn module(memory_pages: u32, body: &str) -> String {
+        let response = r#"{"body":"b2s="}"#;
+        format!(
+            r#"(module
+                (memory (export "memory") {pages})
+                (data (i32.const 0) "{response}")
+                (func (export "funck_alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "funck_call") (param i32 i32) (result i64)
+                    {body}
+                    (i64.const {len})))"#,
+            pages = memory_pages,
+            response = response.replace('"', "\\\""),
+            body = body,
+            len = response.len(),
+        )
+    }
+
+    fn load(dir: &Path, wat: &str, limits: WasmLimits) -> Result<WasmFunck> {
+        let path = dir.join("f.wasm");
+        fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        WasmFunck::load(&path, b"{}", &limits)
+    }
+
+    fn limits(fuel: Option<u64>, max_memory_bytes: Option<u64>) -> WasmLimits {
+        WasmLimits {
+            fuel,
+            max_memory_bytes,
+        }
+    }
+
+    fn call(funck: &WasmFunck) -> Result<Vec<u8>> {
+        funck
+            .call(Request::new(Vec::new(), HashMap::new()))
+            .map(|r| Vec::from(r.body()))
+    }
+
+    #[test]
+    fn calls_return_the_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let funck = load(dir.path(), &module(1, ""), WasmLimits::default()).unwrap();
+        assert_eq!(funck.name(), "f");
+        assert_eq!(call(&funck).unwrap(), b"ok");
+        assert_eq!(call(&funck).unwrap(), b"ok");
+    }
+
+    #[test]
+    fn calls_run_out_of_fuel() {
+        let dir = tempfile::tempdir().unwrap();
+        let endless = module(1, "(loop (br 0))");
+        let funck = load(dir.path(), &endless, limits(Some(10_000), None)).unwrap();
+        for _ in 0..2 {
+            match call(&funck) {
+                Err(Error::OutOfFuel { name, fuel }) => {
+                    assert_eq!(name, "f");
+                    assert_eq!(fuel, 10_000);
+                }
+                other => panic!("expected to run out of fuel, got {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn memory_is_capped() {
+        const PAGE: u64 = 64 * 1024;
+        let grow = "(if (i32.eq (memory.grow (i32.const 3)) (i32.const -1)) (then unreachable))";
+        let cases = [
+            // (initial pages, cap, loads, calls succeed)
+            (1, None, true, true),
+            (1, Some(4 * PAGE), true, true),
+            (1, Some(2 * PAGE), true, false),
+            (4, Some(2 * PAGE), false, false),
+        ];
+        for &(pages, cap, loads, succeeds) in cases.iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let loaded = load(dir.path(), &module(pages, grow), limits(None, cap));
+            assert_eq!(loaded.is_ok(), loads, "{} pages, cap {:?}", pages, cap);
+            if let Ok(funck) = loaded {
+                let result = call(&funck);
+                assert_eq!(result.is_ok(), succeeds, "{} pages, cap {:?}", pages, cap);
+                if !succeeds {
+                    assert!(matches!(result, Err(Error::Trapped { .. })));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn validate_limits() {
+        let cases = [
+            (limits(None, None), true),
+            (limits(Some(1), Some(1)), true),
+            (limits(Some(0), None), false),
+            (limits(None, Some(0)), false),
+        ];
+        for (limits, valid) in cases.iter() {
+            assert_eq!(limits.validate().is_ok(), *valid, "{:?}", limits);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// A WorkerPool runs jobs on a fixed set of threads, from a bounded queue.
+///
+/// The threads exit once the pool is dropped and the queue is drained.
+pub struct WorkerPool {
+    sender: mpsc::SyncSender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, capacity: usize) -> WorkerPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || WorkerPool::work(receiver));
+        }
+        WorkerPool { sender }
+    }
+
+    /// Queues a job, handing it back if the queue is full.
+    pub fn submit(&self, job: Job) -> Result<(), Job> {
+        self.sender.try_send(job).map_err(|e| match e {
+            mpsc::TrySendError::Full(job) | mpsc::TrySendError::Disconnected(job) => job,
+        })
+    }
+
+    fn work(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) {
+        loop {
+            // The lock is only held while waiting, so jobs run concurrently.
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => break,
+            };
+            match job {
+                // A panicking job must not take the worker down with it.
+                Ok(job) => {
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        log::error!("worker job panicked");
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn full_queue_rejects_jobs() {
+        let pool = WorkerPool::new(1, 1);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        // Occupies the only worker until released.
+        assert!(pool
+            .submit(Box::new(move || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().ok();
+            }))
+            .is_ok());
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(pool.submit(Box::new(|| {})).is_ok());
+        assert!(pool.submit(Box::new(|| {})).is_err());
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn jobs_run_after_a_panic() {
+        let pool = WorkerPool::new(1, 4);
+        let (tx, rx) = mpsc::channel();
+
+        assert!(pool.submit(Box::new(|| panic!("job failed"))).is_ok());
+        assert!(pool.submit(Box::new(move || tx.send(()).unwrap())).is_ok());
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -1,13 +1,40 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use warp::Filter;
 
 use super::handlers;
-use super::response::handle_error;
-use crate::funcky::FunckManager;
+use super::response::{handle_error, PayloadTooLarge};
+use crate::funcky::{Caller, FunckManager};
+use crate::telemetry::TRACEPARENT_HEADER;
 
 const ADD_FUNCTION_ROUTE_PATH: &str = "_funck_add";
+const ADD_PREBUILT_ROUTE_PATH: &str = "_funck_add_prebuilt";
+const ADD_GIT_ROUTE_PATH: &str = "_funck_add_git";
 const STAT_ROUTE_PATH: &str = "_stat";
+const LIMITS_ROUTE_PATH: &str = "_limits";
+const INVOCATIONS_ROUTE_PATH: &str = "_invocations";
+const SCHEDULES_ROUTE_PATH: &str = "_schedules";
+const PIPELINES_ROUTE_PATH: &str = "_pipelines";
+const SECRETS_ROUTE_PATH: &str = "_secrets";
+const ROUTES_ROUTE_PATH: &str = "_routes";
+const KV_ROUTE_PATH: &str = "_kv";
+const NAMESPACES_ROUTE_PATH: &str = "_namespaces";
+const BUILD_CACHE_ROUTE_PATH: &str = "_build_cache";
+const DEPLOYMENTS_ROUTE_PATH: &str = "_deployments";
+const BUILDS_ROUTE_PATH: &str = "_builds";
+const SOURCES_ROUTE_PATH: &str = "_sources";
+const REBUILD_ROUTE_PATH: &str = "_rebuild";
+
+const API_KEY_HEADER: &str = "x-api-key";
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Upper bound on call bodies. Functions set their own, lower limit in their manifest.
+const MAX_CALL_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Upper bound on source bundles, which may vendor their dependencies.
+const MAX_BUNDLE_BYTES: u64 = 64 * 1024 * 1024;
 
 fn with_manager(
     manager: Arc<FunckManager>,
@@ -15,44 +42,561 @@ fn with_manager(
     warp::any().map(move || manager.clone())
 }
 
+fn with_caller() -> impl Filter<Extract = (Caller,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .map(|addr: Option<SocketAddr>, api_key| Caller {
+            api_key,
+            ip: addr.map(|a| a.ip()),
+        })
+}
+
+/// Extracts the namespace token of a request, sent as `Authorization: Bearer {token}`.
+fn with_token() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>(AUTHORIZATION_HEADER).map(|value: Option<String>| {
+        value.and_then(|v| {
+            v.strip_prefix(BEARER_PREFIX)
+                .map(|t| String::from(t.trim()))
+        })
+    })
+}
+
+/// Extracts the name of the function the rest of the path points to, as in `{fn}` or `{ns}/{fn}`.
+/// Functions of a namespace require its token.
+fn with_function(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path::tail()
+        .and(with_token())
+        .and(with_manager(manager))
+        .and_then(
+            |tail: warp::path::Tail, token: Option<String>, manager: Arc<FunckManager>| async move {
+                if tail.as_str().is_empty() {
+                    return Err(warp::reject::not_found());
+                }
+                let fn_name = String::from(tail.as_str());
+                manager
+                    .authorize_function(&fn_name, token.as_deref())
+                    .map_err(warp::reject::custom)?;
+                Ok(fn_name)
+            },
+        )
+}
+
+/// Like `warp::body::content_length_limit`, but also accepts requests without a body, e.g. GETs.
+fn optional_content_length_limit(
+    limit: u64,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(l) if l > limit => Err(warp::reject::custom(PayloadTooLarge { limit })),
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
 pub fn all(
     manager: Arc<FunckManager>,
 ) -> impl Filter<Extract = impl ::warp::Reply, Error = warp::Rejection> + Clone {
     add_function(manager.clone())
+        .or(add_prebuilt(manager.clone()))
+        .or(add_git(manager.clone()))
         .or(call_arbitrary(manager.clone()))
-        .or(stat(manager))
+        .or(call_async(manager.clone()))
+        .or(get_invocation(manager.clone()))
+        .or(call_pipeline(manager.clone()))
+        .or(set_pipeline(manager.clone()))
+        .or(remove_pipeline(manager.clone()))
+        .or(list_pipelines(manager.clone()))
+        .or(set_secret(manager.clone()))
+        .or(remove_secret(manager.clone()))
+        .or(list_secrets(manager.clone()))
+        .or(set_limits(manager.clone()))
+        .or(remove_limits(manager.clone()))
+        .or(set_schedule(manager.clone()))
+        .or(remove_schedule(manager.clone()))
+        .or(get_schedule(manager.clone()))
+        .or(list_schedules(manager.clone()))
+        .or(get_kv(manager.clone()))
+        .or(clear_kv(manager.clone()))
+        .or(list_routes(manager.clone()))
+        .or(set_namespace(manager.clone()))
+        .or(remove_namespace(manager.clone()))
+        .or(list_namespaces(manager.clone()))
+        .or(purge_build_cache(manager.clone()))
+        .or(list_build_cache(manager.clone()))
+        .or(list_deployments(manager.clone()))
+        .or(list_builds(manager.clone()))
+        .or(cancel_build(manager.clone()))
+        .or(get_source(manager.clone()))
+        .or(rebuild(manager.clone()))
+        .or(stat(manager.clone()))
+        .or(call_route(manager))
 }
 
 fn add_function(
     manager: Arc<FunckManager>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // Functions are deployed to the default namespace, or to `/_funck_add/{ns}`.
+    let namespace = warp::path::param::<String>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
     warp::post()
         .and(warp::path(ADD_FUNCTION_ROUTE_PATH))
+        .and(namespace)
+        .and(warp::path::end())
         .and(with_manager(manager))
-        .and(warp::body::content_length_limit(100 * 1024)) // 100kb payload limit.
-        .and(warp::multipart::form())
+        .and(warp::body::content_length_limit(MAX_BUNDLE_BYTES))
+        .and(warp::multipart::form().max_length(MAX_BUNDLE_BYTES))
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_token())
         .and_then(handlers::add)
         .recover(handle_error)
 }
 
-fn call_arbitrary(
+fn add_prebuilt(
     manager: Arc<FunckManager>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let namespace = warp::path::param::<String>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
     warp::post()
+        .and(warp::path(ADD_PREBUILT_ROUTE_PATH))
+        .and(namespace)
+        .and(warp::path::end())
         .and(with_manager(manager))
-        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::content_length_limit(MAX_BUNDLE_BYTES))
+        .and(warp::multipart::form().max_length(MAX_BUNDLE_BYTES))
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_token())
+        .and_then(handlers::add_prebuilt)
+        .recover(handle_error)
+}
+
+fn add_git(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let namespace = warp::path::param::<String>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
+    warp::post()
+        .and(warp::path(ADD_GIT_ROUTE_PATH))
+        .and(namespace)
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_token())
+        .and_then(handlers::add_git)
+        .recover(handle_error)
+}
+
+fn call_arbitrary(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path::path("call")
+        .and(warp::method())
+        .and(with_manager(manager))
+        .and(optional_content_length_limit(MAX_CALL_BODY_BYTES))
         .and(warp::body::bytes())
-        .and(warp::path::path("call"))
         .and(warp::path::tail())
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_caller())
         .and_then(handlers::call)
         .recover(handle_error)
 }
 
+/// Calls the function whose custom route matches the request. Tried last, so it never shadows the
+/// server's own routes.
+fn call_route(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(with_manager(manager.clone()))
+        .and_then(handlers::resolve_route)
+        .and(with_manager(manager))
+        .and(optional_content_length_limit(MAX_CALL_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_caller())
+        .and_then(handlers::call_route)
+        .recover(handle_error)
+}
+
+fn call_async(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path::path("call-async"))
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(MAX_CALL_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_caller())
+        .and_then(handlers::call_async)
+        .recover(handle_error)
+}
+
+fn call_pipeline(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path("pipeline"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        // The first step's own limit is checked when it is admitted.
+        .and(warp::body::content_length_limit(MAX_CALL_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and(with_caller())
+        .and(with_token())
+        .and_then(handlers::call_pipeline)
+        .recover(handle_error)
+}
+
+fn get_invocation(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(INVOCATIONS_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::get_invocation)
+        .recover(handle_error)
+}
+
+fn get_kv(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(KV_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::get_kv)
+        .recover(handle_error)
+}
+
+fn clear_kv(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(KV_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::clear_kv)
+        .recover(handle_error)
+}
+
+fn list_routes(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(ROUTES_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_routes)
+        .recover(handle_error)
+}
+
 fn stat(
     manager: Arc<FunckManager>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::get()
         .and(warp::path(STAT_ROUTE_PATH))
         .and(with_manager(manager))
+        .and(with_token())
         .and_then(handlers::stat)
+        .recover(handle_error)
+}
+
+fn set_limits(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(warp::path(LIMITS_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and_then(handlers::set_limits)
+        .recover(handle_error)
+}
+
+fn remove_limits(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(LIMITS_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::remove_limits)
+        .recover(handle_error)
+}
+
+fn set_schedule(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(warp::path(SCHEDULES_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and_then(handlers::set_schedule)
+        .recover(handle_error)
+}
+
+fn remove_schedule(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(SCHEDULES_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::remove_schedule)
+        .recover(handle_error)
+}
+
+fn get_schedule(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(SCHEDULES_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::get_schedule)
+        .recover(handle_error)
+}
+
+fn list_schedules(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(SCHEDULES_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_schedules)
+        .recover(handle_error)
+}
+
+fn set_pipeline(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(warp::path(PIPELINES_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_token())
+        .and_then(handlers::set_pipeline)
+        .recover(handle_error)
+}
+
+fn remove_pipeline(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(PIPELINES_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::remove_pipeline)
+        .recover(handle_error)
+}
+
+fn list_pipelines(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(PIPELINES_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_pipelines)
+        .recover(handle_error)
+}
+
+fn list_deployments(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(DEPLOYMENTS_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_deployments)
+        .recover(handle_error)
+}
+
+fn list_builds(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let namespace = warp::path::param::<String>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
+    warp::get()
+        .and(warp::path(BUILDS_ROUTE_PATH))
+        .and(namespace)
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_builds)
+        .recover(handle_error)
+}
+
+fn cancel_build(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(BUILDS_ROUTE_PATH))
+        .and(warp::path::param::<u64>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::cancel_build)
+        .recover(handle_error)
+}
+
+fn get_source(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(SOURCES_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(warp::query::<handlers::VersionQuery>())
+        .and(with_manager(manager))
+        .and_then(handlers::get_source)
+        .recover(handle_error)
+}
+
+fn rebuild(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path(REBUILD_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(warp::query::<handlers::VersionQuery>())
+        .and(with_manager(manager))
+        .and(warp::header::optional::<String>(TRACEPARENT_HEADER))
+        .and_then(handlers::rebuild)
+        .recover(handle_error)
+}
+
+fn set_secret(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(warp::path(SECRETS_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(with_token())
+        .and_then(handlers::set_secret)
+        .recover(handle_error)
+}
+
+fn remove_secret(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(SECRETS_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::remove_secret)
+        .recover(handle_error)
+}
+
+fn list_secrets(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(SECRETS_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_secrets)
+        .recover(handle_error)
+}
+
+fn set_namespace(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(warp::path(NAMESPACES_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and_then(handlers::set_namespace)
+        .recover(handle_error)
+}
+
+fn remove_namespace(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(NAMESPACES_ROUTE_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::remove_namespace)
+        .recover(handle_error)
+}
+
+fn list_namespaces(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(NAMESPACES_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and_then(handlers::list_namespaces)
+        .recover(handle_error)
+}
+
+fn purge_build_cache(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path(BUILD_CACHE_ROUTE_PATH))
+        .and(with_function(manager.clone()))
+        .and(with_manager(manager))
+        .and_then(handlers::purge_build_cache)
+        .recover(handle_error)
+}
+
+fn list_build_cache(
+    manager: Arc<FunckManager>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path(BUILD_CACHE_ROUTE_PATH))
+        .and(warp::path::end())
+        .and(with_manager(manager))
+        .and(with_token())
+        .and_then(handlers::list_build_cache)
+        .recover(handle_error)
 }
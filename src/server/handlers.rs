@@ -1,27 +1,34 @@
 use std::collections::HashMap;
-use std::fs;
-use std::io;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Buf;
 
 use futures::StreamExt;
 
-use snafu::{ResultExt, Snafu};
+use serde::{Deserialize, Serialize};
 
-use tempfile::NamedTempFile;
+use snafu::{ResultExt, Snafu};
 
 use warp::{
-    http::{header::HeaderName, HeaderValue, StatusCode},
+    http::{
+        header::{self, HeaderName},
+        HeaderValue, Method, StatusCode,
+    },
     hyper::Body,
-    reply,
+    path::FullPath,
+    reply, Reply,
 };
 
 use super::message::{ErrorMessage, Message};
 use super::zip;
-use crate::funcky::{DropDir, Error as MgError, FunckManager};
+use crate::funcky::{
+    is_valid_function_name, split, BuildFlags, Caller, Error as MgError, FunckManager, GitSpec,
+    Limits, NamespaceSpec, PipelineSpec, Prebuilt, ScheduleSpec, SecretSpec,
+};
+use crate::telemetry::{SpanContext, TRACEPARENT_HEADER};
 
 impl warp::reject::Reject for MgError {}
 
@@ -31,94 +38,315 @@ pub enum Error {
         source: warp::Error,
     },
 
-    FailedToWriteBody {
-        source: io::Error,
-    },
-
-    FailedToDeleteSourceBundle {
-        source: io::Error,
-    },
-
     #[snafu(display("{}", source))]
     ManagerAddError {
         source: MgError,
     },
     MissingPartData,
 
-    #[snafu(display("{}", source))]
-    CallError {
-        source: MgError,
+    #[snafu(display("Missing form field: {}", name))]
+    MissingPart {
+        name: String,
     },
 
-    InvalidHeader,
+    #[snafu(display("Invalid header name: {}", name))]
+    InvalidHeaderName {
+        name: String,
+    },
+
+    #[snafu(display("Invalid value for header {}", name))]
+    InvalidHeaderValue {
+        name: String,
+    },
+
+    #[snafu(display("Invalid status code: {}", status))]
+    InvalidStatus {
+        status: String,
+    },
 }
 
+/// Response metadata key holding the HTTP status code of the response.
+const STATUS_METADATA_KEY: &str = "funcky-status";
+
+/// Response metadata keys with this prefix are reserved for the server, and never sent as headers.
+const RESERVED_METADATA_PREFIX: &str = "funcky-";
+
+/// Content type of responses that don't set one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+const BUNDLE_CONTENT_TYPE: &str = "application/zip";
+
 fn header_name(name: &str) -> Result<HeaderName, Error> {
-    HeaderName::from_str(name).map_err(|_e| Error::InvalidHeader)
+    HeaderName::from_str(name).map_err(|_e| Error::InvalidHeaderName {
+        name: String::from(name),
+    })
+}
+
+fn header_val(name: &HeaderName, value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|_e| Error::InvalidHeaderValue {
+        name: name.to_string(),
+    })
+}
+
+fn status_code(status: &str) -> Result<StatusCode, Error> {
+    status
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .ok_or_else(|| Error::InvalidStatus {
+            status: String::from(status),
+        })
+}
+
+fn build_request(
+    body: bytes::Bytes,
+    trace_context: SpanContext,
+    mut metadata: HashMap<String, String>,
+) -> funck::Request {
+    let body_vec = if body.is_empty() {
+        Vec::new()
+    } else {
+        body.bytes().to_vec()
+    };
+
+    // Forward the trace context so the invocation span is parented to this request.
+    metadata.insert(
+        String::from(TRACEPARENT_HEADER),
+        trace_context.to_traceparent(),
+    );
+    funck::Request::new(body_vec, metadata)
+}
+
+/// Converts a function response to an HTTP response.
+///
+/// Metadata are sent as headers, one header per line of the value so that e.g. several
+/// `Set-Cookie` headers can be sent. `funcky-status` sets the status code.
+fn build_http_response(resp: &funck::Response) -> Result<reply::Response, Error> {
+    let mut http_resp = reply::Response::new(Body::from(Vec::from(resp.body())));
+
+    for (key, value) in resp.metadata().iter() {
+        if key.eq_ignore_ascii_case(STATUS_METADATA_KEY) {
+            *http_resp.status_mut() = status_code(value)?;
+            continue;
+        }
+        if key
+            .to_ascii_lowercase()
+            .starts_with(RESERVED_METADATA_PREFIX)
+        {
+            continue;
+        }
+
+        let name = header_name(key)?;
+        for line in value.lines() {
+            let val = header_val(&name, line)?;
+            http_resp.headers_mut().append(name.clone(), val);
+        }
+    }
+
+    if !resp.body().is_empty() && !http_resp.headers().contains_key(header::CONTENT_TYPE) {
+        http_resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(DEFAULT_CONTENT_TYPE),
+        );
+    }
+
+    Ok(http_resp)
+}
+
+fn into_http_response(
+    result: Result<funck::Response, MgError>,
+) -> Result<reply::Response, warp::Rejection> {
+    match result {
+        Ok(resp) => match build_http_response(&resp) {
+            Ok(http_resp) => Ok(http_resp),
+            Err(e) => {
+                log::warn!("function returned an invalid response: {}", e);
+                Ok(reply::with_status(
+                    reply::json(&ErrorMessage::new(&format!(
+                        "Function returned an invalid response: {}",
+                        e
+                    ))),
+                    StatusCode::BAD_GATEWAY,
+                )
+                .into_response())
+            }
+        },
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
+
+fn parse_traceparent(header: Option<String>) -> Option<SpanContext> {
+    header.and_then(|h| SpanContext::from_traceparent(&h))
+}
+
+async fn part_text(part: &mut warp::multipart::Part) -> Result<String, Error> {
+    let data = part
+        .data()
+        .await
+        .ok_or(Error::MissingPartData)?
+        .context(FailedToReadBody)?;
+    Ok(String::from_utf8_lossy(data.bytes()).trim().to_string())
 }
 
-fn header_val(name: &str) -> Result<HeaderValue, Error> {
-    HeaderValue::from_str(name).map_err(|_e| Error::InvalidHeader)
+/// Reads a build option sent alongside a source bundle.
+async fn add_flag(flags: &mut BuildFlags, mut part: warp::multipart::Part) -> Result<(), Error> {
+    match part.name() {
+        "features" => {
+            flags.features = part_text(&mut part)
+                .await?
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect()
+        }
+        "no_default_features" => {
+            flags.no_default_features = matches!(part_text(&mut part).await?.as_str(), "1" | "true")
+        }
+        "profile" => flags.profile = Some(part_text(&mut part).await?),
+        "rustflags" => flags.rustflags = Some(part_text(&mut part).await?),
+        _ => {}
+    }
+    Ok(())
 }
 
 async fn add_part(
     manager: Arc<FunckManager>,
     mut part: warp::multipart::Part,
+    namespace: Option<&str>,
+    flags: BuildFlags,
+    trace_context: SpanContext,
 ) -> Result<(), Error> {
+    let tracer = manager.tracer();
+
+    let upload_span = tracer.start_span("deploy.upload", Some(&trace_context));
     let body = part
         .data()
         .await
         .ok_or(Error::MissingPartData)?
         .context(FailedToReadBody)?;
 
-    let fname = Path::new(part.filename().unwrap());
-    let project_name = fname.file_stem().unwrap();
-
-    // TODO: Use a tempdir or a tempdir-like abstraction for the compilation request so that it
-    // gets dropped once compilation is done.
+    // The name of the function is the stem of the bundle's file name.
+    let name = part
+        .filename()
+        .and_then(|f| Path::new(f).file_stem())
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !is_valid_function_name(&name) {
+        return Err(Error::ManagerAddError {
+            source: MgError::InvalidBundle {
+                reason: format!(
+                    "invalid function name [{}]: bundles must be named after their function, which may only contain ASCII letters, digits, '-' and '_'",
+                    name
+                ),
+            },
+        });
+    }
 
-    // Save zip file.
-    let dst_zip_path = NamedTempFile::new_in(&manager.cfg.tmp_dir).unwrap();
-    log::debug!("writing source zip to {}", dst_zip_path.path().display());
-    fs::write(&dst_zip_path.path(), body.bytes()).context(FailedToWriteBody)?;
+    // The bundle is kept, so the source of the deployment can be downloaded and rebuilt.
+    let (source_hash, bundle) = manager
+        .store_source(body.bytes())
+        .context(ManagerAddError)?;
+    upload_span.end();
 
-    // Extract zip file.
-    let tgt_dir = DropDir::new(
-        manager.cfg.tmp_dir.join(project_name),
-        project_name.to_string_lossy().as_ref(),
-    )
-    .context(FailedToWriteBody)?;
+    let namespace = namespace.map(String::from);
+    tokio::task::spawn_blocking(move || {
+        deploy_bundle(
+            &manager,
+            &bundle,
+            &name,
+            namespace.as_deref(),
+            flags,
+            source_hash,
+            trace_context,
+        )
+    })
+    .await
+    .unwrap_or(Err(MgError::ExtractJoinFailure))
+    .context(ManagerAddError)
+}
 
-    log::debug!(
-        "unzip {} => {}",
-        dst_zip_path.path().display(),
-        tgt_dir.path().display()
-    );
+/// Extracts a stored source bundle and queues its build. Blocks while extracting.
+fn deploy_bundle(
+    manager: &FunckManager,
+    bundle: &Path,
+    name: &str,
+    namespace: Option<&str>,
+    flags: BuildFlags,
+    source_hash: String,
+    trace_context: SpanContext,
+) -> Result<(), MgError> {
+    let (tgt_dir, _lease) = manager.source_directory(namespace, name)?;
 
-    zip::unzip(&dst_zip_path.path(), &tgt_dir.path()).unwrap();
+    log::debug!("unzip {} => {}", bundle.display(), tgt_dir.path().display());
 
-    // Delete zip file.
-    fs::remove_file(dst_zip_path).context(FailedToDeleteSourceBundle)?;
+    let unzip_span = manager
+        .tracer()
+        .start_span("deploy.unzip", Some(&trace_context));
+    zip::unzip(bundle, tgt_dir.path()).map_err(|e| MgError::InvalidBundle {
+        reason: e.to_string(),
+    })?;
+    unzip_span.end();
 
     // Add to manager.
-    manager.add(tgt_dir).context(ManagerAddError)
+    manager.add(tgt_dir, namespace, flags, source_hash, Some(trace_context))
 }
 
 // TODO: Add content-type to indicate file extension (zip, tar.gz, tar.xz)
 pub async fn add(
+    namespace: Option<String>,
     manager: Arc<FunckManager>,
     mut form_data: warp::multipart::FormData,
+    traceparent: Option<String>,
+    token: Option<String>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    log::info!("POST/add");
+    match &namespace {
+        Some(ns) => log::info!("POST/add/{}", ns),
+        None => log::info!("POST/add"),
+    }
+
+    if let Some(ns) = &namespace {
+        manager
+            .authorize_namespace(ns, token.as_deref())
+            .map_err(warp::reject::custom)?;
+    }
 
+    let mut span = manager
+        .tracer()
+        .start_server_span("deploy", parse_traceparent(traceparent).as_ref());
+
+    // Build options may come after the bundle in the form, so it is read in full first.
+    let mut flags = BuildFlags::default();
+    let mut sources = Vec::new();
     while let Some(Ok(part)) = form_data.next().await {
         if part.name() == "src" {
-            if let Err(e) = add_part(manager.clone(), part).await {
-                return Ok(reply::with_status(
-                    reply::json(&ErrorMessage::new(&e)),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ));
+            sources.push(part);
+        } else if let Err(e) = add_flag(&mut flags, part).await {
+            return Ok(reply::with_status(
+                reply::json(&ErrorMessage::new(&e)),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    }
+
+    for part in sources {
+        if let Err(e) = add_part(
+            manager.clone(),
+            part,
+            namespace.as_deref(),
+            flags.clone(),
+            span.context(),
+        )
+        .await
+        {
+            span.set_error(&e);
+            if let Error::ManagerAddError { source } = e {
+                return Err(warp::reject::custom(source));
             }
+            return Ok(reply::with_status(
+                reply::json(&ErrorMessage::new(&e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
         }
     }
     Ok(reply::with_status(
@@ -127,44 +355,798 @@ pub async fn add(
     ))
 }
 
-pub async fn stat(manager: Arc<FunckManager>) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn stat(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log::info!("GET/stat");
 
-    let stats = manager.stat();
+    let stats = manager
+        .stat(token.as_deref())
+        .map_err(warp::reject::custom)?;
     Ok(reply::json(&stats))
 }
 
+pub async fn set_limits(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+    limits: Limits,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("PUT/limits/{}", fn_name);
+
+    manager
+        .set_limits(&fn_name, limits)
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn remove_limits(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/limits/{}", fn_name);
+
+    if manager
+        .remove_limits(&fn_name)
+        .map_err(warp::reject::custom)?
+    {
+        Ok(reply::with_status(
+            reply::json(&Message::new("OK")),
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(reply::with_status(
+            reply::json(&ErrorMessage::new(&format!(
+                "No limits for function: {}",
+                fn_name
+            ))),
+            StatusCode::NOT_FOUND,
+        ))
+    }
+}
+
 pub async fn call(
+    method: Method,
     manager: Arc<FunckManager>,
     body: bytes::Bytes,
     path: warp::path::Tail,
+    traceparent: Option<String>,
+    caller: Caller,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    log::info!("POST/{}", path.as_str());
+    log::info!("{}/{}", method, path.as_str());
 
-    let body_vec = if body.is_empty() {
-        Vec::new()
-    } else {
-        body.bytes().to_vec()
+    let mut span = manager
+        .tracer()
+        .start_server_span("call", parse_traceparent(traceparent).as_ref());
+    span.set_attribute("funcky.function", path.as_str());
+
+    let fn_name = String::from(path.as_str());
+    let timeout = match manager.admit_http_call(&fn_name, Some(method.as_str()), body.len()) {
+        Ok(t) => t,
+        Err(e) => {
+            span.set_error(&e);
+            return Err(warp::reject::custom(e));
+        }
+    };
+
+    let req = build_request(body, span.context(), HashMap::new());
+    let result = call_with_timeout(manager, fn_name, req, caller, timeout).await;
+    if let Err(e) = &result {
+        span.set_error(e);
+    }
+    into_http_response(result)
+}
+
+/// A function call matched by a custom route.
+pub struct RouteTarget {
+    method: Method,
+    path: FullPath,
+    function: String,
+    params: HashMap<String, String>,
+}
+
+pub async fn resolve_route(
+    method: Method,
+    path: FullPath,
+    manager: Arc<FunckManager>,
+) -> Result<RouteTarget, warp::Rejection> {
+    match manager
+        .resolve_route(method.as_str(), path.as_str())
+        .map_err(warp::reject::custom)?
+    {
+        Some((function, params)) => Ok(RouteTarget {
+            method,
+            path,
+            function,
+            params,
+        }),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+pub async fn call_route(
+    target: RouteTarget,
+    manager: Arc<FunckManager>,
+    body: bytes::Bytes,
+    traceparent: Option<String>,
+    caller: Caller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!(
+        "{}{} => {}",
+        target.method,
+        target.path.as_str(),
+        target.function
+    );
+
+    let mut span = manager
+        .tracer()
+        .start_server_span("route", parse_traceparent(traceparent).as_ref());
+    span.set_attribute("funcky.function", &target.function);
+    span.set_attribute("http.target", target.path.as_str());
+
+    let timeout = match manager.admit_http_call(&target.function, None, body.len()) {
+        Ok(t) => t,
+        Err(e) => {
+            span.set_error(&e);
+            return Err(warp::reject::custom(e));
+        }
     };
-    let req = funck::Request::new(body_vec, HashMap::new());
-
-    match manager.call(path.as_str(), req) {
-        Ok(resp) => {
-            let body = Body::from(Vec::from(resp.body()));
-            let mut http_resp = reply::Response::new(body);
-            for (k, v) in resp.metadata().iter().filter_map(|(a, b)| {
-                if let Ok(name) = header_name(a) {
-                    if let Ok(val) = header_val(b) {
-                        return Some((name, val));
-                    }
-                }
-                log::warn!("skipped invalid header: [{}={}]", a, b);
-                None
-            }) {
-                http_resp.headers_mut().insert(k, v);
+
+    let req = build_request(body, span.context(), target.params);
+    let result = call_with_timeout(manager, target.function, req, caller, timeout).await;
+    if let Err(e) = &result {
+        span.set_error(e);
+    }
+    into_http_response(result)
+}
+
+pub async fn get_kv(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/kv/{}", fn_name);
+
+    let entries = manager.kv_entries(&fn_name).map_err(warp::reject::custom)?;
+    Ok(reply::json(&entries))
+}
+
+pub async fn clear_kv(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/kv/{}", fn_name);
+
+    manager.clear_kv(&fn_name).map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn list_routes(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/routes");
+
+    let routes = manager
+        .routes(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&routes))
+}
+
+/// Runs a call off the async runtime, giving up on it after `timeout`.
+///
+/// A call that times out can't be interrupted, so it keeps running in the background.
+async fn call_with_timeout(
+    manager: Arc<FunckManager>,
+    fn_name: String,
+    req: funck::Request,
+    caller: Caller,
+    timeout: Option<Duration>,
+) -> Result<funck::Response, MgError> {
+    let name = fn_name.clone();
+    let call = tokio::task::spawn_blocking(move || manager.call(&name, req, &caller));
+
+    let joined = match timeout {
+        Some(t) => match tokio::time::timeout(t, call).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                return Err(MgError::CallTimedOut {
+                    name: fn_name,
+                    timeout_ms: t.as_millis() as u64,
+                })
             }
-            Ok(http_resp)
+        },
+        None => call.await,
+    };
+    joined.unwrap_or(Err(MgError::CallJoinFailure))
+}
+
+#[derive(Serialize)]
+struct Deployed {
+    functions: Vec<String>,
+}
+
+/// Function name of a prebuilt library uploaded without one: `libecho.so` deploys `echo`.
+fn prebuilt_name(filename: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match stem.strip_prefix("lib") {
+        Some(name) if filename.ends_with(".so") => String::from(name),
+        _ => stem,
+    }
+}
+
+/// Reads the form of a prebuilt deploy: the `artifact` itself, its `sha256` and optionally its
+/// `name` and `manifest`.
+async fn read_prebuilt(mut form_data: warp::multipart::FormData) -> Result<Prebuilt, Error> {
+    let mut artifact = None;
+    let mut sha256 = None;
+    let mut name = None;
+    let mut cargo_toml = None;
+    while let Some(Ok(mut part)) = form_data.next().await {
+        match part.name() {
+            "artifact" => {
+                let filename = part.filename().map(prebuilt_name).unwrap_or_default();
+                let data = part
+                    .data()
+                    .await
+                    .ok_or(Error::MissingPartData)?
+                    .context(FailedToReadBody)?;
+                artifact = Some((filename, data.bytes().to_vec()));
+            }
+            "sha256" => sha256 = Some(part_text(&mut part).await?),
+            "name" => name = Some(part_text(&mut part).await?),
+            "manifest" => cargo_toml = Some(part_text(&mut part).await?),
+            _ => {}
         }
-        Err(e) => Err(warp::reject::custom(e)),
     }
+
+    let (filename, data) = artifact.ok_or_else(|| Error::MissingPart {
+        name: String::from("artifact"),
+    })?;
+    Ok(Prebuilt {
+        name: name.unwrap_or(filename),
+        data,
+        sha256: sha256.ok_or_else(|| Error::MissingPart {
+            name: String::from("sha256"),
+        })?,
+        cargo_toml,
+    })
+}
+
+pub async fn add_prebuilt(
+    namespace: Option<String>,
+    manager: Arc<FunckManager>,
+    form_data: warp::multipart::FormData,
+    traceparent: Option<String>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match &namespace {
+        Some(ns) => log::info!("POST/add_prebuilt/{}", ns),
+        None => log::info!("POST/add_prebuilt"),
+    }
+
+    if let Some(ns) = &namespace {
+        manager
+            .authorize_namespace(ns, token.as_deref())
+            .map_err(warp::reject::custom)?;
+    }
+
+    let prebuilt = match read_prebuilt(form_data).await {
+        Ok(prebuilt) => prebuilt,
+        Err(e) => {
+            return Ok(reply::with_status(
+                reply::json(&ErrorMessage::new(&e)),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let mut span = manager
+        .tracer()
+        .start_server_span("deploy", parse_traceparent(traceparent).as_ref());
+    match manager.add_prebuilt(prebuilt, namespace.as_deref(), Some(span.context())) {
+        Ok(functions) => {
+            span.end();
+            Ok(reply::with_status(
+                reply::json(&Deployed { functions }),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            span.set_error(&e);
+            span.end();
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CheckedOut {
+    commit: String,
+}
+
+pub async fn add_git(
+    namespace: Option<String>,
+    manager: Arc<FunckManager>,
+    spec: GitSpec,
+    traceparent: Option<String>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match &namespace {
+        Some(ns) => log::info!("POST/add_git/{} {}", ns, spec.url),
+        None => log::info!("POST/add_git {}", spec.url),
+    }
+
+    if let Some(ns) = &namespace {
+        manager
+            .authorize_namespace(ns, token.as_deref())
+            .map_err(warp::reject::custom)?;
+    }
+
+    let mut span = manager
+        .tracer()
+        .start_server_span("deploy", parse_traceparent(traceparent).as_ref());
+    let trace_context = span.context();
+
+    // Fetching may take a while, and blocks.
+    let deploy_manager = manager.clone();
+    let added = tokio::task::spawn_blocking(move || {
+        deploy_manager.add_git(spec, namespace.as_deref(), Some(trace_context))
+    })
+    .await
+    .unwrap_or(Err(MgError::CheckoutJoinFailure));
+
+    match added {
+        Ok(commit) => {
+            span.end();
+            Ok(reply::json(&CheckedOut { commit }))
+        }
+        Err(e) => {
+            span.set_error(&e);
+            span.end();
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+/// Selects a version of a deployment, the current one by default.
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    version: Option<u64>,
+}
+
+pub async fn get_source(
+    fn_name: String,
+    query: VersionQuery,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/sources/{}", fn_name);
+
+    let version = manager
+        .version(&fn_name, query.version)
+        .map_err(warp::reject::custom)?;
+    let data = manager
+        .read_source(&fn_name, &version)
+        .map_err(warp::reject::custom)?;
+
+    let mut resp = reply::Response::new(Body::from(data));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(BUNDLE_CONTENT_TYPE),
+    );
+    let file_name = format!("{}-{}.zip", fn_name.replace('/', "-"), version.version);
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))
+    {
+        resp.headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(resp)
+}
+
+#[derive(Serialize)]
+struct Rebuilding {
+    /// Version whose source is rebuilt.
+    version: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+}
+
+pub async fn rebuild(
+    fn_name: String,
+    query: VersionQuery,
+    manager: Arc<FunckManager>,
+    traceparent: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("POST/rebuild/{}", fn_name);
+
+    let version = manager
+        .version(&fn_name, query.version)
+        .map_err(warp::reject::custom)?;
+    let (namespace, name) = split(&fn_name);
+    let namespace = namespace.map(String::from);
+    let name = String::from(name);
+
+    let mut span = manager
+        .tracer()
+        .start_server_span("rebuild", parse_traceparent(traceparent).as_ref());
+    let trace_context = span.context();
+
+    let rebuilt = match version.source.clone() {
+        // Git deploys are checked out again, at the same commit.
+        Some(source) => {
+            let spec = GitSpec {
+                url: source.url,
+                reference: Some(source.commit),
+                subdir: source.subdir,
+                name: Some(name),
+                build: Some(version.flags.clone()),
+            };
+            let deploy_manager = manager.clone();
+            tokio::task::spawn_blocking(move || {
+                deploy_manager.add_git(spec, namespace.as_deref(), Some(trace_context))
+            })
+            .await
+            .unwrap_or(Err(MgError::CheckoutJoinFailure))
+            .map(Some)
+        }
+        None => {
+            let deploy_manager = manager.clone();
+            let version = version.clone();
+            tokio::task::spawn_blocking(move || {
+                let bundle = deploy_manager.source_bundle(&fn_name, &version)?;
+                deploy_bundle(
+                    &deploy_manager,
+                    &bundle,
+                    &name,
+                    namespace.as_deref(),
+                    version.flags.clone(),
+                    version.source_hash.clone().unwrap_or_default(),
+                    trace_context,
+                )
+                // A stored bundle was extracted when it was deployed, so failing now is ours.
+                .map_err(|e| match e {
+                    MgError::InvalidBundle { reason } => MgError::FailedToExtractSource {
+                        name: fn_name,
+                        version: version.version,
+                        reason,
+                    },
+                    e => e,
+                })
+            })
+            .await
+            .unwrap_or(Err(MgError::ExtractJoinFailure))
+            .map(|_| None)
+        }
+    };
+
+    match rebuilt {
+        Ok(commit) => {
+            span.end();
+            Ok(reply::json(&Rebuilding {
+                version: version.version,
+                commit,
+            }))
+        }
+        Err(e) => {
+            span.set_error(&e);
+            span.end();
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct InvocationAccepted {
+    id: String,
+}
+
+pub async fn call_async(
+    manager: Arc<FunckManager>,
+    body: bytes::Bytes,
+    path: warp::path::Tail,
+    traceparent: Option<String>,
+    caller: Caller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("POST/call-async/{}", path.as_str());
+
+    let span = manager
+        .tracer()
+        .start_server_span("call-async", parse_traceparent(traceparent).as_ref());
+
+    let fn_name = String::from(path.as_str());
+    manager
+        .admit_http_call(&fn_name, Some("POST"), body.len())
+        .map_err(warp::reject::custom)?;
+    let req = build_request(body, span.context(), HashMap::new());
+    let id = manager
+        .call_async(&fn_name, req, &caller)
+        .map_err(warp::reject::custom)?;
+
+    Ok(reply::with_status(
+        reply::json(&InvocationAccepted { id }),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn get_invocation(
+    id: String,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/invocations/{}", id);
+
+    let invocation = manager
+        .invocation(&id, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    match invocation {
+        Some(invocation) => Ok(reply::with_status(reply::json(&invocation), StatusCode::OK)),
+        None => Ok(reply::with_status(
+            reply::json(&ErrorMessage::new(&format!("Unknown invocation: {}", id))),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+pub async fn set_schedule(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+    spec: ScheduleSpec,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("PUT/schedules/{}", fn_name);
+
+    manager
+        .set_schedule(&fn_name, spec)
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn remove_schedule(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/schedules/{}", fn_name);
+
+    manager
+        .remove_schedule(&fn_name)
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn get_schedule(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/schedules/{}", fn_name);
+
+    match manager.schedule(&fn_name).map_err(warp::reject::custom)? {
+        Some(schedule) => Ok(reply::with_status(reply::json(&schedule), StatusCode::OK)),
+        None => Ok(reply::with_status(
+            reply::json(&ErrorMessage::new(&format!(
+                "No schedule for function: {}",
+                fn_name
+            ))),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+pub async fn list_schedules(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/schedules");
+
+    let schedules = manager
+        .schedules(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&schedules))
+}
+
+pub async fn call_pipeline(
+    pipeline_name: String,
+    manager: Arc<FunckManager>,
+    body: bytes::Bytes,
+    traceparent: Option<String>,
+    caller: Caller,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("POST/pipeline/{}", pipeline_name);
+
+    let mut span = manager
+        .tracer()
+        .start_server_span("pipeline", parse_traceparent(traceparent).as_ref());
+    span.set_attribute("funcky.pipeline", &pipeline_name);
+
+    // Steps are native or WebAssembly calls, which block.
+    let req = build_request(body, span.context(), HashMap::new());
+    let name = pipeline_name.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        manager.call_pipeline(&name, req, &caller, token.as_deref())
+    })
+    .await
+    .unwrap_or(Err(MgError::CallJoinFailure));
+    if let Err(e) = &result {
+        span.set_error(e);
+    }
+    into_http_response(result)
+}
+
+pub async fn set_pipeline(
+    pipeline_name: String,
+    manager: Arc<FunckManager>,
+    spec: PipelineSpec,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("PUT/pipelines/{}", pipeline_name);
+
+    manager
+        .set_pipeline(&pipeline_name, spec, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn remove_pipeline(
+    pipeline_name: String,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/pipelines/{}", pipeline_name);
+
+    manager
+        .remove_pipeline(&pipeline_name, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn list_pipelines(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/pipelines");
+
+    let pipelines = manager
+        .pipelines(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&pipelines))
+}
+
+pub async fn list_deployments(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/deployments");
+
+    let deployments = manager
+        .deployments(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&deployments))
+}
+
+pub async fn set_secret(
+    secret_name: String,
+    manager: Arc<FunckManager>,
+    spec: SecretSpec,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("PUT/secrets/{}", secret_name);
+
+    manager
+        .set_secret(&secret_name, spec, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn remove_secret(
+    secret_name: String,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/secrets/{}", secret_name);
+
+    manager
+        .remove_secret(&secret_name, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn list_secrets(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/secrets");
+
+    let secrets = manager
+        .secrets(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&secrets))
+}
+
+pub async fn set_namespace(
+    namespace: String,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+    spec: NamespaceSpec,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("PUT/namespaces/{}", namespace);
+
+    manager
+        .set_namespace(&namespace, spec, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn remove_namespace(
+    namespace: String,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/namespaces/{}", namespace);
+
+    manager
+        .remove_namespace(&namespace, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn list_namespaces(
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/namespaces");
+
+    let namespaces = manager.namespaces().map_err(warp::reject::custom)?;
+    Ok(reply::json(&namespaces))
+}
+
+pub async fn list_builds(
+    namespace: Option<String>,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/builds");
+
+    let builds = manager
+        .build_queue(namespace.as_deref(), token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&builds))
+}
+
+pub async fn cancel_build(
+    id: u64,
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/builds/{}", id);
+
+    let build = manager
+        .cancel_build(id, token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&build))
+}
+
+pub async fn purge_build_cache(
+    fn_name: String,
+    manager: Arc<FunckManager>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("DELETE/build_cache/{}", fn_name);
+
+    manager
+        .purge_build_cache(&fn_name)
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&Message::new("OK")))
+}
+
+pub async fn list_build_cache(
+    manager: Arc<FunckManager>,
+    token: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log::info!("GET/build_cache");
+
+    let entries = manager
+        .build_cache(token.as_deref())
+        .map_err(warp::reject::custom)?;
+    Ok(reply::json(&entries))
 }
@@ -15,15 +15,33 @@ impl Message {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct FailedStep {
+    step: usize,
+    function: String,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct ErrorMessage {
     error: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failed_step: Option<FailedStep>,
 }
 
 impl ErrorMessage {
     pub fn new<T: fmt::Display>(error: &T) -> ErrorMessage {
         ErrorMessage {
             error: error.to_string(),
+            failed_step: None,
         }
     }
+
+    pub fn with_failed_step(mut self, step: usize, function: &str) -> ErrorMessage {
+        self.failed_step = Some(FailedStep {
+            step,
+            function: String::from(function),
+        });
+        self
+    }
 }
@@ -1,12 +1,14 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use snafu::{ensure, ResultExt, Snafu};
 
 use tokio::sync::oneshot;
-use tokio::task::{spawn, JoinError, JoinHandle};
+use tokio::task::{spawn, spawn_blocking, JoinError, JoinHandle};
+use tokio::time::timeout;
 
 use super::filters;
-use crate::funcky::FunckManager;
+use crate::funcky::{FunckManager, QueuedBuilds};
 
 #[derive(Debug, Snafu)]
 pub enum ServerError {
@@ -18,6 +20,18 @@ pub enum ServerError {
 
 pub type Result<T, E = ServerError> = std::result::Result<T, E>;
 
+/// How the server winds down when asked to stop.
+pub struct ShutdownOptions {
+    /// How long in-flight calls are given to complete.
+    pub drain_timeout: Duration,
+
+    /// What happens to builds still queued.
+    pub queued_builds: QueuedBuilds,
+
+    /// How long builds are given to complete, after which they're cancelled and persisted.
+    pub build_wait: Duration,
+}
+
 struct SrvProcess {
     pub join_handle: JoinHandle<()>,
     pub tx_stop: oneshot::Sender<()>,
@@ -45,6 +59,8 @@ impl Server {
                 rx.await.ok();
             });
 
+        FunckManager::start_scheduler(&self.manager);
+
         log::info!("starting http layer");
         let join_handle: JoinHandle<()> = spawn(srv);
         self.handle = Some(SrvProcess {
@@ -56,16 +72,46 @@ impl Server {
         Ok(())
     }
 
-    pub async fn stop(&mut self) -> Result<()> {
+    pub async fn stop(&mut self, options: ShutdownOptions) -> Result<()> {
         log::info!("Shutdown signal received.");
         let handle = self
             .handle
             .take()
             .ok_or_else(|| ServerError::StopWithoutStartError)?;
+        let deadline = Instant::now() + options.drain_timeout;
+        self.manager.begin_shutdown();
         ensure!(handle.tx_stop.send(()).is_ok(), ShutdownRequestError);
 
-        log::info!("waiting for server to quit gracefully");
-        handle.join_handle.await.context(ShutdownError)?;
+        log::info!(
+            "waiting up to {:?} for in-flight calls to complete",
+            options.drain_timeout
+        );
+        match timeout(options.drain_timeout, handle.join_handle).await {
+            Ok(joined) => joined.context(ShutdownError)?,
+            Err(_) => log::warn!("HTTP connections still open after the drain period"),
+        }
+
+        // Asynchronous and scheduled calls don't go through the HTTP layer.
+        let manager = self.manager.clone();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let in_flight = spawn_blocking(move || manager.drain(remaining))
+            .await
+            .context(ShutdownError)?;
+        if in_flight > 0 {
+            log::warn!(
+                "{} calls still running after the drain period, waiting for them to unload",
+                in_flight
+            );
+        }
+
+        let manager = self.manager.clone();
+        let stopped =
+            spawn_blocking(move || manager.shutdown(options.queued_builds, options.build_wait))
+                .await
+                .context(ShutdownError)?;
+        if let Err(e) = stopped {
+            log::error!("failed to shut down cleanly: {}", e);
+        }
 
         Ok(())
     }
@@ -1,19 +1,94 @@
-use serde::Serialize;
-
-use warp::http::StatusCode;
+use warp::http::{header, HeaderValue, StatusCode};
 use warp::reject::Rejection;
+use warp::Reply;
 
 use super::message::ErrorMessage;
-use crate::funcky::{Error as FnError, LoaderError};
+use crate::funcky::{
+    BuildCacheError, Error as FnError, GitError, LoaderError, ManifestError, NamespaceError,
+    PipelineError, RouteError, SchedulerError, SecretsError,
+};
+
+/// Rejection of requests whose body is over the server-wide limit.
+#[derive(Debug)]
+pub struct PayloadTooLarge {
+    pub limit: u64,
+}
 
-pub fn get_serializable(err: &FnError) -> impl Serialize {
+impl warp::reject::Reject for PayloadTooLarge {}
+
+pub fn get_serializable(err: &FnError) -> ErrorMessage {
     match err {
         FnError::CallError {
-            source: LoaderError::UnknownFunction { .. },
+            source: LoaderError::UnknownFunction { .. } | LoaderError::WasmError { .. },
         } => ErrorMessage::new(err),
-        FnError::CallError {
-            source: LoaderError::CallError { source, .. },
-        } => ErrorMessage::new(source),
+        FnError::FunctionFailed { source, .. } => ErrorMessage::new(source),
+        FnError::InvalidLimits { .. }
+        | FnError::InvalidBuildFlags { .. }
+        | FnError::ChecksumMismatch { .. }
+        | FnError::InvalidArtifact { .. }
+        | FnError::InvalidBundle { .. }
+        | FnError::LoadError { .. }
+        | FnError::TooManyInFlight { .. }
+        | FnError::RateLimited { .. }
+        | FnError::MethodNotAllowed { .. }
+        | FnError::BodyTooLarge { .. }
+        | FnError::CallTimedOut { .. }
+        | FnError::ShuttingDown
+        | FnError::AsyncQueueFull { .. }
+        | FnError::StepQueueFull { .. }
+        | FnError::DeployInProgress { .. }
+        | FnError::UnknownBuild { .. }
+        | FnError::UnknownDeployment { .. }
+        | FnError::UnknownVersion { .. }
+        | FnError::SourceNotStored { .. } => ErrorMessage::new(err),
+        FnError::SchedulerError {
+            source:
+                SchedulerError::InvalidCronExpression { .. }
+                | SchedulerError::CronNeverFires { .. }
+                | SchedulerError::UnknownSchedule { .. },
+        } => ErrorMessage::new(err),
+        FnError::PipelineError {
+            source: PipelineError::InvalidPipeline { .. } | PipelineError::UnknownPipeline { .. },
+        } => ErrorMessage::new(err),
+        FnError::RouteError {
+            source: RouteError::InvalidRoute { .. } | RouteError::RouteConflict { .. },
+        } => ErrorMessage::new(err),
+        FnError::SecretsError {
+            source:
+                SecretsError::InvalidSecret { .. }
+                | SecretsError::UnknownSecret { .. }
+                | SecretsError::MissingMasterKey,
+        } => ErrorMessage::new(err),
+        FnError::NamespaceError {
+            source:
+                NamespaceError::InvalidNamespace { .. }
+                | NamespaceError::UnknownNamespace { .. }
+                | NamespaceError::Unauthorized { .. }
+                | NamespaceError::NamespaceNotEmpty { .. }
+                | NamespaceError::FunctionQuotaExceeded { .. }
+                | NamespaceError::BuildQuotaExceeded { .. }
+                | NamespaceError::DiskQuotaExceeded { .. },
+        } => ErrorMessage::new(err),
+        FnError::BuildCacheError {
+            source: BuildCacheError::CacheInUse { .. } | BuildCacheError::UnknownCacheEntry { .. },
+        } => ErrorMessage::new(err),
+        FnError::ManifestError {
+            source: ManifestError::MalformedManifest { .. } | ManifestError::InvalidManifest { .. },
+        } => ErrorMessage::new(err),
+        FnError::GitError {
+            source:
+                GitError::InvalidGitSpec { .. }
+                | GitError::UnknownRef { .. }
+                | GitError::MissingSubdirectory { .. }
+                | GitError::GitFailed { .. }
+                | GitError::GitTimedOut { .. },
+        } => ErrorMessage::new(err),
+        FnError::PipelineStepFailed {
+            step,
+            function,
+            source,
+            ..
+        } => get_serializable(source).with_failed_step(*step, function),
         _ => ErrorMessage::new(&String::from("Internal Server Error")),
     }
 }
@@ -23,17 +98,217 @@ pub fn get_status_code(err: &FnError) -> StatusCode {
         FnError::CallError {
             source: LoaderError::UnknownFunction { .. },
         } => StatusCode::NOT_FOUND,
+        FnError::FunctionFailed { status, .. } => {
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        FnError::InvalidLimits { .. }
+        | FnError::InvalidBuildFlags { .. }
+        | FnError::ChecksumMismatch { .. }
+        | FnError::InvalidArtifact { .. }
+        | FnError::InvalidBundle { .. } => StatusCode::BAD_REQUEST,
+        FnError::ManifestError {
+            source: ManifestError::MalformedManifest { .. } | ManifestError::InvalidManifest { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::GitError {
+            source: GitError::InvalidGitSpec { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::GitError {
+            source: GitError::UnknownRef { .. } | GitError::MissingSubdirectory { .. },
+        } => StatusCode::UNPROCESSABLE_ENTITY,
+        // The repository couldn't be fetched.
+        FnError::GitError {
+            source: GitError::GitFailed { .. },
+        } => StatusCode::BAD_GATEWAY,
+        FnError::GitError {
+            source: GitError::GitTimedOut { .. },
+        } => StatusCode::GATEWAY_TIMEOUT,
+        // A library that doesn't load is a well-formed upload with unusable contents.
+        FnError::LoadError { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        FnError::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+        FnError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        FnError::CallTimedOut { .. } => StatusCode::GATEWAY_TIMEOUT,
+        FnError::ShuttingDown | FnError::AsyncQueueFull { .. } | FnError::StepQueueFull { .. } => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        FnError::DeployInProgress { .. } => StatusCode::CONFLICT,
+        FnError::UnknownBuild { .. }
+        | FnError::UnknownDeployment { .. }
+        | FnError::UnknownVersion { .. }
+        | FnError::SourceNotStored { .. } => StatusCode::NOT_FOUND,
+        FnError::SchedulerError {
+            source:
+                SchedulerError::InvalidCronExpression { .. } | SchedulerError::CronNeverFires { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::SchedulerError {
+            source: SchedulerError::UnknownSchedule { .. },
+        } => StatusCode::NOT_FOUND,
+        FnError::PipelineError {
+            source: PipelineError::InvalidPipeline { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::PipelineError {
+            source: PipelineError::UnknownPipeline { .. },
+        } => StatusCode::NOT_FOUND,
+        FnError::RouteError {
+            source: RouteError::InvalidRoute { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::RouteError {
+            source: RouteError::RouteConflict { .. },
+        } => StatusCode::CONFLICT,
+        FnError::SecretsError {
+            source: SecretsError::InvalidSecret { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::SecretsError {
+            source: SecretsError::UnknownSecret { .. },
+        } => StatusCode::NOT_FOUND,
+        FnError::SecretsError {
+            source: SecretsError::MissingMasterKey,
+        } => StatusCode::SERVICE_UNAVAILABLE,
+        FnError::NamespaceError {
+            source: NamespaceError::InvalidNamespace { .. },
+        } => StatusCode::BAD_REQUEST,
+        FnError::NamespaceError {
+            source: NamespaceError::UnknownNamespace { .. },
+        } => StatusCode::NOT_FOUND,
+        FnError::NamespaceError {
+            source: NamespaceError::Unauthorized { .. },
+        } => StatusCode::UNAUTHORIZED,
+        FnError::NamespaceError {
+            source: NamespaceError::NamespaceNotEmpty { .. },
+        } => StatusCode::CONFLICT,
+        FnError::NamespaceError {
+            source:
+                NamespaceError::FunctionQuotaExceeded { .. } | NamespaceError::DiskQuotaExceeded { .. },
+        } => StatusCode::FORBIDDEN,
+        FnError::NamespaceError {
+            source: NamespaceError::BuildQuotaExceeded { .. },
+        } => StatusCode::TOO_MANY_REQUESTS,
+        FnError::BuildCacheError {
+            source: BuildCacheError::CacheInUse { .. },
+        } => StatusCode::CONFLICT,
+        FnError::BuildCacheError {
+            source: BuildCacheError::UnknownCacheEntry { .. },
+        } => StatusCode::NOT_FOUND,
+        FnError::PipelineStepFailed { source, .. } => get_status_code(source),
+        FnError::TooManyInFlight { .. } | FnError::RateLimited { .. } => {
+            StatusCode::TOO_MANY_REQUESTS
+        }
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
+/// Returns the number of seconds after which a rejected call can be retried, if applicable.
+pub fn get_retry_after(err: &FnError) -> Option<u64> {
+    match err {
+        // Concurrency slots free up as soon as a call completes, so there is no exact wait.
+        FnError::TooManyInFlight { .. }
+        | FnError::AsyncQueueFull { .. }
+        | FnError::StepQueueFull { .. } => Some(1),
+        FnError::PipelineStepFailed { source, .. } => get_retry_after(source),
+        FnError::RateLimited { retry_after, .. } => {
+            let secs = retry_after.as_secs();
+            Some(if retry_after.subsec_nanos() > 0 {
+                secs + 1
+            } else {
+                secs
+            })
+        }
+        _ => None,
+    }
+}
+
 pub async fn handle_error(rejection: Rejection) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(err) = rejection.find::<FnError>() {
-        Ok(warp::reply::with_status(
+        let mut resp = warp::reply::with_status(
             warp::reply::json(&get_serializable(err)),
             get_status_code(err),
-        ))
+        )
+        .into_response();
+
+        if let Some(retry_after) = get_retry_after(err) {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        Ok(resp)
+    } else if let Some(PayloadTooLarge { limit }) = rejection.find::<PayloadTooLarge>() {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage::new(&format!(
+                "Request body exceeds the {} byte limit",
+                limit
+            ))),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )
+        .into_response())
     } else {
         Err(rejection)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn rate_limited(retry_after: Duration) -> FnError {
+        FnError::RateLimited {
+            name: String::from("f"),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let cases = [
+            (Duration::from_secs(0), 0),
+            (Duration::from_millis(1), 1),
+            (Duration::from_millis(999), 1),
+            (Duration::from_secs(1), 1),
+            (Duration::from_millis(1500), 2),
+            (Duration::from_secs(30), 30),
+        ];
+        for &(retry_after, expected) in cases.iter() {
+            assert_eq!(
+                get_retry_after(&rate_limited(retry_after)),
+                Some(expected),
+                "{:?}",
+                retry_after
+            );
+        }
+    }
+
+    #[test]
+    fn retry_after_of_other_errors() {
+        let too_many = FnError::TooManyInFlight {
+            name: String::from("f"),
+        };
+        assert_eq!(get_retry_after(&too_many), Some(1));
+
+        let queue_full = FnError::AsyncQueueFull {
+            name: String::from("f"),
+        };
+        assert_eq!(get_retry_after(&queue_full), Some(1));
+        assert_eq!(
+            get_status_code(&queue_full),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let steps_full = FnError::StepQueueFull {
+            name: String::from("f"),
+        };
+        assert_eq!(get_retry_after(&steps_full), Some(1));
+        assert_eq!(
+            get_status_code(&steps_full),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let step = FnError::PipelineStepFailed {
+            pipeline: String::from("p"),
+            step: 2,
+            function: String::from("f"),
+            source: Box::new(rate_limited(Duration::from_millis(2500))),
+        };
+        assert_eq!(get_retry_after(&step), Some(3));
+
+        assert_eq!(get_retry_after(&FnError::ShuttingDown), None);
+    }
+}
@@ -18,9 +18,6 @@ pub fn unzip(zip_file: &Path, tgt_dir: &Path) -> io::Result<()> {
         Ok(())
     } else {
         let code = status.code().unwrap_or(1);
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("exited with status {}", code),
-        ))
+        Err(io::Error::other(format!("exited with status {}", code)))
     }
 }
@@ -1,33 +1,208 @@
+// Snafu context selectors are named after their variants, which all end in `Error`.
+#![allow(clippy::enum_variant_names)]
+
+use std::env;
+use std::fs;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
 use std::time::Duration;
 
 use env_logger::Env;
 
+use tokio::signal::unix::{signal, SignalKind};
+
 mod server;
-use server::Server;
+use server::{Server, ShutdownOptions};
 
 mod funcky;
-use funcky::{Config, FunckManager};
+use funcky::{
+    BuildOptions, Config, FunckManager, NetworkMode, QueuedBuilds, RegistrySource, SandboxConfig,
+};
+
+mod telemetry;
+use telemetry::ExportTarget;
 
 const SO_DIR: &str = "./shared_object";
+const STATE_DIR: &str = "./state";
+const BUILD_CACHE_DIR: &str = "./build_cache";
+
+const OTLP_ENDPOINT_ENV: &str = "FUNCKY_OTLP_ENDPOINT";
+const TRACE_FILE_ENV: &str = "FUNCKY_TRACE_FILE";
+
+const SECRETS_KEY_ENV: &str = "FUNCKY_SECRETS_KEY";
+const SECRETS_KEY_FILE_ENV: &str = "FUNCKY_SECRETS_KEY_FILE";
+
+const INVOCATION_RETENTION_ENV: &str = "FUNCKY_INVOCATION_RETENTION_SECS";
+const DEFAULT_INVOCATION_RETENTION_SECS: u64 = 3600;
+const MAX_INVOCATIONS_ENV: &str = "FUNCKY_MAX_INVOCATIONS";
+const DEFAULT_MAX_INVOCATIONS: u64 = 10_000;
+const ASYNC_WORKERS_ENV: &str = "FUNCKY_ASYNC_WORKERS";
+const DEFAULT_ASYNC_WORKERS: u64 = 8;
+const ASYNC_QUEUE_CAPACITY_ENV: &str = "FUNCKY_ASYNC_QUEUE_CAPACITY";
+const DEFAULT_ASYNC_QUEUE_CAPACITY: u64 = 256;
+const STEP_WORKERS_ENV: &str = "FUNCKY_STEP_WORKERS";
+const DEFAULT_STEP_WORKERS: u64 = 16;
+const STEP_QUEUE_CAPACITY_ENV: &str = "FUNCKY_STEP_QUEUE_CAPACITY";
+const DEFAULT_STEP_QUEUE_CAPACITY: u64 = 64;
+
+const BUILD_CACHE_MAX_BYTES_ENV: &str = "FUNCKY_BUILD_CACHE_MAX_BYTES";
+const DEFAULT_BUILD_CACHE_MAX_BYTES: u64 = 10 * 1024 * 1024 * 1024;
+const CARGO_HOME_ENV: &str = "FUNCKY_CARGO_HOME";
+
+const LOCAL_REGISTRY_ENV: &str = "FUNCKY_LOCAL_REGISTRY";
+const DIRECTORY_SOURCE_ENV: &str = "FUNCKY_DIRECTORY_SOURCE";
+const BUILD_NETWORK_ENV: &str = "FUNCKY_BUILD_NETWORK";
+
+const BUILD_CPU_SECS_ENV: &str = "FUNCKY_BUILD_CPU_SECS";
+const DEFAULT_BUILD_CPU_SECS: u64 = 600;
+const BUILD_MEMORY_BYTES_ENV: &str = "FUNCKY_BUILD_MEMORY_BYTES";
+const DEFAULT_BUILD_MEMORY_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+const BUILD_FILE_SIZE_BYTES_ENV: &str = "FUNCKY_BUILD_FILE_SIZE_BYTES";
+const DEFAULT_BUILD_FILE_SIZE_BYTES: u64 = 1024 * 1024 * 1024;
+const BUILD_TIMEOUT_SECS_ENV: &str = "FUNCKY_BUILD_TIMEOUT_SECS";
+const DEFAULT_BUILD_TIMEOUT_SECS: u64 = 900;
+const BUILD_ISOLATION_ENV: &str = "FUNCKY_BUILD_ISOLATION";
+
+const SOURCE_RETENTION_ENV: &str = "FUNCKY_SOURCE_RETENTION";
+const DEFAULT_SOURCE_RETENTION: u64 = 5;
 
-fn block_til_ctrlc() {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+const SHUTDOWN_DRAIN_SECS_ENV: &str = "FUNCKY_SHUTDOWN_DRAIN_SECS";
+const DEFAULT_SHUTDOWN_DRAIN_SECS: u64 = 30;
+const SHUTDOWN_BUILDS_ENV: &str = "FUNCKY_SHUTDOWN_BUILDS";
+const SHUTDOWN_BUILD_WAIT_SECS_ENV: &str = "FUNCKY_SHUTDOWN_BUILD_WAIT_SECS";
+const DEFAULT_SHUTDOWN_BUILD_WAIT_SECS: u64 = 60;
 
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C Handler");
+async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("SIGINT received"),
+        _ = terminate.recv() => log::info!("SIGTERM received"),
+    }
+}
 
-    while running.load(Ordering::SeqCst) {
-        thread::sleep(Duration::from_millis(500));
+fn trace_export_target() -> ExportTarget {
+    if let Ok(endpoint) = env::var(OTLP_ENDPOINT_ENV) {
+        ExportTarget::Otlp(endpoint)
+    } else if let Ok(path) = env::var(TRACE_FILE_ENV) {
+        ExportTarget::File(PathBuf::from(path))
+    } else {
+        ExportTarget::Disabled
     }
 }
 
+fn invocation_retention() -> Duration {
+    let secs = env::var(INVOCATION_RETENTION_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INVOCATION_RETENTION_SECS);
+    Duration::from_secs(secs)
+}
+
+fn build_cache_max_bytes() -> u64 {
+    env::var(BUILD_CACHE_MAX_BYTES_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BUILD_CACHE_MAX_BYTES)
+}
+
+fn env_u64(var: &str, default: u64) -> u64 {
+    env::var(var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn sandbox_config() -> SandboxConfig {
+    SandboxConfig {
+        cpu_secs: env_u64(BUILD_CPU_SECS_ENV, DEFAULT_BUILD_CPU_SECS),
+        memory_bytes: env_u64(BUILD_MEMORY_BYTES_ENV, DEFAULT_BUILD_MEMORY_BYTES),
+        file_size_bytes: env_u64(BUILD_FILE_SIZE_BYTES_ENV, DEFAULT_BUILD_FILE_SIZE_BYTES),
+        timeout: Duration::from_secs(env_u64(BUILD_TIMEOUT_SECS_ENV, DEFAULT_BUILD_TIMEOUT_SECS)),
+        isolate: matches!(
+            env::var(BUILD_ISOLATION_ENV).as_deref(),
+            Ok("1") | Ok("true")
+        ),
+    }
+}
+
+fn build_options() -> BuildOptions {
+    let registry = if let Ok(path) = env::var(LOCAL_REGISTRY_ENV) {
+        Some(RegistrySource::Local(PathBuf::from(path)))
+    } else if let Ok(path) = env::var(DIRECTORY_SOURCE_ENV) {
+        Some(RegistrySource::Directory(PathBuf::from(path)))
+    } else {
+        None
+    };
+
+    // A mirror is only useful if cargo doesn't go around it.
+    let default_network = if registry.is_some() {
+        NetworkMode::Offline
+    } else {
+        NetworkMode::Online
+    };
+    let network = match env::var(BUILD_NETWORK_ENV).as_deref() {
+        Ok("online") => NetworkMode::Online,
+        Ok("offline") => NetworkMode::Offline,
+        Ok("frozen") => NetworkMode::Frozen,
+        Ok(other) => {
+            log::error!(
+                "invalid {} [{}], expected online, offline or frozen",
+                BUILD_NETWORK_ENV,
+                other
+            );
+            default_network
+        }
+        Err(_) => default_network,
+    };
+
+    BuildOptions {
+        registry,
+        network,
+        sandbox: sandbox_config(),
+    }
+}
+
+fn shutdown_options() -> ShutdownOptions {
+    let queued_builds = match env::var(SHUTDOWN_BUILDS_ENV).as_deref() {
+        Ok("finish") => QueuedBuilds::Finish,
+        Ok("persist") | Err(_) => QueuedBuilds::Persist,
+        Ok(other) => {
+            log::error!(
+                "invalid {} [{}], expected finish or persist",
+                SHUTDOWN_BUILDS_ENV,
+                other
+            );
+            QueuedBuilds::Persist
+        }
+    };
+
+    ShutdownOptions {
+        drain_timeout: Duration::from_secs(env_u64(
+            SHUTDOWN_DRAIN_SECS_ENV,
+            DEFAULT_SHUTDOWN_DRAIN_SECS,
+        )),
+        queued_builds,
+        build_wait: Duration::from_secs(env_u64(
+            SHUTDOWN_BUILD_WAIT_SECS_ENV,
+            DEFAULT_SHUTDOWN_BUILD_WAIT_SECS,
+        )),
+    }
+}
+
+/// The configured master key. A key file that can't be read is an error, rather than starting
+/// without secrets.
+fn secrets_key() -> Result<Option<String>, String> {
+    if let Ok(key) = env::var(SECRETS_KEY_ENV) {
+        return Ok(Some(key));
+    }
+    let path = match env::var(SECRETS_KEY_FILE_ENV) {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    fs::read_to_string(&path)
+        .map(Some)
+        .map_err(|e| format!("failed to read secrets key file [{}]: {}", path, e))
+}
+
 fn init_logger() {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 }
@@ -35,9 +210,31 @@ fn init_logger() {
 #[tokio::main]
 async fn main() {
     init_logger();
+    let secrets_key = match secrets_key() {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
     let config = Config {
         shared_object_directory: PathBuf::from(SO_DIR),
         tmp_dir: PathBuf::from("build_tmp"),
+        state_directory: PathBuf::from(STATE_DIR),
+        trace_export: trace_export_target(),
+        invocation_retention: invocation_retention(),
+        max_invocations: env_u64(MAX_INVOCATIONS_ENV, DEFAULT_MAX_INVOCATIONS) as usize,
+        async_workers: env_u64(ASYNC_WORKERS_ENV, DEFAULT_ASYNC_WORKERS) as usize,
+        async_queue_capacity: env_u64(ASYNC_QUEUE_CAPACITY_ENV, DEFAULT_ASYNC_QUEUE_CAPACITY)
+            as usize,
+        step_workers: env_u64(STEP_WORKERS_ENV, DEFAULT_STEP_WORKERS) as usize,
+        step_queue_capacity: env_u64(STEP_QUEUE_CAPACITY_ENV, DEFAULT_STEP_QUEUE_CAPACITY) as usize,
+        secrets_key,
+        build_cache_directory: PathBuf::from(BUILD_CACHE_DIR),
+        build_cache_max_bytes: build_cache_max_bytes(),
+        cargo_home: env::var(CARGO_HOME_ENV).ok().map(PathBuf::from),
+        build_options: build_options(),
+        source_retention: env_u64(SOURCE_RETENTION_ENV, DEFAULT_SOURCE_RETENTION) as usize,
     };
 
     let r_manager = FunckManager::new(config);
@@ -48,6 +245,7 @@ async fn main() {
 
     let mut manager = r_manager.unwrap();
     manager.start().unwrap();
+    let tracer = manager.tracer();
 
     log::info!("server starting up...");
     let mut server = Server::new(manager);
@@ -57,11 +255,12 @@ async fn main() {
     }
 
     log::info!("HTTP server started");
-    block_til_ctrlc();
+    wait_for_signal().await;
 
     log::info!("exit signal received, waiting for server to terminate...");
-    if let Err(e) = server.stop().await {
+    if let Err(e) = server.stop(shutdown_options()).await {
         log::error!("{}", e);
     }
+    tracer.shutdown();
     log::info!("goodbye");
 }
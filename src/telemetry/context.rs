@@ -0,0 +1,155 @@
+use std::fmt;
+
+const TRACEPARENT_VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// Identifies a span within a trace, as propagated by the W3C `traceparent` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl SpanContext {
+    /// Creates the context of a new root span.
+    pub fn new_root() -> SpanContext {
+        SpanContext {
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            flags: FLAG_SAMPLED,
+        }
+    }
+
+    /// Creates the context of a new span that is a child of this one.
+    pub fn new_child(&self) -> SpanContext {
+        SpanContext {
+            trace_id: self.trace_id,
+            span_id: rand::random(),
+            flags: self.flags,
+        }
+    }
+
+    /// Parses a `traceparent` header value (`00-<trace_id>-<span_id>-<flags>`).
+    pub fn from_traceparent(header: &str) -> Option<SpanContext> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        if parts.len() < 4 || parts[0] != TRACEPARENT_VERSION {
+            return None;
+        }
+
+        let mut trace_id = [0; 16];
+        let mut span_id = [0; 8];
+        decode_hex(parts[1], &mut trace_id)?;
+        decode_hex(parts[2], &mut span_id)?;
+
+        let mut flags = [0; 1];
+        decode_hex(parts[3], &mut flags)?;
+
+        // All-zero identifiers are invalid per the spec.
+        if trace_id.iter().all(|b| *b == 0) || span_id.iter().all(|b| *b == 0) {
+            return None;
+        }
+
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            flags: flags[0],
+        })
+    }
+
+    pub fn to_traceparent(self) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            TRACEPARENT_VERSION,
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.flags
+        )
+    }
+}
+
+impl fmt::Display for SpanContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_traceparent())
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(src: &str, dst: &mut [u8]) -> Option<()> {
+    if src.len() != dst.len() * 2 {
+        return None;
+    }
+
+    for (i, byte) in dst.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(src.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn traceparent_is_parsed() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(
+            encode_hex(&context.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(encode_hex(&context.span_id), "b7ad6b7169203331");
+        assert_eq!(context.flags, FLAG_SAMPLED);
+
+        // Surrounding whitespace is ignored.
+        let padded = format!(" {}\t", TRACEPARENT);
+        assert_eq!(SpanContext::from_traceparent(&padded), Some(context));
+    }
+
+    #[test]
+    fn traceparent_round_trips() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+        assert_eq!(context.to_string(), TRACEPARENT);
+
+        let root = SpanContext::new_root();
+        assert_eq!(
+            SpanContext::from_traceparent(&root.to_traceparent()),
+            Some(root)
+        );
+    }
+
+    #[test]
+    fn malformed_traceparent_is_rejected() {
+        let cases = [
+            "",
+            "00",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-1",
+            "00-0af7651916cd43dd8448eb211c8031zz-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c8031é-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+        ];
+        for header in cases.iter() {
+            assert_eq!(SpanContext::from_traceparent(header), None, "{}", header);
+        }
+    }
+
+    #[test]
+    fn child_continues_the_trace() {
+        let parent = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        let child = parent.new_child();
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_eq!(child.flags, parent.flags);
+        assert_ne!(child.span_id, parent.span_id);
+    }
+}
@@ -0,0 +1,340 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use snafu::{ResultExt, Snafu};
+
+use super::context::encode_hex;
+use super::span::{unix_nanos, SpanData, SpanKind};
+
+const MAX_BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+const OTLP_TRACES_PATH: &str = "/v1/traces";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to open trace file [{}]: {}", path.display(), source))]
+    FailedToOpenTraceFile { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Failed to write spans to trace file: {}", source))]
+    FailedToWriteSpans { source: io::Error },
+
+    #[snafu(display("Failed to send spans to collector: {}", source))]
+    FailedToSendSpans { source: reqwest::Error },
+
+    #[snafu(display("Collector rejected spans with status {}", status))]
+    CollectorRejectedSpans { status: u16 },
+}
+
+/// Where finished spans are sent.
+#[derive(Clone, Debug)]
+pub enum ExportTarget {
+    /// Spans are discarded.
+    Disabled,
+
+    /// Spans are sent as OTLP/HTTP JSON to the collector at this base URL (e.g. `http://localhost:4318`).
+    Otlp(String),
+
+    /// Spans are appended to this file, one OTLP JSON document per line.
+    File(PathBuf),
+}
+
+enum Sink {
+    Otlp {
+        // Built lazily on the exporter thread, since the blocking client can't be created or
+        // dropped from within the async runtime.
+        client: Option<reqwest::blocking::Client>,
+        url: String,
+    },
+    File(File),
+}
+
+impl Sink {
+    fn open(target: &ExportTarget) -> Result<Option<Sink>, Error> {
+        match target {
+            ExportTarget::Disabled => Ok(None),
+            ExportTarget::Otlp(endpoint) => Ok(Some(Sink::Otlp {
+                client: None,
+                url: format!("{}{}", endpoint.trim_end_matches('/'), OTLP_TRACES_PATH),
+            })),
+            ExportTarget::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(FailedToOpenTraceFile { path: path.clone() })?;
+                Ok(Some(Sink::File(file)))
+            }
+        }
+    }
+
+    fn write(&mut self, document: &Value) -> Result<(), Error> {
+        match self {
+            Sink::Otlp { client, url } => {
+                let resp = client
+                    .get_or_insert_with(reqwest::blocking::Client::new)
+                    .post(url.as_str())
+                    .header("Content-Type", "application/json")
+                    .body(document.to_string())
+                    .send()
+                    .context(FailedToSendSpans)?;
+                snafu::ensure!(
+                    resp.status().is_success(),
+                    CollectorRejectedSpans {
+                        status: resp.status().as_u16()
+                    }
+                );
+                Ok(())
+            }
+            Sink::File(file) => {
+                writeln!(file, "{}", document).context(FailedToWriteSpans)?;
+                file.flush().context(FailedToWriteSpans)
+            }
+        }
+    }
+}
+
+pub(super) struct Exporter {
+    sink: Sink,
+    service_name: String,
+}
+
+impl Exporter {
+    pub fn new(target: &ExportTarget, service_name: &str) -> Result<Option<Exporter>, Error> {
+        Ok(Sink::open(target)?.map(|sink| Exporter {
+            sink,
+            service_name: String::from(service_name),
+        }))
+    }
+
+    /// Batches incoming spans and flushes them periodically, until the channel is closed.
+    pub fn run(mut self, span_rx: mpsc::Receiver<SpanData>) {
+        let mut batch = Vec::new();
+        loop {
+            match span_rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(span) => {
+                    batch.push(span);
+                    if batch.len() < MAX_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.flush(&mut batch);
+                    break;
+                }
+            }
+            self.flush(&mut batch);
+        }
+        log::debug!("span exporter stopped");
+    }
+
+    fn flush(&mut self, batch: &mut Vec<SpanData>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let document = self.encode(batch);
+        if let Err(e) = self.sink.write(&document) {
+            log::warn!("dropped {} spans: {}", batch.len(), e);
+        }
+        batch.clear();
+    }
+
+    fn encode(&self, batch: &[SpanData]) -> Value {
+        let spans: Vec<Value> = batch.iter().map(encode_span).collect();
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [string_attribute("service.name", &self.service_name)]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans
+                }]
+            }]
+        })
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn encode_span(span: &SpanData) -> Value {
+    let kind = match span.kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+    };
+
+    // Status codes: 0 = unset, 2 = error.
+    let status = match &span.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 0 }),
+    };
+
+    let mut encoded = json!({
+        "traceId": encode_hex(&span.context.trace_id),
+        "spanId": encode_hex(&span.context.span_id),
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(span.start_time).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end_time).to_string(),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(k, v)| string_attribute(k, v))
+            .collect::<Vec<Value>>(),
+        "status": status,
+    });
+
+    if let Some(parent) = &span.parent_span_id {
+        encoded["parentSpanId"] = Value::String(encode_hex(parent));
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::super::{SpanContext, Tracer};
+    use super::*;
+
+    /// Answers one OTLP request with `status`, returning the request's path and body.
+    fn serve_once(status: &'static str) -> (String, thread::JoinHandle<(String, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = String::from(request_line.split(' ').nth(1).unwrap());
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                let name = header.next().unwrap();
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = header.next().unwrap().trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            (path, serde_json::from_slice(&body).unwrap())
+        });
+        (endpoint, handle)
+    }
+
+    fn record_span(tracer: &Arc<Tracer>) -> SpanContext {
+        let parent = SpanContext::new_root();
+        let mut span = tracer.start_server_span("deploy", Some(&parent));
+        span.set_attribute("funcky.function", "echo");
+        span.set_error("build failed");
+        span.end();
+        parent
+    }
+
+    fn only_span(document: &Value) -> &Value {
+        let spans = document["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 1);
+        &spans[0]
+    }
+
+    #[test]
+    fn spans_are_exported_to_collector() {
+        let (endpoint, collector) = serve_once("200 OK");
+        let tracer = Arc::new(Tracer::new(&ExportTarget::Otlp(endpoint + "/"), "funcky").unwrap());
+
+        let parent = record_span(&tracer);
+        tracer.shutdown();
+
+        let (path, document) = collector.join().unwrap();
+        assert_eq!(path, OTLP_TRACES_PATH);
+        assert_eq!(
+            document["resourceSpans"][0]["resource"]["attributes"][0],
+            string_attribute("service.name", "funcky")
+        );
+
+        let span = only_span(&document);
+        assert_eq!(span["name"], "deploy");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["traceId"], encode_hex(&parent.trace_id));
+        assert_eq!(span["parentSpanId"], encode_hex(&parent.span_id));
+        assert_eq!(
+            span["attributes"][0],
+            string_attribute("funcky.function", "echo")
+        );
+        assert_eq!(
+            span["status"],
+            json!({ "code": 2, "message": "build failed" })
+        );
+    }
+
+    #[test]
+    fn collector_errors_are_reported() {
+        let (endpoint, collector) = serve_once("500 Internal Server Error");
+        let mut sink = Sink::open(&ExportTarget::Otlp(endpoint)).unwrap().unwrap();
+
+        let result = sink.write(&json!({}));
+        collector.join().unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::CollectorRejectedSpans { status: 500 })
+        ));
+    }
+
+    #[test]
+    fn spans_are_appended_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spans.jsonl");
+
+        for _ in 0..2 {
+            let tracer =
+                Arc::new(Tracer::new(&ExportTarget::File(path.clone()), "funcky").unwrap());
+            record_span(&tracer);
+            tracer.shutdown();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let documents: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(documents.len(), 2);
+        for document in documents.iter() {
+            assert_eq!(only_span(document)["name"], "deploy");
+        }
+    }
+
+    #[test]
+    fn disabled_tracer_exports_nothing() {
+        assert!(Exporter::new(&ExportTarget::Disabled, "funcky")
+            .unwrap()
+            .is_none());
+    }
+}
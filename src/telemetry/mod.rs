@@ -0,0 +1,12 @@
+mod context;
+mod exporter;
+mod span;
+mod tracer;
+
+// === Public Exports ===
+pub use context::SpanContext;
+pub use exporter::{Error, ExportTarget};
+pub use tracer::Tracer;
+
+/// Name of the W3C trace context propagation header.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{SpanContext, Tracer};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+}
+
+/// A finished span, ready to be exported.
+#[derive(Clone, Debug)]
+pub struct SpanData {
+    pub name: String,
+    pub kind: SpanKind,
+    pub context: SpanContext,
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start_time: SystemTime,
+    pub end_time: SystemTime,
+    pub attributes: Vec<(String, String)>,
+    pub error: Option<String>,
+}
+
+/// A span in progress. The span is recorded when it is ended or dropped.
+pub struct Span {
+    data: Option<SpanData>,
+    tracer: Arc<Tracer>,
+}
+
+impl Span {
+    pub(super) fn new(
+        tracer: Arc<Tracer>,
+        name: &str,
+        kind: SpanKind,
+        parent: Option<&SpanContext>,
+        start_time: SystemTime,
+    ) -> Span {
+        let (context, parent_span_id) = match parent {
+            Some(p) => (p.new_child(), Some(p.span_id)),
+            None => (SpanContext::new_root(), None),
+        };
+
+        Span {
+            data: Some(SpanData {
+                name: String::from(name),
+                kind,
+                context,
+                parent_span_id,
+                start_time,
+                end_time: start_time,
+                attributes: Vec::new(),
+                error: None,
+            }),
+            tracer,
+        }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        // Data is only taken out when the span is recorded.
+        self.data.as_ref().unwrap().context
+    }
+
+    pub fn set_attribute<T: ToString>(&mut self, key: &str, value: T) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((String::from(key), value.to_string()));
+        }
+    }
+
+    pub fn set_error<T: ToString>(&mut self, error: T) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = Some(error.to_string());
+        }
+    }
+
+    pub fn end(mut self) {
+        self.record();
+    }
+
+    fn record(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end_time = SystemTime::now();
+            self.tracer.export(data);
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.record();
+    }
+}
+
+pub fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
@@ -0,0 +1,87 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use super::exporter::{Error, ExportTarget, Exporter};
+use super::span::{Span, SpanData, SpanKind};
+use super::SpanContext;
+
+struct ExportHandle {
+    span_tx: mpsc::Sender<SpanData>,
+    handle: thread::JoinHandle<()>,
+}
+
+/// The Tracer creates spans and forwards them to a background exporter thread.
+pub struct Tracer {
+    export_handle: Mutex<Option<ExportHandle>>,
+}
+
+impl Tracer {
+    pub fn new(target: &ExportTarget, service_name: &str) -> Result<Tracer, Error> {
+        let export_handle = Exporter::new(target, service_name)?.map(|exporter| {
+            let (span_tx, span_rx) = mpsc::channel();
+            let handle = thread::spawn(move || exporter.run(span_rx));
+            ExportHandle { span_tx, handle }
+        });
+
+        Ok(Tracer {
+            export_handle: Mutex::new(export_handle),
+        })
+    }
+
+    /// Starts an internal span, as a child of `parent` if provided.
+    pub fn start_span(self: &Arc<Self>, name: &str, parent: Option<&SpanContext>) -> Span {
+        Span::new(
+            self.clone(),
+            name,
+            SpanKind::Internal,
+            parent,
+            SystemTime::now(),
+        )
+    }
+
+    /// Starts a span for an incoming request, continuing the caller's trace if provided.
+    pub fn start_server_span(self: &Arc<Self>, name: &str, parent: Option<&SpanContext>) -> Span {
+        Span::new(
+            self.clone(),
+            name,
+            SpanKind::Server,
+            parent,
+            SystemTime::now(),
+        )
+    }
+
+    /// Starts an internal span that began at an earlier point in time.
+    pub fn start_span_at(
+        self: &Arc<Self>,
+        name: &str,
+        parent: Option<&SpanContext>,
+        start_time: SystemTime,
+    ) -> Span {
+        Span::new(self.clone(), name, SpanKind::Internal, parent, start_time)
+    }
+
+    pub(super) fn export(&self, span: SpanData) {
+        if let Ok(guard) = self.export_handle.lock() {
+            if let Some(export_handle) = guard.as_ref() {
+                // The exporter only goes away on shutdown, spans ending after that are dropped.
+                export_handle.span_tx.send(span).ok();
+            }
+        }
+    }
+
+    /// Flushes pending spans and stops the exporter thread.
+    pub fn shutdown(&self) {
+        let export_handle = match self.export_handle.lock() {
+            Ok(mut guard) => guard.take(),
+            Err(_) => None,
+        };
+
+        if let Some(export_handle) = export_handle {
+            drop(export_handle.span_tx);
+            if export_handle.handle.join().is_err() {
+                log::error!("span exporter thread panicked");
+            }
+        }
+    }
+}